@@ -0,0 +1,15 @@
+fn main() {
+    // A bundled protoc, so building does not depend on one being installed.
+    std::env::set_var(
+        "PROTOC",
+        protoc_bin_vendored::protoc_bin_path().expect("no bundled protoc for this platform"),
+    );
+    std::env::set_var(
+        "PROTOC_INCLUDE",
+        protoc_bin_vendored::include_path().expect("no bundled protobuf includes"),
+    );
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/xss_check_srv.proto"], &["proto"])
+        .expect("failed to compile proto/xss_check_srv.proto");
+}
@@ -0,0 +1,13 @@
+CREATE TABLE notifications (
+    id BIGSERIAL PRIMARY KEY,
+    token TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    source TEXT,
+    received_at TIMESTAMPTZ NOT NULL,
+    -- Still buffered, waiting for a poller.
+    pending BOOLEAN NOT NULL DEFAULT TRUE
+);
+
+CREATE INDEX notifications_token ON notifications (token, received_at);
+CREATE INDEX notifications_received_at ON notifications (received_at);
+CREATE INDEX notifications_pending ON notifications (id) WHERE pending;
@@ -0,0 +1,3 @@
+-- Request metadata (user agent, referer, captured headers).
+-- `source` now holds just the client IP.
+ALTER TABLE notifications ADD COLUMN meta JSONB;
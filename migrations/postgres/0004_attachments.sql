@@ -0,0 +1,11 @@
+-- Files sent as multipart parts along with a hit (screenshots, DOM dumps).
+CREATE TABLE attachments (
+    id BIGSERIAL PRIMARY KEY,
+    notification_id BIGINT NOT NULL REFERENCES notifications (id),
+    name TEXT NOT NULL,
+    filename TEXT,
+    content_type TEXT,
+    data BYTEA NOT NULL
+);
+
+CREATE INDEX attachments_notification ON attachments (notification_id);
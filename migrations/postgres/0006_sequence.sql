@@ -0,0 +1,10 @@
+-- Stable ids and per token sequence numbers, backfilled for existing hits.
+ALTER TABLE notifications ADD COLUMN uuid UUID;
+ALTER TABLE notifications ADD COLUMN seq BIGINT;
+
+UPDATE notifications SET uuid = gen_random_uuid();
+UPDATE notifications SET seq = numbered.seq
+FROM (SELECT id, row_number() OVER (PARTITION BY token ORDER BY id) AS seq FROM notifications) AS numbered
+WHERE numbered.id = notifications.id;
+
+CREATE INDEX notifications_seq ON notifications (token, seq);
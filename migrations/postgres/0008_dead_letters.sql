@@ -0,0 +1,9 @@
+-- Webhook deliveries that failed every try, kept until re-driven or deleted.
+CREATE TABLE dead_letters (
+    id BIGSERIAL PRIMARY KEY,
+    url TEXT NOT NULL,
+    notification JSONB NOT NULL,
+    attempts BIGINT NOT NULL,
+    last_error TEXT NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL
+);
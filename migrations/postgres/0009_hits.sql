@@ -0,0 +1,2 @@
+-- Identical hits collapsed into one record by dedup.
+ALTER TABLE notifications ADD COLUMN hits BIGINT NOT NULL DEFAULT 1;
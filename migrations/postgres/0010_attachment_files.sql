@@ -0,0 +1,3 @@
+-- Attachments kept as files under attachments.dir, named by their SHA-256.
+ALTER TABLE attachments ADD COLUMN file_id TEXT, ADD COLUMN size BIGINT NOT NULL DEFAULT 0;
+UPDATE attachments SET size = octet_length(data);
@@ -0,0 +1,11 @@
+CREATE TABLE notifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    token TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    source TEXT,
+    received_at TEXT NOT NULL,
+    -- Still buffered, waiting for a poller.
+    pending INTEGER NOT NULL DEFAULT 1
+);
+
+CREATE INDEX notifications_pending ON notifications (pending, id);
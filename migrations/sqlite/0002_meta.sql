@@ -0,0 +1,3 @@
+-- Request metadata (user agent, referer, captured headers) as JSON.
+-- `source` now holds just the client IP.
+ALTER TABLE notifications ADD COLUMN meta TEXT;
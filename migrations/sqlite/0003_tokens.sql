@@ -0,0 +1,5 @@
+-- Registered tokens, `info` holds the serialized settings.
+CREATE TABLE tokens (
+    token TEXT PRIMARY KEY,
+    info TEXT NOT NULL
+);
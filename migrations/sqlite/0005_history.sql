@@ -0,0 +1,2 @@
+-- Paging through a token's hits for the history API.
+CREATE INDEX notifications_token ON notifications (token, received_at);
@@ -0,0 +1,3 @@
+-- Filtering the history API by client IP. Token and time are covered by
+-- notifications_token, text matches scan the token's rows.
+CREATE INDEX notifications_source ON notifications (token, source);
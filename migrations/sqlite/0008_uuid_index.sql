@@ -0,0 +1,2 @@
+-- Notifications are looked up by uuid for the links notifiers post.
+CREATE UNIQUE INDEX notifications_uuid ON notifications (uuid);
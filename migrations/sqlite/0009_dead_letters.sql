@@ -0,0 +1,9 @@
+-- Webhook deliveries that failed every try, kept until re-driven or deleted.
+CREATE TABLE dead_letters (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    notification TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT NOT NULL,
+    failed_at TEXT NOT NULL
+);
@@ -0,0 +1,4 @@
+-- Attachments kept as files under attachments.dir, named by their SHA-256.
+ALTER TABLE attachments ADD COLUMN file_id TEXT;
+ALTER TABLE attachments ADD COLUMN size INTEGER NOT NULL DEFAULT 0;
+UPDATE attachments SET size = length(data);
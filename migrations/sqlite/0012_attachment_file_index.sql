@@ -0,0 +1,2 @@
+-- Files are looked up by id to check who may download them.
+CREATE INDEX attachments_file_id ON attachments (file_id);
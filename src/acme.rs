@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context, Error};
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{header, uri::Authority, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::get,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, OrderStatus,
+};
+use rcgen::{Certificate, CertificateParams, DistinguishedName};
+use tracing::error;
+
+use crate::{config::AcmeConfig, AppState};
+
+/// Pending HTTP-01 challenges, token to key authorization.
+pub type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+/// Certificates older than this are renewed. Let's Encrypt issues for 90 days.
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often to ask for the issued certificate before giving the order up.
+const CERTIFICATE_ATTEMPTS: u32 = 30;
+
+pub async fn challenge(
+    UrlPath(token): UrlPath<String>,
+    State(state): State<AppState>,
+) -> Result<String, StatusCode> {
+    state
+        .challenges
+        .lock()
+        .expect("")
+        .get(&token)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// The router for `acme.http_bind`. Only challenges are answered over plain
+/// HTTP, everything else is redirected to the HTTPS listener on `https_port`.
+pub fn http_routes(state: AppState, https_port: u16) -> Router {
+    Router::new()
+        .route("/.well-known/acme-challenge/:token", get(challenge))
+        .fallback(move |headers: HeaderMap, uri: Uri| async move {
+            to_https(&headers, &uri, https_port)
+        })
+        .with_state(state)
+}
+
+fn to_https(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    match https_location(headers, uri, https_port) {
+        Some(location) => Redirect::permanent(&location).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Where `uri` lives on the HTTPS listener, `None` without a usable `Host`.
+fn https_location(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Option<String> {
+    let host = headers.get(header::HOST)?.to_str().ok()?;
+    let authority: Authority = host.parse().ok()?;
+    let path = uri.path_and_query().map_or("/", |p| p.as_str());
+    Some(match https_port {
+        443 => format!("https://{}{path}", authority.host()),
+        port => format!("https://{}:{port}{path}", authority.host()),
+    })
+}
+
+/// Loads the cached certificate, or a throwaway self-signed one if there is
+/// none yet, so the TLS listener can start before the first order completes.
+pub async fn initial_config(config: &AcmeConfig) -> Result<RustlsConfig, Error> {
+    let (cert, key) = (
+        config.cache_dir.join("cert.pem"),
+        config.cache_dir.join("key.pem"),
+    );
+    if cert.is_file() && key.is_file() {
+        return Ok(RustlsConfig::from_pem_file(cert, key).await?);
+    }
+    let placeholder = rcgen::generate_simple_self_signed(config.domains.clone())?;
+    Ok(RustlsConfig::from_pem(
+        placeholder.serialize_pem()?.into_bytes(),
+        placeholder.serialize_private_key_pem().into_bytes(),
+    )
+    .await?)
+}
+
+/// Keeps the certificate fresh for as long as the server runs.
+pub async fn renew_loop(config: AcmeConfig, challenges: Challenges, rustls: RustlsConfig) {
+    loop {
+        let mut next = CHECK_INTERVAL;
+        if needs_renewal(&config.cache_dir.join("cert.pem")) {
+            match provision(&config, &challenges).await {
+                Ok((cert, key)) => {
+                    if let Err(e) = rustls
+                        .reload_from_pem(cert.into_bytes(), key.into_bytes())
+                        .await
+                    {
+                        error!("Failed to load renewed certificate: {e}");
+                    }
+                }
+                Err(e) => {
+                    error!("Certificate provisioning failed: {e:#}");
+                    next = RETRY_INTERVAL;
+                }
+            }
+        }
+        tokio::time::sleep(next).await;
+    }
+}
+
+fn needs_renewal(cert: &Path) -> bool {
+    let Ok(modified) = std::fs::metadata(cert).and_then(|m| m.modified()) else {
+        return true;
+    };
+    SystemTime::now()
+        .duration_since(modified)
+        .is_ok_and(|age| age > RENEW_AFTER)
+}
+
+async fn account(config: &AcmeConfig) -> Result<Account, Error> {
+    let path = config.cache_dir.join("account.json");
+    if let Ok(saved) = std::fs::read(&path) {
+        let credentials: AccountCredentials = serde_json::from_slice(&saved)?;
+        return Ok(Account::from_credentials(credentials).await?);
+    }
+    let contact: Vec<&str> = config.contact.iter().map(String::as_str).collect();
+    let (account, credentials) = Account::create(
+        &NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        &config.directory,
+        None,
+    )
+    .await?;
+    std::fs::write(&path, serde_json::to_vec(&credentials)?)
+        .with_context(|| format!("saving {}", path.display()))?;
+    Ok(account)
+}
+
+/// Runs one ACME order through the HTTP-01 challenge and caches the result.
+async fn provision(
+    config: &AcmeConfig,
+    challenges: &Challenges,
+) -> Result<(String, String), Error> {
+    std::fs::create_dir_all(&config.cache_dir)?;
+    let account = account(config).await?;
+    let identifiers: Vec<_> = config
+        .domains
+        .iter()
+        .cloned()
+        .map(Identifier::Dns)
+        .collect();
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &identifiers,
+        })
+        .await?;
+
+    let mut issued = Vec::new();
+    for authz in order.authorizations().await? {
+        match authz.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => bail!("authorization is {status:?}"),
+        }
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.r#type == ChallengeType::Http01)
+            .ok_or(anyhow!("no http-01 challenge offered"))?;
+        let key_auth = order.key_authorization(challenge);
+        challenges
+            .lock()
+            .expect("")
+            .insert(challenge.token.clone(), key_auth.as_str().to_owned());
+        issued.push((challenge.token.clone(), challenge.url.clone()));
+    }
+    for (_, url) in &issued {
+        order.set_challenge_ready(url).await?;
+    }
+
+    let result = finish(&mut order, config).await;
+    let mut guard = challenges.lock().expect("");
+    for (token, _) in &issued {
+        guard.remove(token);
+    }
+    result
+}
+
+async fn finish(
+    order: &mut instant_acme::Order,
+    config: &AcmeConfig,
+) -> Result<(String, String), Error> {
+    let mut delay = Duration::from_millis(500);
+    for _ in 0..10 {
+        match order.refresh().await?.status {
+            OrderStatus::Ready => break,
+            OrderStatus::Invalid => bail!("order became invalid"),
+            _ => {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+    }
+    if order.state().status != OrderStatus::Ready {
+        bail!("order did not become ready in time");
+    }
+
+    let mut params = CertificateParams::new(config.domains.clone());
+    params.distinguished_name = DistinguishedName::new();
+    let cert = Certificate::from_params(params)?;
+    order.finalize(&cert.serialize_request_der()?).await?;
+    let mut chain = None;
+    for _ in 0..CERTIFICATE_ATTEMPTS {
+        chain = order.certificate().await?;
+        if chain.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    let chain = chain.ok_or(anyhow!("certificate was not issued in time"))?;
+    let key = cert.serialize_private_key_pem();
+    std::fs::write(config.cache_dir.join("key.pem"), &key)?;
+    std::fs::write(config.cache_dir.join("cert.pem"), &chain)?;
+    Ok((chain, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(host: &str, uri: &str, port: u16) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, host.parse().unwrap());
+        https_location(&headers, &uri.parse().unwrap(), port)
+    }
+
+    #[test]
+    fn redirects_to_the_https_port() {
+        assert_eq!(
+            location("xss.example:80", "/poll?x=1", 443).as_deref(),
+            Some("https://xss.example/poll?x=1")
+        );
+        assert_eq!(
+            location("[::1]:8080", "/", 8443).as_deref(),
+            Some("https://[::1]:8443/")
+        );
+    }
+
+    #[test]
+    fn refuses_unusable_hosts() {
+        assert_eq!(location("evil.example/path", "/", 443), None);
+        assert_eq!(
+            https_location(&HeaderMap::new(), &"/".parse().unwrap(), 443),
+            None
+        );
+    }
+}
@@ -0,0 +1,95 @@
+//! Sockets handed over by systemd through `LISTEN_FDS`, so a socket-activated
+//! service can serve on port 443 without ever being allowed to bind it.
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    os::unix::net::UnixListener,
+    path::Path,
+};
+
+use listenfd::ListenFd;
+use tracing::{info, warn};
+
+/// The listening sockets the process inherited, each taken over by the
+/// configured listener it matches.
+pub struct Inherited {
+    tcp: Vec<TcpListener>,
+    unix: Vec<UnixListener>,
+}
+
+impl Inherited {
+    /// Collects the sockets passed in `LISTEN_FDS`, if they are meant for this
+    /// process. Call early, this clears the variables so children do not see them.
+    pub fn from_env() -> Inherited {
+        let mut fds = ListenFd::from_env();
+        let mut inherited = Inherited {
+            tcp: Vec::new(),
+            unix: Vec::new(),
+        };
+        for idx in 0..fds.len() {
+            if let Ok(Some(listener)) = fds.take_tcp_listener(idx) {
+                inherited.tcp.push(listener);
+            } else if let Ok(Some(listener)) = fds.take_unix_listener(idx) {
+                inherited.unix.push(listener);
+            } else {
+                warn!(
+                    "Ignoring inherited fd {}, not a listening stream socket",
+                    idx + 3
+                );
+            }
+        }
+        inherited
+    }
+
+    /// The inherited socket for `addr`, if any. A wildcard address matches any
+    /// wildcard on the same port, as systemd binds `ListenStream=443` on `[::]`.
+    pub fn take_tcp(&mut self, addr: SocketAddr) -> Option<TcpListener> {
+        let idx = self.tcp.iter().position(|listener| {
+            listener.local_addr().is_ok_and(|local| {
+                local == addr
+                    || (local.port() == addr.port()
+                        && local.ip().is_unspecified()
+                        && addr.ip().is_unspecified())
+            })
+        })?;
+        let listener = self.tcp.swap_remove(idx);
+        listener.set_nonblocking(true).ok()?;
+        info!("Using the inherited socket for {addr}");
+        Some(listener)
+    }
+
+    /// The inherited socket bound to `path`, if any.
+    pub fn take_unix(&mut self, path: &Path) -> Option<UnixListener> {
+        let idx = self.unix.iter().position(|listener| {
+            listener
+                .local_addr()
+                .is_ok_and(|local| local.as_pathname() == Some(path))
+        })?;
+        let listener = self.unix.swap_remove(idx);
+        listener.set_nonblocking(true).ok()?;
+        info!("Using the inherited socket for unix:{}", path.display());
+        Some(listener)
+    }
+
+    /// Warns about sockets no configured listener asked for, which would
+    /// otherwise accept connections nobody answers. Closes them.
+    pub fn close_unused(self) {
+        for listener in self.tcp {
+            match listener.local_addr() {
+                Ok(addr) => warn!("Closing inherited socket {addr}, nothing is configured for it"),
+                Err(_) => warn!("Closing an inherited socket nothing is configured for"),
+            }
+        }
+        for listener in self.unix {
+            let path = listener.local_addr().ok();
+            let path = path.as_ref().and_then(|addr| addr.as_pathname());
+            match path {
+                Some(path) => warn!(
+                    "Closing inherited socket unix:{}, nothing is configured for it",
+                    path.display()
+                ),
+                None => warn!("Closing an inherited socket nothing is configured for"),
+            }
+        }
+    }
+}
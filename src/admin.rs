@@ -0,0 +1,311 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    auth::ApiKey, hub::PollError, storage::DeadLetter, tokens::TokenInfo, AppError, AppState,
+};
+
+/// Most dead letters listed or re-driven at once.
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LimitQuery {
+    #[serde(default = "LimitQuery::default_limit")]
+    limit: i64,
+}
+
+impl LimitQuery {
+    fn default_limit() -> i64 {
+        100
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Poller {
+    /// For `POST /admin/pollers/:id/kick`.
+    id: u64,
+    /// The tokens or `prefix*` patterns it waits on.
+    tokens: Vec<String>,
+    #[schema(value_type = String)]
+    client: IpAddr,
+    suspended_at: DateTime<Utc>,
+    /// Seconds it has been waiting so far.
+    waiting: i64,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Pollers {
+    /// Suspended polls, oldest first.
+    pollers: Vec<Poller>,
+    max_pollers: usize,
+    max_pollers_per_token: usize,
+    /// Hits waiting for a poll.
+    buffered: usize,
+    /// Hits handed out but not acknowledged yet.
+    inflight: usize,
+    /// Open websocket, SSE and NDJSON subscriptions.
+    streams: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DeadLetters {
+    dead_letters: Vec<DeadLetter>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Redriven {
+    redriven: usize,
+    /// Left parked as their webhook is no longer configured.
+    skipped: usize,
+}
+
+/// Who is waiting on which tokens right now, and how full the queue is.
+#[utoipa::path(
+    get,
+    path = "/admin/pollers",
+    tag = "admin",
+    responses((status = 200, body = Pollers), (status = 403, description = "Not an admin key")),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn pollers(State(state): State<AppState>, key: ApiKey) -> Response {
+    if !key.is_admin() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let config = state.config();
+    let now = Utc::now();
+    let hub = state.futures.lock().expect("");
+    let pollers = hub
+        .pollers
+        .iter()
+        .map(|(matcher, poll)| Poller {
+            id: poll.id,
+            tokens: matcher.0.iter().map(ToString::to_string).collect(),
+            client: poll.client,
+            suspended_at: poll.suspended_at,
+            waiting: (now - poll.suspended_at).num_seconds(),
+            expires_at: poll.expires_at,
+        })
+        .collect();
+    Json(Pollers {
+        pollers,
+        max_pollers: config.limits.max_pollers,
+        max_pollers_per_token: config.limits.max_pollers_per_token,
+        buffered: hub.buffered(),
+        inflight: hub.inflight(),
+        streams: hub.streams(),
+    })
+    .into_response()
+}
+
+/// Ends one suspended poll with 410 `revoked`.
+#[utoipa::path(
+    post,
+    path = "/admin/pollers/{id}/kick",
+    tag = "admin",
+    params(("id" = u64, Path, description = "Poller id from GET /admin/pollers")),
+    responses(
+        (status = 204, description = "Ended with 410 `revoked`"),
+        (status = 403, description = "Not an admin key"),
+        (status = 404, description = "No such poll waiting"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn kick(Path(id): Path<u64>, State(state): State<AppState>, key: ApiKey) -> StatusCode {
+    if !key.is_admin() {
+        return StatusCode::FORBIDDEN;
+    }
+    let Some(poll) = state.futures.lock().expect("").take_poller(id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    poll.fulfill(Err(PollError::Revoked));
+    StatusCode::NO_CONTENT
+}
+
+/// Refuses every further hit and poll for `token` and ends the polls and
+/// streams waiting on it with 410 `revoked`. Stored hits are kept, buffered
+/// ones are no longer handed out.
+#[utoipa::path(
+    post,
+    path = "/admin/tokens/{token}/revoke",
+    tag = "admin",
+    params(("token" = String, Path, description = "Token")),
+    responses((status = 204, description = "Revoked"), (status = 403, description = "Not an admin key")),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn revoke(
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<StatusCode, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    let now = Utc::now();
+    let info = {
+        let mut tokens = state.tokens.lock().expect("");
+        let info = tokens.entry(token.clone()).or_insert_with(|| TokenInfo {
+            token: token.clone(),
+            label: None,
+            created_at: now,
+            expires_at: None,
+            secret: None,
+            revoked_at: None,
+            fanout: None,
+            retention_days: None,
+            public_key: None,
+        });
+        info.revoked_at.get_or_insert(now);
+        info.clone()
+    };
+    let (buffered, pollers) = state.futures.lock().expect("").purge(&token);
+    for poller in pollers {
+        poller.fulfill(Err(PollError::Revoked));
+    }
+    state.settle(buffered.iter().map(|n| n.id).collect());
+    state.storage.save_token(&info).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Webhook deliveries that failed every try, oldest first.
+#[utoipa::path(
+    get,
+    path = "/admin/dead-letters",
+    tag = "admin",
+    params(LimitQuery),
+    responses(
+        (status = 200, body = DeadLetters),
+        (status = 400, description = "`limit` outside 1 to 1000"),
+        (status = 403, description = "Not an admin key"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn dead_letters(
+    Query(query): Query<LimitQuery>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<Response, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    if !(1..=MAX_LIMIT).contains(&query.limit) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let dead_letters = state.storage.dead_letters(query.limit).await?;
+    Ok(Json(DeadLetters { dead_letters }).into_response())
+}
+
+/// Delivers one dead letter again in the background, 409 if its webhook is
+/// gone from the configuration.
+#[utoipa::path(
+    post,
+    path = "/admin/dead-letters/{id}/redrive",
+    tag = "admin",
+    params(("id" = i64, Path, description = "Dead letter id")),
+    responses(
+        (status = 202, description = "Queued for delivery"),
+        (status = 403, description = "Not an admin key"),
+        (status = 404, description = "No such dead letter"),
+        (status = 409, description = "Its webhook is no longer configured"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn redrive(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<StatusCode, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    let Some(letter) = state.storage.dead_letter(id).await? else {
+        return Ok(StatusCode::NOT_FOUND);
+    };
+    if !state.notifiers.can_redrive(&letter.url) {
+        return Ok(StatusCode::CONFLICT);
+    }
+    redrive_letter(&state, id).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Takes dead letter `id` out of storage and delivers it again. Should its
+/// webhook have gone away meanwhile it is parked once more.
+async fn redrive_letter(state: &AppState, id: i64) -> Result<(), AppError> {
+    if let Some(letter) = state.storage.take_dead_letter(id).await? {
+        if let Err(letter) = state.notifiers.redrive(state, letter) {
+            state.storage.park(&letter).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Delivers up to `limit` of the oldest dead letters again.
+#[utoipa::path(
+    post,
+    path = "/admin/dead-letters/redrive",
+    tag = "admin",
+    params(LimitQuery),
+    responses((status = 202, body = Redriven), (status = 403, description = "Not an admin key")),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn redrive_all(
+    Query(query): Query<LimitQuery>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<Response, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    if !(1..=MAX_LIMIT).contains(&query.limit) {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let mut redriven = Redriven {
+        redriven: 0,
+        skipped: 0,
+    };
+    for letter in state.storage.dead_letters(query.limit).await? {
+        if state.notifiers.can_redrive(&letter.url) {
+            redrive_letter(&state, letter.id).await?;
+            redriven.redriven += 1;
+        } else {
+            redriven.skipped += 1;
+        }
+    }
+    Ok((StatusCode::ACCEPTED, Json(redriven)).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/dead-letters/{id}",
+    tag = "admin",
+    params(("id" = i64, Path, description = "Dead letter id")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 403, description = "Not an admin key"),
+        (status = 404, description = "No such dead letter"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn delete_dead_letter(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<StatusCode, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    Ok(match state.storage.take_dead_letter(id).await? {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    })
+}
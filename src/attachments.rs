@@ -0,0 +1,408 @@
+//! Files sent along with hits, kept under `attachments.dir` or in an S3 bucket
+//! rather than inline in storage. Each is named by the SHA-256 of its content,
+//! so a screenshot a payload sends a hundred times is stored once.
+
+use std::{
+    io,
+    path::{Path as FsPath, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Error};
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use tempfile::{NamedTempFile, TempPath};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::ApiKey,
+    config::AttachmentsConfig,
+    matcher::Pattern,
+    model::{Attachment, Notification},
+    s3::Bucket,
+    storage::Keys,
+    tokens::hex,
+    AppError, AppState,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+/// How much of a file `sniff` looks at.
+const SNIFF_LEN: usize = 1024;
+/// Where uploads are spooled under `attachments.dir`, on the same filesystem
+/// so keeping one is a rename.
+const INCOMING: &str = ".incoming";
+/// What sealed files are stored as, whatever they hold.
+const SEALED_TYPE: &str = "application/octet-stream";
+/// Signed in place of the SHA-256 of a body sealed while it is uploaded.
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Where the file with this id lives, in a subdirectory per first two hex
+/// digits to keep directories small.
+fn path(dir: &FsPath, id: &str) -> PathBuf {
+    dir.join(&id[..2]).join(id)
+}
+
+/// Whether `id` can be the name of a stored file.
+fn valid(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// A stored file as it is read.
+pub type Content = Pin<Box<dyn Stream<Item = Result<Bytes, Error>> + Send>>;
+
+/// Where attachment files are kept, set up from `attachments` at startup.
+/// With `[encryption]` each file is sealed with the same keys as the
+/// notifications, bound to its id.
+pub struct Store {
+    backend: Backend,
+    keys: Option<Arc<Keys>>,
+}
+
+enum Backend {
+    Dir(PathBuf),
+    S3(Box<Bucket>),
+}
+
+impl Store {
+    pub fn open(config: &AttachmentsConfig, keys: Option<Arc<Keys>>) -> Result<Store, Error> {
+        let backend = match (&config.dir, &config.s3) {
+            (_, Some(s3)) => Backend::S3(Box::new(Bucket::new(s3)?)),
+            (Some(dir), None) => {
+                // Left over from uploads cut short by a crash.
+                let incoming = dir.join(INCOMING);
+                match std::fs::remove_dir_all(&incoming) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        warn!("Failed to clear {}: {e}", incoming.display())
+                    }
+                    _ => {}
+                }
+                std::fs::create_dir_all(&incoming)
+                    .with_context(|| format!("failed to create {}", incoming.display()))?;
+                Backend::Dir(dir.clone())
+            }
+            (None, None) => unreachable!("validated attachments"),
+        };
+        Ok(Store { backend, keys })
+    }
+
+    /// A new file to write an upload to as it arrives, deleted when dropped
+    /// unless `save` keeps it.
+    pub fn spool(&self) -> Result<NamedTempFile, Error> {
+        let file = match &self.backend {
+            Backend::Dir(dir) => NamedTempFile::new_in(dir.join(INCOMING))?,
+            Backend::S3(_) => NamedTempFile::new()?,
+        };
+        Ok(file)
+    }
+
+    /// Keeps the content of `attachment` unless the same content already is,
+    /// and leaves only the description in `attachment`.
+    pub async fn save(&self, attachment: &mut Attachment) -> Result<(), Error> {
+        if let Some(spooled) = attachment.spooled.take() {
+            let id = attachment.id.clone().expect("hashed while spooled");
+            return self.save_spooled(&id, spooled, attachment.size).await;
+        }
+        let id = hex(&Sha256::digest(&attachment.data));
+        let data = std::mem::take(&mut attachment.data);
+        match &self.backend {
+            Backend::Dir(dir) => save_file(dir, &id, &data[..], self.keys.as_deref()).await?,
+            Backend::S3(bucket) => {
+                if !bucket.exists(&id).await? {
+                    match &self.keys {
+                        Some(keys) => {
+                            let mut sealed = Vec::new();
+                            keys.seal_file(&data[..], &mut sealed, id.as_bytes())
+                                .await?;
+                            bucket.put(&id, sealed, SEALED_TYPE).await?;
+                        }
+                        None => {
+                            let content_type = sniff(&data);
+                            bucket.put(&id, data, content_type).await?;
+                        }
+                    }
+                }
+            }
+        }
+        attachment.id = Some(id);
+        Ok(())
+    }
+
+    async fn save_spooled(&self, id: &str, spooled: Arc<TempPath>, size: u64) -> Result<(), Error> {
+        match &self.backend {
+            Backend::Dir(dir) => match (&self.keys, Arc::try_unwrap(spooled)) {
+                (None, Ok(spooled)) => move_file(dir, id, spooled).await?,
+                (Some(keys), Ok(spooled)) => {
+                    let file = tokio::fs::File::open(&spooled).await?;
+                    save_file(dir, id, file, Some(keys)).await?
+                }
+                // Not expected before it is kept, but then copied rather than taken.
+                (keys, Err(shared)) => {
+                    let file = tokio::fs::File::open(&*shared).await?;
+                    save_file(dir, id, file, keys.as_deref()).await?
+                }
+            },
+            Backend::S3(bucket) => {
+                if bucket.exists(id).await? {
+                    return Ok(());
+                }
+                let mut file = tokio::fs::File::open(&*spooled).await?;
+                let Some(keys) = &self.keys else {
+                    let mut head = [0; SNIFF_LEN];
+                    let read = file.read(&mut head).await?;
+                    file.seek(SeekFrom::Start(0)).await?;
+                    let body = reqwest::Body::wrap_stream(ReaderStream::new(file));
+                    return bucket
+                        .put_stream(id, body, id, size, sniff(&head[..read]))
+                        .await;
+                };
+                // Sealed while it is uploaded.
+                let (sealing, sealed) = tokio::io::duplex(64 * 1024);
+                let body = reqwest::Body::wrap_stream(ReaderStream::new(sealed));
+                let length = Keys::sealed_len(size);
+                let (put, seal) = tokio::join!(
+                    bucket.put_stream(id, body, UNSIGNED_PAYLOAD, length, SEALED_TYPE),
+                    keys.seal_file(file, sealing, id.as_bytes()),
+                );
+                seal?;
+                put?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The content of the file `id` as it is read, `None` if there is no such
+    /// file.
+    pub async fn load(&self, id: &str) -> Result<Option<Content>, Error> {
+        if !valid(id) {
+            return Ok(None);
+        }
+        let file: Pin<Box<dyn AsyncRead + Send>> = match &self.backend {
+            Backend::Dir(dir) => match tokio::fs::File::open(path(dir, id)).await {
+                Ok(file) => Box::pin(file),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            },
+            Backend::S3(bucket) => match bucket.get(id).await? {
+                Some(object) => Box::pin(StreamReader::new(object.map_err(io::Error::other))),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(match &self.keys {
+            Some(keys) => Box::pin(keys.clone().open_file(file, id.as_bytes().to_vec())),
+            None => Box::pin(ReaderStream::new(file).map_err(Error::from)),
+        }))
+    }
+
+    /// A presigned download URL for the file `id`, for buckets only. Sealed
+    /// files have none, they can only be opened here.
+    pub fn url(&self, id: &str) -> Option<String> {
+        match (&self.backend, &self.keys) {
+            (Backend::S3(bucket), None) => Some(bucket.presign(id)),
+            _ => None,
+        }
+    }
+
+    /// Fills in `url` for the attachments of `notification` kept in a bucket.
+    pub fn presign(&self, notification: &mut Notification) {
+        for attachment in &mut notification.attachments {
+            if let Some(id) = &attachment.id {
+                attachment.url = self.url(id);
+            }
+        }
+    }
+}
+
+/// Touches the file at `path` if there is one, so it counts as carried again
+/// for `max_age_days`, and else creates its directory.
+async fn touch(path: &FsPath) -> Result<bool, Error> {
+    match std::fs::File::options().write(true).open(path) {
+        Ok(file) => {
+            file.set_modified(SystemTime::now())?;
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let parent = path.parent().expect("file in a subdirectory");
+            tokio::fs::create_dir_all(parent).await?;
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes what `content` reads as the file `id`, sealed with `keys` if given.
+async fn save_file(
+    dir: &FsPath,
+    id: &str,
+    mut content: impl AsyncRead + Unpin,
+    keys: Option<&Keys>,
+) -> Result<(), Error> {
+    let path = path(dir, id);
+    if !touch(&path).await? {
+        // Written aside and renamed, so a file of that name is always complete.
+        let partial = dir.join(INCOMING).join(format!("{id}.{}", Uuid::new_v4()));
+        let mut file = tokio::fs::File::create(&partial).await?;
+        match keys {
+            Some(keys) => keys.seal_file(content, &mut file, id.as_bytes()).await?,
+            None => {
+                tokio::io::copy(&mut content, &mut file).await?;
+            }
+        }
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&partial, &path).await?;
+    }
+    Ok(())
+}
+
+async fn move_file(dir: &FsPath, id: &str, spooled: TempPath) -> Result<(), Error> {
+    let path = path(dir, id);
+    if !touch(&path).await? {
+        spooled.persist(&path)?;
+    }
+    Ok(())
+}
+
+/// A stored file by its SHA-256, the `id` in a notification's attachment, for
+/// API keys that may poll a token some stored hit carried it for. Images and
+/// PDFs are shown inline, text as plain text and anything else is a download,
+/// all sandboxed.
+#[utoipa::path(
+    get,
+    path = "/api/attachments/{id}",
+    tag = "history",
+    params(("id" = String, Path, description = "SHA-256 of the file")),
+    responses(
+        (status = 200, description = "The file, with the type its content shows", content_type = "application/octet-stream"),
+        (status = 401, description = "Missing or unknown API key"),
+        (status = 404, description = "No such file, or none the API key may see"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<Response, AppError> {
+    let Some(store) = &state.attachments else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    // Keys limited to some tokens only see files their hits carried, without
+    // learning whether other files exist.
+    if !key.covers(&Pattern::parse("*")) {
+        let tokens = state.storage.file_tokens(&id).await?;
+        if !tokens.iter().any(|token| key.allows(token)) {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+    }
+    let Some(mut content) = store.load(&id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let mut head = Vec::new();
+    while head.len() < SNIFF_LEN {
+        match content.try_next().await? {
+            Some(chunk) => head.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    let content_type = sniff(&head[..head.len().min(SNIFF_LEN)]);
+    let disposition = match content_type {
+        "application/octet-stream" => format!("attachment; filename=\"{id}\""),
+        _ => "inline".to_owned(),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_owned()),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CONTENT_SECURITY_POLICY, "sandbox".to_owned()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            // The id changes with the content.
+            (
+                header::CACHE_CONTROL,
+                "private, max-age=31536000, immutable".to_owned(),
+            ),
+        ],
+        StreamBody::new(stream::once(async { Ok(head.into()) }).chain(content)),
+    )
+        .into_response())
+}
+
+/// The type of `data`, or of a file starting with it, by its first bytes,
+/// rather than whatever the payload claimed when sending it.
+fn sniff(data: &[u8]) -> &'static str {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [b'%', b'P', b'D', b'F', b'-', ..] => "application/pdf",
+        // A character may be cut off at the end of the start of a file.
+        _ if std::str::from_utf8(data).map_or_else(|e| e.error_len().is_none(), |_| true) => {
+            "text/plain; charset=utf-8"
+        }
+        _ => "application/octet-stream",
+    }
+}
+
+/// Deletes files no hit carried for `attachments.max_age_days` every hour,
+/// starting right away.
+pub async fn sweep_loop(state: AppState) {
+    let Some(Store {
+        backend: Backend::Dir(dir),
+        ..
+    }) = state.attachments.as_deref()
+    else {
+        return;
+    };
+    loop {
+        if let Some(days) = state
+            .config()
+            .attachments
+            .as_ref()
+            .and_then(|attachments| attachments.max_age_days)
+        {
+            let max_age = Duration::from_secs(days.saturating_mul(86_400));
+            match sweep(dir, max_age).await {
+                Ok(0) => {}
+                Ok(deleted) => info!("Deleted {deleted} attachments past their max age"),
+                Err(e) => warn!("Deleting old attachments failed: {e:#}"),
+            }
+        }
+        tokio::time::sleep(SWEEP_INTERVAL).await;
+    }
+}
+
+async fn sweep(dir: &FsPath, max_age: Duration) -> Result<u64, Error> {
+    let Some(before) = SystemTime::now().checked_sub(max_age) else {
+        return Ok(0);
+    };
+    let mut deleted = 0;
+    let mut subdirs = match tokio::fs::read_dir(dir).await {
+        Ok(subdirs) => subdirs,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(subdir) = subdirs.next_entry().await? {
+        if !subdir.file_type().await?.is_dir() || subdir.file_name() == INCOMING {
+            continue;
+        }
+        let mut files = tokio::fs::read_dir(subdir.path()).await?;
+        while let Some(file) = files.next_entry().await? {
+            if file.metadata().await?.modified()? < before {
+                tokio::fs::remove_file(file.path()).await?;
+                deleted += 1;
+            }
+        }
+    }
+    Ok(deleted)
+}
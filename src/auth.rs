@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+
+use crate::{config::ApiKeyConfig, matcher::Pattern, mtls::ClientCert, AppState};
+
+/// The API key a request authenticated with.
+///
+/// Without any `api_keys` configured every request is let through with full access.
+pub enum ApiKey {
+    Open,
+    Key(ApiKeyConfig),
+}
+
+impl ApiKey {
+    /// Whether this key may poll or subscribe to `token`.
+    pub fn allows(&self, token: &str) -> bool {
+        match self {
+            ApiKey::Open => true,
+            ApiKey::Key(key) => {
+                key.tokens.is_empty()
+                    || key
+                        .tokens
+                        .iter()
+                        .any(|pattern| Pattern::parse(pattern).matches(token))
+            }
+        }
+    }
+
+    /// Whether this key may poll every token `pattern` matches.
+    pub fn covers(&self, pattern: &Pattern) -> bool {
+        match self {
+            ApiKey::Open => true,
+            ApiKey::Key(key) => {
+                key.tokens.is_empty()
+                    || key
+                        .tokens
+                        .iter()
+                        .any(|allowed| pattern.within(&Pattern::parse(allowed)))
+            }
+        }
+    }
+
+    /// Rejects keys without access to `token` with 403.
+    pub fn check(&self, token: &str) -> Result<(), StatusCode> {
+        match self.allows(token) {
+            true => Ok(()),
+            false => Err(StatusCode::FORBIDDEN),
+        }
+    }
+
+    /// Rejects keys without access to all of `pattern` with 403.
+    pub fn check_pattern(&self, pattern: &Pattern) -> Result<(), StatusCode> {
+        match self.covers(pattern) {
+            true => Ok(()),
+            false => Err(StatusCode::FORBIDDEN),
+        }
+    }
+
+    /// The configured key for the client certificate `cert`, or else the one
+    /// matching `presented`, 401 if there is none. Without a certificate it is
+    /// always 401 when `tls.require_client_cert` is set.
+    pub fn authenticate(
+        state: &AppState,
+        presented: Option<&str>,
+        cert: &ClientCert,
+    ) -> Result<ApiKey, StatusCode> {
+        let config = state.config();
+        if config
+            .tls
+            .as_ref()
+            .is_some_and(|tls| tls.require_client_cert)
+            && !cert.presented
+        {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let keys = &config.api_keys;
+        if keys.is_empty() {
+            return Ok(ApiKey::Open);
+        }
+        if let Some(name) = &cert.common_name {
+            if let Some(key) = keys
+                .iter()
+                .find(|key| key.client_cert.as_ref() == Some(name))
+            {
+                return Ok(ApiKey::Key(key.clone()));
+            }
+        }
+        let presented = presented.ok_or(StatusCode::UNAUTHORIZED)?;
+        keys.iter()
+            .find(|key| {
+                key.key
+                    .as_ref()
+                    .is_some_and(|key| constant_time_eq(key.as_bytes(), presented.as_bytes()))
+            })
+            .map(|key| ApiKey::Key(key.clone()))
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        match self {
+            ApiKey::Open => true,
+            ApiKey::Key(key) => key.admin,
+        }
+    }
+}
+
+/// The key sent as `X-Api-Key`, or else as an `Authorization: Bearer` token.
+pub fn presented<'a>(api_key: Option<&'a str>, authorization: Option<&'a str>) -> Option<&'a str> {
+    api_key.or_else(|| authorization?.strip_prefix("Bearer "))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ApiKey {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let header = |name| parts.headers.get(name).and_then(|v| v.to_str().ok());
+        let presented = presented(header("x-api-key"), header(header::AUTHORIZATION.as_str()));
+        let cert = parts
+            .extensions
+            .get::<ClientCert>()
+            .cloned()
+            .unwrap_or_default();
+        ApiKey::authenticate(state, presented, &cert)
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Error};
+use chrono::{DateTime, Duration, Utc};
+use clap::{Parser, ValueEnum};
+use uuid::Uuid;
+
+use xss_check_srv::{client::Client, e2e, HistoryFilter, Notification};
+
+/// Largest page the history API hands out.
+const PER_PAGE: u32 = 500;
+
+/// Prints the hits of a token, like tcpdump for callbacks
+///
+/// Shows the stored hits, then with --follow waits for new ones.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Where the server is reachable, e.g. https://callbacks.example.com
+    url: String,
+    token: String,
+    /// Needed once the server lists [[api_keys]].
+    #[arg(long, env = "XSS_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// Keep running and print hits as they arrive.
+    #[arg(long, short)]
+    follow: bool,
+    /// Only stored hits received since then, a time like 2024-05-01T12:00:00Z
+    /// or an age like 30s, 10m, 2h or 1d.
+    #[arg(long, value_parser = parse_since)]
+    since: Option<DateTime<Utc>>,
+    #[arg(long, value_enum, default_value_t = Format::Summary)]
+    format: Format,
+    /// An age-keygen identity file, to decrypt the hits of a token minted with
+    /// its public key.
+    #[arg(long)]
+    identity: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// One line per hit: time, sequence number, client, user agent and payload.
+    Summary,
+    /// The whole notification as indented JSON.
+    Pretty,
+    /// The whole notification as one line of JSON, for jq.
+    Json,
+}
+
+fn parse_since(text: &str) -> Result<DateTime<Utc>, Error> {
+    if let Ok(at) = DateTime::parse_from_rfc3339(text) {
+        return Ok(at.with_timezone(&Utc));
+    }
+    let unit = text.chars().last().unwrap_or_default();
+    let amount: i64 = text[..text.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| anyhow!("expected a time or an age like 10m"))?;
+    let age = match unit {
+        's' => Duration::try_seconds(amount),
+        'm' => Duration::try_minutes(amount),
+        'h' => Duration::try_hours(amount),
+        'd' => Duration::try_days(amount),
+        _ => bail!("the age unit must be s, m, h or d"),
+    };
+    age.and_then(|age| Utc::now().checked_sub_signed(age))
+        .ok_or_else(|| anyhow!("{text} is too far back"))
+}
+
+fn print(format: Format, notification: &Notification) -> Result<(), Error> {
+    let text = match format {
+        Format::Pretty => serde_json::to_string_pretty(notification)?,
+        Format::Json => serde_json::to_string(notification)?,
+        Format::Summary => summary(notification),
+    };
+    writeln!(io::stdout().lock(), "{text}")?;
+    Ok(())
+}
+
+fn summary(notification: &Notification) -> String {
+    let meta = &notification.meta;
+    let mut line = format!(
+        "{} #{} {}",
+        notification.received_at.format("%Y-%m-%d %H:%M:%S"),
+        notification.seq,
+        meta.client_ip
+            .map_or_else(|| "-".to_owned(), |ip| ip.to_string()),
+    );
+    if let Some(user_agent) = &meta.user_agent {
+        line.push_str(&format!(" {user_agent:?}"));
+    }
+    let mut data: Vec<_> = notification.data.iter().collect();
+    data.sort();
+    for (name, value) in data {
+        line.push_str(&format!(" {name}={value:?}"));
+    }
+    for attachment in &notification.attachments {
+        let name = attachment.filename.as_ref().unwrap_or(&attachment.name);
+        line.push_str(&format!(" [{name}, {} bytes]", attachment.size));
+    }
+    line
+}
+
+async fn run(args: Args) -> Result<(), Error> {
+    let identity = match &args.identity {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("failed to read {}: {e}", path.display()))?;
+            let identity = e2e::identity(&text)
+                .ok_or_else(|| anyhow!("no age identity in {}", path.display()))?;
+            Some(identity)
+        }
+        None => None,
+    };
+    let open = |mut notification: Notification| -> Result<Notification, Error> {
+        if let Some(identity) = &identity {
+            e2e::open(&mut notification, identity)?;
+        }
+        Ok(notification)
+    };
+    let mut client = Client::new(&args.url)?;
+    if let Some(key) = &args.api_key {
+        client = client.with_api_key(key);
+    }
+    // Hits still buffered show up in the history and again on the stream.
+    let mut seen: HashSet<Uuid> = HashSet::new();
+    let filter = HistoryFilter {
+        token: args.token.clone(),
+        since: args.since,
+        ..HistoryFilter::default()
+    };
+    for page in 1.. {
+        let history = client.history(&filter, page, PER_PAGE).await?;
+        for notification in history.notifications {
+            seen.insert(notification.uuid);
+            print(args.format, &open(notification)?)?;
+        }
+        if i64::from(page) * i64::from(PER_PAGE) >= history.total {
+            break;
+        }
+    }
+    if !args.follow {
+        return Ok(());
+    }
+    let mut events = client.events(&args.token);
+    loop {
+        let notification = events.next().await?;
+        if seen.insert(notification.uuid) {
+            print(args.format, &open(notification)?)?;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Args::parse()).await {
+        // Piped into head or the like, which has seen enough.
+        if e.downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+        {
+            return;
+        }
+        eprintln!("xss-tail: {e:#}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,535 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    time::Duration,
+};
+
+use anyhow::Error;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::Parser;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+use xss_check_srv::{client::Client, HistoryFilter, Notification, TokenInfo};
+
+/// Stored hits loaded when picking a token, the newest ones.
+const HISTORY: u32 = 500;
+/// How often the screen is redrawn while nothing happens.
+const TICK: Duration = Duration::from_millis(100);
+const HELP: &str = "enter watch · tab pane · t triage · p/n copy payload/notify URL · u copy link · r reload · q quit";
+
+/// Tokens on the left, the selected token's hits on the right, live
+///
+/// Triage marks only last as long as the session.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Where the server is reachable, e.g. https://callbacks.example.com
+    url: String,
+    /// Needed once the server lists [[api_keys]].
+    #[arg(long, env = "XSS_API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// Also list a token that was never registered with POST /tokens, may be repeated.
+    #[arg(long = "token", short)]
+    tokens: Vec<String>,
+}
+
+/// What background tasks report back to the UI.
+enum Update {
+    Tokens(Vec<TokenInfo>),
+    History(String, Vec<Notification>),
+    Hit(String, Box<Notification>),
+    Error(String),
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    Tokens,
+    Hits,
+}
+
+struct App {
+    client: Client,
+    base_url: String,
+    /// Given with --token, listed even if the server does not know them.
+    extra: Vec<String>,
+    tokens: Vec<TokenInfo>,
+    token_list: ListState,
+    /// The token whose hits are shown.
+    watching: Option<String>,
+    follow: Option<JoinHandle<()>>,
+    /// Newest first.
+    hits: Vec<Notification>,
+    hit_list: ListState,
+    triaged: HashSet<Uuid>,
+    focus: Focus,
+    status: String,
+    updates: UnboundedSender<Update>,
+}
+
+/// `text` with control characters escaped. Hits are attacker controlled and
+/// must not get to send escape sequences to the terminal.
+fn clean(text: &str) -> String {
+    let mut cleaned = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() {
+            cleaned.extend(c.escape_default());
+        } else {
+            cleaned.push(c);
+        }
+    }
+    cleaned
+}
+
+/// Puts `text` on the clipboard through the terminal (OSC 52), which also works over SSH.
+fn copy(text: &str) -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    write!(out, "\x1b]52;c;{}\x07", STANDARD.encode(text))?;
+    out.flush()
+}
+
+fn fetch_tokens(client: Client, updates: UnboundedSender<Update>) {
+    tokio::spawn(async move {
+        let update = match client.tokens().await {
+            Ok(tokens) => Update::Tokens(tokens),
+            Err(e) => Update::Error(format!("Loading tokens failed: {e:#}")),
+        };
+        let _ = updates.send(update);
+    });
+}
+
+/// Loads the newest stored hits for `token`, then streams new ones.
+async fn follow(client: Client, token: String, updates: UnboundedSender<Update>) {
+    let filter = HistoryFilter {
+        token: token.clone(),
+        ..HistoryFilter::default()
+    };
+    let history = async {
+        let first = client.history(&filter, 1, HISTORY).await?;
+        let pages = (first.total as u64).div_ceil(u64::from(HISTORY)).max(1) as u32;
+        if pages == 1 {
+            return Ok::<_, Error>(first.notifications);
+        }
+        Ok(client.history(&filter, pages, HISTORY).await?.notifications)
+    };
+    match history.await {
+        Ok(notifications) => {
+            let _ = updates.send(Update::History(token.clone(), notifications));
+        }
+        Err(e) => {
+            let _ = updates.send(Update::Error(format!("Loading history failed: {e:#}")));
+        }
+    }
+    let mut events = client.events(&token);
+    loop {
+        match events.next().await {
+            Ok(notification) => {
+                if updates
+                    .send(Update::Hit(token.clone(), Box::new(notification)))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => {
+                let _ = updates.send(Update::Error(format!("Following {token} stopped: {e:#}")));
+                return;
+            }
+        }
+    }
+}
+
+impl App {
+    /// Every listed token: the server's, then the --token ones it does not know.
+    fn token_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.tokens.iter().map(|info| info.token.clone()).collect();
+        for token in &self.extra {
+            if !names.contains(token) {
+                names.push(token.clone());
+            }
+        }
+        names
+    }
+
+    fn selected_token(&self) -> Option<String> {
+        self.token_list
+            .selected()
+            .and_then(|i| self.token_names().get(i).cloned())
+    }
+
+    fn selected_hit(&self) -> Option<&Notification> {
+        self.hit_list.selected().and_then(|i| self.hits.get(i))
+    }
+
+    fn watch(&mut self, token: String) {
+        if let Some(follow) = self.follow.take() {
+            follow.abort();
+        }
+        self.hits.clear();
+        self.hit_list.select(None);
+        self.status = format!("Watching {}", clean(&token));
+        self.follow = Some(tokio::spawn(follow(
+            self.client.clone(),
+            token.clone(),
+            self.updates.clone(),
+        )));
+        self.watching = Some(token);
+    }
+
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Tokens(tokens) => {
+                self.tokens = tokens;
+                if self.token_list.selected().is_none() && !self.token_names().is_empty() {
+                    self.token_list.select(Some(0));
+                }
+            }
+            Update::History(token, notifications) if self.watching.as_ref() == Some(&token) => {
+                let mut older: Vec<Notification> = notifications
+                    .into_iter()
+                    .rev()
+                    .filter(|old| self.hits.iter().all(|hit| hit.uuid != old.uuid))
+                    .collect();
+                self.hits.append(&mut older);
+                if self.hit_list.selected().is_none() && !self.hits.is_empty() {
+                    self.hit_list.select(Some(0));
+                }
+            }
+            Update::Hit(token, notification) if self.watching.as_ref() == Some(&token) => {
+                if self.hits.iter().any(|hit| hit.uuid == notification.uuid) {
+                    return;
+                }
+                self.hits.insert(0, *notification);
+                // Keep the selection on the same hit as the list grows at the top.
+                match self.hit_list.selected() {
+                    Some(i) => self.hit_list.select(Some(i + 1)),
+                    None => self.hit_list.select(Some(0)),
+                }
+            }
+            Update::History(..) | Update::Hit(..) => {}
+            Update::Error(message) => self.status = clean(&message),
+        }
+    }
+
+    /// Handles a key press, returning false to quit.
+    fn key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Down | KeyCode::Char('j') => self.step(true),
+            KeyCode::Up | KeyCode::Char('k') => self.step(false),
+            KeyCode::Tab | KeyCode::Left | KeyCode::Right | KeyCode::Char('h' | 'l') => {
+                self.focus = match self.focus {
+                    Focus::Tokens => Focus::Hits,
+                    Focus::Hits => Focus::Tokens,
+                };
+            }
+            KeyCode::Enter if self.focus == Focus::Tokens => {
+                if let Some(token) = self.selected_token() {
+                    self.watch(token);
+                    self.focus = Focus::Hits;
+                }
+            }
+            KeyCode::Char('t' | ' ') => {
+                if let Some(uuid) = self.selected_hit().map(|hit| hit.uuid) {
+                    if !self.triaged.remove(&uuid) {
+                        self.triaged.insert(uuid);
+                    }
+                }
+            }
+            KeyCode::Char('p') => self.copy_token_url("payload.js"),
+            KeyCode::Char('n') => self.copy_token_url("notify"),
+            KeyCode::Char('u') => {
+                if let Some(hit) = self.selected_hit() {
+                    let url = format!("{}/n/{}", self.base_url, hit.uuid);
+                    self.copied(&url);
+                }
+            }
+            KeyCode::Char('r') => {
+                fetch_tokens(self.client.clone(), self.updates.clone());
+                self.status = "Reloading tokens".to_owned();
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Moves the highlight of the focused pane one down or up.
+    fn step(&mut self, down: bool) {
+        let tokens = self.token_names().len();
+        let (list, len) = match self.focus {
+            Focus::Tokens => (&mut self.token_list, tokens),
+            Focus::Hits => (&mut self.hit_list, self.hits.len()),
+        };
+        if len == 0 {
+            return;
+        }
+        let next = match (list.selected(), down) {
+            (None, _) => 0,
+            (Some(i), true) => (i + 1).min(len - 1),
+            (Some(i), false) => i.saturating_sub(1),
+        };
+        list.select(Some(next));
+    }
+
+    /// Copies `{base_url}/{path}?token=...` for the highlighted token, with its
+    /// notify secret if it has one.
+    fn copy_token_url(&mut self, path: &str) {
+        let Some(token) = self.selected_token() else {
+            return;
+        };
+        let mut url = format!("{}/{path}?token={token}", self.base_url);
+        let secret = self
+            .tokens
+            .iter()
+            .find(|info| info.token == token)
+            .and_then(|info| info.secret.as_ref());
+        if let Some(secret) = secret {
+            url.push_str(&format!("&s={secret}"));
+        }
+        self.copied(&url);
+    }
+
+    fn copied(&mut self, text: &str) {
+        self.status = match copy(text) {
+            Ok(()) => format!("Copied {}", clean(text)),
+            Err(e) => format!("Copying failed: {e}"),
+        };
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(30), Constraint::Percentage(70)])
+                .areas(main);
+        let [list, detail] =
+            Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(right);
+        self.draw_tokens(frame, left);
+        self.draw_hits(frame, list);
+        self.draw_detail(frame, detail);
+        let status_line = if self.status.is_empty() {
+            HELP.to_owned()
+        } else {
+            format!("{} · {HELP}", self.status)
+        };
+        frame.render_widget(Paragraph::new(status_line).dim(), status);
+    }
+
+    fn block(&self, title: String, focus: Focus) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        if self.focus == focus {
+            block.border_style(Style::new().bold())
+        } else {
+            block.border_style(Style::new().dim())
+        }
+    }
+
+    fn draw_tokens(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .token_names()
+            .into_iter()
+            .map(|token| {
+                let info = self.tokens.iter().find(|info| info.token == token);
+                let watching = self.watching.as_ref() == Some(&token);
+                let mut spans = vec![Span::raw(if watching { "● " } else { "  " })];
+                match info.and_then(|info| info.label.as_ref()) {
+                    Some(label) => {
+                        spans.push(Span::raw(clean(label)));
+                        spans.push(Span::raw(format!(" {}", clean(&token))).dim());
+                    }
+                    None => spans.push(Span::raw(clean(&token))),
+                }
+                let line = Line::from(spans);
+                if info.is_some_and(|info| info.revoked_at.is_some() || info.expired()) {
+                    ListItem::new(line.crossed_out())
+                } else {
+                    ListItem::new(line)
+                }
+            })
+            .collect();
+        let list = List::new(items)
+            .block(self.block("Tokens".to_owned(), Focus::Tokens))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, area, &mut self.token_list);
+    }
+
+    fn draw_hits(&mut self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .hits
+            .iter()
+            .map(|hit| {
+                let triaged = self.triaged.contains(&hit.uuid);
+                let page = hit
+                    .data
+                    .get("url")
+                    .or(hit.meta.referer.as_ref())
+                    .map_or(String::new(), |url| clean(url));
+                let line = Line::from(vec![
+                    Span::raw(if triaged { "✓ " } else { "  " }),
+                    Span::raw(hit.received_at.format("%m-%d %H:%M:%S ").to_string()),
+                    Span::raw(format!("#{:<5} ", hit.seq)).bold(),
+                    Span::raw(format!(
+                        "{:<16} ",
+                        hit.meta
+                            .client_ip
+                            .map_or("-".to_owned(), |ip| ip.to_string())
+                    )),
+                    Span::raw(page),
+                ]);
+                if triaged {
+                    ListItem::new(line.dim())
+                } else {
+                    ListItem::new(line)
+                }
+            })
+            .collect();
+        let title = match &self.watching {
+            Some(token) => format!(
+                "Hits for {} ({}, {} triaged)",
+                clean(token),
+                self.hits.len(),
+                self.hits
+                    .iter()
+                    .filter(|hit| self.triaged.contains(&hit.uuid))
+                    .count()
+            ),
+            None => "Hits (pick a token and press enter)".to_owned(),
+        };
+        let list = List::new(items)
+            .block(self.block(title, Focus::Hits))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, area, &mut self.hit_list);
+    }
+
+    fn draw_detail(&self, frame: &mut Frame, area: Rect) {
+        let Some(hit) = self.selected_hit() else {
+            frame.render_widget(Block::bordered().title("Hit"), area);
+            return;
+        };
+        let field = |name: &str, value: String| {
+            Line::from(vec![
+                Span::raw(format!("{name}: ")).bold(),
+                Span::raw(value),
+            ])
+        };
+        let meta = &hit.meta;
+        let mut lines = vec![
+            field("received", hit.received_at.to_rfc3339()),
+            field(
+                "client",
+                meta.client_ip
+                    .map_or("unknown".to_owned(), |ip| ip.to_string()),
+            ),
+            field(
+                "user-agent",
+                meta.user_agent.as_deref().map_or("-".to_owned(), clean),
+            ),
+            field(
+                "referer",
+                meta.referer.as_deref().map_or("-".to_owned(), clean),
+            ),
+        ];
+        for (name, value) in &meta.headers {
+            lines.push(field(&clean(name), clean(value)));
+        }
+        lines.push(Line::raw(""));
+        let mut data: Vec<_> = hit.data.iter().collect();
+        data.sort();
+        for (name, value) in data {
+            lines.push(field(&clean(name), clean(value)));
+        }
+        for (index, attachment) in hit.attachments.iter().enumerate() {
+            let name = attachment.filename.as_ref().unwrap_or(&attachment.name);
+            lines.push(field(
+                &format!("attachment {index}"),
+                format!(
+                    "{} ({} bytes), {}/n/{}/attachments/{index}",
+                    clean(name),
+                    attachment.data.len(),
+                    self.base_url,
+                    hit.uuid
+                ),
+            ));
+        }
+        let paragraph = Paragraph::new(lines)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(format!("Hit #{} {}", hit.seq, hit.uuid)));
+        frame.render_widget(paragraph, area);
+    }
+}
+
+async fn run(
+    terminal: &mut DefaultTerminal,
+    mut app: App,
+    mut updates: UnboundedReceiver<Update>,
+) -> Result<(), Error> {
+    loop {
+        while let Ok(update) = updates.try_recv() {
+            app.apply(update);
+        }
+        terminal.draw(|frame| app.draw(frame))?;
+        // Blocks this thread for at most a tick, the requests run on the others.
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !app.key(key.code, key.modifiers) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let mut client = match Client::new(&args.url) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("xss-tui: {e:#}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(key) = &args.api_key {
+        client = client.with_api_key(key);
+    }
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut token_list = ListState::default();
+    if !args.tokens.is_empty() {
+        token_list.select(Some(0));
+    }
+    let app = App {
+        client: client.clone(),
+        base_url: args.url.trim_end_matches('/').to_owned(),
+        extra: args.tokens,
+        tokens: Vec::new(),
+        token_list,
+        watching: None,
+        follow: None,
+        hits: Vec::new(),
+        hit_list: ListState::default(),
+        triaged: HashSet::new(),
+        focus: Focus::Tokens,
+        status: String::new(),
+        updates: tx.clone(),
+    };
+    fetch_tokens(client, tx);
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, app, rx).await;
+    ratatui::restore();
+    if let Err(e) = result {
+        eprintln!("xss-tui: {e:#}");
+        std::process::exit(1);
+    }
+}
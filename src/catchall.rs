@@ -0,0 +1,110 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    accept, model::Payload, pages, request_meta, tls_fingerprint::ClientHello, AppError, AppState,
+};
+
+/// Stream that requests to unknown paths are recorded under.
+pub const TOKEN: &str = "catchall";
+
+/// Headers that may carry credentials for this server, as when a mistyped
+/// admin or poll URL lands here. Recorded as present, never with their value.
+const REDACTED: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+/// Records a request no route matched as a hit for [`TOKEN`], so payloads with
+/// a mangled URL and curious scanners still show up. Answers 404 either way,
+/// unless the path is one of the static `pages`, which are served instead.
+pub async fn record(
+    method: Method,
+    uri: Uri,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    hello: ClientHello,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    let config = state.config();
+    if matches!(method, Method::GET | Method::HEAD) {
+        if let Some(page) = pages::serve(&config, uri.path()) {
+            return Ok(page);
+        }
+    }
+    if !config.capture.catchall {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let mut data = Payload::new();
+    data.insert("method".to_owned(), method.to_string());
+    data.insert("path".to_owned(), uri.path().to_owned());
+    if let Some(query) = uri.query() {
+        data.insert("query".to_owned(), query.to_owned());
+    }
+    data.insert(
+        "headers".to_owned(),
+        serde_json::to_string(&recorded(&headers))?,
+    );
+    if !body.is_empty() {
+        let mut body = String::from_utf8_lossy(&body).into_owned();
+        let mut end = body.len().min(config.limits.max_value_len);
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        body.truncate(end);
+        data.insert("body".to_owned(), body);
+    }
+    let meta = request_meta(&config, &headers, source, hello);
+    accept(&state, TOKEN.to_owned(), data, Vec::new(), meta).await?;
+    Ok(StatusCode::NOT_FOUND.into_response())
+}
+
+/// `headers` as recorded, with the `REDACTED` ones blanked out.
+fn recorded(headers: &HeaderMap) -> BTreeMap<&str, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match REDACTED.contains(&name.as_str()) {
+                true => "[redacted]".to_owned(),
+                false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            };
+            (name.as_str(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_credentials() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("Authorization", "Bearer admin-key"),
+            ("X-Api-Key", "admin-key"),
+            ("Cookie", "session=1"),
+            ("Proxy-Authorization", "Basic Zm9vOmJhcg=="),
+            ("User-Agent", "curl/8.0"),
+        ] {
+            headers.insert(
+                axum::http::HeaderName::try_from(name).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        let recorded = recorded(&headers);
+        assert_eq!(recorded["user-agent"], "curl/8.0");
+        for name in REDACTED {
+            assert_eq!(recorded[name], "[redacted]");
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+
+use crate::config::{Eviction, Guarantee, Kick, LogFormat, LogLevel};
+
+/// Simple xss challenge check polling service
+///
+/// Flags override the config file and environment variables.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// TOML config file, also read from `XSS_CONFIG`.
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: 127.0.0.1:3000]
+    #[arg(long)]
+    pub bind: Option<SocketAddr>,
+    /// Override just the port of `--bind`.
+    #[arg(long, short)]
+    pub port: Option<u16>,
+    /// Suspended pollers allowed before the oldest gets kicked [default: 10000]
+    #[arg(long)]
+    pub max_pollers: Option<usize>,
+    /// Suspended pollers one token may hold before its own oldest gets kicked [default: 100]
+    #[arg(long)]
+    pub max_pollers_per_token: Option<usize>,
+    /// Who gets kicked once --max-pollers is reached, `none` refuses new polls [default: fair]
+    #[arg(long, value_enum)]
+    pub kick: Option<Kick>,
+    /// Upper bound for the `wait=` parameter of /poll-notified, in seconds [default: 3600]
+    #[arg(long)]
+    pub max_wait: Option<u64>,
+    /// Notifications kept per token while nobody is polling it, 0 disables buffering [default: 16]
+    #[arg(long)]
+    pub buffer_depth: Option<usize>,
+    /// What to drop once a token's buffer is full [default: drop-oldest]
+    #[arg(long, value_enum)]
+    pub buffer_eviction: Option<Eviction>,
+    /// Bytes all buffers may hold together before the least recently buffered
+    /// hits are dropped [default: 67108864]
+    #[arg(long)]
+    pub buffer_max_bytes: Option<usize>,
+    /// Whether polled hits must be acknowledged with POST /ack [default: at-most-once]
+    #[arg(long, value_enum)]
+    pub delivery_guarantee: Option<Guarantee>,
+    /// [default: info]
+    #[arg(long, value_enum)]
+    pub log_level: Option<LogLevel>,
+    /// [default: text]
+    #[arg(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+}
@@ -0,0 +1,328 @@
+//! Talks to a running server, so integrators do not have to hand roll the HTTP
+//! calls: minting tokens, waiting for hits and reading the history.
+
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use anyhow::Error;
+use reqwest::{header, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    history::History,
+    model::{ErrorBody, Notification},
+    storage::HistoryFilter,
+    tokens::{Created, NewToken, TokenInfo},
+};
+
+/// How long `Client::next` asks the server to hold each poll.
+const POLL_WAIT: Duration = Duration::from_secs(60);
+/// Slack on top of the poll wait before the request itself gives up.
+const POLL_SLACK: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delays between reconnection attempts double up to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A response the server refused with, the body being its explanation if any.
+#[derive(Debug)]
+pub struct Status {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl Status {
+    /// Whether trying again later can help: polls evicted to make room, an
+    /// overloaded or restarting server, or a proxy in between failing.
+    pub fn is_transient(&self) -> bool {
+        self.status == StatusCode::CONFLICT
+            || self.status == StatusCode::REQUEST_TIMEOUT
+            || self.status == StatusCode::TOO_MANY_REQUESTS
+            || self.status.is_server_error()
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server answered {}", self.status)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Status {}
+
+/// Whether `error` is worth retrying, see `Status::is_transient`. Connection
+/// problems are, anything else the server refused is not.
+fn transient(error: &Error) -> bool {
+    match error.downcast_ref::<Status>() {
+        Some(status) => status.is_transient(),
+        None => error.is::<reqwest::Error>(),
+    }
+}
+
+/// A client for one server. Cheap to clone, clones share connections.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl Client {
+    /// `base_url` is where the server is reachable, e.g. `https://callbacks.example.com`.
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        Ok(Client {
+            http: Client::builder().build()?,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            api_key: None,
+        })
+    }
+
+    /// Speaks HTTP/2 from the first byte, also over plain `http://`, so
+    /// concurrent polls share one connection instead of a socket each. Only for
+    /// servers reached directly, proxies in between rarely pass such h2c on.
+    pub fn with_http2(mut self) -> Result<Self, Error> {
+        self.http = Client::builder().http2_prior_knowledge().build()?;
+        Ok(self)
+    }
+
+    fn builder() -> reqwest::ClientBuilder {
+        reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .user_agent(concat!("xss_check_srv-client/", env!("CARGO_PKG_VERSION")))
+    }
+
+    /// Sends `key` as `X-Api-Key`, needed once the server lists `[[api_keys]]`.
+    pub fn with_api_key(mut self, key: impl Into<String>) -> Self {
+        self.api_key = Some(key.into());
+        self
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}{path}", self.base_url));
+        match &self.api_key {
+            Some(key) => request.header("x-api-key", key),
+            None => request,
+        }
+    }
+
+    /// Sends `request`, turning refusals into `Status` errors.
+    async fn send(request: RequestBuilder) -> Result<Response, Error> {
+        let response = request.send().await?;
+        if response.status().is_client_error() || response.status().is_server_error() {
+            let status = response.status();
+            let message = response.text().await.unwrap_or_default();
+            return Err(Status { status, message }.into());
+        }
+        Ok(response)
+    }
+
+    async fn json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, Error> {
+        Ok(Client::send(request).await?.json().await?)
+    }
+
+    /// Mints a token, see `POST /tokens`. Needs an admin key.
+    pub async fn create_token(&self, new: &NewToken) -> Result<Created, Error> {
+        Client::json(self.request(reqwest::Method::POST, "/tokens").json(new)).await
+    }
+
+    /// The registered tokens the API key may poll.
+    pub async fn tokens(&self) -> Result<Vec<TokenInfo>, Error> {
+        Client::json(self.request(reqwest::Method::GET, "/api/tokens")).await
+    }
+
+    /// Deletes a token and everything stored for it. Needs an admin key.
+    pub async fn delete_token(&self, token: &str) -> Result<(), Error> {
+        let path = format!("/api/tokens/{}", urlencode(token));
+        Client::send(self.request(reqwest::Method::DELETE, &path)).await?;
+        Ok(())
+    }
+
+    /// One long poll: the next hit for `token`, or `None` if nothing arrived
+    /// within `wait` (capped by the server's `limits.max_wait`).
+    pub async fn poll(&self, token: &str, wait: Duration) -> Result<Option<Notification>, Error> {
+        self.poll_since(token, None, wait).await
+    }
+
+    /// Like `poll`, but with `since` the `seq` of the last hit seen, first
+    /// returns any later one the server still has, in case a response got lost.
+    pub async fn poll_since(
+        &self,
+        token: &str,
+        since: Option<u64>,
+        wait: Duration,
+    ) -> Result<Option<Notification>, Error> {
+        let mut request = self
+            .request(reqwest::Method::GET, "/poll-notified")
+            .query(&[("token", token), ("wait", &wait.as_secs().to_string())])
+            .timeout(wait + POLL_SLACK);
+        if let Some(since) = since {
+            request = request.query(&[("since", since)]);
+        }
+        let response = Client::send(request).await?;
+        if response.status() == StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        let body = response.bytes().await?;
+        // With heartbeats the status is always 200, the body tells how it ended.
+        if let Ok(error) = serde_json::from_slice::<ErrorBody>(&body) {
+            let status = match error.error.as_str() {
+                "timeout" => return Ok(None),
+                "evicted" => StatusCode::CONFLICT,
+                "expired" | "revoked" => StatusCode::GONE,
+                "internal" => StatusCode::INTERNAL_SERVER_ERROR,
+                _ => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            return Err(Status {
+                status,
+                message: error.message,
+            }
+            .into());
+        }
+        Ok(Some(serde_json::from_slice(&body)?))
+    }
+
+    /// Waits however long it takes for the next hit for `token`, polling again
+    /// after timeouts and reconnecting with growing delays when the connection
+    /// drops, the poll is evicted or the server is overloaded or restarting.
+    /// Fails only on errors retrying cannot fix, such as a revoked token.
+    ///
+    /// With at-least-once delivery, confirm the hit with `ack` once handled.
+    pub async fn next(&self, token: &str) -> Result<Notification, Error> {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            match self.poll(token, POLL_WAIT).await {
+                Ok(Some(notification)) => return Ok(notification),
+                Ok(None) => backoff = Duration::from_secs(1),
+                Err(e) if transient(&e) => {
+                    warn!("Polling failed, retrying in {backoff:?}: {e:#}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Confirms a hit handed out with a `delivery_id`, see `POST /ack`.
+    pub async fn ack(&self, delivery_id: Uuid) -> Result<(), Error> {
+        let request = self
+            .request(reqwest::Method::POST, "/ack")
+            .query(&[("delivery_id", delivery_id.to_string())]);
+        Client::send(request).await?;
+        Ok(())
+    }
+
+    /// Every hit for `token` as it arrives, over `/events`.
+    pub fn events(&self, token: &str) -> Events {
+        Events {
+            client: self.clone(),
+            token: token.to_owned(),
+            response: None,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// One page of stored hits matching `filter`, oldest first. Pages count from 1.
+    pub async fn history(
+        &self,
+        filter: &HistoryFilter,
+        page: u32,
+        per_page: u32,
+    ) -> Result<History, Error> {
+        let request = self
+            .request(reqwest::Method::GET, "/api/notifications")
+            .query(filter)
+            .query(&[("page", page), ("per_page", per_page)]);
+        Client::json(request).await
+    }
+}
+
+/// A subscription to `/events`, see `Client::events`. Connects on the first
+/// `next` and reconnects whenever the stream drops, like a browser would.
+pub struct Events {
+    client: Client,
+    token: String,
+    response: Option<Response>,
+    /// Received bytes not yet ending in a blank line.
+    buffer: Vec<u8>,
+    pending: VecDeque<Notification>,
+}
+
+impl Events {
+    /// The next hit. Fails only on errors reconnecting cannot fix, such as a
+    /// revoked token; hits arriving while disconnected may be missed unless the
+    /// server buffered them.
+    pub async fn next(&mut self) -> Result<Notification, Error> {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            if let Some(notification) = self.pending.pop_front() {
+                return Ok(notification);
+            }
+            match self.read().await {
+                Ok(()) => backoff = Duration::from_secs(1),
+                Err(e) if transient(&e) => {
+                    self.response = None;
+                    self.buffer.clear();
+                    warn!("Event stream dropped, reconnecting in {backoff:?}: {e:#}");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Connects if needed and reads one chunk, queueing the events it completes.
+    async fn read(&mut self) -> Result<(), Error> {
+        let response = match &mut self.response {
+            Some(response) => response,
+            None => {
+                let request = self
+                    .client
+                    .request(reqwest::Method::GET, "/events")
+                    .query(&[("token", &self.token)])
+                    .header(header::ACCEPT, "text/event-stream");
+                self.response.insert(Client::send(request).await?)
+            }
+        };
+        let Some(chunk) = response.chunk().await? else {
+            self.response = None;
+            self.buffer.clear();
+            return Ok(());
+        };
+        self.buffer.extend_from_slice(&chunk);
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let event = String::from_utf8_lossy(&event);
+            let data: Vec<&str> = event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect();
+            if !data.is_empty() {
+                self.pending
+                    .push_back(serde_json::from_str(&data.join("\n"))?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Percent-encodes `text` for use as one path segment.
+fn urlencode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
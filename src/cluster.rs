@@ -0,0 +1,249 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Error;
+use futures::StreamExt;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Script};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{config::RedisConfig, dispatch, model::Notification, tokens::TokenInfo, AppState};
+
+/// How long a claim on a hit is remembered. A hit still buffered on the
+/// instance that took it after this may be handed out there once more.
+const CLAIM_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// Fans notifications out to every replica through Redis pub/sub, so a hit
+/// received by one instance wakes pollers parked on another. Which replica
+/// hands a hit to a poll is settled by claiming it, see `claim`, and the
+/// registered tokens live in Redis too so every replica checks the same ones.
+#[derive(Clone)]
+pub struct Cluster {
+    connection: MultiplexedConnection,
+    channel: String,
+    /// Identifies messages this instance published itself.
+    instance: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: u64,
+    message: Message,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Message {
+    /// A hit the origin took, already dispatched there.
+    Hit(Box<Notification>),
+    /// A token was minted or changed, or deleted without `info`.
+    Token {
+        token: String,
+        info: Option<TokenInfo>,
+    },
+}
+
+impl Cluster {
+    pub async fn connect(config: &RedisConfig) -> Result<Cluster, Error> {
+        let client = redis::Client::open(config.url.as_str())?;
+        Ok(Cluster {
+            connection: client.get_multiplexed_async_connection().await?,
+            channel: config.channel.clone(),
+            instance: rand::random(),
+        })
+    }
+
+    pub async fn ping(&self) -> Result<(), Error> {
+        let _: String = redis::cmd("PING")
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    fn sequence_key(&self, token: &str) -> String {
+        format!("{}:seq:{token}", self.channel)
+    }
+
+    pub async fn next_seq(&self, token: &str) -> Result<u64, Error> {
+        Ok(self
+            .connection
+            .clone()
+            .incr(self.sequence_key(token), 1)
+            .await?)
+    }
+
+    /// Makes counters Redis does not know yet continue from what is stored.
+    pub async fn seed_sequences(&self, sequences: &HashMap<String, u64>) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        for (token, seq) in sequences {
+            pipe.set_nx(self.sequence_key(token), seq).ignore();
+        }
+        let _: () = pipe.query_async(&mut self.connection.clone()).await?;
+        Ok(())
+    }
+
+    pub async fn publish(&self, notification: &Notification) -> Result<(), Error> {
+        self.send(Message::Hit(Box::new(notification.clone())))
+            .await
+    }
+
+    async fn send(&self, message: Message) -> Result<(), Error> {
+        let envelope = Envelope {
+            origin: self.instance,
+            message,
+        };
+        let _: () = self
+            .connection
+            .clone()
+            .publish(&self.channel, serde_json::to_string(&envelope)?)
+            .await?;
+        Ok(())
+    }
+
+    fn claim_key(&self, uuid: Uuid) -> String {
+        format!("{}:claim:{uuid}", self.channel)
+    }
+
+    /// Whether this instance may hand the hit `uuid` to a poll: the first
+    /// replica to ask wins, and keeps winning when it asks again.
+    pub async fn claim(&self, uuid: Uuid) -> Result<bool, Error> {
+        let mut connection = self.connection.clone();
+        let key = self.claim_key(uuid);
+        let set: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(self.instance)
+            .arg("NX")
+            .arg("EX")
+            .arg(CLAIM_TTL)
+            .query_async(&mut connection)
+            .await?;
+        if set.is_some() {
+            return Ok(true);
+        }
+        let owner: Option<u64> = connection.get(&key).await?;
+        Ok(owner == Some(self.instance))
+    }
+
+    /// Gives up a claim this instance won but could not use, so the hit's
+    /// origin can still hand it out.
+    pub async fn release(&self, uuid: Uuid) -> Result<(), Error> {
+        let script = Script::new(
+            "if redis.call('GET', KEYS[1]) == ARGV[1] then redis.call('DEL', KEYS[1]) end",
+        );
+        let _: () = script
+            .key(self.claim_key(uuid))
+            .arg(self.instance)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+
+    fn tokens_key(&self) -> String {
+        format!("{}:tokens", self.channel)
+    }
+
+    /// Registers tokens Redis does not know yet, from this instance's storage.
+    pub async fn seed_tokens(&self, tokens: &HashMap<String, TokenInfo>) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        for (token, info) in tokens {
+            pipe.hset_nx(self.tokens_key(), token, serde_json::to_string(info)?)
+                .ignore();
+        }
+        let _: () = pipe.query_async(&mut self.connection.clone()).await?;
+        Ok(())
+    }
+
+    /// Every token registered with any replica.
+    pub async fn tokens(&self) -> Result<HashMap<String, TokenInfo>, Error> {
+        let saved: HashMap<String, String> =
+            self.connection.clone().hgetall(self.tokens_key()).await?;
+        let mut tokens = HashMap::new();
+        for (token, info) in saved {
+            match serde_json::from_str(&info) {
+                Ok(info) => {
+                    tokens.insert(token, info);
+                }
+                Err(e) => warn!("Ignoring malformed token in redis: {e}"),
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Registers `info` with every replica.
+    pub async fn save_token(&self, info: &TokenInfo) -> Result<(), Error> {
+        let _: () = self
+            .connection
+            .clone()
+            .hset(self.tokens_key(), &info.token, serde_json::to_string(info)?)
+            .await?;
+        self.send(Message::Token {
+            token: info.token.clone(),
+            info: Some(info.clone()),
+        })
+        .await
+    }
+
+    /// Forgets `token` on every replica.
+    pub async fn delete_token(&self, token: &str) -> Result<(), Error> {
+        let _: () = self
+            .connection
+            .clone()
+            .hdel(self.tokens_key(), token)
+            .await?;
+        self.send(Message::Token {
+            token: token.to_owned(),
+            info: None,
+        })
+        .await
+    }
+}
+
+/// Dispatches hits other replicas published to local pollers and applies
+/// their token changes, reconnecting on failure.
+///
+/// Only the instance that received a hit buffers it, the others merely wake
+/// whoever is already waiting on them.
+pub async fn subscribe_loop(config: RedisConfig, state: AppState) {
+    let Some(cluster) = state.cluster.clone() else {
+        return;
+    };
+    loop {
+        if let Err(e) = subscribe(&config, &state, &cluster).await {
+            error!("Redis subscription failed: {e:#}");
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn subscribe(config: &RedisConfig, state: &AppState, cluster: &Cluster) -> Result<(), Error> {
+    let client = redis::Client::open(config.url.as_str())?;
+    let mut pubsub = client.get_async_pubsub().await?;
+    pubsub.subscribe(&config.channel).await?;
+    // Token changes published while we were not listening.
+    *state.tokens.lock().expect("") = cluster.tokens().await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let envelope: Envelope = match serde_json::from_slice(message.get_payload_bytes()) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Ignoring malformed cluster message: {e}");
+                continue;
+            }
+        };
+        // Our own hits were dispatched when they arrived.
+        if envelope.origin == cluster.instance {
+            continue;
+        }
+        match envelope.message {
+            Message::Hit(notification) => dispatch(state, *notification, false).await,
+            Message::Token { token, info } => {
+                let mut tokens = state.tokens.lock().expect("");
+                match info {
+                    Some(info) => tokens.insert(token, info),
+                    None => tokens.remove(&token),
+                };
+            }
+        }
+    }
+    Ok(())
+}
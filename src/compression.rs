@@ -0,0 +1,19 @@
+use axum::http::{Extensions, HeaderMap, StatusCode, Version};
+use tower_http::compression::{
+    predicate::{DefaultPredicate, Predicate},
+    CompressionLayer,
+};
+
+/// Compresses responses with gzip or brotli for clients sending
+/// `Accept-Encoding`, as hits carrying DOM snapshots and screenshots get large.
+/// Skips what the default predicate does (images, event streams, tiny bodies)
+/// and heartbeat polls, whose spaces would sit in the encoder instead of
+/// keeping the connection alive.
+pub fn layer() -> CompressionLayer<impl Predicate> {
+    let bufferable = |_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions| {
+        headers
+            .get("x-accel-buffering")
+            .is_none_or(|value| value != "no")
+    };
+    CompressionLayer::new().compress_when(DefaultPredicate::new().and(bufferable))
+}
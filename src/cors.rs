@@ -0,0 +1,30 @@
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::{config::NotifyResponse, Config, NOTIFICATION_ID};
+
+/// Lets injected scripts `fetch()` the beacon routes from the victim's origin,
+/// answering preflights for them. `None` when no origin is allowed.
+pub fn layer(config: &Config) -> Option<CorsLayer> {
+    let origins = match config.cors.allow_origins.as_slice() {
+        [] => return None,
+        [any] if any == "*" => AllowOrigin::from(Any),
+        origins => AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin).expect("validated origin")),
+        ),
+    };
+    let layer = CorsLayer::new()
+        .allow_origin(origins)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers(Any)
+        .max_age(Duration::from_secs(24 * 60 * 60));
+    // A disguised endpoint should not name the header it left out.
+    Some(match config.notify_response {
+        NotifyResponse::Status => layer.expose_headers([HeaderName::from_static(NOTIFICATION_ID)]),
+        _ => layer,
+    })
+}
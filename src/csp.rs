@@ -0,0 +1,171 @@
+//! Collects Content-Security-Policy violation reports, so a policy under test
+//! can point `report-uri` or `report-to` here. Each report becomes a hit for
+//! the token in the path, its fields normalized to the same snake_case names
+//! whether a browser sent the legacy `application/csp-report` body or a
+//! Reporting API batch.
+
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, Path, Query, RawBody, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+
+use crate::{
+    accept, model::Payload, request_meta, tls_fingerprint::ClientHello, upload, AppError, AppState,
+};
+
+/// Reporting API body fields whose legacy names differ by more than case.
+const RENAMED: &[(&str, &str)] = &[
+    ("documentURL", "document_uri"),
+    ("blockedURL", "blocked_uri"),
+    ("sample", "script_sample"),
+];
+
+/// Records each CSP violation in the body as a hit for `token`, with query
+/// parameters such as `s=` added to every one. Reports of other types in a
+/// Reporting API batch are skipped.
+#[utoipa::path(
+    post,
+    path = "/csp-report/{token}",
+    tag = "beacons",
+    params(("token" = String, Path, description = "Token")),
+    request_body(
+        content = Object,
+        description = "A `{\"csp-report\": {..}}` report, or a Reporting API batch as `application/reports+json`",
+        content_type = "application/csp-report",
+    ),
+    responses(
+        (status = 204, description = "Reports recorded"),
+        (status = 400, description = "Not a CSP report"),
+        (status = 410, description = "Token expired or revoked"),
+        (status = 413, description = "Body over `limits.max_body`"),
+        (status = 415, description = "Neither a CSP report nor a Reporting API batch"),
+    ),
+)]
+pub async fn report(
+    Path(token): Path<String>,
+    Query(params): Query<Payload>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    hello: ClientHello,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Result<Response, AppError> {
+    let config = state.config();
+    let mime = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    let batch = match mime.as_str() {
+        "application/csp-report" | "application/json" => false,
+        "application/reports+json" => true,
+        _ => return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response()),
+    };
+    let body = match upload::read(body, config.limits.max_body).await {
+        Ok(body) => body,
+        Err(status) => return Ok(status.into_response()),
+    };
+    let reports = serde_json::from_slice(&body)
+        .ok()
+        .and_then(|body| match batch {
+            false => legacy(body),
+            true => reporting_api(body),
+        });
+    let Some(reports) = reports else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    for fields in reports {
+        let mut data = params.clone();
+        data.extend(fields);
+        let meta = request_meta(&config, &headers, source, hello.clone());
+        let accepted = accept(&state, token.clone(), data, Vec::new(), meta).await?;
+        if !accepted.status.is_success() {
+            return Ok(accepted.status.into_response());
+        }
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// The `csp-report` object of a `report-uri` body, keys already kebab-case.
+fn legacy(body: Value) -> Option<Vec<Payload>> {
+    let Value::Object(mut body) = body else {
+        return None;
+    };
+    let Some(Value::Object(report)) = body.remove("csp-report") else {
+        return None;
+    };
+    let mut fields = fields(
+        report
+            .into_iter()
+            .map(|(key, value)| (key.replace('-', "_"), value)),
+    );
+    fields.insert("report".to_owned(), "csp-violation".to_owned());
+    Some(vec![fields])
+}
+
+/// The `csp-violation` reports of a Reporting API batch, with their camelCase
+/// body keys turned into the legacy names. `url` and `user_agent` come along.
+fn reporting_api(body: Value) -> Option<Vec<Payload>> {
+    let Value::Array(batch) = body else {
+        return None;
+    };
+    let mut reports = Vec::new();
+    for report in batch {
+        let Value::Object(mut report) = report else {
+            return None;
+        };
+        if report.get("type").and_then(Value::as_str) != Some("csp-violation") {
+            continue;
+        }
+        let Some(Value::Object(body)) = report.remove("body") else {
+            return None;
+        };
+        let mut fields = fields(body.into_iter().map(|(key, value)| (snake(&key), value)));
+        for key in ["url", "user_agent"] {
+            if let Some(Value::String(value)) = report.remove(key) {
+                fields.insert(key.to_owned(), value);
+            }
+        }
+        // Legacy reports name the directive this way, so filters match both.
+        if let Some(directive) = fields.get("effective_directive").cloned() {
+            fields
+                .entry("violated_directive".to_owned())
+                .or_insert(directive);
+        }
+        fields.insert("report".to_owned(), "csp-violation".to_owned());
+        reports.push(fields);
+    }
+    Some(reports)
+}
+
+/// Report fields as hit fields, numbers as text and nulls left out.
+fn fields(report: impl Iterator<Item = (String, Value)>) -> Payload {
+    report
+        .filter_map(|(key, value)| match value {
+            Value::Null => None,
+            Value::String(text) => Some((key, text)),
+            other => Some((key, other.to_string())),
+        })
+        .collect()
+}
+
+/// `lineNumber` as `line_number`, and the few renamed ones as their legacy name.
+fn snake(key: &str) -> String {
+    if let Some((_, legacy)) = RENAMED.iter().find(|(name, _)| *name == key) {
+        return (*legacy).to_owned();
+    }
+    let mut snake = String::new();
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
@@ -0,0 +1,100 @@
+//! Collapses identical hits into one record, as payloads on frequently reloaded
+//! pages fire again with every visit.
+
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::DedupConfig,
+    lru::Lru,
+    model::{Attachment, Payload},
+};
+
+/// Fingerprints tracked at most, beyond that the oldest is forgotten even
+/// if its window has not passed yet.
+const MAX_FINGERPRINTS: usize = 100_000;
+
+/// Identifies a hit by its token, fields, attachments and client IP.
+pub type Fingerprint = [u8; 32];
+
+struct Seen {
+    id: i64,
+    at: Instant,
+}
+
+/// The hits recorded within the last `window`, by fingerprint.
+pub struct Dedup {
+    window: Duration,
+    seen: Mutex<Lru<Fingerprint, Seen>>,
+}
+
+impl Dedup {
+    pub fn new(config: &DedupConfig) -> Self {
+        Dedup {
+            window: Duration::from_secs(config.window),
+            seen: Mutex::new(Lru::new(MAX_FINGERPRINTS)),
+        }
+    }
+
+    /// The notification an identical hit was recorded as, if that was less than
+    /// `window` ago. The window starts with the first hit, so a page that keeps
+    /// reloading still leaves one record per window.
+    pub fn duplicate_of(&self, fingerprint: &Fingerprint) -> Option<i64> {
+        let seen = self.seen.lock().expect("");
+        let seen = seen.get(fingerprint)?;
+        (seen.at.elapsed() < self.window).then_some(seen.id)
+    }
+
+    /// Remembers the hit with `fingerprint` as recorded under `id`.
+    pub fn remember(&self, fingerprint: Fingerprint, id: i64) {
+        let seen = Seen {
+            id,
+            at: Instant::now(),
+        };
+        self.seen.lock().expect("").insert(fingerprint, seen);
+    }
+}
+
+/// Hashes what makes two hits the same: the token, the fields in name order
+/// with surrounding whitespace trimmed from values, the attachments and the
+/// client IP.
+pub fn fingerprint(
+    token: &str,
+    data: &Payload,
+    attachments: &[Attachment],
+    client_ip: Option<IpAddr>,
+) -> Fingerprint {
+    let mut hash = Sha256::new();
+    // Length prefixes keep `a=bc` and `ab=c` apart.
+    let mut part = |bytes: &[u8]| {
+        hash.update((bytes.len() as u64).to_le_bytes());
+        hash.update(bytes);
+    };
+    part(token.as_bytes());
+    let mut fields: Vec<_> = data.iter().collect();
+    fields.sort();
+    for (name, value) in fields {
+        part(name.as_bytes());
+        part(value.trim().as_bytes());
+    }
+    for attachment in attachments {
+        part(attachment.name.as_bytes());
+        // A spooled upload was hashed as it arrived.
+        match &attachment.id {
+            Some(id) => part(id.as_bytes()),
+            None => part(&attachment.data),
+        }
+    }
+    part(
+        client_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+    hash.finalize().into()
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::Deserialize;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::{auth::ApiKey, config::Guarantee, hub::Hub, model::Notification, AppState};
+
+/// How often held deliveries are checked for an expired visibility timeout.
+const REDELIVER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records that `notification` goes out with a poll: settled right away, or
+/// in at-least-once mode held until acknowledged and given a delivery id.
+pub fn hand_out(state: &AppState, hub: &mut Hub, notification: Notification) -> Notification {
+    let delivery = &state.config().delivery;
+    match delivery.guarantee {
+        Guarantee::AtMostOnce => {
+            state.settle(vec![notification.id]);
+            notification
+        }
+        Guarantee::AtLeastOnce => hub.hold(notification, delivery.visibility_timeout()),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Ack {
+    /// As handed out with the poll delivery.
+    delivery_id: Uuid,
+}
+
+/// Confirms a poll delivery, so the notification is not handed out again.
+#[utoipa::path(
+    post,
+    path = "/ack",
+    tag = "polling",
+    params(Ack),
+    responses(
+        (status = 204, description = "Acknowledged"),
+        (status = 404, description = "Unknown, already acknowledged or redelivered"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn ack(
+    Query(Ack { delivery_id }): Query<Ack>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> StatusCode {
+    let notification = {
+        let mut hub = state.futures.lock().expect("");
+        let Some(token) = hub.held_for(delivery_id) else {
+            return StatusCode::NOT_FOUND;
+        };
+        if let Err(status) = key.check(token) {
+            return status;
+        }
+        hub.ack(delivery_id).expect("just found")
+    };
+    state.settle(vec![notification.id]);
+    StatusCode::NO_CONTENT
+}
+
+/// Hands out unacknowledged deliveries again once their visibility timeout
+/// passes, to a waiting poller or else first in line in the buffer.
+pub async fn redeliver_loop(state: AppState) {
+    loop {
+        tokio::time::sleep(REDELIVER_INTERVAL).await;
+        let mut woken = Vec::new();
+        {
+            let mut hub = state.futures.lock().expect("");
+            for notification in hub.expired() {
+                let fanout = state.fanout(&notification.token);
+                let pollers = hub.take_pollers(&notification.token, fanout);
+                if pollers.is_empty() {
+                    state.requeue(&mut hub, notification);
+                    continue;
+                }
+                woken.push((pollers, hand_out(&state, &mut hub, notification)));
+            }
+        }
+        for (pollers, notification) in woken {
+            for poller in pollers {
+                poller.fulfill(Ok(notification.clone()));
+            }
+        }
+    }
+}
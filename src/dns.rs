@@ -0,0 +1,499 @@
+//! A small authoritative DNS server for `dns.zone`, for payloads that can only
+//! make a target resolve a name: blind SSRF, XXE or command injection behind a
+//! firewall that lets nothing but DNS out. Every query for a name under
+//! `<token>.<zone>` is recorded as a hit for `token`. A and AAAA queries in the
+//! zone are answered with `dns.answers`, other types get an empty answer and
+//! names outside the zone are refused.
+
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Semaphore,
+    task, time,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    accept,
+    model::{IpVersion, Meta, Payload},
+    ratelimit::limited,
+    AppState,
+};
+
+const HEADER_LEN: usize = 12;
+/// Largest answer a UDP client takes without EDNS, longer ones are truncated.
+const UDP_MAX: usize = 512;
+/// How long a TCP connection may sit between queries.
+const TCP_IDLE: Duration = Duration::from_secs(10);
+/// Hits being recorded at once, queries beyond that are answered but not
+/// recorded. UDP sources are easily spoofed, so floods must not pile up tasks.
+const MAX_RECORDING: usize = 256;
+
+const QR: u16 = 0x8000;
+const OPCODE: u16 = 0x7800;
+const AA: u16 = 0x0400;
+const TC: u16 = 0x0200;
+const RD: u16 = 0x0100;
+
+const NOERROR: u8 = 0;
+const FORMERR: u8 = 1;
+const NOTIMP: u8 = 4;
+const REFUSED: u8 = 5;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Serves `dns.bind` over UDP and TCP until `shutdown` resolves. Returns right
+/// away when `dns` is not configured.
+pub async fn serve(state: AppState, shutdown: impl Future<Output = ()>) -> Result<(), Error> {
+    let config = state.config();
+    let Some(dns) = &config.dns else {
+        return Ok(());
+    };
+    let socket = UdpSocket::bind(dns.bind).await?;
+    let listener = TcpListener::bind(dns.bind).await?;
+    info!("DNS listening on {}", dns.bind);
+    let recording = Arc::new(Semaphore::new(MAX_RECORDING));
+    tokio::select! {
+        () = udp(state.clone(), socket, recording.clone()) => {}
+        () = tcp(state, listener, recording) => {}
+        () = shutdown => {}
+    }
+    Ok(())
+}
+
+async fn udp(state: AppState, socket: UdpSocket, recording: Arc<Semaphore>) {
+    let mut packet = [0; 4096];
+    loop {
+        let (len, source) = match socket.recv_from(&mut packet).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to receive a DNS query: {e}");
+                continue;
+            }
+        };
+        let packet = &packet[..len];
+        let Some(reply) = answer(&state, &recording, packet, source, "udp", UDP_MAX) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&reply, source).await {
+            debug!("Failed to answer {source}: {e}");
+        }
+    }
+}
+
+async fn tcp(state: AppState, listener: TcpListener, recording: Arc<Semaphore>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, source)) => {
+                let recording = recording.clone();
+                task::spawn(connection(state.clone(), recording, stream, source));
+            }
+            Err(e) => {
+                warn!("Failed to accept a DNS connection: {e}");
+                // Usually out of file descriptors, give some a chance to close.
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Answers length-prefixed queries until the client closes the connection or
+/// stays quiet for `TCP_IDLE`.
+async fn connection(
+    state: AppState,
+    recording: Arc<Semaphore>,
+    mut stream: TcpStream,
+    source: SocketAddr,
+) {
+    loop {
+        let Ok(Ok(len)) = time::timeout(TCP_IDLE, stream.read_u16()).await else {
+            return;
+        };
+        let mut packet = vec![0; usize::from(len)];
+        let Ok(Ok(_)) = time::timeout(TCP_IDLE, stream.read_exact(&mut packet)).await else {
+            return;
+        };
+        let max_len = usize::from(u16::MAX);
+        let Some(reply) = answer(&state, &recording, &packet, source, "tcp", max_len) else {
+            return;
+        };
+        let mut framed = (reply.len() as u16).to_be_bytes().to_vec();
+        framed.extend(reply);
+        if stream.write_all(&framed).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// The reply to one query packet, no longer than `max_len`, recording the query
+/// if it names a token. `None` for packets that deserve no answer.
+fn answer(
+    state: &AppState,
+    recording: &Arc<Semaphore>,
+    packet: &[u8],
+    source: SocketAddr,
+    transport: &'static str,
+    max_len: usize,
+) -> Option<Vec<u8>> {
+    let config = state.config();
+    let dns = config.dns.as_ref()?;
+    let header = Header::parse(packet)?;
+    let question = match Question::parse(&header, packet) {
+        Ok(question) => question,
+        Err(rcode) => return Some(reply(&header, rcode, None, &[], 0)),
+    };
+    let zone: Vec<String> = dns.zone(&config)?.split('.').map(str::to_owned).collect();
+    if !question.labels.ends_with(&zone) {
+        return Some(reply(&header, REFUSED, Some(&question), &[], 0));
+    }
+    let below = &question.labels[..question.labels.len() - zone.len()];
+    if let Some((token, subdomain)) = below.split_last() {
+        record(
+            state,
+            recording,
+            token.clone(),
+            subdomain,
+            question.qtype,
+            source,
+            transport,
+        );
+    }
+    let records: Vec<IpAddr> = dns
+        .answers
+        .iter()
+        .copied()
+        .filter(|ip| {
+            question.qclass == CLASS_IN
+                && match ip {
+                    IpAddr::V4(_) => question.qtype == TYPE_A,
+                    IpAddr::V6(_) => question.qtype == TYPE_AAAA,
+                }
+        })
+        .collect();
+    let mut out = reply(&header, NOERROR, Some(&question), &records, dns.ttl);
+    if out.len() > max_len {
+        // The client is expected to ask again over TCP.
+        out = reply(&header, NOERROR, Some(&question), &[], dns.ttl);
+        out[2] |= (TC >> 8) as u8;
+    }
+    Some(out)
+}
+
+/// Records the query as a hit for `token` in the background, so the answer does
+/// not wait for storage. For a token minted with a `secret` the label in front
+/// of it is taken to be the secret, like `s=` on `/notify`. Sources over their
+/// `rate_limit` and queries past `MAX_RECORDING` in flight are not recorded.
+fn record(
+    state: &AppState,
+    recording: &Arc<Semaphore>,
+    token: String,
+    mut subdomain: &[String],
+    qtype: u16,
+    source: SocketAddr,
+    transport: &'static str,
+) {
+    if limited(state, source.ip()) {
+        debug!("Not recording a DNS query from {source}, over the rate limit");
+        return;
+    }
+    let Ok(permit) = recording.clone().try_acquire_owned() else {
+        debug!("Not recording a DNS query from {source}, too many in flight");
+        return;
+    };
+    let mut data = Payload::new();
+    let has_secret = state
+        .tokens
+        .lock()
+        .expect("")
+        .get(&token)
+        .is_some_and(|info| info.secret.is_some());
+    if has_secret {
+        if let Some((secret, rest)) = subdomain.split_last() {
+            data.insert("s".to_owned(), secret.clone());
+            subdomain = rest;
+        }
+    }
+    if !subdomain.is_empty() {
+        data.insert("subdomain".to_owned(), subdomain.join("."));
+    }
+    data.insert("via".to_owned(), "dns".to_owned());
+    data.insert("qtype".to_owned(), type_name(qtype));
+    data.insert("transport".to_owned(), transport.to_owned());
+    // The resolver asking on the target's behalf, not the target itself.
+    let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+    let meta = Meta {
+        remote_addr: Some(source),
+        client_ip: Some(source.ip()),
+        ip_version: Some(IpVersion::of(source.ip())),
+        ..Meta::default()
+    };
+    let state = state.clone();
+    state.writes.clone().spawn(async move {
+        match accept(&state, token, data, Vec::new(), meta).await {
+            Ok(accepted) if !accepted.status.is_success() => {
+                debug!("DNS hit refused with {}", accepted.status);
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to record a DNS hit: {e:#}"),
+        }
+        drop(permit);
+    });
+}
+
+/// The mnemonic of a query type, `TYPE<n>` for ones without a common name.
+fn type_name(qtype: u16) -> String {
+    let name = match qtype {
+        TYPE_A => "A",
+        2 => "NS",
+        5 => "CNAME",
+        6 => "SOA",
+        12 => "PTR",
+        15 => "MX",
+        16 => "TXT",
+        TYPE_AAAA => "AAAA",
+        33 => "SRV",
+        64 => "SVCB",
+        65 => "HTTPS",
+        255 => "ANY",
+        other => return format!("TYPE{other}"),
+    };
+    name.to_owned()
+}
+
+struct Header {
+    id: u16,
+    flags: u16,
+    qdcount: u16,
+}
+
+impl Header {
+    /// `None` for packets too short to answer and for responses, which may
+    /// have been reflected at us.
+    fn parse(packet: &[u8]) -> Option<Header> {
+        if packet.len() < HEADER_LEN {
+            return None;
+        }
+        let word = |at: usize| u16::from_be_bytes([packet[at], packet[at + 1]]);
+        let header = Header {
+            id: word(0),
+            flags: word(2),
+            qdcount: word(4),
+        };
+        (header.flags & QR == 0).then_some(header)
+    }
+}
+
+struct Question {
+    /// Lowercased, since resolvers may randomize the case of names they ask for.
+    labels: Vec<String>,
+    qtype: u16,
+    qclass: u16,
+    /// As it was sent, to be repeated in the reply.
+    wire: Vec<u8>,
+}
+
+impl Question {
+    /// The single question of a standard query, or the rcode to refuse it with.
+    fn parse(header: &Header, packet: &[u8]) -> Result<Question, u8> {
+        if header.flags & OPCODE != 0 {
+            return Err(NOTIMP);
+        }
+        if header.qdcount != 1 {
+            return Err(FORMERR);
+        }
+        let mut at = HEADER_LEN;
+        let mut labels = Vec::new();
+        loop {
+            let len = usize::from(*packet.get(at).ok_or(FORMERR)?);
+            at += 1;
+            if len == 0 {
+                break;
+            }
+            // Compression pointers have nothing to point back to in the first name.
+            if len > 63 {
+                return Err(FORMERR);
+            }
+            let label = packet.get(at..at + len).ok_or(FORMERR)?;
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            at += len;
+            // At most 255 bytes with the root label still to come.
+            if at - HEADER_LEN + 1 > 255 {
+                return Err(FORMERR);
+            }
+        }
+        let fixed = packet.get(at..at + 4).ok_or(FORMERR)?;
+        Ok(Question {
+            labels,
+            qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+            qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+            wire: packet[HEADER_LEN..at + 4].to_vec(),
+        })
+    }
+}
+
+/// A reply to `header` repeating `question`, with an A or AAAA record per
+/// address in `records`.
+fn reply(
+    header: &Header,
+    rcode: u8,
+    question: Option<&Question>,
+    records: &[IpAddr],
+    ttl: u32,
+) -> Vec<u8> {
+    let authoritative = if rcode == NOERROR { AA } else { 0 };
+    let flags = QR | authoritative | (header.flags & (OPCODE | RD)) | u16::from(rcode);
+    let mut out = Vec::with_capacity(UDP_MAX);
+    out.extend(header.id.to_be_bytes());
+    out.extend(flags.to_be_bytes());
+    out.extend(u16::from(question.is_some()).to_be_bytes());
+    out.extend((records.len() as u16).to_be_bytes());
+    // No authority or additional records.
+    out.extend([0; 4]);
+    if let Some(question) = question {
+        out.extend(&question.wire);
+    }
+    for ip in records {
+        let (rtype, rdata) = match ip {
+            IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+        };
+        // Points back at the name in the question.
+        out.extend([0xc0, HEADER_LEN as u8]);
+        out.extend(rtype.to_be_bytes());
+        out.extend(CLASS_IN.to_be_bytes());
+        out.extend(ttl.to_be_bytes());
+        out.extend((rdata.len() as u16).to_be_bytes());
+        out.extend(rdata);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A standard query for `name` with id 0x1234 and RD set.
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend(qtype.to_be_bytes());
+        packet.extend(CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    fn parse(packet: &[u8]) -> Result<Question, u8> {
+        let header = Header::parse(packet).expect("valid header");
+        Question::parse(&header, packet)
+    }
+
+    #[test]
+    fn parses_a_query() {
+        let question = parse(&query("Sub.ABCD.xss.example", TYPE_AAAA)).unwrap();
+        assert_eq!(question.labels, ["sub", "abcd", "xss", "example"]);
+        assert_eq!(question.qtype, TYPE_AAAA);
+        assert_eq!(question.qclass, CLASS_IN);
+        assert_eq!(question.wire.len(), 22 + 4);
+    }
+
+    #[test]
+    fn ignores_short_packets_and_responses() {
+        assert!(Header::parse(&[]).is_none());
+        assert!(Header::parse(&query("a.b", TYPE_A)[..HEADER_LEN - 1]).is_none());
+        let mut response = query("a.b", TYPE_A);
+        response[2] |= 0x80;
+        assert!(Header::parse(&response).is_none());
+    }
+
+    #[test]
+    fn refuses_other_opcodes_and_question_counts() {
+        let mut notify = query("a.b", TYPE_A);
+        notify[2] |= 0x20;
+        assert_eq!(parse(&notify).err(), Some(NOTIMP));
+        let mut two = query("a.b", TYPE_A);
+        two[5] = 2;
+        assert_eq!(parse(&two).err(), Some(FORMERR));
+        let mut none = query("a.b", TYPE_A);
+        none[5] = 0;
+        assert_eq!(parse(&none).err(), Some(FORMERR));
+    }
+
+    #[test]
+    fn refuses_truncated_questions() {
+        let packet = query("abcd.xss.example", TYPE_A);
+        // Cut inside a label, before the root label, and inside type and class.
+        for len in [
+            HEADER_LEN,
+            HEADER_LEN + 3,
+            HEADER_LEN + 18,
+            packet.len() - 1,
+        ] {
+            assert_eq!(parse(&packet[..len]).err(), Some(FORMERR), "cut at {len}");
+        }
+        let mut overlong = packet[..HEADER_LEN].to_vec();
+        overlong.extend([10, b'a', b'b']);
+        assert_eq!(parse(&overlong).err(), Some(FORMERR));
+    }
+
+    #[test]
+    fn refuses_compression_pointers() {
+        let mut header = query("a.b", TYPE_A)[..HEADER_LEN].to_vec();
+        // Past the end of the packet.
+        let mut past_end = header.clone();
+        past_end.extend([0xc0, 0xff, 0, 1, 0, 1]);
+        assert_eq!(parse(&past_end).err(), Some(FORMERR));
+        // At itself, which would loop.
+        header.extend([1, b'a', 0xc0, HEADER_LEN as u8, 0, 1, 0, 1]);
+        assert_eq!(parse(&header).err(), Some(FORMERR));
+    }
+
+    #[test]
+    fn refuses_names_over_255_bytes() {
+        let label = "a".repeat(63);
+        let longest = format!("{label}.{label}.{label}.{}", "a".repeat(61));
+        assert_eq!(parse(&query(&longest, TYPE_A)).unwrap().labels.len(), 4);
+        let too_long = format!("{label}.{label}.{label}.{}", "a".repeat(62));
+        assert_eq!(parse(&query(&too_long, TYPE_A)).err(), Some(FORMERR));
+        let many = [label.as_str(); 5].join(".");
+        assert_eq!(parse(&query(&many, TYPE_A)).err(), Some(FORMERR));
+    }
+
+    #[test]
+    fn answers_with_records() {
+        let packet = query("abcd.xss.example", TYPE_A);
+        let header = Header::parse(&packet).unwrap();
+        let question = Question::parse(&header, &packet).unwrap();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let out = reply(&header, NOERROR, Some(&question), &[ip], 60);
+        assert_eq!(&out[..2], [0x12, 0x34]);
+        assert_eq!(u16::from_be_bytes([out[2], out[3]]), QR | AA | RD);
+        assert_eq!(&out[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&out[HEADER_LEN..packet.len()], &packet[HEADER_LEN..]);
+        let answer = &out[packet.len()..];
+        assert_eq!(&answer[..2], [0xc0, HEADER_LEN as u8]);
+        assert_eq!(&answer[6..10], 60u32.to_be_bytes());
+        assert_eq!(&answer[10..], [0, 4, 192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn refusals_are_not_authoritative() {
+        let packet = query("abcd.elsewhere", TYPE_A);
+        let header = Header::parse(&packet).unwrap();
+        let out = reply(&header, REFUSED, None, &[], 0);
+        assert_eq!(out.len(), HEADER_LEN);
+        assert_eq!(u16::from_be_bytes([out[2], out[3]]), QR | RD | 5);
+    }
+}
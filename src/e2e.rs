@@ -0,0 +1,147 @@
+//! End-to-end encryption for tokens minted with an age `public_key`. Their hits
+//! are sealed to it the moment they arrive, before storage, pollers, webhooks
+//! or notifiers see them, so a compromised server has only ciphertext to leak.
+//! Whoever holds the identity opens them, e.g. with `xss-tail --identity`.
+
+use std::{
+    io::{self, BufWriter, Read, Write},
+    iter,
+    str::FromStr,
+    sync::Arc,
+};
+
+use age::{
+    x25519::{Identity, Recipient},
+    Encryptor,
+};
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    attachments::Store,
+    model::{Attachment, Meta, Notification, Payload},
+    tokens::hex,
+};
+
+/// The only field of a sealed notification's `data`, holding the ASCII armored
+/// fields and metadata.
+pub const SEALED: &str = "$age";
+
+/// The recipient `key` names, `None` unless it is an `age1...` X25519 public key.
+pub fn recipient(key: &str) -> Option<Recipient> {
+    Recipient::from_str(key.trim()).ok()
+}
+
+/// The first identity in `text`, an `age-keygen` key file.
+pub fn identity(text: &str) -> Option<Identity> {
+    text.lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .and_then(|line| Identity::from_str(line).ok())
+}
+
+/// What the `$age` field holds.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    data: Payload,
+    meta: Meta,
+}
+
+/// Moves `data` and `meta` into the `$age` field and encrypts the content of
+/// `attachments`, spooled ones into new spool files named by the hash of the
+/// ciphertext. Only the attachments' names and sizes stay readable.
+pub async fn seal(
+    recipient: &Recipient,
+    data: &mut Payload,
+    meta: &mut Meta,
+    attachments: &mut [Attachment],
+    store: Option<&Store>,
+) -> Result<(), Error> {
+    let envelope = serde_json::to_vec(&Envelope {
+        data: std::mem::take(data),
+        meta: std::mem::take(meta),
+    })?;
+    let sealed = age::encrypt_and_armor(recipient, &envelope)?;
+    data.insert(SEALED.to_owned(), sealed);
+    for attachment in attachments {
+        match (attachment.spooled.take(), store) {
+            (Some(spooled), Some(store)) => {
+                let (file, path) = store.spool()?.into_parts();
+                let recipient = recipient.clone();
+                let (id, size) = tokio::task::spawn_blocking(move || {
+                    encrypt_file(&recipient, &mut std::fs::File::open(&*spooled)?, file)
+                })
+                .await??;
+                attachment.id = Some(id);
+                attachment.size = size;
+                attachment.spooled = Some(Arc::new(path));
+            }
+            _ => {
+                attachment.data = age::encrypt(recipient, &attachment.data)?;
+                attachment.size = attachment.data.len() as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes `input` encrypted to `output`, returning the SHA-256 and length of
+/// what was written.
+fn encrypt_file(
+    recipient: &Recipient,
+    input: &mut impl Read,
+    output: std::fs::File,
+) -> Result<(String, u64), Error> {
+    let mut output = Hashing {
+        inner: BufWriter::new(output),
+        hash: Sha256::new(),
+        size: 0,
+    };
+    let encryptor = Encryptor::with_recipients(iter::once(recipient as _))?;
+    let mut writer = encryptor.wrap_output(&mut output)?;
+    io::copy(input, &mut writer)?;
+    writer.finish()?;
+    output.inner.flush()?;
+    Ok((hex(&output.hash.finalize()), output.size))
+}
+
+struct Hashing<W> {
+    inner: W,
+    hash: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hash.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Undoes `seal` on a notification with `identity`, leaving notifications
+/// that were not sealed as they are. Attachments kept as files are age files
+/// of their own, for `age -d` once downloaded.
+pub fn open(notification: &mut Notification, identity: &Identity) -> Result<(), Error> {
+    let (1, Some(sealed)) = (notification.data.len(), notification.data.get(SEALED)) else {
+        return Ok(());
+    };
+    let context = || format!("failed to decrypt notification {}", notification.uuid);
+    let envelope = age::decrypt(identity, sealed.as_bytes()).with_context(context)?;
+    let envelope: Envelope = serde_json::from_slice(&envelope).with_context(context)?;
+    for attachment in &mut notification.attachments {
+        if !attachment.data.is_empty() {
+            attachment.data = age::decrypt(identity, &attachment.data).with_context(context)?;
+            attachment.size = attachment.data.len() as u64;
+        }
+    }
+    notification.data = envelope.data;
+    notification.meta = envelope.meta;
+    Ok(())
+}
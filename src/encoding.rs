@@ -0,0 +1,121 @@
+use std::convert::Infallible;
+
+use async_trait::async_trait;
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::error;
+
+/// How a response body is encoded, picked from the request's `Accept` header.
+///
+/// MessagePack and CBOR carry exactly what the JSON would, field names and
+/// all, so consumers can switch without mapping anything differently.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl Encoding {
+    fn from_media_type(media_type: &str) -> Option<Encoding> {
+        match media_type {
+            "application/json" | "application/*" | "*/*" => Some(Encoding::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Encoding::MsgPack)
+            }
+            "application/cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The supported type `accept` prefers most, the first of equally preferred
+    /// ones. Falls back to JSON, also when nothing listed is supported.
+    pub fn negotiate(accept: &str) -> Encoding {
+        let mut best = (0.0, Encoding::Json);
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let Some(encoding) = Encoding::from_media_type(&media_type) else {
+                continue;
+            };
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q > best.0 {
+                best = (q, encoding);
+            }
+        }
+        best.1
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::MsgPack => "application/msgpack",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, anyhow::Error> {
+        if self == Encoding::Json {
+            return Ok(serde_json::to_vec(value)?);
+        }
+        // Through JSON values, so uuids, addresses and attachments come out as
+        // the same strings rather than each format's compact binary forms.
+        let value = serde_json::to_value(value)?;
+        let mut body = Vec::new();
+        match self {
+            Encoding::Json => unreachable!(),
+            Encoding::MsgPack => rmp_serde::encode::write_named(&mut body, &value)?,
+            Encoding::Cbor => ciborium::into_writer(&value, &mut body)?,
+        }
+        Ok(body)
+    }
+
+    /// `value` as the body of a `status` response.
+    pub fn respond<T: Serialize>(self, status: StatusCode, value: &T) -> Response {
+        self.try_respond(status, value).unwrap_or_else(|e| {
+            error!("Failed to encode a response: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })
+    }
+
+    /// Like `respond`, leaving encoding failures to the caller.
+    pub fn try_respond<T: Serialize>(
+        self,
+        status: StatusCode,
+        value: &T,
+    ) -> Result<Response, anyhow::Error> {
+        let body = self.encode(value)?;
+        Ok((
+            status,
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(self.content_type()),
+                ),
+                (header::VARY, HeaderValue::from_static("accept")),
+            ],
+            body,
+        )
+            .into_response())
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Encoding {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map_or(Encoding::Json, Encoding::negotiate))
+    }
+}
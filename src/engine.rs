@@ -0,0 +1,222 @@
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Error};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use axum::{body::Body, http::Request, response::IntoResponse, Router};
+use tokio::task;
+use tokio_util::task::TaskTracker;
+use tower::{Layer, Service};
+
+use crate::{
+    acme, attachments, cluster, dedup, delivery, endpoints,
+    geoip::{self, GeoIp},
+    hub::Hub,
+    notifiers::{self, Dispatcher, Notifier},
+    ratelimit::{Quotas, RateLimiter},
+    retention,
+    storage::{self, Storage},
+    telemetry::LogHandle,
+    tokens, AppState, Cluster, Config, Metrics,
+};
+
+/// Puts an `AppState` together, optionally with storage and notifiers of the
+/// embedding app instead of the ones `config` describes. Injected storage
+/// replaces `[storage]` entirely, injected notifiers come on top of the
+/// configured ones and survive reloads.
+pub struct Engine {
+    config: Config,
+    storage: Option<Arc<dyn Storage>>,
+    notifiers: Vec<Arc<dyn Notifier>>,
+    log_filter: Option<LogHandle>,
+}
+
+impl Engine {
+    pub fn new(config: Config) -> Self {
+        Engine {
+            config,
+            storage: None,
+            notifiers: Vec::new(),
+            log_filter: None,
+        }
+    }
+
+    pub fn storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn notifier(mut self, notifier: Arc<dyn Notifier>) -> Self {
+        self.notifiers.push(notifier);
+        self
+    }
+
+    /// Lets config reloads change the log level, see `telemetry::init`.
+    pub fn log_filter(mut self, log_filter: LogHandle) -> Self {
+        self.log_filter = Some(log_filter);
+        self
+    }
+
+    /// Checks the configuration, opens storage, restores buffered hits, tokens and
+    /// sequence numbers, and starts the background loops (token purging,
+    /// retention, redelivery, digests, redis) on the current tokio runtime.
+    pub async fn start(self) -> Result<AppState, Error> {
+        let Engine {
+            config,
+            storage,
+            notifiers,
+            log_filter,
+        } = self;
+        config.validate()?;
+        let storage = match storage {
+            Some(storage) => storage,
+            None => storage::connect(&config.storage)
+                .await
+                .context("failed to open storage")?,
+        };
+        let keys = match &config.encryption {
+            Some(encryption) => Some(Arc::new(
+                storage::Keys::load(encryption)
+                    .await
+                    .context("failed to load the encryption keys")?,
+            )),
+            None => None,
+        };
+        let storage = match &keys {
+            Some(keys) => Arc::new(storage::Encrypted::new(storage, keys.clone())),
+            None => storage,
+        };
+        let mut hub = Hub::default();
+        let mut evicted = Vec::new();
+        let pending = storage
+            .pending()
+            .await
+            .context("failed to restore buffers")?;
+        for notification in pending {
+            evicted.extend(hub.buffer(&config.buffer, notification).map(|n| n.id));
+        }
+        evicted.extend(hub.shed(config.buffer.max_bytes).iter().map(|n| n.id));
+        let mut tokens = storage
+            .tokens()
+            .await
+            .context("failed to load tokens")?
+            .into_iter()
+            .map(|info| (info.token.clone(), info))
+            .collect();
+        let sequences = storage
+            .sequences()
+            .await
+            .context("failed to load sequence numbers")?;
+        let cluster = match &config.redis {
+            Some(redis) => {
+                let cluster = Cluster::connect(redis)
+                    .await
+                    .context("failed to connect to redis")?;
+                cluster
+                    .seed_sequences(&sequences)
+                    .await
+                    .context("failed to seed sequence numbers")?;
+                cluster
+                    .seed_tokens(&tokens)
+                    .await
+                    .context("failed to seed tokens")?;
+                tokens = cluster
+                    .tokens()
+                    .await
+                    .context("failed to load tokens from redis")?;
+                Some(cluster)
+            }
+            None => None,
+        };
+        let rate_limiter = config
+            .rate_limit
+            .as_ref()
+            .map(|limit| Arc::new(RateLimiter::new(limit)));
+        let quotas = config
+            .token_limits
+            .as_ref()
+            .map(|limits| Arc::new(Quotas::new(limits)));
+        let dedup = config
+            .dedup
+            .as_ref()
+            .map(|dedup| Arc::new(dedup::Dedup::new(dedup)));
+        let geoip = match &config.geoip {
+            Some(geoip) => Some(Arc::new(
+                GeoIp::open(geoip).context("failed to load the GeoIP databases")?,
+            )),
+            None => None,
+        };
+        let attachments = match &config.attachments {
+            Some(attachments) => Some(Arc::new(
+                attachments::Store::open(attachments, keys)
+                    .context("failed to set up attachment storage")?,
+            )),
+            None => None,
+        };
+        let notifiers =
+            Dispatcher::new(&config, notifiers).context("failed to set up notifiers")?;
+        let state = AppState {
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            futures: Arc::new(Mutex::new(hub)),
+            challenges: acme::Challenges::default(),
+            storage,
+            cluster,
+            tokens: Arc::new(Mutex::new(tokens)),
+            rate_limiter: Arc::new(ArcSwapOption::new(rate_limiter)),
+            quotas: Arc::new(ArcSwapOption::new(quotas)),
+            dedup: Arc::new(ArcSwapOption::new(dedup)),
+            geoip: Arc::new(ArcSwapOption::new(geoip)),
+            idempotency: Arc::default(),
+            attachments,
+            shutting_down: Arc::default(),
+            handing_over: Arc::default(),
+            writes: TaskTracker::new(),
+            metrics: Metrics::new(),
+            log_filter,
+            sequences: Arc::new(Mutex::new(sequences)),
+            notifiers,
+        };
+        state.settle(evicted);
+        if let Some(redis) = &state.config().redis {
+            task::spawn(cluster::subscribe_loop(redis.clone(), state.clone()));
+        }
+        task::spawn(tokens::purge_loop(state.clone()));
+        task::spawn(retention::purge_loop(state.clone()));
+        task::spawn(geoip::reload_loop(state.clone()));
+        task::spawn(attachments::sweep_loop(state.clone()));
+        task::spawn(delivery::redeliver_loop(state.clone()));
+        task::spawn(notifiers::digest_loop(state.clone()));
+        Ok(state)
+    }
+}
+
+/// Answers the callback routes (`/notify`, `/poll-notified`, ...) and hands
+/// every other request to the wrapped service, in place of the catch-all token.
+/// The wrapped service has to be served with connect info, as for `routes`.
+#[derive(Clone)]
+pub struct CallbackLayer {
+    state: AppState,
+}
+
+impl CallbackLayer {
+    pub fn new(state: AppState) -> Self {
+        CallbackLayer { state }
+    }
+}
+
+impl<S> Layer<S> for CallbackLayer
+where
+    S: Service<Request<Body>, Error = Infallible> + Clone + Send + 'static,
+    S::Response: IntoResponse,
+    S::Future: Send + 'static,
+{
+    type Service = Router;
+
+    fn layer(&self, inner: S) -> Router {
+        endpoints(&self.state)
+            .with_state(self.state.clone())
+            .fallback_service(inner)
+    }
+}
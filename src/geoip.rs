@@ -0,0 +1,110 @@
+//! Locates the client IPs of hits with MaxMind databases, loading the files
+//! again whenever they are replaced.
+
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Error};
+use maxminddb::{geoip2, Reader};
+use tracing::{info, warn};
+
+use crate::{config::GeoIpConfig, model::Geo, AppState};
+
+struct Database {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    reader: Reader<Vec<u8>>,
+}
+
+impl Database {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let modified = modified(path);
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(Database {
+            path: path.to_owned(),
+            modified,
+            reader,
+        })
+    }
+
+    fn replaced(&self) -> bool {
+        modified(&self.path) != self.modified
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The databases of `geoip`, as loaded.
+pub struct GeoIp {
+    city: Option<Database>,
+    asn: Option<Database>,
+}
+
+impl GeoIp {
+    pub fn open(config: &GeoIpConfig) -> Result<Self, Error> {
+        Ok(GeoIp {
+            city: config.city.as_deref().map(Database::open).transpose()?,
+            asn: config.asn.as_deref().map(Database::open).transpose()?,
+        })
+    }
+
+    /// What the databases know about `ip`, `None` if nothing.
+    pub fn locate(&self, ip: IpAddr) -> Option<Geo> {
+        let mut geo = Geo::default();
+        if let Some(city) = &self.city {
+            if let Ok(found) = city.reader.lookup::<geoip2::City>(ip) {
+                geo.country = found
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_owned);
+                geo.city = found
+                    .city
+                    .and_then(|city| city.names?.get("en").copied())
+                    .map(str::to_owned);
+            }
+        }
+        if let Some(asn) = &self.asn {
+            if let Ok(found) = asn.reader.lookup::<geoip2::Asn>(ip) {
+                geo.asn = found.autonomous_system_number;
+                geo.as_org = found.autonomous_system_organization.map(str::to_owned);
+            }
+        }
+        let known = geo.country.is_some() || geo.city.is_some() || geo.asn.is_some();
+        known.then_some(geo)
+    }
+
+    fn replaced(&self) -> bool {
+        self.city.iter().chain(&self.asn).any(Database::replaced)
+    }
+}
+
+/// Checks every `geoip.reload` seconds whether the databases were replaced and
+/// loads them again if so. A file that fails to load keeps the old one in use.
+pub async fn reload_loop(state: AppState) {
+    loop {
+        let config = state.config();
+        let interval = config.geoip.as_ref().map_or(60, |geoip| geoip.reload);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        let config = state.config();
+        let (Some(geoip), Some(current)) = (&config.geoip, state.geoip.load_full()) else {
+            continue;
+        };
+        if !current.replaced() {
+            continue;
+        }
+        match GeoIp::open(geoip) {
+            Ok(reloaded) => {
+                info!("Reloaded the GeoIP databases");
+                state.geoip.store(Some(Arc::new(reloaded)));
+            }
+            Err(e) => warn!("Reloading the GeoIP databases failed: {e:#}"),
+        }
+    }
+}
@@ -0,0 +1,548 @@
+//! `/graphql`: tokens, stored hits and their totals for consumers that want to
+//! pick fields and join them in one request, plus a `hits` subscription over
+//! `/graphql/ws` fed by the same fan-out as the other streams.
+
+use async_graphql::{
+    futures_util::Stream, Context, Data, EmptyMutation, Enum, ErrorExtensions, InputObject, Object,
+    SimpleObject, Subscription,
+};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
+use axum::{
+    extract::{ws::WebSocketUpgrade, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Router,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use futures::stream;
+use uuid::Uuid;
+
+use crate::{
+    auth::{self, ApiKey},
+    config,
+    history::{DEFAULT_PER_PAGE, MAX_PER_PAGE},
+    hub,
+    model::{self, Notification},
+    mtls::ClientCert,
+    storage::{HistoryFilter, Stats},
+    tokens::{self, TokenInfo},
+    AppState,
+};
+
+/// Nesting deeper than this is refused before anything runs.
+const MAX_DEPTH: usize = 10;
+
+pub type Schema = async_graphql::Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// `/graphql` for queries, `/graphql/ws` for subscriptions and
+/// `/graphql/schema.graphql` with the SDL, for generating clients.
+pub fn routes() -> Router<AppState> {
+    let schema = Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .limit_depth(MAX_DEPTH)
+        .finish();
+    Router::new()
+        .route("/graphql", post(execute))
+        .route("/graphql/ws", get(subscribe))
+        .route("/graphql/schema.graphql", get(sdl))
+        .layer(Extension(schema))
+}
+
+async fn execute(
+    State(state): State<AppState>,
+    Extension(schema): Extension<Schema>,
+    key: ApiKey,
+    request: GraphQLRequest,
+) -> GraphQLResponse {
+    schema
+        .execute(request.into_inner().data(state).data(key))
+        .await
+        .into()
+}
+
+/// Takes the API key from the upgrade request's headers or, since browsers
+/// cannot set those on WebSockets, from `x-api-key` or `authorization` in the
+/// `connection_init` payload.
+async fn subscribe(
+    State(state): State<AppState>,
+    Extension(schema): Extension<Schema>,
+    protocol: GraphQLProtocol,
+    headers: HeaderMap,
+    cert: ClientCert,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let presented = auth::presented(header("x-api-key"), header(header::AUTHORIZATION.as_str()));
+    let upfront = match presented {
+        Some(presented) => match ApiKey::authenticate(&state, Some(presented), &cert) {
+            Ok(key) => Some(key),
+            Err(status) => return status.into_response(),
+        },
+        None => None,
+    };
+    upgrade
+        .protocols(async_graphql::http::ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |socket| {
+            GraphQLWebSocket::new(socket, schema, protocol)
+                .on_connection_init(move |payload| async move {
+                    let key = match upfront {
+                        Some(key) => key,
+                        None => {
+                            let entry = |name| payload.get(name)?.as_str();
+                            let presented =
+                                auth::presented(entry("x-api-key"), entry("authorization"));
+                            ApiKey::authenticate(&state, presented, &cert).map_err(refused)?
+                        }
+                    };
+                    let mut data = Data::default();
+                    data.insert(state);
+                    data.insert(key);
+                    Ok(data)
+                })
+                .serve()
+        })
+}
+
+async fn sdl(Extension(schema): Extension<Schema>) -> String {
+    schema.sdl()
+}
+
+/// An error carrying the status the HTTP API would have answered with as its
+/// `code` extension.
+fn refused(status: StatusCode) -> async_graphql::Error {
+    async_graphql::Error::new(status.canonical_reason().unwrap_or_default())
+        .extend_with(|_, e| e.set("code", status.as_u16()))
+}
+
+/// What the HTTP history API takes as query parameters.
+#[derive(InputObject)]
+struct NotificationFilter {
+    token: String,
+    /// Received at or after.
+    since: Option<DateTime<Utc>>,
+    /// Received before.
+    until: Option<DateTime<Utc>>,
+    /// Client IP the hit came from.
+    ip: Option<String>,
+    user_agent: Option<String>,
+    /// Restricts `q` to this payload field.
+    field: Option<String>,
+    /// Text contained in a payload value.
+    q: Option<String>,
+}
+
+impl NotificationFilter {
+    /// The storage filter, once `key` is known to be allowed the token.
+    fn check(self, key: &ApiKey) -> async_graphql::Result<HistoryFilter> {
+        key.check(&self.token).map_err(refused)?;
+        let ip = match self.ip {
+            Some(ip) => Some(ip.parse().map_err(|_| refused(StatusCode::BAD_REQUEST))?),
+            None => None,
+        };
+        Ok(HistoryFilter {
+            token: self.token,
+            since: self.since,
+            until: self.until,
+            ip,
+            user_agent: self.user_agent,
+            field: self.field,
+            q: self.q,
+        })
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Registered tokens the API key may poll, oldest first.
+    async fn tokens(&self, ctx: &Context<'_>) -> Vec<Token> {
+        let state = ctx.data_unchecked::<AppState>();
+        let key = ctx.data_unchecked::<ApiKey>();
+        tokens::visible(state, key).into_iter().map(Token).collect()
+    }
+
+    /// One page of stored hits matching `filter`, oldest first. Pages count from 1.
+    async fn notifications(
+        &self,
+        ctx: &Context<'_>,
+        filter: NotificationFilter,
+        #[graphql(default = 1)] page: u32,
+        #[graphql(default_with = "DEFAULT_PER_PAGE")] per_page: u32,
+    ) -> async_graphql::Result<NotificationPage> {
+        let state = ctx.data_unchecked::<AppState>();
+        let filter = filter.check(ctx.data_unchecked::<ApiKey>())?;
+        if page == 0 || per_page == 0 || per_page > MAX_PER_PAGE {
+            return Err(refused(StatusCode::BAD_REQUEST));
+        }
+        let offset = i64::from(page - 1) * i64::from(per_page);
+        let (notifications, total) = state
+            .storage
+            .history(&filter, offset, per_page.into())
+            .await?;
+        Ok(NotificationPage {
+            page,
+            per_page,
+            total,
+            nodes: notifications.into_iter().map(Hit).collect(),
+        })
+    }
+
+    /// A stored hit by its uuid.
+    async fn notification(
+        &self,
+        ctx: &Context<'_>,
+        uuid: Uuid,
+    ) -> async_graphql::Result<Option<Hit>> {
+        let state = ctx.data_unchecked::<AppState>();
+        let Some(notification) = state.storage.notification(uuid).await? else {
+            return Ok(None);
+        };
+        ctx.data_unchecked::<ApiKey>()
+            .check(&notification.token)
+            .map_err(refused)?;
+        Ok(Some(Hit(notification)))
+    }
+
+    /// Totals over the stored hits matching `filter`.
+    async fn stats(
+        &self,
+        ctx: &Context<'_>,
+        filter: NotificationFilter,
+    ) -> async_graphql::Result<Stats> {
+        let state = ctx.data_unchecked::<AppState>();
+        let filter = filter.check(ctx.data_unchecked::<ApiKey>())?;
+        Ok(state.storage.stats(&filter).await?)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every hit for `token` as it arrives, those buffered while nobody
+    /// listened first.
+    async fn hits(
+        &self,
+        ctx: &Context<'_>,
+        token: String,
+    ) -> async_graphql::Result<impl Stream<Item = Hit>> {
+        let state = ctx.data_unchecked::<AppState>();
+        if state.accepting_polls().is_err() {
+            return Err(refused(StatusCode::SERVICE_UNAVAILABLE));
+        }
+        ctx.data_unchecked::<ApiKey>()
+            .check(&token)
+            .map_err(refused)?;
+        state.check_token(&token).map_err(refused)?;
+        let subscription = hub::Subscription::new(state, token);
+        Ok(stream::unfold(
+            subscription,
+            |mut subscription| async move {
+                let notification = subscription.rx.recv().await?;
+                Some((Hit(notification), subscription))
+            },
+        ))
+    }
+}
+
+#[derive(SimpleObject)]
+struct NotificationPage {
+    page: u32,
+    per_page: u32,
+    /// Stored notifications matching the filter across all pages.
+    total: i64,
+    nodes: Vec<Hit>,
+}
+
+/// How a token's hits are spread over its waiting polls.
+#[derive(Clone, Copy, PartialEq, Eq, Enum)]
+enum Fanout {
+    Broadcast,
+    Single,
+}
+
+impl From<config::Fanout> for Fanout {
+    fn from(fanout: config::Fanout) -> Self {
+        match fanout {
+            config::Fanout::Broadcast => Fanout::Broadcast,
+            config::Fanout::Single => Fanout::Single,
+        }
+    }
+}
+
+struct Token(TokenInfo);
+
+#[Object]
+impl Token {
+    async fn token(&self) -> &str {
+        &self.0.token
+    }
+
+    async fn label(&self) -> Option<&str> {
+        self.0.label.as_deref()
+    }
+
+    async fn created_at(&self) -> DateTime<Utc> {
+        self.0.created_at
+    }
+
+    async fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.0.expires_at
+    }
+
+    /// Only shown to admin keys.
+    async fn secret(&self) -> Option<&str> {
+        self.0.secret.as_deref()
+    }
+
+    async fn revoked_at(&self) -> Option<DateTime<Utc>> {
+        self.0.revoked_at
+    }
+
+    /// Unset when the token follows `delivery.fanout`.
+    async fn fanout(&self) -> Option<Fanout> {
+        self.0.fanout.map(Into::into)
+    }
+
+    /// Unset when the token follows `retention.days`, 0 when its hits are kept forever.
+    async fn retention_days(&self) -> Option<u64> {
+        self.0.retention_days
+    }
+
+    /// The age public key its hits are end-to-end encrypted to.
+    async fn public_key(&self) -> Option<&str> {
+        self.0.public_key.as_deref()
+    }
+
+    /// Totals over all of the token's stored hits.
+    async fn stats(&self, ctx: &Context<'_>) -> async_graphql::Result<Stats> {
+        let state = ctx.data_unchecked::<AppState>();
+        let filter = HistoryFilter {
+            token: self.0.token.clone(),
+            ..HistoryFilter::default()
+        };
+        Ok(state.storage.stats(&filter).await?)
+    }
+}
+
+/// A name and value, for the maps of the JSON envelope.
+#[derive(SimpleObject)]
+struct Field {
+    name: String,
+    value: String,
+}
+
+fn fields<'a>(map: impl IntoIterator<Item = (&'a String, &'a String)>) -> Vec<Field> {
+    let mut fields: Vec<Field> = map
+        .into_iter()
+        .map(|(name, value)| Field {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect();
+    fields.sort_by(|a, b| a.name.cmp(&b.name));
+    fields
+}
+
+/// One hit, the JSON envelope's `Notification`.
+struct Hit(Notification);
+
+#[Object(name = "Notification")]
+impl Hit {
+    async fn v(&self) -> u32 {
+        model::Version::CURRENT
+    }
+
+    async fn id(&self) -> i64 {
+        self.0.id
+    }
+
+    async fn uuid(&self) -> Uuid {
+        self.0.uuid
+    }
+
+    async fn seq(&self) -> u64 {
+        self.0.seq
+    }
+
+    async fn token(&self) -> &str {
+        &self.0.token
+    }
+
+    async fn received_at(&self) -> DateTime<Utc> {
+        self.0.received_at
+    }
+
+    /// The parameters the payload sent, by name.
+    async fn data(&self) -> Vec<Field> {
+        fields(&self.0.data)
+    }
+
+    async fn meta(&self) -> Meta<'_> {
+        Meta(&self.0.meta)
+    }
+
+    async fn attachments(&self) -> Vec<Attachment<'_>> {
+        self.0.attachments.iter().map(Attachment).collect()
+    }
+
+    /// How many identical hits this one stands for, see `dedup`.
+    async fn hits(&self) -> u64 {
+        self.0.hits
+    }
+}
+
+struct Meta<'a>(&'a model::Meta);
+
+#[Object]
+impl Meta<'_> {
+    /// The peer that connected, possibly a proxy.
+    async fn remote_addr(&self) -> Option<String> {
+        self.0.remote_addr.map(|addr| addr.to_string())
+    }
+
+    /// Where the hit really came from, see `trusted_proxies`.
+    async fn client_ip(&self) -> Option<String> {
+        self.0.client_ip.map(|ip| ip.to_string())
+    }
+
+    /// Whether the connection was IPv4 or IPv6.
+    async fn ip_version(&self) -> Option<model::IpVersion> {
+        self.0.ip_version
+    }
+
+    async fn user_agent(&self) -> Option<&str> {
+        self.0.user_agent.as_deref()
+    }
+
+    async fn referer(&self) -> Option<&str> {
+        self.0.referer.as_deref()
+    }
+
+    /// Whichever of `capture.headers` the request carried.
+    async fn headers(&self) -> Vec<Field> {
+        fields(&self.0.headers)
+    }
+
+    /// Where `clientIp` is, when `geoip` is configured and knows it.
+    async fn geo(&self) -> Option<Geo<'_>> {
+        self.0.geo.as_ref().map(Geo)
+    }
+
+    /// What `userAgent` says the payload ran in, if it is recognised.
+    async fn agent(&self) -> Option<Agent<'_>> {
+        self.0.agent.as_ref().map(Agent)
+    }
+
+    /// How the client opened TLS, when this server terminated it.
+    async fn tls(&self) -> Option<TlsFingerprint<'_>> {
+        self.0.tls.as_ref().map(TlsFingerprint)
+    }
+}
+
+struct TlsFingerprint<'a>(&'a model::TlsFingerprint);
+
+#[Object]
+impl TlsFingerprint<'_> {
+    /// The JA3 MD5.
+    async fn ja3(&self) -> &str {
+        &self.0.ja3
+    }
+
+    async fn ja4(&self) -> &str {
+        &self.0.ja4
+    }
+}
+
+struct Agent<'a>(&'a model::Agent);
+
+#[Object]
+impl Agent<'_> {
+    /// e.g. `Chrome`, `Firefox` or `Googlebot`.
+    async fn browser(&self) -> Option<&str> {
+        self.0.browser.as_deref()
+    }
+
+    async fn browser_version(&self) -> Option<&str> {
+        self.0.browser_version.as_deref()
+    }
+
+    /// e.g. `Windows 10`, `Mac OSX` or `Android`.
+    async fn os(&self) -> Option<&str> {
+        self.0.os.as_deref()
+    }
+
+    async fn os_version(&self) -> Option<&str> {
+        self.0.os_version.as_deref()
+    }
+
+    async fn device(&self) -> model::Device {
+        self.0.device
+    }
+}
+
+struct Geo<'a>(&'a model::Geo);
+
+#[Object]
+impl Geo<'_> {
+    /// ISO 3166-1 code of the country, e.g. `DE`.
+    async fn country(&self) -> Option<&str> {
+        self.0.country.as_deref()
+    }
+
+    /// The city's English name.
+    async fn city(&self) -> Option<&str> {
+        self.0.city.as_deref()
+    }
+
+    /// The autonomous system the address belongs to.
+    async fn asn(&self) -> Option<u32> {
+        self.0.asn
+    }
+
+    async fn as_org(&self) -> Option<&str> {
+        self.0.as_org.as_deref()
+    }
+}
+
+struct Attachment<'a>(&'a model::Attachment);
+
+#[Object]
+impl Attachment<'_> {
+    /// The form field it was sent as.
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn filename(&self) -> Option<&str> {
+        self.0.filename.as_deref()
+    }
+
+    async fn content_type(&self) -> Option<&str> {
+        self.0.content_type.as_deref()
+    }
+
+    /// The SHA-256 of the content when it is kept as a file, served by
+    /// `GET /api/attachments/{id}`.
+    async fn id(&self) -> Option<&str> {
+        self.0.id.as_deref()
+    }
+
+    /// A presigned download URL when the file is kept in `attachments.s3`.
+    async fn url(&self, ctx: &Context<'_>) -> Option<String> {
+        let store = ctx.data_unchecked::<AppState>().attachments.as_ref()?;
+        store.url(self.0.id.as_deref()?)
+    }
+
+    /// In bytes.
+    async fn size(&self) -> u64 {
+        self.0.size
+    }
+
+    /// Base64 encoded, empty when the content is kept as a file.
+    async fn data(&self) -> String {
+        STANDARD.encode(&self.0.data)
+    }
+}
@@ -0,0 +1,341 @@
+//! The gRPC API from `proto/xss_check_srv.proto`, for consumers that would
+//! rather generate a client than speak JSON. It is mounted on the HTTP listener,
+//! which takes HTTP/2 with prior knowledge also without TLS, and optionally
+//! served on its own `grpc.bind`. Streams go through HTTP/2 flow control, so a
+//! slow reader only holds up its own stream.
+
+use std::{future::Future, pin::Pin};
+
+use anyhow::Error;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use prost_types::Timestamp;
+use tonic::{
+    transport::{Certificate, Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+use tracing::info;
+
+use crate::{
+    auth::{self, ApiKey},
+    config::Fanout,
+    history::{DEFAULT_PER_PAGE, MAX_PER_PAGE},
+    hub::Subscription,
+    model::{Agent, Attachment, Device, Geo, IpVersion, Meta, Notification},
+    mtls::ClientCert,
+    storage::HistoryFilter,
+    tokens::{self, Created, NewToken},
+    AppState,
+};
+
+/// Generated from `proto/xss_check_srv.proto`.
+pub mod pb {
+    #![allow(clippy::all)]
+    tonic::include_proto!("xss_check_srv.v1");
+}
+
+use pb::callbacks_server::CallbacksServer;
+
+/// The gRPC service over `state`, for embedding apps running their own tonic server.
+pub fn service(state: AppState) -> CallbacksServer<Callbacks> {
+    CallbacksServer::new(Callbacks { state })
+}
+
+/// Serves the gRPC API on `grpc.bind` until `shutdown` completes. Does nothing
+/// when `grpc` is not configured.
+pub async fn serve(state: AppState, shutdown: impl Future<Output = ()>) -> Result<(), Error> {
+    let config = state.config();
+    let Some(grpc) = &config.grpc else {
+        return Ok(());
+    };
+    let mut server = Server::builder();
+    if let Some(tls) = &config.tls {
+        let identity = Identity::from_pem(
+            tokio::fs::read(&tls.cert).await?,
+            tokio::fs::read(&tls.key).await?,
+        );
+        let mut tls_config = ServerTlsConfig::new().identity(identity);
+        if let Some(ca) = &tls.client_ca {
+            tls_config = tls_config
+                .client_ca_root(Certificate::from_pem(tokio::fs::read(ca).await?))
+                .client_auth_optional(true);
+        }
+        server = server.tls_config(tls_config)?;
+    }
+    info!("gRPC listening on {}", grpc.bind);
+    server
+        .add_service(service(state.clone()))
+        .serve_with_shutdown(grpc.bind, shutdown)
+        .await?;
+    Ok(())
+}
+
+pub struct Callbacks {
+    state: AppState,
+}
+
+impl Callbacks {
+    /// The key of the call's client certificate or the one it presented in
+    /// `x-api-key` or `authorization: Bearer`. Mounted on the HTTP listener the
+    /// certificate comes from there, on `grpc.bind` from tonic's own TLS.
+    fn key<T>(&self, request: &Request<T>) -> Result<ApiKey, StatusCode> {
+        let metadata = request.metadata();
+        let entry = |name| metadata.get(name).and_then(|v| v.to_str().ok());
+        let presented = auth::presented(entry("x-api-key"), entry("authorization"));
+        let cert = match request.extensions().get::<ClientCert>() {
+            Some(cert) => cert.clone(),
+            None => request
+                .peer_certs()
+                .and_then(|chain| {
+                    chain
+                        .first()
+                        .map(|cert| ClientCert::from_der(cert.get_ref()))
+                })
+                .unwrap_or_default(),
+        };
+        ApiKey::authenticate(&self.state, presented, &cert)
+    }
+}
+
+/// The gRPC equivalent of what the HTTP API would have answered.
+fn status(code: StatusCode) -> Status {
+    let message = code.canonical_reason().unwrap_or_default();
+    match code {
+        StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+        StatusCode::FORBIDDEN => Status::permission_denied(message),
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::GONE => Status::failed_precondition("token expired or revoked"),
+        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+        _ => Status::unknown(message),
+    }
+}
+
+fn internal(error: Error) -> Status {
+    Status::internal(format!("{error:#}"))
+}
+
+fn timestamp(at: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: at.timestamp(),
+        nanos: at.timestamp_subsec_nanos() as i32,
+    }
+}
+
+fn datetime(at: Timestamp) -> Option<DateTime<Utc>> {
+    let nanos = u32::try_from(at.nanos).ok()?;
+    DateTime::from_timestamp(at.seconds, nanos)
+}
+
+impl From<Notification> for pb::Notification {
+    fn from(notification: Notification) -> Self {
+        pb::Notification {
+            v: crate::model::Version::CURRENT,
+            id: notification.id,
+            uuid: notification.uuid.to_string(),
+            seq: notification.seq,
+            token: notification.token,
+            received_at: Some(timestamp(notification.received_at)),
+            data: notification.data,
+            meta: Some(notification.meta.into()),
+            attachments: notification
+                .attachments
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            hits: notification.hits,
+        }
+    }
+}
+
+impl From<Meta> for pb::Meta {
+    fn from(meta: Meta) -> Self {
+        pb::Meta {
+            remote_addr: meta.remote_addr.map(|addr| addr.to_string()),
+            client_ip: meta.client_ip.map(|ip| ip.to_string()),
+            user_agent: meta.user_agent,
+            ip_version: match meta.ip_version {
+                None => pb::IpVersion::Unspecified,
+                Some(IpVersion::V4) => pb::IpVersion::V4,
+                Some(IpVersion::V6) => pb::IpVersion::V6,
+            }
+            .into(),
+            referer: meta.referer,
+            headers: meta.headers.into_iter().collect(),
+            geo: meta.geo.map(Into::into),
+            agent: meta.agent.map(Into::into),
+            tls: meta.tls.map(|tls| pb::TlsFingerprint {
+                ja3: tls.ja3,
+                ja4: tls.ja4,
+            }),
+        }
+    }
+}
+
+impl From<Agent> for pb::Agent {
+    fn from(agent: Agent) -> Self {
+        pb::Agent {
+            browser: agent.browser,
+            browser_version: agent.browser_version,
+            os: agent.os,
+            os_version: agent.os_version,
+            device: match agent.device {
+                Device::Desktop => pb::Device::Desktop,
+                Device::Mobile => pb::Device::Mobile,
+                Device::Bot => pb::Device::Bot,
+                Device::Appliance => pb::Device::Appliance,
+                Device::Other => pb::Device::Other,
+            }
+            .into(),
+        }
+    }
+}
+
+impl From<Geo> for pb::Geo {
+    fn from(geo: Geo) -> Self {
+        pb::Geo {
+            country: geo.country,
+            city: geo.city,
+            asn: geo.asn,
+            as_org: geo.as_org,
+        }
+    }
+}
+
+impl From<Attachment> for pb::Attachment {
+    fn from(attachment: Attachment) -> Self {
+        pb::Attachment {
+            name: attachment.name,
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            data: attachment.data,
+            id: attachment.id,
+            size: attachment.size,
+        }
+    }
+}
+
+impl From<Created> for pb::Token {
+    fn from(created: Created) -> Self {
+        let info = created.info;
+        pb::Token {
+            token: info.token,
+            label: info.label,
+            created_at: Some(timestamp(info.created_at)),
+            expires_at: info.expires_at.map(timestamp),
+            secret: info.secret,
+            notify_url: created.notify_url,
+            poll_url: created.poll_url,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl pb::callbacks_server::Callbacks for Callbacks {
+    async fn create_token(
+        &self,
+        request: Request<pb::CreateTokenRequest>,
+    ) -> Result<Response<pb::Token>, Status> {
+        if !self.key(&request).map_err(status)?.is_admin() {
+            return Err(status(StatusCode::FORBIDDEN));
+        }
+        let request = request.into_inner();
+        let fanout = match request.fanout() {
+            pb::Fanout::Unspecified => None,
+            pb::Fanout::Broadcast => Some(Fanout::Broadcast),
+            pb::Fanout::Single => Some(Fanout::Single),
+        };
+        let new = NewToken {
+            label: request.label,
+            ttl: request.ttl,
+            secret: request.secret,
+            fanout,
+            retention_days: request.retention_days,
+            public_key: request.public_key,
+        };
+        let info = tokens::mint(&self.state, new)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::invalid_argument("ttl out of range or invalid public_key"))?;
+        // No Host header to guess from, links point at the HTTP listener.
+        let config = self.state.config();
+        let base = self.state.public_url(&config.bind.to_string());
+        Ok(Response::new(Created::new(&base, info).into()))
+    }
+
+    type StreamNotificationsStream =
+        Pin<Box<dyn Stream<Item = Result<pb::Notification, Status>> + Send>>;
+
+    async fn stream_notifications(
+        &self,
+        request: Request<pb::StreamNotificationsRequest>,
+    ) -> Result<Response<Self::StreamNotificationsStream>, Status> {
+        let key = self.key(&request).map_err(status)?;
+        let token = request.into_inner().token;
+        if self.state.accepting_polls().is_err() {
+            return Err(status(StatusCode::SERVICE_UNAVAILABLE));
+        }
+        key.check(&token).map_err(status)?;
+        self.state.check_token(&token).map_err(status)?;
+        let subscription = Subscription::new(&self.state, token);
+        let notifications = stream::unfold(subscription, |mut subscription| async move {
+            let notification = subscription.rx.recv().await?;
+            Some((Ok(notification.into()), subscription))
+        });
+        Ok(Response::new(Box::pin(notifications)))
+    }
+
+    async fn list_history(
+        &self,
+        request: Request<pb::ListHistoryRequest>,
+    ) -> Result<Response<pb::HistoryPage>, Status> {
+        let key = self.key(&request).map_err(status)?;
+        let request = request.into_inner();
+        key.check(&request.token).map_err(status)?;
+        let page = request.page.max(1);
+        let per_page = match request.per_page {
+            0 => DEFAULT_PER_PAGE,
+            per_page if per_page > MAX_PER_PAGE => {
+                return Err(Status::invalid_argument(format!(
+                    "per_page must be at most {MAX_PER_PAGE}"
+                )))
+            }
+            per_page => per_page,
+        };
+        let out_of_range = || Status::invalid_argument("timestamp out of range");
+        let filter = HistoryFilter {
+            token: request.token,
+            since: match request.since {
+                Some(since) => Some(datetime(since).ok_or_else(out_of_range)?),
+                None => None,
+            },
+            until: match request.until {
+                Some(until) => Some(datetime(until).ok_or_else(out_of_range)?),
+                None => None,
+            },
+            ip: request
+                .ip
+                .map(|ip| ip.parse())
+                .transpose()
+                .map_err(|_| Status::invalid_argument("ip is not an IP address"))?,
+            user_agent: request.user_agent,
+            field: request.field,
+            q: request.q,
+        };
+        let offset = i64::from(page - 1) * i64::from(per_page);
+        let (notifications, total) = self
+            .state
+            .storage
+            .history(&filter, offset, per_page.into())
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(pb::HistoryPage {
+            token: filter.token,
+            page,
+            per_page,
+            total,
+            notifications: notifications.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
@@ -0,0 +1,74 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{notifiers, AppState};
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    ready: bool,
+    shutting_down: bool,
+    storage: Check,
+    /// Absent without a `[redis]` section.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    redis: Option<Check>,
+    /// Forwarding to webhooks and other notifiers, which never affects readiness.
+    notifiers: notifiers::Status,
+    pollers: usize,
+    max_pollers: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn from(result: Result<(), anyhow::Error>) -> Self {
+        Check {
+            ok: result.is_ok(),
+            error: result.err().map(|e| format!("{e:#}")),
+        }
+    }
+}
+
+/// Liveness, answered as long as the process serves requests at all.
+#[utoipa::path(get, path = "/healthz", tag = "operations", responses((status = 200, body = String)))]
+pub async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// 200 when this instance can take traffic, 503 while a dependency is down or it drains.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses((status = 200, body = Readiness), (status = 503, body = Readiness)),
+)]
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let config = state.config();
+    let storage = Check::from(state.storage.ping().await);
+    let redis = match &state.cluster {
+        Some(cluster) => Some(Check::from(cluster.ping().await)),
+        None => None,
+    };
+    let shutting_down = state.accepting_polls().is_err();
+    let ready = storage.ok && redis.as_ref().is_none_or(|redis| redis.ok) && !shutting_down;
+    let readiness = Readiness {
+        ready,
+        shutting_down,
+        storage,
+        redis,
+        notifiers: state.notifiers.status(),
+        pollers: state.futures.lock().expect("").pollers.len(),
+        max_pollers: config.limits.max_pollers,
+    };
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
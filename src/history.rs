@@ -0,0 +1,251 @@
+use axum::{
+    body::StreamBody,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::{future, stream};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    attachments::Content, auth::ApiKey, encoding::Encoding, model::Notification,
+    storage::HistoryFilter, AppError, AppState,
+};
+
+/// Largest page `per_page` may ask for.
+pub const MAX_PER_PAGE: u32 = 500;
+pub const DEFAULT_PER_PAGE: u32 = 50;
+
+/// Attachment types shown inline, everything else is only offered as a download.
+const INLINE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageQuery {
+    /// 1-based.
+    #[serde(default = "PageQuery::default_page")]
+    page: u32,
+    #[serde(default = "PageQuery::default_per_page")]
+    per_page: u32,
+}
+
+impl PageQuery {
+    fn default_page() -> u32 {
+        1
+    }
+
+    fn default_per_page() -> u32 {
+        DEFAULT_PER_PAGE
+    }
+}
+
+/// A weak validator for a history response, changing with the highest `seq`
+/// it covers and how many hits it counts.
+fn etag(seq: u64, total: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!("W/\"{seq}-{total}\"")).expect("digits and quotes")
+}
+
+/// Whether the request's `If-None-Match` lists `etag`, compared weakly.
+fn not_modified(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let etag = etag.to_str().unwrap_or_default();
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag))
+}
+
+fn with_etag(mut response: Response, etag: HeaderValue) -> Response {
+    response.headers_mut().insert(header::ETAG, etag);
+    response
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct History {
+    pub token: String,
+    pub page: u32,
+    pub per_page: u32,
+    /// Stored notifications matching the filter across all pages.
+    pub total: i64,
+    pub notifications: Vec<Notification>,
+}
+
+/// Stored hits for a token, oldest first, whether or not anyone polled them.
+/// Always empty with the memory backend. Answers `304` while the `ETag` sent
+/// in `If-None-Match` still matches.
+#[utoipa::path(
+    get,
+    path = "/api/notifications",
+    tag = "history",
+    params(PageQuery, HistoryFilter),
+    responses(
+        (status = 200, content(("application/json" = History), ("application/msgpack" = History), ("application/cbor" = History))),
+        (status = 304, description = "Nothing changed since the `If-None-Match` ETag"),
+        (status = 400, description = "Bad page or filter"),
+        (status = 403, description = "The API key may not read this token"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn list(
+    Query(page): Query<PageQuery>,
+    Query(filter): Query<HistoryFilter>,
+    State(state): State<AppState>,
+    key: ApiKey,
+    encoding: Encoding,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Err(status) = key.check(&filter.token) {
+        return Ok(status.into_response());
+    }
+    if page.page == 0 || page.per_page == 0 || page.per_page > MAX_PER_PAGE {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let (seq, total) = state.storage.revision(&filter).await?;
+    let etag = etag(seq, total);
+    if not_modified(&headers, &etag) {
+        return Ok(with_etag(StatusCode::NOT_MODIFIED.into_response(), etag));
+    }
+    let offset = i64::from(page.page - 1) * i64::from(page.per_page);
+    let (mut notifications, total) = state
+        .storage
+        .history(&filter, offset, page.per_page.into())
+        .await?;
+    notifications.iter_mut().for_each(|n| state.presign(n));
+    let history = History {
+        token: filter.token,
+        page: page.page,
+        per_page: page.per_page,
+        total,
+        notifications,
+    };
+    Ok(with_etag(encoding.respond(StatusCode::OK, &history), etag))
+}
+
+/// Deletes a hit from storage and from the buffer it may still be waiting in.
+#[utoipa::path(
+    delete,
+    path = "/api/notifications/{id}",
+    tag = "history",
+    params(("id" = i64, Path, description = "Sequence number of the notification")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 403, description = "Not an admin key"),
+        (status = 404, description = "No such notification"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn delete(
+    Path(id): Path<i64>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<StatusCode, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    let buffered = state.futures.lock().expect("").remove(id);
+    let stored = state.storage.delete_notification(id).await?;
+    Ok(match buffered || stored {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    })
+}
+
+/// A stored hit by its uuid, which grants access like for [`attachment`]. Hits
+/// never change, so the `ETag` only saves sending one again.
+#[utoipa::path(
+    get,
+    path = "/n/{uuid}",
+    tag = "history",
+    params(("uuid" = Uuid, Path, description = "Notification id")),
+    responses(
+        (status = 200, content(("application/json" = Notification), ("application/msgpack" = Notification), ("application/cbor" = Notification))),
+        (status = 304, description = "Already has it per `If-None-Match`"),
+        (status = 404, description = "Unknown, or not persisted"),
+    ),
+)]
+pub async fn show(
+    Path(uuid): Path<Uuid>,
+    State(state): State<AppState>,
+    encoding: Encoding,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let Some(mut notification) = state.storage.notification(uuid).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    state.presign(&mut notification);
+    let etag = HeaderValue::from_str(&format!("W/\"{}\"", notification.seq)).expect("digits");
+    if not_modified(&headers, &etag) {
+        return Ok(with_etag(StatusCode::NOT_MODIFIED.into_response(), etag));
+    }
+    Ok(with_etag(
+        encoding.respond(StatusCode::OK, &notification),
+        etag,
+    ))
+}
+
+/// A file of a stored hit, by its position among the hit's attachments. The
+/// notification's uuid in the path is what grants access, so links posted by
+/// notifiers work without an API key. Images are shown inline, anything else is
+/// a sandboxed download.
+#[utoipa::path(
+    get,
+    path = "/n/{uuid}/attachments/{index}",
+    tag = "history",
+    params(("uuid" = Uuid, Path, description = "Notification id"), ("index" = usize, Path, description = "Position in the notification's attachments")),
+    responses(
+        (status = 200, description = "The file, inline for images", content_type = "application/octet-stream"),
+        (status = 404, description = "Unknown notification or index"),
+    ),
+)]
+pub async fn attachment(
+    Path((uuid, index)): Path<(Uuid, usize)>,
+    State(state): State<AppState>,
+) -> Result<Response, AppError> {
+    let Some(mut notification) = state.storage.notification(uuid).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if index >= notification.attachments.len() {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let attachment = notification.attachments.swap_remove(index);
+    let content: Content = match (&attachment.id, &state.attachments) {
+        (Some(id), Some(store)) => match store.load(id).await? {
+            Some(content) => content,
+            None => return Ok(StatusCode::NOT_FOUND.into_response()),
+        },
+        (Some(_), None) => return Ok(StatusCode::NOT_FOUND.into_response()),
+        (None, _) => Box::pin(stream::once(future::ready(Ok(attachment.data.into())))),
+    };
+    let inline = attachment
+        .content_type
+        .as_deref()
+        .filter(|content_type| INLINE_TYPES.contains(content_type));
+    let filename: String = attachment
+        .filename
+        .unwrap_or(attachment.name)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        .collect();
+    let disposition = match (inline, filename.is_empty()) {
+        (Some(_), _) => "inline".to_owned(),
+        (None, true) => "attachment".to_owned(),
+        (None, false) => format!("attachment; filename=\"{filename}\""),
+    };
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                inline.unwrap_or("application/octet-stream").to_owned(),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+            (header::CONTENT_SECURITY_POLICY, "sandbox".to_owned()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+        ],
+        StreamBody::new(content),
+    )
+        .into_response())
+}
@@ -0,0 +1,818 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    future::Future,
+    net::IpAddr,
+    ops::DerefMut,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::{BufferConfig, Eviction, Fanout, Kick, Limits},
+    matcher::{Matcher, Pattern},
+    model::{ErrorBody, Notification},
+    AppState,
+};
+
+pub type PollResult = Result<Notification, PollError>;
+
+/// Seconds clients are told to wait before polling again after a 503.
+pub const RETRY_AFTER: u64 = 5;
+
+/// Notifications a stream may fall behind by before it is disconnected.
+const STREAM_BACKLOG: usize = 256;
+
+/// Why a poll ended without a notification: `Timeout` is the normal outcome
+/// of waiting, `Evicted`, `Overloaded`, `ShuttingDown` and `Reconnect` say to poll again,
+/// `Revoked` and `Expired` not to, `Internal` that the server is at fault.
+#[derive(Debug)]
+pub enum PollError {
+    /// Nothing arrived within `wait`.
+    Timeout,
+    /// Kicked to make room for another poller.
+    Evicted,
+    /// An admin revoked the token or kicked the poll, do not come back.
+    Revoked,
+    /// The token expired while waiting.
+    Expired,
+    /// Refused because too many polls are waiting, see `kick = "none"`.
+    Overloaded,
+    /// The server is draining before exit.
+    ShuttingDown,
+    /// The server is handing over to a new instance already on the same
+    /// port, poll again right away.
+    Reconnect,
+    /// Something broke on our side, the details only go to the log.
+    Internal(Error),
+}
+
+impl PollError {
+    fn code(&self) -> &'static str {
+        match self {
+            PollError::Timeout => "timeout",
+            PollError::Evicted => "evicted",
+            PollError::Revoked => "revoked",
+            PollError::Expired => "expired",
+            PollError::Overloaded => "overloaded",
+            PollError::ShuttingDown => "shutting_down",
+            PollError::Reconnect => "reconnect",
+            PollError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for PollError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PollError::Timeout => "Nothing arrived in time",
+            PollError::Evicted => "Evicted to make room for other polls",
+            PollError::Revoked => "Revoked by an admin",
+            PollError::Expired => "Token expired",
+            PollError::Overloaded => "Too many pollers, try again later",
+            PollError::ShuttingDown => "Server shutting down",
+            PollError::Reconnect => "Server handing over, poll again now",
+            PollError::Internal(_) => "Internal server error",
+        })
+    }
+}
+
+impl PollError {
+    /// What the response says about it.
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            error: self.code().to_owned(),
+            message: self.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for PollError {
+    fn into_response(self) -> Response {
+        let body = Json(self.body());
+        match self {
+            PollError::Timeout => StatusCode::NO_CONTENT.into_response(),
+            PollError::Evicted => (StatusCode::CONFLICT, body).into_response(),
+            PollError::Expired | PollError::Revoked => (StatusCode::GONE, body).into_response(),
+            PollError::Overloaded | PollError::ShuttingDown => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, RETRY_AFTER.to_string())],
+                body,
+            )
+                .into_response(),
+            PollError::Reconnect => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "0")],
+                body,
+            )
+                .into_response(),
+            PollError::Internal(e) => {
+                error!("Poll failed: {e:#}");
+                (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+            }
+        }
+    }
+}
+
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+pub struct ReqPoll {
+    data: Arc<Mutex<Option<PollResult>>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    /// Names the poll in `/admin/pollers`.
+    pub id: u64,
+    /// Who is polling, as far as trusted proxies tell.
+    pub client: IpAddr,
+    pub suspended_at: DateTime<Utc>,
+    /// When the poll gives up with 204, never without `wait=`.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ReqPoll {
+    pub fn new(client: IpAddr, wait: Option<Duration>) -> ReqPoll {
+        let suspended_at = Utc::now();
+        ReqPoll {
+            data: Arc::new(Mutex::new(None)),
+            waker: Arc::new(Mutex::new(None)),
+            id: NEXT_POLL.fetch_add(1, Ordering::Relaxed) + 1,
+            client,
+            suspended_at,
+            expires_at: wait
+                .and_then(|wait| chrono::Duration::from_std(wait).ok())
+                .map(|wait| suspended_at + wait),
+        }
+    }
+    pub fn fulfill(&self, data: PollResult) {
+        *self.data.lock().expect("") = Some(data);
+        let waker = self.waker.lock().expect("");
+        let Some(waker) = waker.as_ref() else {
+            return;
+        };
+        waker.wake_by_ref();
+    }
+
+    /// The outcome, if it arrived and nobody awaited it.
+    pub fn take(&self) -> Option<PollResult> {
+        self.data.lock().expect("").take()
+    }
+}
+
+impl Future for &ReqPoll {
+    type Output = PollResult;
+    fn poll(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let mut data = self.data.lock().expect("");
+        match std::mem::take(data.deref_mut()) {
+            Some(res) => std::task::Poll::Ready(res),
+            None => {
+                *self.waker.lock().expect("") = Some(cx.waker().clone());
+                std::task::Poll::Pending
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Hub {
+    /// Suspended polls with the tokens each is waiting on.
+    pub pollers: VecDeque<(Matcher, Arc<ReqPoll>)>,
+    /// Notifications that arrived while nobody was polling their token.
+    buffers: HashMap<String, VecDeque<Notification>>,
+    /// Roughly how much memory `buffers` holds, see `footprint`.
+    buffered_bytes: usize,
+    /// Long-lived subscribers (websockets) that get every matching notification.
+    streams: Vec<(u64, String, Sender<Notification>)>,
+    next_stream: u64,
+    /// Polled in at-least-once mode but not acknowledged yet, by delivery id,
+    /// with when to redeliver them.
+    inflight: HashMap<Uuid, (Instant, Notification)>,
+}
+
+impl Hub {
+    /// Buffers `notification`, returning whichever one got dropped to stay within bounds.
+    pub fn buffer(
+        &mut self,
+        config: &BufferConfig,
+        notification: Notification,
+    ) -> Option<Notification> {
+        if config.depth == 0 {
+            return Some(notification);
+        }
+        let held = self
+            .buffers
+            .get(&notification.token)
+            .map_or(0, VecDeque::len);
+        let mut evicted = None;
+        if held >= config.depth {
+            match config.eviction {
+                Eviction::DropOldest => evicted = self.take_at(&notification.token, 0),
+                Eviction::DropNewest => return Some(notification),
+            }
+        }
+        self.buffered_bytes += footprint(&notification);
+        self.buffers
+            .entry(notification.token.clone())
+            .or_default()
+            .push_back(notification);
+        evicted
+    }
+
+    /// Drops the least recently buffered notifications, whatever their token,
+    /// until the buffers fit in `max_bytes`, and returns them.
+    pub fn shed(&mut self, max_bytes: usize) -> Vec<Notification> {
+        let mut shed = Vec::new();
+        while self.buffered_bytes > max_bytes {
+            let Some(token) = self
+                .buffers
+                .iter()
+                .filter_map(|(token, buffer)| Some((token, buffer.front()?.id)))
+                .min_by_key(|(_, id)| *id)
+                .map(|(token, _)| token.clone())
+            else {
+                break;
+            };
+            shed.extend(self.take_at(&token, 0));
+        }
+        shed
+    }
+
+    /// Removes the notification at `i` in `token`'s buffer, and the buffer once empty.
+    fn take_at(&mut self, token: &str, i: usize) -> Option<Notification> {
+        let buffer = self.buffers.get_mut(token)?;
+        let notification = buffer.remove(i)?;
+        if buffer.is_empty() {
+            self.buffers.remove(token);
+        }
+        self.buffered_bytes -= footprint(&notification);
+        Some(notification)
+    }
+
+    /// Suspends `poller` on `matcher`, first kicking whoever has to make room for
+    /// it: the oldest poller of a token or prefix already holding
+    /// `max_pollers_per_token`, otherwise one picked by `kick` past `max_pollers`.
+    /// A poll counts against every pattern it waits on. Returns whether someone
+    /// was kicked.
+    pub fn enqueue(
+        &mut self,
+        limits: &Limits,
+        matcher: Matcher,
+        poller: Arc<ReqPoll>,
+    ) -> Result<bool, PollError> {
+        let crowded = matcher.0.iter().find(|pattern| {
+            let held = self
+                .pollers
+                .iter()
+                .filter(|(m, _)| m.contains(pattern))
+                .count();
+            held >= limits.max_pollers_per_token
+        });
+        let full = crowded.is_some() || self.pollers.len() >= limits.max_pollers;
+        if full && matches!(limits.kick, Kick::None) {
+            return Err(PollError::Overloaded);
+        }
+        let kicked = if let Some(pattern) = crowded {
+            self.pollers
+                .iter()
+                .position(|(m, _)| m.contains(pattern))
+                .and_then(|i| self.pollers.remove(i))
+        } else if self.pollers.len() >= limits.max_pollers {
+            match limits.kick {
+                Kick::Fair => {
+                    let i = self.busiest();
+                    self.pollers.remove(i)
+                }
+                Kick::Oldest | Kick::None => self.pollers.pop_front(),
+            }
+        } else {
+            None
+        };
+        let was_kicked = kicked.is_some();
+        if let Some((_, kicked)) = kicked {
+            kicked.fulfill(Err(PollError::Evicted));
+        }
+        self.pollers.push_back((matcher, poller));
+        Ok(was_kicked)
+    }
+
+    /// Whether any poll waits on `token`.
+    pub fn has_pollers(&self, token: &str) -> bool {
+        self.pollers.iter().any(|(m, _)| m.matches(token))
+    }
+
+    /// Takes the polls matching `token` that a hit for it goes to.
+    pub fn take_pollers(&mut self, token: &str, fanout: Fanout) -> Vec<Arc<ReqPoll>> {
+        match fanout {
+            Fanout::Broadcast => {
+                let mut taken = Vec::new();
+                self.pollers.retain(|(m, r)| {
+                    if !m.matches(token) {
+                        return true;
+                    }
+                    taken.push(r.clone());
+                    false
+                });
+                taken
+            }
+            Fanout::Single => self
+                .pollers
+                .iter()
+                .position(|(m, _)| m.matches(token))
+                .and_then(|i| self.pollers.remove(i))
+                .map(|(_, r)| r)
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Position of the oldest poller of the token or prefix holding the most of them.
+    fn busiest(&self) -> usize {
+        let mut held: HashMap<&Pattern, (usize, usize)> = HashMap::new();
+        for (i, (matcher, _)) in self.pollers.iter().enumerate() {
+            for pattern in &matcher.0 {
+                held.entry(pattern).or_insert((0, i)).0 += 1;
+            }
+        }
+        held.into_values()
+            .max_by(|(a, i), (b, j)| a.cmp(b).then(j.cmp(i)))
+            .map_or(0, |(_, i)| i)
+    }
+
+    /// Open websocket, SSE and NDJSON subscriptions.
+    pub fn streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Deliveries handed out but not acknowledged yet.
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    /// Notifications currently buffered across all tokens.
+    pub fn buffered(&self) -> usize {
+        self.buffers.values().map(VecDeque::len).sum()
+    }
+
+    /// Roughly how many bytes the buffered notifications take up.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Takes the oldest notification buffered for a token `matcher` matches.
+    pub fn take_buffered(&mut self, matcher: &Matcher) -> Option<Notification> {
+        let token = self
+            .buffers
+            .iter()
+            .filter(|(token, _)| matcher.matches(token))
+            .filter_map(|(token, buffer)| Some((token, buffer.front()?.id)))
+            .min_by_key(|(_, id)| *id)?
+            .0
+            .clone();
+        self.take_at(&token, 0)
+    }
+
+    /// Takes the oldest notification buffered for `token` numbered above
+    /// `after` and at most `until`.
+    pub fn take_buffered_between(
+        &mut self,
+        token: &str,
+        after: u64,
+        until: u64,
+    ) -> Option<Notification> {
+        let i = self
+            .buffers
+            .get(token)?
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.seq > after && n.seq <= until)
+            .min_by_key(|(_, n)| n.seq)?
+            .0;
+        self.take_at(token, i)
+    }
+
+    /// Holds on to `notification` until it is acknowledged or `timeout` passes,
+    /// returning it with the delivery id to acknowledge it by.
+    pub fn hold(&mut self, mut notification: Notification, timeout: Duration) -> Notification {
+        let delivery_id = Uuid::new_v4();
+        notification.delivery_id = Some(delivery_id);
+        self.inflight.insert(
+            delivery_id,
+            (Instant::now() + timeout, notification.clone()),
+        );
+        notification
+    }
+
+    /// Completes a held delivery, returning the notification if it was still held.
+    pub fn ack(&mut self, delivery_id: Uuid) -> Option<Notification> {
+        self.inflight.remove(&delivery_id).map(|(_, n)| n)
+    }
+
+    /// Token of a held delivery.
+    pub fn held_for(&self, delivery_id: Uuid) -> Option<&str> {
+        self.inflight
+            .get(&delivery_id)
+            .map(|(_, n)| n.token.as_str())
+    }
+
+    /// Takes the held deliveries whose visibility timeout has passed.
+    pub fn expired(&mut self) -> Vec<Notification> {
+        let now = Instant::now();
+        let ids: Vec<Uuid> = self
+            .inflight
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.iter()
+            .filter_map(|id| self.inflight.remove(id))
+            .map(|(_, n)| n)
+            .collect()
+    }
+
+    /// Puts a notification back at the front of its token's buffer, ahead of
+    /// anything that arrived after it. Like `buffer`, returns what had to go
+    /// when the buffer is full: the notification itself with `drop-oldest`,
+    /// the newest one in the buffer with `drop-newest`.
+    pub fn requeue(
+        &mut self,
+        config: &BufferConfig,
+        notification: Notification,
+    ) -> Option<Notification> {
+        if config.depth == 0 {
+            return Some(notification);
+        }
+        let held = self
+            .buffers
+            .get(&notification.token)
+            .map_or(0, VecDeque::len);
+        if held >= config.depth && matches!(config.eviction, Eviction::DropOldest) {
+            return Some(notification);
+        }
+        let token = notification.token.clone();
+        self.buffered_bytes += footprint(&notification);
+        self.buffers
+            .entry(token.clone())
+            .or_default()
+            .push_front(notification);
+        if held >= config.depth {
+            return self.take_at(&token, held);
+        }
+        None
+    }
+
+    /// Counts one more hit collapsed into `id` if it is still buffered.
+    pub fn add_hit(&mut self, id: i64) {
+        let mut buffered = self.buffers.values_mut().flatten();
+        if let Some(notification) = buffered.find(|n| n.id == id) {
+            notification.hits += 1;
+        }
+    }
+
+    /// Drops the buffered or held notification `id`, returning whether there was one.
+    pub fn remove(&mut self, id: i64) -> bool {
+        let held = self.inflight.len();
+        self.inflight.retain(|_, (_, n)| n.id != id);
+        if self.inflight.len() != held {
+            return true;
+        }
+        let Some((token, i)) = self.buffers.iter().find_map(|(token, buffer)| {
+            let i = buffer.iter().position(|n| n.id == id)?;
+            Some((token.clone(), i))
+        }) else {
+            return false;
+        };
+        self.take_at(&token, i);
+        true
+    }
+
+    /// Forgets everything about `token`, returning what was buffered or held and
+    /// who was waiting on nothing else. Prefix polls keep waiting for other tokens.
+    /// Its streams end once the senders are dropped.
+    pub fn purge(&mut self, token: &str) -> (Vec<Notification>, Vec<Arc<ReqPoll>>) {
+        let mut buffered: Vec<_> = self.buffers.remove(token).unwrap_or_default().into();
+        self.buffered_bytes -= buffered.iter().map(footprint).sum::<usize>();
+        self.inflight.retain(|_, (_, n)| {
+            if n.token != token {
+                return true;
+            }
+            buffered.push(n.clone());
+            false
+        });
+        let mut pollers = Vec::new();
+        self.pollers.retain_mut(|(m, r)| {
+            m.0.retain(|pattern| !pattern.is_exact(token));
+            if !m.0.is_empty() {
+                return true;
+            }
+            pollers.push(r.clone());
+            false
+        });
+        self.streams.retain(|(_, t, _)| t != token);
+        (buffered, pollers)
+    }
+
+    /// Takes the suspended poll with this id.
+    pub fn take_poller(&mut self, id: u64) -> Option<Arc<ReqPoll>> {
+        let i = self.pollers.iter().position(|(_, poll)| poll.id == id)?;
+        self.pollers.remove(i).map(|(_, poll)| poll)
+    }
+
+    /// Takes every suspended poller and ends all streams, for shutdown.
+    pub fn drain(&mut self) -> Vec<Arc<ReqPoll>> {
+        self.streams.clear();
+        self.pollers.drain(..).map(|(_, poller)| poller).collect()
+    }
+
+    /// Hands `notification` to every stream subscribed to its token, returning
+    /// whether any of them took it.
+    pub fn stream(&mut self, notification: &Notification) -> bool {
+        let mut delivered = false;
+        self.streams.retain(|(id, t, tx)| {
+            if *t != notification.token {
+                return true;
+            }
+            match tx.try_send(notification.clone()) {
+                Ok(()) => {
+                    delivered = true;
+                    true
+                }
+                // Dropping the sender ends the stream once it sent what it has.
+                Err(TrySendError::Full(_)) => {
+                    warn!("Disconnecting stream {id}, it fell {STREAM_BACKLOG} hits behind");
+                    false
+                }
+                // The receiver is gone.
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+        delivered
+    }
+}
+
+/// What buffering `notification` costs, counting its payload, recorded headers
+/// and attachments plus a fixed overhead for the rest.
+fn footprint(notification: &Notification) -> usize {
+    let meta = &notification.meta;
+    let data: usize = notification
+        .data
+        .iter()
+        .map(|(k, v)| k.len() + v.len())
+        .sum();
+    let headers: usize = meta.headers.iter().map(|(k, v)| k.len() + v.len()).sum();
+    let attachments: usize = notification
+        .attachments
+        .iter()
+        .map(|attachment| attachment.name.len() + attachment.data.len())
+        .sum();
+    512 + notification.token.len()
+        + data
+        + headers
+        + meta.user_agent.as_ref().map_or(0, String::len)
+        + meta.referer.as_ref().map_or(0, String::len)
+        + attachments
+}
+
+/// A registered stream, unsubscribed again when dropped.
+pub struct Subscription {
+    futures: Futures,
+    id: u64,
+    pub rx: Receiver<Notification>,
+}
+
+impl Subscription {
+    /// Subscribes to `token`, first replaying whatever was buffered for it, as
+    /// much as fits its backlog.
+    pub fn new(state: &AppState, token: String) -> Subscription {
+        let futures = &state.futures;
+        let (tx, rx) = mpsc::channel(STREAM_BACKLOG);
+        let mut guard = futures.lock().expect("");
+        let mut replayed = Vec::new();
+        while tx.capacity() > 0 {
+            let Some(notification) = guard.take_buffered(&Matcher::exact(&token)) else {
+                break;
+            };
+            replayed.push(notification.id);
+            let _ = tx.try_send(notification);
+        }
+        state.settle(replayed);
+        let id = guard.next_stream;
+        guard.next_stream += 1;
+        guard.streams.push((id, token, tx));
+        Subscription {
+            futures: futures.clone(),
+            id,
+            rx,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut guard = self.futures.lock().expect("");
+        guard.streams.retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+pub type Futures = Arc<Mutex<Hub>>;
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::model::{Meta, Payload, Version};
+
+    fn hit(id: i64, token: &str) -> Notification {
+        Notification {
+            v: Version,
+            id,
+            uuid: Uuid::new_v4(),
+            seq: id as u64,
+            token: token.to_owned(),
+            received_at: Utc::now(),
+            data: Payload::new(),
+            meta: Meta::default(),
+            attachments: Vec::new(),
+            hits: 1,
+            delivery_id: None,
+            trace: None,
+        }
+    }
+
+    fn poll() -> Arc<ReqPoll> {
+        Arc::new(ReqPoll::new(IpAddr::V4(Ipv4Addr::LOCALHOST), None))
+    }
+
+    fn drain(hub: &mut Hub, token: &str) -> Vec<i64> {
+        std::iter::from_fn(|| hub.take_buffered(&Matcher::exact(token)))
+            .map(|n| n.id)
+            .collect()
+    }
+
+    fn status(result: PollResult) -> StatusCode {
+        result.err().expect("no hit").into_response().status()
+    }
+
+    #[test]
+    fn evicts_at_the_buffer_cap() {
+        let mut config = BufferConfig {
+            depth: 2,
+            ..BufferConfig::default()
+        };
+        let mut hub = Hub::default();
+        assert!(hub.buffer(&config, hit(1, "a")).is_none());
+        assert!(hub.buffer(&config, hit(2, "a")).is_none());
+        assert_eq!(hub.buffer(&config, hit(3, "a")).map(|n| n.id), Some(1));
+        // Other tokens have buffers of their own.
+        assert!(hub.buffer(&config, hit(4, "b")).is_none());
+        assert_eq!(drain(&mut hub, "a"), [2, 3]);
+
+        config.eviction = Eviction::DropNewest;
+        hub.buffer(&config, hit(5, "a"));
+        hub.buffer(&config, hit(6, "a"));
+        assert_eq!(hub.buffer(&config, hit(7, "a")).map(|n| n.id), Some(7));
+        assert_eq!(drain(&mut hub, "a"), [5, 6]);
+    }
+
+    #[test]
+    fn sheds_the_oldest_across_tokens() {
+        let config = BufferConfig::default();
+        let mut hub = Hub::default();
+        for (id, token) in [(1, "a"), (2, "b"), (3, "a")] {
+            hub.buffer(&config, hit(id, token));
+        }
+        let each = footprint(&hit(0, "a"));
+        let shed: Vec<i64> = hub.shed(2 * each).iter().map(|n| n.id).collect();
+        assert_eq!(shed, [1]);
+        assert_eq!(hub.buffered_bytes(), 2 * each);
+        assert_eq!(drain(&mut hub, "a"), [3]);
+        assert_eq!(drain(&mut hub, "b"), [2]);
+    }
+
+    #[test]
+    fn kicked_polls_get_409() {
+        let limits = Limits {
+            max_pollers: 2,
+            kick: Kick::Oldest,
+            ..Limits::default()
+        };
+        let mut hub = Hub::default();
+        let (first, second, third) = (poll(), poll(), poll());
+        assert!(!hub
+            .enqueue(&limits, Matcher::exact("a"), first.clone())
+            .unwrap());
+        assert!(!hub
+            .enqueue(&limits, Matcher::exact("b"), second.clone())
+            .unwrap());
+        assert!(hub
+            .enqueue(&limits, Matcher::exact("c"), third.clone())
+            .unwrap());
+        assert_eq!(status(first.take().unwrap()), StatusCode::CONFLICT);
+        assert!(second.take().is_none());
+        assert_eq!(hub.pollers.len(), 2);
+    }
+
+    #[test]
+    fn token_over_its_poller_limit_kicks_its_own() {
+        let limits = Limits {
+            max_pollers_per_token: 1,
+            ..Limits::default()
+        };
+        let mut hub = Hub::default();
+        let (other, first, second) = (poll(), poll(), poll());
+        hub.enqueue(&limits, Matcher::exact("b"), other.clone())
+            .unwrap();
+        hub.enqueue(&limits, Matcher::exact("a"), first.clone())
+            .unwrap();
+        assert!(hub.enqueue(&limits, Matcher::exact("a"), second).unwrap());
+        assert_eq!(status(first.take().unwrap()), StatusCode::CONFLICT);
+        assert!(other.take().is_none());
+    }
+
+    #[test]
+    fn refuses_polls_past_max_pollers_with_503() {
+        let limits = Limits {
+            max_pollers: 1,
+            kick: Kick::None,
+            ..Limits::default()
+        };
+        let mut hub = Hub::default();
+        let waiting = poll();
+        hub.enqueue(&limits, Matcher::exact("a"), waiting.clone())
+            .unwrap();
+        let refused = hub
+            .enqueue(&limits, Matcher::exact("b"), poll())
+            .unwrap_err()
+            .into_response();
+        assert_eq!(refused.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(refused.headers()[header::RETRY_AFTER], "5");
+        assert!(waiting.take().is_none());
+        assert_eq!(hub.pollers.len(), 1);
+    }
+
+    #[test]
+    fn requeue_keeps_the_original_order() {
+        let config = BufferConfig::default();
+        let mut hub = Hub::default();
+        for id in 1..=3 {
+            hub.buffer(&config, hit(id, "a"));
+        }
+        let first = hub.take_buffered(&Matcher::exact("a")).unwrap();
+        let second = hub.take_buffered(&Matcher::exact("a")).unwrap();
+        hub.requeue(&config, second);
+        hub.requeue(&config, first);
+        assert_eq!(drain(&mut hub, "a"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn requeue_into_a_full_buffer_follows_eviction() {
+        let mut config = BufferConfig {
+            depth: 2,
+            ..BufferConfig::default()
+        };
+        let mut hub = Hub::default();
+        hub.buffer(&config, hit(2, "a"));
+        hub.buffer(&config, hit(3, "a"));
+        // The requeued hit is the oldest, so it is the one to go.
+        assert_eq!(hub.requeue(&config, hit(1, "a")).map(|n| n.id), Some(1));
+        config.eviction = Eviction::DropNewest;
+        assert_eq!(hub.requeue(&config, hit(1, "a")).map(|n| n.id), Some(3));
+        assert_eq!(drain(&mut hub, "a"), [1, 2]);
+    }
+
+    #[test]
+    fn disconnects_lagging_streams() {
+        let mut hub = Hub::default();
+        let (tx, mut rx) = mpsc::channel(STREAM_BACKLOG);
+        hub.streams.push((1, "a".to_owned(), tx));
+        for id in 0..STREAM_BACKLOG as i64 {
+            assert!(hub.stream(&hit(id, "a")));
+        }
+        assert!(!hub.stream(&hit(-1, "a")));
+        assert!(hub.streams.is_empty());
+        // What was queued still arrives, then the stream ends.
+        for id in 0..STREAM_BACKLOG as i64 {
+            assert_eq!(rx.try_recv().map(|n| n.id), Ok(id));
+        }
+        assert_eq!(
+            rx.try_recv().err(),
+            Some(mpsc::error::TryRecvError::Disconnected)
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Remembers the `idem=` keys of recent hits, so a payload retrying its
+//! beacon over a flaky network does not leave a notification per attempt.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::lru::Lru;
+
+/// Keys tracked at most, beyond that the oldest is forgotten even if its
+/// window has not passed yet.
+const MAX_KEYS: usize = 100_000;
+
+struct Seen {
+    id: i64,
+    at: Instant,
+}
+
+/// The notification each recent `(token, key)` was recorded as.
+pub struct Keys {
+    seen: Mutex<Lru<(String, String), Seen>>,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Keys {
+            seen: Mutex::new(Lru::new(MAX_KEYS)),
+        }
+    }
+}
+
+impl Keys {
+    /// The notification `key` was first recorded as for `token`, if that was
+    /// less than `window` ago.
+    pub fn replay_of(&self, token: &str, key: &str, window: Duration) -> Option<i64> {
+        let seen = self.seen.lock().expect("");
+        let seen = seen.get(&(token.to_owned(), key.to_owned()))?;
+        (seen.at.elapsed() < window).then_some(seen.id)
+    }
+
+    /// Remembers the hit for `token` carrying `key` as recorded under `id`.
+    pub fn remember(&self, token: String, key: String, id: i64) {
+        let seen = Seen {
+            id,
+            at: Instant::now(),
+        };
+        self.seen.lock().expect("").insert((token, key), seen);
+    }
+}
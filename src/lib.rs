@@ -0,0 +1,1453 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{ConnectInfo, Path, Query, RawBody, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    middleware,
+    response::{AppendHeaders, IntoResponse, Response},
+    routing::{any, delete, get, post},
+    Router,
+};
+use axum_macros::debug_handler;
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::Utc;
+use futures::stream;
+use serde::Deserialize;
+use telemetry::LogHandle;
+use tokio::task;
+use tokio_util::task::TaskTracker;
+use tracing::{error, warn, Instrument, Span};
+use tracing_subscriber::filter::LevelFilter;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use auth::ApiKey;
+use cluster::Cluster;
+use config::{Fanout, NotifyResponse};
+use dedup::Dedup;
+use encoding::Encoding;
+use geoip::GeoIp;
+use hub::{Futures, Hub, PollError, PollResult, ReqPoll};
+use matcher::{Matcher, Pattern};
+use metrics::Metrics;
+use model::Version;
+use notifiers::Dispatcher;
+use ratelimit::{Quotas, RateLimiter};
+use tls_fingerprint::ClientHello;
+use tokens::Tokens;
+
+// What embedding apps need to build an `Engine` and implement its traits.
+pub use async_trait::async_trait;
+pub use config::Config;
+pub use engine::{CallbackLayer, Engine};
+pub use history::History;
+pub use model::{Attachment, IpVersion, Meta, Notification, Payload};
+pub use notifiers::{Hit, Notifier};
+pub use reqwest;
+pub use storage::{DeadLetter, HistoryFilter, Memory as MemoryStorage, Stats, Storage};
+pub use tokens::{Created, NewToken, TokenInfo};
+
+pub mod acme;
+pub mod activation;
+mod admin;
+mod attachments;
+mod auth;
+mod catchall;
+pub mod cli;
+pub mod client;
+mod cluster;
+mod compression;
+pub mod config;
+mod cors;
+mod csp;
+mod dedup;
+mod delivery;
+pub mod dns;
+pub mod e2e;
+mod encoding;
+mod engine;
+mod geoip;
+mod graphql;
+pub mod grpc;
+mod health;
+mod history;
+mod hub;
+mod idempotency;
+mod lru;
+mod matcher;
+mod metrics;
+pub mod model;
+pub mod mtls;
+mod ndjson;
+mod notifiers;
+mod openapi;
+mod pages;
+mod payloads;
+mod proxy;
+mod ratelimit;
+mod retention;
+mod s3;
+pub mod smtp;
+mod sse;
+mod storage;
+pub mod telemetry;
+pub mod tls_fingerprint;
+mod tokens;
+mod ui;
+pub mod unix;
+mod upload;
+mod useragent;
+mod ws;
+
+struct AppError(anyhow::Error);
+
+// Tell axum how to convert `AppError` into a response.
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Something went wrong: {}", self.0),
+        )
+            .into_response()
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
+
+/// Everything the handlers share, cheap to clone.
+#[derive(Clone)]
+pub struct AppState {
+    /// Swapped on SIGHUP, see `reload`.
+    config: Arc<ArcSwap<Config>>,
+    futures: Futures,
+    challenges: acme::Challenges,
+    storage: Arc<dyn Storage>,
+    cluster: Option<Cluster>,
+    tokens: Tokens,
+    rate_limiter: Arc<ArcSwapOption<RateLimiter<IpAddr>>>,
+    quotas: Arc<ArcSwapOption<Quotas>>,
+    /// Fingerprints of recent hits, see `dedup`.
+    dedup: Arc<ArcSwapOption<Dedup>>,
+    /// Locates client IPs, see `geoip`.
+    geoip: Arc<ArcSwapOption<GeoIp>>,
+    /// `idem=` keys of recent hits, see `idempotency`.
+    idempotency: Arc<idempotency::Keys>,
+    /// Where attachment files go, inline in storage when unset.
+    attachments: Option<Arc<attachments::Store>>,
+    /// Set once a shutdown signal arrived, new polls are refused from then on.
+    shutting_down: Arc<AtomicBool>,
+    /// Set when shutting down for a new instance, see `hand_over`.
+    handing_over: Arc<AtomicBool>,
+    /// Background storage writes, awaited before exit.
+    writes: TaskTracker,
+    metrics: Metrics,
+    /// Only there when the binary set up logging, see `telemetry::init`.
+    log_filter: Option<LogHandle>,
+    /// The last sequence number handed out per token, unless Redis keeps them.
+    sequences: Arc<Mutex<HashMap<String, u64>>>,
+    notifiers: Dispatcher,
+}
+
+impl AppState {
+    /// Starts the server's state for `config`, see `Engine` to hand in storage
+    /// or notifiers of your own.
+    pub async fn new(config: Config, log_filter: Option<LogHandle>) -> Result<Self, Error> {
+        let mut engine = Engine::new(config);
+        if let Some(log_filter) = log_filter {
+            engine = engine.log_filter(log_filter);
+        }
+        engine.start().await
+    }
+
+    /// Adds download URLs to the attachments of `notification` kept in a bucket.
+    fn presign(&self, notification: &mut Notification) {
+        if let Some(store) = &self.attachments {
+            store.presign(notification);
+        }
+    }
+
+    /// The current configuration. Hold on to it rather than calling this repeatedly.
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    /// Switches to `config`, keeping rate limit state unless its settings changed.
+    pub async fn reload(&self, config: Config, rustls: Option<&RustlsConfig>) {
+        let old = self.config();
+        if config.rate_limit != old.rate_limit {
+            let limiter = config.rate_limit.as_ref().map(RateLimiter::new);
+            self.rate_limiter.store(limiter.map(Arc::new));
+        }
+        if config.token_limits != old.token_limits {
+            let quotas = config.token_limits.as_ref().map(Quotas::new);
+            self.quotas.store(quotas.map(Arc::new));
+        }
+        if config.dedup != old.dedup {
+            let dedup = config.dedup.as_ref().map(Dedup::new);
+            self.dedup.store(dedup.map(Arc::new));
+        }
+        if config.geoip != old.geoip {
+            match config.geoip.as_ref().map(GeoIp::open).transpose() {
+                Ok(geoip) => self.geoip.store(geoip.map(Arc::new)),
+                Err(e) => error!("Keeping the old GeoIP databases: {e:#}"),
+            }
+        }
+        if let Some(log_filter) = &self.log_filter {
+            if let Err(e) = log_filter.reload(LevelFilter::from(config.log_level)) {
+                error!("Failed to change the log level: {e}");
+            }
+        }
+        if let (Some(rustls), Some(tls)) = (rustls, &config.tls) {
+            match mtls::server_config(tls) {
+                Ok(server_config) => rustls.reload_from_config(server_config),
+                Err(e) => error!("Keeping the old certificate, loading the new one failed: {e:#}"),
+            }
+        }
+        self.notifiers.reload(&config);
+        self.config.store(Arc::new(config));
+    }
+    /// 404 for tokens failing the `token_secret` signature, 410 once they expired.
+    fn check_token(&self, token: &str) -> Result<(), StatusCode> {
+        if let Some(secret) = &self.config().token_secret {
+            // The catch-all stream is built in rather than minted, so it has no mac.
+            if token != catchall::TOKEN && !tokens::verify(secret, token) {
+                return Err(StatusCode::NOT_FOUND);
+            }
+        }
+        let tokens = self.tokens.lock().expect("");
+        if tokens
+            .get(token)
+            .is_some_and(|info| info.expired() || info.revoked_at.is_some())
+        {
+            return Err(StatusCode::GONE);
+        }
+        Ok(())
+    }
+
+    /// The `token` label of per-token metrics: the token's hash once it was
+    /// minted, so made-up tokens cannot add series and `/metrics` shows no
+    /// secrets, `unregistered` for every other one.
+    fn metric_label(&self, token: &str) -> String {
+        if token == catchall::TOKEN || self.tokens.lock().expect("").contains_key(token) {
+            telemetry::token_hash(token)
+        } else {
+            "unregistered".to_owned()
+        }
+    }
+
+    /// Base URL for links handed out to users, `public_url` or guessed from the request.
+    fn public_url(&self, host: &str) -> String {
+        if let Some(url) = &self.config().public_url {
+            return url.trim_end_matches('/').to_owned();
+        }
+        let scheme = if self.config().tls.is_some() || self.config().acme.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        format!("{scheme}://{host}")
+    }
+
+    fn accepting_polls(&self) -> Result<(), PollError> {
+        if self.handing_over.load(Ordering::SeqCst) {
+            return Err(PollError::Reconnect);
+        }
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(PollError::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    /// Refuses new polls and sends every waiting poller and stream away.
+    /// Buffered notifications stay pending in storage for the next start.
+    pub fn drain(&self) {
+        self.drain_with(|| PollError::ShuttingDown);
+    }
+
+    /// Like `drain`, but tells pollers to come back right away, for when a new
+    /// instance already listens on the same port and reads the same storage.
+    pub fn hand_over(&self) {
+        self.handing_over.store(true, Ordering::SeqCst);
+        self.drain_with(|| PollError::Reconnect);
+    }
+
+    fn drain_with(&self, error: fn() -> PollError) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.notifiers.stop();
+        let pollers = self.futures.lock().expect("").drain();
+        for poller in pollers {
+            poller.fulfill(Err(error()));
+        }
+    }
+
+    /// Waits for background storage writes and closes storage, which writes the
+    /// memory backend's snapshot. Call it last before exiting.
+    pub async fn flush(&self) {
+        self.writes.close();
+        self.writes.wait().await;
+        if let Err(e) = self.storage.close().await {
+            error!("Failed to close storage: {e:#}");
+        }
+    }
+
+    /// Pending HTTP-01 challenges, answered under `/.well-known/acme-challenge/`.
+    pub fn challenges(&self) -> &acme::Challenges {
+        &self.challenges
+    }
+
+    /// How hits for `token` are spread over its waiting polls.
+    fn fanout(&self, token: &str) -> Fanout {
+        let tokens = self.tokens.lock().expect("");
+        tokens
+            .get(token)
+            .and_then(|info| info.fanout)
+            .unwrap_or(self.config().delivery.fanout)
+    }
+
+    /// The sequence number for the next hit on `token`. Replicas share their
+    /// counters in Redis.
+    async fn next_seq(&self, token: &str) -> Result<u64, Error> {
+        if let Some(cluster) = &self.cluster {
+            return cluster.next_seq(token).await;
+        }
+        let mut sequences = self.sequences.lock().expect("");
+        let seq = sequences.entry(token.to_owned()).or_default();
+        *seq += 1;
+        Ok(*seq)
+    }
+
+    /// Whether this instance may hand `notification` to a poll: always on its
+    /// own, with replicas once it won the claim on it, so only one of them
+    /// does. Another replica having won it means it went out there, so it is
+    /// settled here too. When Redis fails the instance that took the hit,
+    /// its `origin`, delivers it.
+    async fn claim(&self, notification: &Notification, origin: bool) -> bool {
+        let Some(cluster) = &self.cluster else {
+            return true;
+        };
+        match cluster.claim(notification.uuid).await {
+            Ok(true) => true,
+            Ok(false) => {
+                if origin {
+                    self.settle(vec![notification.id]);
+                }
+                false
+            }
+            Err(e) => {
+                warn!("Claiming notification {} failed: {e:#}", notification.id);
+                origin
+            }
+        }
+    }
+
+    /// Gives up a claim in the background, see `Cluster::release`.
+    fn release(&self, uuid: Uuid) {
+        let Some(cluster) = self.cluster.clone() else {
+            return;
+        };
+        task::spawn(async move {
+            if let Err(e) = cluster.release(uuid).await {
+                warn!("Releasing the claim on {uuid} failed: {e:#}");
+            }
+        });
+    }
+
+    /// Puts `notification` back into its token's buffer, see `Hub::requeue`,
+    /// evicting like `dispatch` does when that makes the buffers too big.
+    fn requeue(&self, hub: &mut Hub, notification: Notification) {
+        let config = self.config();
+        let evicted = hub.requeue(&config.buffer, notification);
+        let shed = hub.shed(config.buffer.max_bytes);
+        self.evicted(evicted, shed);
+    }
+
+    /// Counts and settles what a token's full buffer pushed out and what was
+    /// shed to fit `buffer.max_bytes`.
+    fn evicted(&self, evicted: Option<Notification>, shed: Vec<Notification>) {
+        if let Some(evicted) = evicted {
+            self.metrics.evictions.with_label_values(&["buffer"]).inc();
+            self.settle(vec![evicted.id]);
+        }
+        if !shed.is_empty() {
+            self.metrics
+                .evictions
+                .with_label_values(&["memory"])
+                .inc_by(shed.len() as u64);
+            self.settle(shed.iter().map(|n| n.id).collect());
+        }
+    }
+
+    /// Records in the background that these notifications left the buffer.
+    fn settle(&self, ids: Vec<i64>) {
+        if ids.is_empty() {
+            return;
+        }
+        let storage = self.storage.clone();
+        self.writes.spawn(async move {
+            if let Err(e) = storage.settle(&ids).await {
+                error!("Failed to settle notifications {ids:?}: {e:#}");
+            }
+        });
+    }
+}
+
+/// Every route of the server, for serving with
+/// `into_make_service_with_connect_info::<SocketAddr>()`, which the beacon and
+/// poll handlers need for the client address.
+pub fn routes(state: AppState) -> Router {
+    endpoints(&state)
+        .fallback(any(catchall::record).layer(middleware::from_fn_with_state(
+            state.clone(),
+            ratelimit::per_ip,
+        )))
+        .with_state(state)
+}
+
+/// `app` answering only paths matching one of `routes` (a trailing `*` matching
+/// a prefix) and `404` to the rest before anything records them. All of `app`
+/// when `routes` is empty.
+pub fn restrict(app: Router, routes: &[String]) -> Router {
+    if routes.is_empty() {
+        return app;
+    }
+    let allowed = Arc::new(Matcher(routes.iter().map(|r| Pattern::parse(r)).collect()));
+    app.layer(middleware::from_fn(
+        move |request: axum::http::Request<axum::body::Body>, next: middleware::Next<_>| {
+            let allowed = allowed.clone();
+            async move {
+                match allowed.matches(request.uri().path()) {
+                    true => next.run(request).await,
+                    false => StatusCode::NOT_FOUND.into_response(),
+                }
+            }
+        },
+    ))
+}
+
+/// The routes without the catch-all fallback, see `CallbackLayer`.
+fn endpoints(state: &AppState) -> Router<AppState> {
+    let config = state.config();
+    // Routes payloads call from the victim's page.
+    let mut beacons =
+        Router::new()
+            .route(
+                "/notify",
+                get(notify)
+                    .post(notify_post)
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        ratelimit::per_ip,
+                    )),
+            )
+            .route(
+                "/notify/:token",
+                get(notify_path).post(notify_post_path).route_layer(
+                    middleware::from_fn_with_state(state.clone(), ratelimit::per_ip),
+                ),
+            )
+            .route(
+                "/b.gif",
+                get(beacon_gif).route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    ratelimit::per_ip,
+                )),
+            )
+            .route(
+                "/csp-report/:token",
+                post(csp::report).route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    ratelimit::per_ip,
+                )),
+            )
+            .route("/payload.js", get(payloads::script))
+            .route("/payloads/:name", get(payloads::named));
+    if let Some(cors) = cors::layer(&config) {
+        beacons = beacons.layer(cors);
+    }
+    // Routes handing out whole hits, which may carry large payloads.
+    let hits = Router::new()
+        .route("/poll-notified", get(poll_notified))
+        .route("/p/:token", get(poll_path))
+        .route("/api/notifications", get(history::list))
+        .route("/n/:uuid", get(history::show))
+        .route("/n/:uuid/attachments/:index", get(history::attachment))
+        .route("/api/attachments/:id", get(attachments::get))
+        .layer(compression::layer());
+    Router::new()
+        .merge(beacons)
+        .merge(hits)
+        .route("/ack", post(delivery::ack))
+        .route("/ws", get(ws::subscribe))
+        // For consumers multiplexing polls and gRPC streams over one HTTP/2 connection.
+        .route_service(
+            "/xss_check_srv.v1.Callbacks/*rpc",
+            grpc::service(state.clone()),
+        )
+        .route("/events", get(sse::events))
+        .route("/stream", get(ndjson::stream))
+        .route("/tokens", post(tokens::create))
+        .route("/api/notifications/:id", delete(history::delete))
+        .route("/api/tokens", get(tokens::list))
+        .route("/api/tokens/:token", delete(tokens::delete))
+        .route("/admin/pollers", get(admin::pollers))
+        .route("/admin/pollers/:id/kick", post(admin::kick))
+        .route("/admin/tokens/:token/revoke", post(admin::revoke))
+        .route("/admin/dead-letters", get(admin::dead_letters))
+        .route("/admin/dead-letters/redrive", post(admin::redrive_all))
+        .route("/admin/dead-letters/:id/redrive", post(admin::redrive))
+        .route("/admin/dead-letters/:id", delete(admin::delete_dead_letter))
+        .route("/ui", get(ui::index))
+        .route("/ui/", get(ui::index))
+        .route("/ui/*path", get(ui::asset))
+        .merge(openapi::docs())
+        .merge(graphql::routes())
+        .route("/metrics", get(metrics::export))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            telemetry::log_request,
+        ))
+        // Probes and challenges stay out of request logs and latency metrics.
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/.well-known/acme-challenge/:token", get(acme::challenge))
+}
+
+/// Builds the state for `config` and returns its routes, to mount the callback
+/// endpoints inside another axum app. Call it from within a tokio runtime, it
+/// starts the background loops right away.
+pub async fn router(config: Config) -> Result<Router, Error> {
+    Ok(routes(AppState::new(config, None).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/notify",
+    tag = "beacons",
+    params(
+        ("token" = Option<String>, Query, description = "Token the hit is for, optional with `token_domain` subdomains"),
+        ("s" = Option<String>, Query, description = "Notify secret of the token, if it has one"),
+        ("idem" = Option<String>, Query, description = "Idempotency key, retries repeating it within `idempotency.window` are not recorded again"),
+        ("callback" = Option<String>, Query, description = "Wrap the reply in a JSONP call to this function"),
+    ),
+    responses(
+        (status = 200, description = "Hit accepted; every other query parameter is recorded, the notification id is in `X-Notification-Id`"),
+        (status = 400, description = "No token"),
+        (status = 403, description = "Wrong or missing notify secret"),
+        (status = 404, description = "Token not signed with `token_secret`"),
+        (status = 410, description = "Token expired or revoked"),
+        (status = 429, description = "Rate limit or token quota exceeded"),
+    ),
+)]
+async fn notify(
+    Query(mut params): Query<Payload>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    hello: ClientHello,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let callback = params.remove("callback");
+    let Some(token) = hit_token(&state.config(), &mut params, &headers) else {
+        return Ok(reply(
+            &state.config(),
+            callback,
+            StatusCode::BAD_REQUEST.into(),
+        ));
+    };
+    let meta = request_meta(&state.config(), &headers, source, hello);
+    let accepted = accept(&state, token, params, Vec::new(), meta).await?;
+    Ok(reply(&state.config(), callback, accepted))
+}
+
+/// `/notify` with the token in the path, for contexts that strip query strings.
+#[utoipa::path(
+    get,
+    path = "/notify/{token}",
+    tag = "beacons",
+    params(("token" = String, Path, description = "Token")),
+    responses(
+        (status = 200, description = "Hit accepted, as for `/notify`"),
+        (status = 410, description = "Token expired or revoked"),
+    ),
+)]
+async fn notify_path(
+    Path(token): Path<String>,
+    Query(mut params): Query<Payload>,
+    source: ConnectInfo<SocketAddr>,
+    state: State<AppState>,
+    hello: ClientHello,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    params.insert("token".to_owned(), token);
+    notify(Query(params), source, state, hello, headers).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/notify/{token}",
+    tag = "beacons",
+    params(("token" = String, Path, description = "Token")),
+    request_body(content = Object, description = "As for `POST /notify`", content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Hit accepted, as for `POST /notify`"),
+        (status = 410, description = "Token expired or revoked"),
+    ),
+)]
+async fn notify_post_path(
+    Path(token): Path<String>,
+    Query(mut params): Query<Payload>,
+    source: ConnectInfo<SocketAddr>,
+    state: State<AppState>,
+    hello: ClientHello,
+    headers: HeaderMap,
+    body: RawBody,
+) -> Result<Response, AppError> {
+    params.insert("token".to_owned(), token);
+    notify_post(Query(params), source, state, hello, headers, body).await
+}
+
+/// What nginx answers for a missing page.
+const NOT_FOUND_PAGE: &str = "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n<center><h1>404 Not Found</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n";
+
+/// A transparent 1x1 GIF.
+const PIXEL: &[u8] = &[
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+/// `/notify` for `<img src=//host/b.gif?token=X>`, answering with a pixel.
+#[utoipa::path(
+    get,
+    path = "/b.gif",
+    tag = "beacons",
+    params(("token" = String, Query, description = "Token")),
+    responses(
+        (status = 200, description = "Hit accepted", content_type = "image/gif"),
+        (status = 400, description = "No token, still a pixel", content_type = "image/gif"),
+    ),
+)]
+async fn beacon_gif(
+    Query(mut params): Query<Payload>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    hello: ClientHello,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let accepted = match hit_token(&state.config(), &mut params, &headers) {
+        Some(token) => {
+            let meta = request_meta(&state.config(), &headers, source, hello);
+            accept(&state, token, params, Vec::new(), meta).await?
+        }
+        None => StatusCode::BAD_REQUEST.into(),
+    };
+    Ok((
+        accepted.status,
+        accepted.id_header(),
+        [
+            (header::CONTENT_TYPE, "image/gif"),
+            (header::CACHE_CONTROL, "public, max-age=86400"),
+        ],
+        PIXEL,
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/notify",
+    tag = "beacons",
+    params(("token" = Option<String>, Query, description = "May also be a body field")),
+    request_body(
+        content = Object,
+        description = "Fields as JSON, a urlencoded form, or multipart parts where files become attachments",
+        content_type = "multipart/form-data",
+    ),
+    responses(
+        (status = 200, description = "Hit accepted"),
+        (status = 400, description = "No token or an unreadable body"),
+        (status = 410, description = "Token expired or revoked"),
+        (status = 413, description = "Body over `limits.max_body`, or a file over `attachments.max_size`"),
+        (status = 429, description = "Rate limit or token quota exceeded"),
+    ),
+)]
+async fn notify_post(
+    Query(mut params): Query<Payload>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    hello: ClientHello,
+    headers: HeaderMap,
+    RawBody(body): RawBody,
+) -> Result<Response, AppError> {
+    let config = state.config();
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    let max_body = config.limits.max_body;
+    let parsed = match mime {
+        "multipart/form-data" => {
+            let (max_size, max_total) = config
+                .attachments
+                .as_ref()
+                .map_or((usize::MAX, usize::MAX), |attachments| {
+                    (attachments.max_size, attachments.max_total)
+                });
+            let limits = upload::Limits {
+                max_body,
+                max_parts: config.limits.max_params,
+                max_size,
+                max_total,
+            };
+            let store = state.attachments.as_deref();
+            upload::multipart(content_type, body, store, &limits).await?
+        }
+        "application/json" | "application/x-www-form-urlencoded" => {
+            upload::read(body, max_body).await.and_then(|body| {
+                match mime {
+                    "application/json" => parse_json_body(&body),
+                    _ => serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+                        .map_err(Error::from),
+                }
+                .map(|fields| (fields, Vec::new()))
+                .map_err(|_| StatusCode::BAD_REQUEST)
+            })
+        }
+        _ => Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    };
+    let (fields, attachments) = match parsed {
+        Ok(parsed) => parsed,
+        Err(refused) => return Ok(reply(&config, None, refused.into())),
+    };
+    params.extend(fields);
+    let callback = params.remove("callback");
+    let Some(token) = hit_token(&state.config(), &mut params, &headers) else {
+        return Ok(reply(
+            &state.config(),
+            callback,
+            StatusCode::BAD_REQUEST.into(),
+        ));
+    };
+    let meta = request_meta(&state.config(), &headers, source, hello);
+    let accepted = accept(&state, token, params, attachments, meta).await?;
+    Ok(reply(&state.config(), callback, accepted))
+}
+
+/// What `notify_response` says, by default the bare status with the
+/// notification id in `X-Notification-Id`. With `callback=cb` it is a
+/// `cb({"ok": .., "status": .., "id": ..})` script for JSONP payloads instead,
+/// always served with `200` since browsers do not run scripts from error responses.
+fn reply(config: &Config, callback: Option<String>, accepted: Accepted) -> Response {
+    let Some(callback) = callback else {
+        return match &config.notify_response {
+            NotifyResponse::Status => (accepted.status, accepted.id_header()).into_response(),
+            NotifyResponse::NoContent => StatusCode::NO_CONTENT.into_response(),
+            NotifyResponse::NotFound => (
+                StatusCode::NOT_FOUND,
+                [(header::CONTENT_TYPE, "text/html")],
+                NOT_FOUND_PAGE,
+            )
+                .into_response(),
+            NotifyResponse::Static {
+                status,
+                body,
+                content_type,
+            } => (
+                StatusCode::from_u16(*status).expect("validated status"),
+                [(header::CONTENT_TYPE, content_type.clone())],
+                body.clone(),
+            )
+                .into_response(),
+        };
+    };
+    let valid = !callback.is_empty()
+        && callback.len() <= 64
+        && callback
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '$' | '.'));
+    if !valid {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let status = accepted.status;
+    let body = serde_json::json!({
+        "ok": status.is_success(),
+        "status": status.as_u16(),
+        "id": accepted.id,
+    });
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/javascript; charset=utf-8",
+            ),
+            (header::CACHE_CONTROL, "no-store"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        // The comment keeps the response from starting with attacker chosen bytes.
+        format!("/**/{callback}({body});"),
+    )
+        .into_response()
+}
+
+/// The `token` parameter, or else the subdomain of `token_domain` the hit was sent to.
+fn hit_token(config: &Config, params: &mut Payload, headers: &HeaderMap) -> Option<String> {
+    if let Some(token) = params.remove("token") {
+        return Some(token);
+    }
+    let domain = config.token_domain.as_deref()?;
+    let host = headers
+        .get(header::HOST)?
+        .to_str()
+        .ok()?
+        .to_ascii_lowercase();
+    let host = host
+        .rsplit_once(':')
+        .map_or(host.as_str(), |(host, _)| host);
+    let token = host
+        .strip_suffix(&domain.to_ascii_lowercase())?
+        .strip_suffix('.')
+        .filter(|token| !token.is_empty())?;
+    Some(token.to_owned())
+}
+
+fn request_meta(
+    config: &Config,
+    headers: &HeaderMap,
+    source: SocketAddr,
+    hello: ClientHello,
+) -> Meta {
+    let value = |name: &str| {
+        headers
+            .get(name)
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+    };
+    let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+    Meta {
+        remote_addr: Some(source),
+        client_ip: Some(proxy::client_ip(
+            &config.trusted_proxies,
+            source.ip(),
+            headers,
+        )),
+        ip_version: Some(IpVersion::of(source.ip())),
+        user_agent: value(header::USER_AGENT.as_str()),
+        referer: value(header::REFERER.as_str()),
+        headers: config
+            .capture
+            .headers
+            .iter()
+            .filter_map(|name| Some((name.clone(), value(name)?)))
+            .collect(),
+        // Filled in by `accept`, once the hit passed the checks.
+        geo: None,
+        agent: None,
+        tls: hello.0,
+    }
+}
+
+/// Flattens a JSON object into string fields. Non-string values are kept as
+/// their JSON encoding so nested payloads survive the trip intact.
+fn parse_json_body(body: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(body)?;
+    Ok(object
+        .into_iter()
+        .map(|(k, v)| match v {
+            serde_json::Value::String(s) => (k, s),
+            v => (k, v.to_string()),
+        })
+        .collect())
+}
+
+/// Answers `/notify` with the id of recorded hits.
+const NOTIFICATION_ID: &str = "x-notification-id";
+
+/// How `accept` answered a hit: the status, and the notification it was
+/// recorded as unless it was refused.
+struct Accepted {
+    status: StatusCode,
+    id: Option<i64>,
+}
+
+impl Accepted {
+    fn recorded(id: i64) -> Self {
+        Accepted {
+            status: StatusCode::OK,
+            id: Some(id),
+        }
+    }
+
+    fn id_header(&self) -> AppendHeaders<Option<(&'static str, String)>> {
+        AppendHeaders(self.id.map(|id| (NOTIFICATION_ID, id.to_string())))
+    }
+}
+
+impl From<StatusCode> for Accepted {
+    fn from(status: StatusCode) -> Self {
+        Accepted { status, id: None }
+    }
+}
+
+/// Persists a hit and hands it to whoever is waiting for its token.
+#[tracing::instrument(
+    name = "notify",
+    skip_all,
+    fields(token = telemetry::token_hash(&token), notification.id)
+)]
+async fn accept(
+    state: &AppState,
+    token: String,
+    mut data: Payload,
+    mut attachments: Vec<Attachment>,
+    mut meta: Meta,
+) -> Result<Accepted, Error> {
+    if let Err(status) = state.check_token(&token) {
+        return Ok(status.into());
+    }
+    let config = state.config();
+    let limits = &config.limits;
+    let max_size = config
+        .attachments
+        .as_ref()
+        .map_or(usize::MAX, |attachments| attachments.max_size);
+    // The token and attachments count as parameters too.
+    if data.len() + attachments.len() + 1 > limits.max_params
+        || attachments.iter().any(|a| a.size > max_size as u64)
+        || [&token]
+            .into_iter()
+            .chain(data.keys())
+            .chain(data.values())
+            .any(|s| s.len() > limits.max_value_len)
+    {
+        return Ok(StatusCode::PAYLOAD_TOO_LARGE.into());
+    }
+    if let Some(info) = state.tokens.lock().expect("").get(&token) {
+        if info.secret.is_some() && !info.admits(data.remove("s").as_deref()) {
+            return Ok(StatusCode::FORBIDDEN.into());
+        }
+    }
+    let window = Duration::from_secs(state.config().idempotency.window);
+    let idem = (!window.is_zero()).then(|| data.remove("idem")).flatten();
+    if let Some(key) = &idem {
+        // A retried beacon, the first attempt already went out.
+        if let Some(id) = state.idempotency.replay_of(&token, key, window) {
+            Span::current().record("notification.id", id);
+            return Ok(Accepted::recorded(id));
+        }
+    }
+    if let Some(quotas) = &*state.quotas.load() {
+        if let Err(throttled) = quotas.check(&token) {
+            let label = state.metric_label(&token);
+            state.metrics.throttled.with_label_values(&[&label]).inc();
+            if throttled == 1 {
+                warn!(
+                    token = telemetry::token_hash(&token),
+                    "Token is over its quota, refusing hits"
+                );
+            }
+            return Ok(StatusCode::TOO_MANY_REQUESTS.into());
+        }
+    }
+    state
+        .metrics
+        .notifications
+        .with_label_values(&[&state.metric_label(&token)])
+        .inc();
+    let dedup = state.dedup.load_full().map(|dedup| {
+        let fingerprint = dedup::fingerprint(&token, &data, &attachments, meta.client_ip);
+        (dedup, fingerprint)
+    });
+    if let Some((dedup, fingerprint)) = &dedup {
+        // Counted towards the first one, which already went out.
+        if let Some(id) = dedup.duplicate_of(fingerprint) {
+            Span::current().record("notification.id", id);
+            state.futures.lock().expect("").add_hit(id);
+            state.storage.add_hit(id).await?;
+            if let Some(key) = idem {
+                state.idempotency.remember(token, key, id);
+            }
+            return Ok(Accepted::recorded(id));
+        }
+    }
+    if let (Some(geoip), Some(ip)) = (&*state.geoip.load(), meta.client_ip) {
+        meta.geo = geoip.locate(ip);
+    }
+    meta.agent = meta.user_agent.as_deref().and_then(useragent::parse);
+    let recipient = state
+        .tokens
+        .lock()
+        .expect("")
+        .get(&token)
+        .and_then(|info| info.public_key.as_deref().and_then(e2e::recipient));
+    if let Some(recipient) = recipient {
+        let store = state.attachments.as_deref();
+        e2e::seal(&recipient, &mut data, &mut meta, &mut attachments, store).await?;
+    }
+    if let Some(store) = &state.attachments {
+        for attachment in &mut attachments {
+            store.save(attachment).await?;
+        }
+    }
+    let seq = state.next_seq(&token).await?;
+    let idem = idem.map(|key| (token.clone(), key));
+    let mut notification = Notification {
+        v: Version,
+        id: 0,
+        uuid: Uuid::new_v4(),
+        seq,
+        token,
+        received_at: Utc::now(),
+        data,
+        meta,
+        attachments,
+        hits: 1,
+        delivery_id: None,
+        trace: telemetry::current(),
+    };
+    let id = state.storage.insert(&notification).await?;
+    notification.id = id;
+    Span::current().record("notification.id", id);
+    if let Some((dedup, fingerprint)) = dedup {
+        dedup.remember(fingerprint, id);
+    }
+    if let Some((token, key)) = idem {
+        state.idempotency.remember(token, key, id);
+    }
+    // Only the instance that took the hit forwards it, not every replica.
+    state.notifiers.forward(state, &notification);
+    if let Some(cluster) = &state.cluster {
+        if let Err(e) = cluster.publish(&notification).await {
+            warn!("Publishing to redis failed, only local pollers get it: {e:#}");
+        }
+    }
+    dispatch(state, notification, true).await;
+    Ok(Accepted::recorded(id))
+}
+
+/// Wakes pollers and streams waiting for the notification's token. Only its
+/// `origin`, the instance that took the hit, buffers it if nobody waits. Polls
+/// only get it once this instance won the claim on it, see `AppState::claim`.
+async fn dispatch(state: &AppState, notification: Notification, origin: bool) {
+    {
+        let mut guard = state.futures.lock().expect("");
+        let streamed = guard.stream(&notification);
+        if !guard.has_pollers(&notification.token) {
+            if !origin {
+                return;
+            }
+            if streamed {
+                // Only streams got it, they never acknowledge.
+                state.settle(vec![notification.id]);
+                return;
+            }
+            let config = state.config();
+            let evicted = guard.buffer(&config.buffer, notification);
+            let shed = guard.shed(config.buffer.max_bytes);
+            state.evicted(evicted, shed);
+            return;
+        }
+    }
+    if !state.claim(&notification, origin).await {
+        return;
+    }
+    let (suspended, notification) = {
+        let mut guard = state.futures.lock().expect("");
+        let suspended = guard.take_pollers(&notification.token, state.fanout(&notification.token));
+        if suspended.is_empty() {
+            // The polls left while we claimed it.
+            if origin {
+                state.requeue(&mut guard, notification);
+            } else {
+                state.release(notification.uuid);
+            }
+            return;
+        }
+        let notification = delivery::hand_out(state, &mut guard, notification);
+        (suspended, notification)
+    };
+    task::spawn(async move {
+        for r in suspended {
+            r.fulfill(Ok(notification.clone()))
+        }
+    });
+}
+
+/// Tokens and prefixes one `/poll-notified` may wait on at once.
+const MAX_POLL_TOKENS: usize = 100;
+
+#[derive(Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct Wait {
+    /// Give up after this many seconds, capped at `limits.max_wait`.
+    wait: Option<u64>,
+    /// Send a space every this many seconds while waiting, 0 for none.
+    /// Defaults to `limits.heartbeat`.
+    heartbeat: Option<u64>,
+    /// The `seq` of the last hit seen, to first catch up on any after it.
+    since: Option<u64>,
+}
+
+/// Waits for a hit on any of the `token` parameters, which may be repeated or
+/// comma separated and end in `*` to match a prefix, or on a token starting with
+/// any `prefix` parameter. `wait` is the seconds to wait before giving up with
+/// 204, capped at `limits.max_wait`.
+///
+/// With heartbeats the `200` goes out right away and spaces follow until the
+/// JSON, which is then either the hit or an `ErrorBody`, `"timeout"` included.
+/// Otherwise each `ErrorBody` code has its own status, see `PollError`.
+///
+/// With `since`, the `seq` of the last hit seen for a single `token`, any later
+/// hit still buffered or stored comes back right away, oldest first.
+#[debug_handler]
+#[utoipa::path(
+    get,
+    path = "/poll-notified",
+    tag = "polling",
+    params(
+        ("token" = String, Query, description = "Token to wait on, repeated or comma separated, a trailing `*` matches a prefix"),
+        ("prefix" = Option<String>, Query, description = "Wait on every token starting with this"),
+        ("wait" = Option<u64>, Query, description = "Give up after this many seconds, capped at `limits.max_wait`"),
+        ("heartbeat" = Option<u64>, Query, description = "Send a space every this many seconds while waiting, 0 for none, defaults to `limits.heartbeat`"),
+        ("since" = Option<u64>, Query, description = "The `seq` of the last hit seen, to first catch up on later ones; needs a single `token`"),
+    ),
+    responses(
+        (status = 200, description = "The next hit", content(("application/json" = Notification), ("application/msgpack" = Notification), ("application/cbor" = Notification))),
+        (status = 204, description = "Nothing arrived within `wait`"),
+        (status = 400, description = "No tokens, too many, a bad `wait`, `heartbeat` or `since`, or `since` with more than one token"),
+        (status = 403, description = "The API key may not poll these tokens"),
+        (status = 409, description = "Evicted to make room for other polls", body = model::ErrorBody),
+        (status = 410, description = "Token expired or revoked", body = model::ErrorBody),
+        (status = 500, description = "The server failed, not the poll", body = model::ErrorBody),
+        (status = 503, description = "Too many polls waiting, or shutting down", body = model::ErrorBody),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+async fn poll_notified(
+    Query(params): Query<Vec<(String, String)>>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    key: ApiKey,
+    encoding: Encoding,
+    headers: HeaderMap,
+) -> Result<Response, PollError> {
+    let mut matcher = Matcher::default();
+    let mut wait = Wait::default();
+    for (name, value) in params {
+        match name.as_str() {
+            "token" => {
+                for token in value.split(',').filter(|t| !t.is_empty()) {
+                    matcher.push(Pattern::parse(token));
+                }
+            }
+            "prefix" if !value.is_empty() => matcher.push(Pattern::Prefix(value)),
+            "wait" => match value.parse() {
+                Ok(secs) => wait.wait = Some(secs),
+                Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+            },
+            "heartbeat" => match value.parse() {
+                Ok(secs) => wait.heartbeat = Some(secs),
+                Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+            },
+            "since" => match value.parse() {
+                Ok(seq) => wait.since = Some(seq),
+                Err(_) => return Ok(StatusCode::BAD_REQUEST.into_response()),
+            },
+            _ => {}
+        }
+    }
+    if matcher.0.is_empty() || matcher.0.len() > MAX_POLL_TOKENS {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    }
+    let client = proxy::client_ip(&state.config().trusted_proxies, source.ip(), &headers);
+    poll(state, key, encoding, matcher, wait, client).await
+}
+
+/// `/poll-notified` with the token in the path, `/p/:token`.
+#[utoipa::path(
+    get,
+    path = "/p/{token}",
+    tag = "polling",
+    params(("token" = String, Path, description = "Token"), Wait),
+    responses(
+        (status = 200, description = "The next hit", content(("application/json" = Notification), ("application/msgpack" = Notification), ("application/cbor" = Notification))),
+        (status = 204, description = "Nothing arrived within `wait`"),
+        (status = 410, description = "Token expired or revoked", body = model::ErrorBody),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+async fn poll_path(
+    Path(token): Path<String>,
+    Query(wait): Query<Wait>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    State(state): State<AppState>,
+    key: ApiKey,
+    encoding: Encoding,
+    headers: HeaderMap,
+) -> Result<Response, PollError> {
+    let client = proxy::client_ip(&state.config().trusted_proxies, source.ip(), &headers);
+    poll(state, key, encoding, Matcher::exact(&token), wait, client).await
+}
+
+#[tracing::instrument(
+    name = "poll",
+    skip_all,
+    fields(
+        token = matcher
+            .0
+            .iter()
+            .map(|p| telemetry::token_hash(&p.to_string()))
+            .collect::<Vec<_>>()
+            .join(","),
+        notification.id,
+        suspended_ms
+    )
+)]
+async fn poll(
+    state: AppState,
+    key: ApiKey,
+    encoding: Encoding,
+    matcher: Matcher,
+    wait: Wait,
+    client: IpAddr,
+) -> Result<Response, PollError> {
+    state.accepting_polls()?;
+    for pattern in &matcher.0 {
+        if let Err(status) = key.check_pattern(pattern) {
+            return Ok(status.into_response());
+        }
+        // Prefixes cannot be checked up front, hits for bad tokens never arrive.
+        if let Pattern::Exact(token) = pattern {
+            if let Err(status) = state.check_token(token) {
+                return Ok(status.into_response());
+            }
+        }
+    }
+    if let Some(since) = wait.since {
+        // Sequence numbers only count up per token.
+        let [Pattern::Exact(token)] = matcher.0.as_slice() else {
+            return Ok(StatusCode::BAD_REQUEST.into_response());
+        };
+        if let Some(mut notification) = catch_up(&state, token, since).await? {
+            state.presign(&mut notification);
+            Span::current().record("notification.id", notification.id);
+            telemetry::link(&notification.trace);
+            return encoding
+                .try_respond(StatusCode::OK, &notification)
+                .map_err(PollError::Internal);
+        }
+    }
+    let config = state.config();
+    let heartbeat = wait.heartbeat.unwrap_or(config.limits.heartbeat);
+    let wait = wait
+        .wait
+        .map(|wait| Duration::from_secs(wait).min(config.limits.max_wait()));
+    let p = Arc::new(ReqPoll::new(client, wait));
+    loop {
+        let buffered = {
+            let mut guard = state.futures.lock().expect("");
+            match guard.take_buffered(&matcher) {
+                Some(notification) => notification,
+                None => {
+                    if guard.enqueue(&config.limits, matcher, p.clone())? {
+                        state.metrics.evictions.with_label_values(&["poller"]).inc();
+                    }
+                    break;
+                }
+            }
+        };
+        if !state.claim(&buffered, true).await {
+            continue;
+        }
+        let mut notification = {
+            let mut guard = state.futures.lock().expect("");
+            delivery::hand_out(&state, &mut guard, buffered)
+        };
+        state.presign(&mut notification);
+        Span::current().record("notification.id", notification.id);
+        telemetry::link(&notification.trace);
+        return encoding
+            .try_respond(StatusCode::OK, &notification)
+            .map_err(PollError::Internal);
+    }
+    let suspended = async move {
+        let mut notification = suspend(state.clone(), p, wait).await?;
+        state.presign(&mut notification);
+        Ok(notification)
+    }
+    .instrument(Span::current());
+    // Only JSON tolerates the spaces in front of it.
+    if heartbeat > 0 && encoding == Encoding::Json {
+        return Ok(heartbeats(Duration::from_secs(heartbeat), suspended));
+    }
+    let notification = suspended.await?;
+    encoding
+        .try_respond(StatusCode::OK, &notification)
+        .map_err(PollError::Internal)
+}
+
+/// The oldest hit for `token` numbered above `since`: buffered, so it is
+/// handed out like any other, or stored and handed out before, e.g. to a poll
+/// whose response got lost on the way.
+async fn catch_up(
+    state: &AppState,
+    token: &str,
+    since: u64,
+) -> Result<Option<Notification>, PollError> {
+    let stored = state
+        .storage
+        .after(token, since)
+        .await
+        .map_err(PollError::Internal)?;
+    let until = stored.as_ref().map_or(u64::MAX, |n| n.seq);
+    let buffered = state
+        .futures
+        .lock()
+        .expect("")
+        .take_buffered_between(token, since, until);
+    match buffered {
+        Some(notification) if state.claim(&notification, true).await => {
+            let mut guard = state.futures.lock().expect("");
+            Ok(Some(delivery::hand_out(state, &mut guard, notification)))
+        }
+        _ => Ok(stored),
+    }
+}
+
+/// Waits for the enqueued poll `p` to be fulfilled, `PollError::Timeout` once
+/// `wait` passed.
+async fn suspend(state: AppState, p: Arc<ReqPoll>, wait: Option<Duration>) -> PollResult {
+    let suspended = Instant::now();
+    let mut waiting = Waiting {
+        state: state.clone(),
+        poll: p.clone(),
+        done: false,
+    };
+    let data = match wait {
+        None => p.as_ref().await,
+        Some(wait) => {
+            match tokio::time::timeout(wait, p.as_ref()).await {
+                Ok(data) => data,
+                Err(_) => {
+                    let removed = state.futures.lock().expect("").take_poller(p.id);
+                    if removed.is_some() {
+                        waiting.done = true;
+                        return Err(PollError::Timeout);
+                    }
+                    // A notify claimed us right as the timer fired, the data is on its way.
+                    p.as_ref().await
+                }
+            }
+        }
+    };
+    waiting.done = true;
+    let span = Span::current();
+    span.record("suspended_ms", suspended.elapsed().as_millis() as u64);
+    let notification = data?;
+    span.record("notification.id", notification.id);
+    telemetry::link(&notification.trace);
+    Ok(notification)
+}
+
+/// A suspended poll, taken out of the hub again if its handler is dropped
+/// before it finished, as when the client disconnects. A hit it was handed in
+/// the meantime goes to another poll or back into the buffer instead of being
+/// lost.
+struct Waiting {
+    state: AppState,
+    poll: Arc<ReqPoll>,
+    done: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let (pollers, notification) = {
+            let mut hub = self.state.futures.lock().expect("");
+            if hub.take_poller(self.poll.id).is_some() {
+                return;
+            }
+            let Some(Ok(notification)) = self.poll.take() else {
+                return;
+            };
+            // Held deliveries go out again once their visibility timeout passes.
+            if notification.delivery_id.is_some() {
+                return;
+            }
+            let fanout = self.state.fanout(&notification.token);
+            let pollers = hub.take_pollers(&notification.token, fanout);
+            if pollers.is_empty() {
+                self.state.requeue(&mut hub, notification);
+                return;
+            }
+            (pollers, notification)
+        };
+        for poller in pollers {
+            poller.fulfill(Ok(notification.clone()));
+        }
+    }
+}
+
+/// A `200` that sends a space every `every` until `suspended` is done, so idle
+/// timeouts of proxies in between never fire, then its outcome as JSON.
+fn heartbeats(
+    every: Duration,
+    suspended: impl Future<Output = PollResult> + Send + 'static,
+) -> Response {
+    let ticks = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+    let body = stream::unfold(Some((Box::pin(suspended), ticks)), |waiting| async move {
+        let (mut suspended, mut ticks) = waiting?;
+        tokio::select! {
+            outcome = &mut suspended => {
+                let json = outcome
+                    .and_then(|notification| {
+                        serde_json::to_vec(&notification).map_err(|e| PollError::Internal(e.into()))
+                    })
+                    .unwrap_or_else(|e| {
+                        if let PollError::Internal(e) = &e {
+                            error!("Poll failed: {e:#}");
+                        }
+                        serde_json::to_vec(&e.body()).unwrap_or_default()
+                    });
+                Some((Ok::<_, Infallible>(Bytes::from(json)), None))
+            }
+            _ = ticks.tick() => {
+                Some((Ok(Bytes::from_static(b" ")), Some((suspended, ticks))))
+            }
+        }
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CACHE_CONTROL, "no-store"),
+            // Keeps nginx from holding the spaces back.
+            (HeaderName::from_static("x-accel-buffering"), "no"),
+        ],
+        StreamBody::new(body),
+    )
+        .into_response()
+}
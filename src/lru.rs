@@ -0,0 +1,97 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+/// A map keeping at most `capacity` entries, forgetting the least recently
+/// used one to make room for a new one.
+pub struct Lru<K, V> {
+    capacity: usize,
+    /// Each entry with when it was last used.
+    entries: HashMap<K, (u64, V)>,
+    /// The keys by when they were last used.
+    order: BTreeMap<u64, K>,
+    next_use: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+        }
+    }
+
+    /// The entry for `key`, created with `new` if there is none, now the most
+    /// recently used.
+    pub fn entry(&mut self, key: K, new: impl FnOnce() -> V) -> &mut V {
+        let used = self.next_use;
+        self.next_use += 1;
+        match self.entries.get_mut(&key) {
+            Some((last, _)) => {
+                self.order.remove(last);
+                *last = used;
+            }
+            None => {
+                if self.entries.len() >= self.capacity {
+                    if let Some((_, oldest)) = self.order.pop_first() {
+                        self.entries.remove(&oldest);
+                    }
+                }
+                self.entries.insert(key.clone(), (used, new()));
+            }
+        }
+        self.order.insert(used, key.clone());
+        &mut self.entries.get_mut(&key).expect("just used").1
+    }
+
+    /// The entry for `key` if there is one, leaving its place in line alone.
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    /// Replaces the entry for `key` with `value`, now the most recently used.
+    pub fn insert(&mut self, key: K, value: V) {
+        if let Some((last, _)) = self.entries.remove(&key) {
+            self.order.remove(&last);
+        }
+        self.entry(key, || value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_the_least_recently_used() {
+        let mut lru = Lru::new(2);
+        *lru.entry("a", || 0) += 1;
+        lru.entry("b", || 0);
+        // Using `a` again makes `b` the one to go.
+        *lru.entry("a", || 0) += 1;
+        lru.entry("c", || 0);
+        assert_eq!(lru.get(&"a"), Some(&2));
+        assert_eq!(lru.get(&"b"), None);
+        assert_eq!(lru.get(&"c"), Some(&0));
+    }
+
+    #[test]
+    fn stays_at_capacity() {
+        let mut lru = Lru::new(3);
+        for i in 0..100 {
+            lru.insert(i, i);
+        }
+        assert_eq!(lru.entries.len(), 3);
+        assert_eq!(lru.order.len(), 3);
+        assert_eq!(lru.get(&96), None);
+        assert_eq!(lru.get(&97), Some(&97));
+        // Replacing an entry does not count as a new one.
+        lru.insert(97, 0);
+        lru.insert(98, 0);
+        assert_eq!(lru.entries.len(), 3);
+        assert_eq!(lru.get(&99), Some(&99));
+    }
+}
@@ -1,161 +1,209 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    future::Future,
-    ops::{Deref, DerefMut},
-    sync::{Arc, Mutex},
-    task::Waker,
+    io,
+    net::{SocketAddr, TcpListener},
+    time::Duration,
 };
 
-use anyhow::{anyhow, Error};
-use axum::{
-    extract::{Query, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::get,
-    Router,
+use axum::Router;
+use axum_server::{
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+    Handle,
 };
-use axum_macros::debug_handler;
-use serde::Deserialize;
-use tokio::task;
+use clap::Parser;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
-const MAX_FUTURES: usize = 10000;
+use xss_check_srv::{
+    acme,
+    activation::Inherited,
+    cli::Args,
+    dns, grpc,
+    mtls::{self, ClientCerts},
+    restrict, routes, smtp, telemetry,
+    tls_fingerprint::Fingerprinting,
+    unix, AppState, Config,
+};
 
-struct AppError(anyhow::Error);
+/// How long open requests get to finish after a shutdown signal.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
 
-// Tell axum how to convert `AppError` into a response.
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e:#}");
+            std::process::exit(2);
+        }
+    };
+    let log_filter = telemetry::init(&config);
+    let mut inherited = Inherited::from_env();
+    let state = AppState::new(config, Some(log_filter))
+        .await
+        .expect("failed to start");
+    let config = state.config();
+    let app = routes(state.clone());
+    let handle = Handle::new();
+    let stopping = CancellationToken::new();
+    task::spawn(shutdown(state.clone(), handle.clone(), stopping.clone()));
+    let grpc = grpc::serve(state.clone(), stopping.clone().cancelled_owned());
+    task::spawn(async move { grpc.await.expect("failed to serve grpc") });
+    let dns = dns::serve(state.clone(), stopping.clone().cancelled_owned());
+    task::spawn(async move { dns.await.expect("failed to serve dns") });
+    let smtp = smtp::serve(state.clone(), stopping.clone().cancelled_owned());
+    task::spawn(async move { smtp.await.expect("failed to serve smtp") });
+    let unix_socket = config
+        .unix
+        .as_ref()
+        .and_then(|unix| inherited.take_unix(&unix.path));
+    let unix = unix::serve(
+        config.clone(),
+        app.clone(),
+        unix_socket,
+        stopping,
+        SHUTDOWN_GRACE,
+    );
+    let unix = task::spawn(async move { unix.await.expect("failed to serve on the unix socket") });
+    let rustls = if let Some(acme) = &config.acme {
+        let rustls = acme::initial_config(acme)
+            .await
+            .expect("failed to prepare acme certificate");
+        task::spawn(acme::renew_loop(
+            acme.clone(),
+            state.challenges().clone(),
+            rustls.clone(),
+        ));
+        task::spawn(reload_loop(state.clone(), args, None));
+        // Challenges come over plain HTTP, nothing else is served there.
+        let listener = bind(&mut inherited, acme.http_bind, config.reuse_port);
+        let challenges = acme::http_routes(state.clone(), config.bind.port());
+        task::spawn(listen(listener, None, challenges, handle.clone()));
+        Some(rustls)
+    } else if let Some(tls) = &config.tls {
+        let rustls = RustlsConfig::from_config(
+            mtls::server_config(tls).expect("failed to load tls certificate"),
+        );
+        task::spawn(reload_loop(state.clone(), args, Some(rustls.clone())));
+        Some(rustls)
+    } else {
+        task::spawn(reload_loop(state.clone(), args, None));
+        None
+    };
+    for listener in &config.listeners {
+        task::spawn(listen(
+            bind(&mut inherited, listener.bind, config.reuse_port),
+            rustls.clone().filter(|_| listener.tls),
+            restrict(app.clone(), &listener.routes),
+            handle.clone(),
+        ));
     }
-}
-
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+    if config.unix.as_ref().is_some_and(|unix| !unix.tcp) {
+        inherited.close_unused();
+        unix.await.expect("unix listener failed");
+    } else {
+        let listener = bind(&mut inherited, config.bind, config.reuse_port);
+        inherited.close_unused();
+        listen(listener, rustls, restrict(app, &config.routes), handle).await;
     }
+    state.flush().await;
+    telemetry::shutdown().await;
 }
 
-struct ReqPoll {
-    data: Arc<Mutex<Option<Result<HashMap<String, String>, Error>>>>,
-    waker: Arc<Mutex<Option<Waker>>>,
+/// The socket systemd handed over for `addr`, or a freshly bound one.
+fn bind(inherited: &mut Inherited, addr: SocketAddr, reuse_port: bool) -> TcpListener {
+    inherited
+        .take_tcp(addr)
+        .map_or_else(|| tcp_listener(addr, reuse_port), Ok)
+        .unwrap_or_else(|e| panic!("failed to bind {addr}: {e}"))
 }
 
-impl ReqPoll {
-    pub fn new() -> ReqPoll {
-        ReqPoll {
-            data: Arc::new(Mutex::new(None)),
-            waker: Arc::new(Mutex::new(None)),
+/// Serves `app` on `listener` until `handle` shuts it down, over TLS with `rustls`,
+/// fingerprinting each client's ClientHello and passing on its certificate.
+async fn listen(listener: TcpListener, rustls: Option<RustlsConfig>, app: Router, handle: Handle) {
+    let addr = listener
+        .local_addr()
+        .expect("listening socket has no address");
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let served = match rustls {
+        Some(rustls) => {
+            info!("Listening on https://{addr}");
+            let acceptor = ClientCerts(RustlsAcceptor::new(rustls).acceptor(Fingerprinting));
+            axum_server::from_tcp(listener)
+                .acceptor(acceptor)
+                .handle(handle)
+                .serve(service)
+                .await
         }
+        None => {
+            info!("Listening on {addr}");
+            axum_server::from_tcp(listener)
+                .handle(handle)
+                .serve(service)
+                .await
+        }
+    };
+    served.unwrap_or_else(|e| panic!("failed to serve on {addr}: {e}"));
+}
+
+/// A socket listening on `addr`. The IPv6 wildcard `[::]` takes IPv4 clients
+/// too, whatever the system default for `IPV6_V6ONLY`. With `reuse_port` other
+/// processes of the same user may listen on `addr` as well.
+fn tcp_listener(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
     }
-    pub fn fulfill(&self, data: Result<HashMap<String, String>, Error>) {
-        *self.data.lock().expect("") = Some(data);
-        let waker = self.waker.lock().expect("");
-        let Some(waker) = waker.as_ref() else {
-            return;
-        };
-        waker.wake_by_ref();
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
     }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
 }
 
-impl Future for &ReqPoll {
-    type Output = Result<HashMap<String, String>, Error>;
-    fn poll(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        let mut data = self.data.lock().expect("");
-        match std::mem::take(data.deref_mut()) {
-            Some(res) => std::task::Poll::Ready(res),
-            None => {
-                *self.waker.lock().expect("") = Some(cx.waker().clone());
-                std::task::Poll::Pending
+/// Re-reads the configuration on every SIGHUP. Listeners, storage and redis keep
+/// their startup settings, suspended pollers are left alone.
+async fn reload_loop(state: AppState, args: Args, rustls: Option<RustlsConfig>) {
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to watch SIGHUP");
+    while hangup.recv().await.is_some() {
+        match Config::load(&args) {
+            Ok(config) => {
+                state.reload(config, rustls.as_ref()).await;
+                info!("Configuration reloaded");
             }
+            Err(e) => error!("Keeping the old configuration, reload failed: {e:#}"),
         }
     }
 }
 
-type Futures = Arc<Mutex<VecDeque<(String, Arc<ReqPoll>)>>>;
-
-#[tokio::main]
-async fn main() {
-    let futures: Futures = Arc::new(Mutex::new(VecDeque::new()));
-
-    let app = Router::new()
-        .route("/notify", get(notify))
-        .route("/poll-notified", get(poll_notified))
-        .with_state(futures);
-    let addr = std::env::var("SOCK_ADDR").unwrap_or(String::from("127.0.0.1:3000"));
-    let server = axum::Server::bind(&addr.parse().unwrap()).serve(app.into_make_service());
-    println!("Listening on {:}", addr);
-    server.await.unwrap();
-}
-
-async fn notify(
-    Query(mut params): Query<HashMap<String, String>>,
-    State(futures): State<Futures>,
-) -> StatusCode {
-    let Some(token) = params.remove("token") else {
-        return StatusCode::BAD_REQUEST;
-    };
-    let suspended = {
-        let mut guard = futures.lock().expect("");
-        let suspended: Vec<_> = guard
-            .deref()
-            .into_iter()
-            .filter(|entry| entry.0 == token)
-            .map(|(_, r)| r.clone())
-            .collect();
-        guard.deref_mut().retain(|(_token, _)| *_token != token);
-        suspended
+/// Waits for SIGTERM or Ctrl-C, drains pollers and lets open requests finish.
+/// SIGUSR2 does the same for a new instance started with `reuse_port`: the
+/// listeners close first so the kernel sends every new connection there, then
+/// pollers are told to reconnect.
+async fn shutdown(state: AppState, handle: Handle, stopping: CancellationToken) {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to watch SIGTERM");
+    let mut hand_over = signal(SignalKind::user_defined2()).expect("failed to watch SIGUSR2");
+    let handing_over = tokio::select! {
+        _ = terminate.recv() => false,
+        _ = tokio::signal::ctrl_c() => false,
+        _ = hand_over.recv() => true,
     };
-    task::spawn(async move {
-        for r in suspended {
-            r.fulfill(Ok(params.clone()))
-        }
-    });
-    StatusCode::OK
-}
-
-#[derive(Deserialize)]
-struct NotifyWait {
-    token: String,
-}
-
-#[debug_handler]
-async fn poll_notified(
-    Query(NotifyWait { token }): Query<NotifyWait>,
-    State(futures): State<Futures>,
-) -> (StatusCode, Result<String, AppError>) {
-    //FIXME: Limit futures
-    let p = Arc::new(ReqPoll::new());
-    {
-        let mut guard = futures.lock().expect("");
-        if guard.deref().len() > MAX_FUTURES {
-            guard
-                .deref_mut()
-                .pop_front()
-                .unwrap()
-                .1
-                .fulfill(Err(anyhow!("You got kicked")))
-        }
-        guard.deref_mut().push_back((token, p.clone()));
+    if handing_over {
+        info!("Handing over to the new instance");
+        handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
+        state.hand_over();
+    } else {
+        info!("Shutting down");
+        state.drain();
+        handle.graceful_shutdown(Some(SHUTDOWN_GRACE));
     }
-    let data = p.as_ref().await;
-    let Ok(data) = data else {
-        return (
-            StatusCode::REQUEST_TIMEOUT,
-            Err(AppError(data.err().unwrap())),
-        );
-    };
-    (
-        StatusCode::OK,
-        serde_json::to_string(&data).map_err(|e| AppError(anyhow!(e.to_string()))),
-    )
+    stopping.cancel();
 }
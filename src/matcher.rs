@@ -0,0 +1,150 @@
+use std::fmt;
+
+/// A token, or with a trailing `*` every token starting with what precedes it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Pattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl Pattern {
+    /// `abc` matches only itself, `abc*` anything starting with `abc`.
+    pub fn parse(pattern: &str) -> Pattern {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => Pattern::Prefix(prefix.to_owned()),
+            None => Pattern::Exact(pattern.to_owned()),
+        }
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        match self {
+            Pattern::Exact(exact) => exact == token,
+            Pattern::Prefix(prefix) => token.starts_with(prefix.as_str()),
+        }
+    }
+
+    /// Whether every token this matches is also matched by `other`.
+    pub fn within(&self, other: &Pattern) -> bool {
+        match (self, other) {
+            (Pattern::Exact(token) | Pattern::Prefix(token), Pattern::Prefix(prefix)) => {
+                token.starts_with(prefix.as_str())
+            }
+            (Pattern::Exact(a), Pattern::Exact(b)) => a == b,
+            (Pattern::Prefix(_), Pattern::Exact(_)) => false,
+        }
+    }
+
+    pub fn is_exact(&self, token: &str) -> bool {
+        matches!(self, Pattern::Exact(exact) if exact == token)
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Exact(token) => f.write_str(token),
+            Pattern::Prefix(prefix) => write!(f, "{prefix}*"),
+        }
+    }
+}
+
+/// The patterns a poll is waiting on, matching a token if any of them does.
+#[derive(Clone, Debug, Default)]
+pub struct Matcher(pub Vec<Pattern>);
+
+impl Matcher {
+    pub fn exact(token: &str) -> Matcher {
+        Matcher(vec![Pattern::Exact(token.to_owned())])
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        self.0.iter().any(|pattern| pattern.matches(token))
+    }
+
+    pub fn contains(&self, pattern: &Pattern) -> bool {
+        self.0.contains(pattern)
+    }
+
+    /// Adds `pattern` unless it is already there.
+    pub fn push(&mut self, pattern: Pattern) {
+        if !self.contains(&pattern) {
+            self.0.push(pattern);
+        }
+    }
+}
+
+/// The value of the most specific pattern matching `token`: an exact one, else
+/// the longest prefix.
+pub fn most_specific<'a, T>(routes: &'a [(Pattern, T)], token: &str) -> Option<&'a T> {
+    routes
+        .iter()
+        .filter(|(pattern, _)| pattern.matches(token))
+        .max_by_key(|(pattern, _)| match pattern {
+            Pattern::Exact(_) => usize::MAX,
+            Pattern::Prefix(prefix) => prefix.len(),
+        })
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_at_the_end_matches_a_prefix() {
+        let pattern = Pattern::parse("engagement42-*");
+        assert_eq!(pattern, Pattern::Prefix("engagement42-".to_owned()));
+        assert!(pattern.matches("engagement42-"));
+        assert!(pattern.matches("engagement42-abc"));
+        assert!(!pattern.matches("engagement42"));
+        assert!(!pattern.matches("xengagement42-abc"));
+        assert_eq!(pattern.to_string(), "engagement42-*");
+    }
+
+    #[test]
+    fn star_elsewhere_is_literal() {
+        let pattern = Pattern::parse("*abc");
+        assert_eq!(pattern, Pattern::Exact("*abc".to_owned()));
+        assert!(pattern.matches("*abc"));
+        assert!(!pattern.matches("xabc"));
+        // Only the last star counts.
+        assert!(Pattern::parse("a*b*").matches("a*bc"));
+        assert!(!Pattern::parse("a*b*").matches("abc"));
+    }
+
+    #[test]
+    fn empty_patterns() {
+        let empty = Pattern::parse("");
+        assert!(empty.matches(""));
+        assert!(!empty.matches("abc"));
+        let everything = Pattern::parse("*");
+        assert!(everything.matches(""));
+        assert!(everything.matches("abc"));
+        assert!(!Matcher::default().matches("abc"));
+    }
+
+    #[test]
+    fn within() {
+        let (exact, prefix) = (Pattern::parse("abc"), Pattern::parse("ab*"));
+        assert!(exact.within(&prefix));
+        assert!(Pattern::parse("abcd*").within(&prefix));
+        assert!(!prefix.within(&exact));
+        assert!(!Pattern::parse("a*").within(&prefix));
+        assert!(prefix.within(&Pattern::parse("*")));
+    }
+
+    #[test]
+    fn most_specific_wins() {
+        let routes = [
+            (Pattern::parse("*"), "any"),
+            (Pattern::parse("ab*"), "ab"),
+            (Pattern::parse("abc*"), "abc"),
+            (Pattern::parse("abcd"), "exact"),
+        ];
+        assert_eq!(most_specific(&routes, "abcd"), Some(&"exact"));
+        assert_eq!(most_specific(&routes, "abce"), Some(&"abc"));
+        assert_eq!(most_specific(&routes, "abx"), Some(&"ab"));
+        assert_eq!(most_specific(&routes, "x"), Some(&"any"));
+        assert_eq!(most_specific(&routes[1..], "x"), None);
+    }
+}
@@ -0,0 +1,163 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use tracing::error;
+
+use crate::{auth::ApiKey, AppState};
+
+/// Everything exported on `/metrics`. Gauges are filled in from the hub at scrape time.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub notifications: IntCounterVec,
+    pub throttled: IntCounterVec,
+    pub evictions: IntCounterVec,
+    pub notifier_deliveries: IntCounterVec,
+    pollers: IntGauge,
+    max_pollers: IntGauge,
+    buffered: IntGauge,
+    buffered_bytes: IntGauge,
+    latency: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let notifications = IntCounterVec::new(
+            Opts::new(
+                "xss_notifications_total",
+                "Accepted hits per minted token hash",
+            ),
+            &["token"],
+        )
+        .expect("valid metric");
+        let throttled = IntCounterVec::new(
+            Opts::new(
+                "xss_throttled_total",
+                "Hits refused by token_limits per minted token hash",
+            ),
+            &["token"],
+        )
+        .expect("valid metric");
+        let evictions = IntCounterVec::new(
+            Opts::new(
+                "xss_evictions_total",
+                "Pollers kicked and buffered hits dropped to stay within limits",
+            ),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let notifier_deliveries = IntCounterVec::new(
+            Opts::new(
+                "xss_notifier_deliveries_total",
+                "Hits forwarded to webhooks and other notifiers",
+            ),
+            &["kind", "result"],
+        )
+        .expect("valid metric");
+        let pollers = IntGauge::new("xss_pollers", "Suspended long polls").expect("valid metric");
+        let max_pollers = IntGauge::new("xss_max_pollers", "Configured limits.max_pollers")
+            .expect("valid metric");
+        let buffered = IntGauge::new("xss_buffered", "Hits waiting in buffers for a poller")
+            .expect("valid metric");
+        let buffered_bytes = IntGauge::new(
+            "xss_buffered_bytes",
+            "Approximate memory held by buffered hits, see buffer.max_bytes",
+        )
+        .expect("valid metric");
+        let latency = HistogramVec::new(
+            HistogramOpts::new("xss_request_duration_seconds", "Handler latency per route"),
+            &["route", "status"],
+        )
+        .expect("valid metric");
+        let registry = Registry::new();
+        for collector in [
+            Box::new(notifications.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(throttled.clone()),
+            Box::new(evictions.clone()),
+            Box::new(notifier_deliveries.clone()),
+            Box::new(pollers.clone()),
+            Box::new(max_pollers.clone()),
+            Box::new(buffered.clone()),
+            Box::new(buffered_bytes.clone()),
+            Box::new(latency.clone()),
+        ] {
+            registry.register(collector).expect("unique metric");
+        }
+        Metrics {
+            registry,
+            notifications,
+            throttled,
+            evictions,
+            notifier_deliveries,
+            pollers,
+            max_pollers,
+            buffered,
+            buffered_bytes,
+            latency,
+        }
+    }
+}
+
+/// Middleware recording how long each matched route took.
+pub async fn track<B>(
+    State(state): State<AppState>,
+    path: MatchedPath,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+    state
+        .metrics
+        .latency
+        .with_label_values(&[path.as_str(), response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+/// Prometheus text exposition, admin keys only once keys are configured.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((status = 200, description = "Prometheus text format", content_type = "text/plain")),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn export(State(state): State<AppState>, key: ApiKey) -> Response {
+    if !key.is_admin() {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let metrics = &state.metrics;
+    {
+        let hub = state.futures.lock().expect("");
+        metrics.pollers.set(hub.pollers.len() as i64);
+        metrics.buffered.set(hub.buffered() as i64);
+        metrics.buffered_bytes.set(hub.buffered_bytes() as i64);
+    }
+    metrics
+        .max_pollers
+        .set(state.config().limits.max_pollers as i64);
+    let mut body = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&metrics.registry.gather(), &mut body) {
+        error!("Failed to encode metrics: {e}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            encoder.format_type().to_owned(),
+        )],
+        body,
+    )
+        .into_response()
+}
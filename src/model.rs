@@ -0,0 +1,234 @@
+//! What the API sends and receives. Every endpoint handing out hits, from polls
+//! and streams to the history and webhooks, uses the `Notification` envelope.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use opentelemetry::trace::SpanContext;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tempfile::TempPath;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub type Payload = HashMap<String, String>;
+
+/// The envelope format, `"v": 1` on the wire. Bumped on incompatible changes;
+/// envelopes from before it was introduced count as version 1, anything newer
+/// is refused rather than misread.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Version;
+
+impl Version {
+    pub const CURRENT: u32 = 1;
+}
+
+impl fmt::Debug for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Version::CURRENT)
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(Version::CURRENT)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let v = u32::deserialize(deserializer)?;
+        if v != Version::CURRENT {
+            return Err(de::Error::custom(format!(
+                "unsupported envelope version {v}, expected {}",
+                Version::CURRENT
+            )));
+        }
+        Ok(Version)
+    }
+}
+
+/// One accepted /notify hit, serialized as the envelope pollers receive.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct Notification {
+    #[serde(default)]
+    #[schema(value_type = u32, example = 1)]
+    pub v: Version,
+    /// Assigned by the storage backend.
+    pub id: i64,
+    /// Stays the same however often the notification is delivered.
+    pub uuid: Uuid,
+    /// Counts the token's hits from 1, so pollers can spot ones they missed.
+    pub seq: u64,
+    pub token: String,
+    pub received_at: DateTime<Utc>,
+    pub data: Payload,
+    pub meta: Meta,
+    /// Files that came as multipart parts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// How many identical hits this one stands for, see `dedup`.
+    #[serde(default = "Notification::one_hit")]
+    pub hits: u64,
+    /// Handed out with polls in at-least-once mode, to be passed to POST /ack.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivery_id: Option<Uuid>,
+    /// The notify span, so the poll it fulfils can link back to it.
+    #[serde(skip)]
+    pub trace: Option<SpanContext>,
+}
+
+impl Notification {
+    fn one_hit() -> u64 {
+        1
+    }
+}
+
+/// What the server saw of the request that carried a hit.
+#[derive(Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct Meta {
+    /// The peer that connected to us, possibly a proxy.
+    #[schema(value_type = Option<String>, example = "203.0.113.7:51234")]
+    pub remote_addr: Option<SocketAddr>,
+    /// Where the hit really came from, see `trusted_proxies`.
+    #[schema(value_type = Option<String>, example = "203.0.113.7")]
+    pub client_ip: Option<IpAddr>,
+    /// Whether the connection to us was IPv4 or IPv6, IPv4 clients of a
+    /// dual-stack listener included.
+    pub ip_version: Option<IpVersion>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    /// Whichever of `capture.headers` the request carried.
+    pub headers: BTreeMap<String, String>,
+    /// Where `client_ip` is, when `geoip` is configured and knows it.
+    #[serde(default)]
+    pub geo: Option<Geo>,
+    /// What `user_agent` says the payload ran in, if it is recognised.
+    #[serde(default)]
+    pub agent: Option<Agent>,
+    /// How the client opened TLS, when this server terminated it.
+    #[serde(default)]
+    pub tls: Option<TlsFingerprint>,
+}
+
+/// Fingerprints of the TLS ClientHello a hit's connection started with.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct TlsFingerprint {
+    /// The JA3 MD5, e.g. `cd08e31494f9531f560d64c695473da9`.
+    pub ja3: String,
+    /// e.g. `t13d1516h2_8daaf6152771_e5627efa2ab1`.
+    pub ja4: String,
+}
+
+/// What the `geoip` databases know about a client IP.
+#[derive(Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct Geo {
+    /// ISO 3166-1 code of the country, e.g. `DE`.
+    pub country: Option<String>,
+    /// The city's English name.
+    pub city: Option<String>,
+    /// The autonomous system the address belongs to.
+    pub asn: Option<u32>,
+    /// Who runs that, e.g. `Deutsche Telekom AG`.
+    pub as_org: Option<String>,
+}
+
+/// The browser, OS and kind of device a User-Agent names.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct Agent {
+    /// e.g. `Chrome`, `Firefox` or `Googlebot`.
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+    /// e.g. `Windows 10`, `Mac OSX` or `Android`.
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    pub device: Device,
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema, async_graphql::Enum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Device {
+    Desktop,
+    /// Phones and tablets.
+    Mobile,
+    /// Crawlers and scanners, including headless browsers that admit to it.
+    Bot,
+    /// Consoles, TVs and other appliances.
+    Appliance,
+    Other,
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ToSchema, async_graphql::Enum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    pub fn of(ip: IpAddr) -> IpVersion {
+        match ip.to_canonical() {
+            IpAddr::V4(_) => IpVersion::V4,
+            IpAddr::V6(_) => IpVersion::V6,
+        }
+    }
+}
+
+/// A file part of a multipart hit, e.g. a canvas screenshot or serialized DOM.
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct Attachment {
+    /// The form field it was sent as.
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    /// The SHA-256 of the content, which `GET /api/attachments/{id}` serves it
+    /// by, when `attachments.dir` keeps it as a file. `data` is empty then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// In bytes.
+    #[serde(default)]
+    pub size: u64,
+    /// A presigned download URL, in poll and history responses when the file
+    /// is kept in `attachments.s3`.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Base64 encoded in JSON.
+    #[serde(with = "base64_data")]
+    #[schema(value_type = String, format = Byte)]
+    pub data: Vec<u8>,
+    /// Where an upload was written as it arrived, instead of `data`, until it
+    /// is kept. Deleted once the last clone is dropped.
+    #[serde(skip)]
+    pub spooled: Option<Arc<TempPath>>,
+}
+
+mod base64_data {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD.decode(encoded).map_err(D::Error::custom)
+    }
+}
+
+/// Why a poll ended without a hit.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ErrorBody {
+    /// `timeout`, `evicted`, `expired`, `revoked`, `overloaded`, `shutting_down`,
+    /// `reconnect` or `internal`.
+    pub error: String,
+    pub message: String,
+}
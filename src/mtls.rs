@@ -0,0 +1,151 @@
+//! Client certificates on the TLS listeners, so pollers and admins can
+//! authenticate with a certificate issued by `tls.client_ca` instead of, or on
+//! top of, an API key. Beacons never need one.
+
+use std::{
+    convert::Infallible,
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Error};
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts, middleware::AddExtension, Extension};
+use axum_server::accept::Accept;
+use rustls::{
+    server::AllowAnyAnonymousOrAuthenticatedClient, Certificate, PrivateKey, RootCertStore,
+    ServerConfig,
+};
+use rustls_pemfile::Item;
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+
+use crate::config::TlsConfig;
+
+/// The certificate the client presented on the connection a request came over.
+#[derive(Clone, Default)]
+pub struct ClientCert {
+    pub presented: bool,
+    /// The subject's common name, if it has one.
+    pub common_name: Option<String>,
+}
+
+impl ClientCert {
+    /// The client certificate `der`, already verified during the handshake.
+    pub fn from_der(der: &[u8]) -> Self {
+        let common_name = x509_parser::parse_x509_certificate(der)
+            .ok()
+            .and_then(|(_, cert)| {
+                let name = cert.subject().iter_common_name().next()?;
+                name.as_str().ok().map(str::to_owned)
+            });
+        ClientCert {
+            presented: true,
+            common_name,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientCert {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(parts
+            .extensions
+            .get::<ClientCert>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Wraps a `RustlsAcceptor` and adds the client certificate of each connection
+/// to its requests once the handshake is done.
+#[derive(Clone)]
+pub struct ClientCerts<A>(pub A);
+
+impl<A, I, S, T> Accept<I, S> for ClientCerts<A>
+where
+    A: Accept<I, S, Stream = TlsStream<T>>,
+    A::Future: Send + 'static,
+    A::Service: Send + 'static,
+    T: Send + 'static,
+{
+    type Stream = TlsStream<T>;
+    type Service = AddExtension<A::Service, ClientCert>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(TlsStream<T>, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let accepting = self.0.accept(stream, service);
+        Box::pin(async move {
+            let (stream, service) = accepting.await?;
+            let cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(|cert| ClientCert::from_der(&cert.0))
+                .unwrap_or_default();
+            Ok((stream, Extension(cert).layer(service)))
+        })
+    }
+}
+
+/// The rustls configuration for `tls`, asking clients for a certificate when
+/// `client_ca` is set.
+pub fn server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>, Error> {
+    let chain = certs(&tls.cert)?;
+    let key = private_key(&tls.key)?;
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let mut config = match &tls.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in certs(ca)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("invalid CA certificate in {}", ca.display()))?;
+            }
+            let verifier = AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed();
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    }
+    .with_single_cert(chain, key)
+    .context("invalid tls certificate or key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn pem(path: &Path) -> Result<Vec<Item>, Error> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn certs(path: &Path) -> Result<Vec<Certificate>, Error> {
+    let certs: Vec<_> = pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        bail!("no certificate in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKey, Error> {
+    pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(der) | Item::RSAKey(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .with_context(|| format!("no private key in {}", path.display()))
+}
@@ -0,0 +1,67 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::stream;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::{auth::ApiKey, hub::Subscription, ws::Subscribe, AppState};
+
+/// How long a quiet stream waits before writing an empty line, so proxies and
+/// clients do not give up on it.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Streams every notification for `token` as newline delimited JSON, one object
+/// per line, for `curl -N` and friends.
+#[utoipa::path(
+    get,
+    path = "/stream",
+    tag = "polling",
+    params(Subscribe),
+    responses((status = 200, description = "One `Notification` per line", content_type = "application/x-ndjson")),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn stream(
+    Query(Subscribe { token }): Query<Subscribe>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<Response, StatusCode> {
+    if state.accepting_polls().is_err() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    key.check(&token)?;
+    state.check_token(&token)?;
+    let subscription = Subscription::new(&state, token);
+    let mut keep_alive = time::interval_at(time::Instant::now() + KEEP_ALIVE, KEEP_ALIVE);
+    keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let lines = stream::unfold(
+        (subscription, keep_alive),
+        |(mut subscription, mut keep_alive)| async move {
+            let line = tokio::select! {
+                notification = subscription.rx.recv() => {
+                    let mut line = serde_json::to_vec(&notification?).unwrap_or_default();
+                    line.push(b'\n');
+                    keep_alive.reset();
+                    line
+                }
+                _ = keep_alive.tick() => b"\n".to_vec(),
+            };
+            Some((
+                Ok::<_, Infallible>(Bytes::from(line)),
+                (subscription, keep_alive),
+            ))
+        },
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        StreamBody::new(lines),
+    )
+        .into_response())
+}
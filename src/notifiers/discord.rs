@@ -0,0 +1,163 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+
+use super::{truncate, Hit, Notifier};
+use crate::{
+    config::DiscordConfig,
+    matcher::{self, Pattern},
+};
+
+/// Longest value shown in an embed field, Discord refuses fields past 1024.
+const MAX_FIELD: usize = 500;
+
+/// Embed color, a warning red.
+const COLOR: u32 = 0xe0_1e_5a;
+
+/// Posts an embed per hit to a Discord webhook.
+pub struct Discord {
+    webhook: Option<Url>,
+    webhooks: Vec<(Pattern, Url)>,
+    tokens: Vec<Pattern>,
+    username: Option<String>,
+}
+
+impl Discord {
+    pub fn new(config: &DiscordConfig) -> Self {
+        let url = |url: &String| Url::parse(url).expect("validated url");
+        Discord {
+            webhook: config.webhook_url.as_ref().map(url),
+            webhooks: config
+                .webhooks
+                .iter()
+                .map(|(pattern, webhook)| (Pattern::parse(pattern), url(webhook)))
+                .collect(),
+            tokens: config.tokens.iter().map(|t| Pattern::parse(t)).collect(),
+            username: config.username.clone(),
+        }
+    }
+
+    fn webhook_for(&self, token: &str) -> Option<&Url> {
+        matcher::most_specific(&self.webhooks, token).or(self.webhook.as_ref())
+    }
+}
+
+/// Escapes the characters Discord renders as markdown.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '*' | '_' | '~' | '`' | '|' | '>' | '#' | '[' | ']' | '(' | ')' | '<' | '@'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn field(name: &str, value: Option<&str>, inline: bool) -> Value {
+    let value = value.map_or_else(
+        || "*unknown*".to_owned(),
+        |v| escape(&truncate(v, MAX_FIELD)),
+    );
+    json!({ "name": name, "value": value, "inline": inline })
+}
+
+/// The embed for `hit`, linking its attachments when `public_url` is known.
+fn message(hit: &Hit, username: Option<&str>) -> Value {
+    let notification = &hit.notification;
+    let token = match &hit.label {
+        Some(label) => format!("{label} ({})", notification.token),
+        None => notification.token.clone(),
+    };
+    let ip = notification.meta.client_ip.map(|ip| ip.to_string());
+    let mut fields = vec![
+        field("Token", Some(&token), true),
+        field("IP", ip.as_deref(), true),
+        field("URL", hit.url(), false),
+        field("User-Agent", notification.meta.user_agent.as_deref(), false),
+    ];
+    let mut image = None;
+    if !notification.attachments.is_empty() {
+        let lines: Vec<String> = notification
+            .attachments
+            .iter()
+            .enumerate()
+            .map(|(i, attachment)| {
+                let name = escape(attachment.filename.as_ref().unwrap_or(&attachment.name));
+                match hit.attachment_url(i) {
+                    Some(url) => {
+                        let is_image = attachment
+                            .content_type
+                            .as_deref()
+                            .is_some_and(|t| t.starts_with("image/"));
+                        if is_image && image.is_none() {
+                            image = Some(url.clone());
+                        }
+                        format!("[{name}]({url})")
+                    }
+                    None => name,
+                }
+            })
+            .collect();
+        fields.push(json!({
+            "name": "Attachments",
+            "value": truncate(&lines.join("\n"), 1000),
+            "inline": false,
+        }));
+    }
+    let mut embed = json!({
+        "title": format!("XSS callback #{}", notification.seq),
+        "color": COLOR,
+        "timestamp": notification.received_at.to_rfc3339(),
+        "fields": fields,
+        "footer": { "text": format!("notification {}", notification.id) },
+    });
+    if let Some(url) = image {
+        embed["image"] = json!({ "url": url });
+    }
+    let mut message = json!({
+        "embeds": [embed],
+        // Payload data must not ping anyone.
+        "allowed_mentions": { "parse": [] },
+    });
+    if let Some(username) = username {
+        message["username"] = json!(username);
+    }
+    message
+}
+
+#[async_trait]
+impl Notifier for Discord {
+    fn kind(&self) -> &'static str {
+        "discord"
+    }
+
+    fn target(&self) -> String {
+        "webhook".to_owned()
+    }
+
+    fn wants(&self, token: &str) -> bool {
+        if !self.tokens.is_empty() && !self.tokens.iter().any(|pattern| pattern.matches(token)) {
+            return false;
+        }
+        self.webhook_for(token).is_some()
+    }
+
+    async fn send(&self, client: &Client, hit: &Hit) -> Result<(), Error> {
+        let Some(webhook) = self.webhook_for(&hit.notification.token) else {
+            return Ok(());
+        };
+        client
+            .post(webhook.clone())
+            .json(&message(hit, self.username.as_deref()))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.without_url())?;
+        Ok(())
+    }
+}
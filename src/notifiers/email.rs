@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::Client;
+use tracing::warn;
+
+use super::{truncate, Hit, Notifier};
+use crate::{
+    config::{Digest, EmailConfig, SmtpSecurity},
+    matcher::Pattern,
+    AppState,
+};
+
+/// How long talking to the SMTP server may take.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the digest loop looks again while no digest is configured.
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Longest token shown in a subject line.
+const MAX_SUBJECT_TOKEN: usize = 60;
+
+type Transport = AsyncSmtpTransport<Tokio1Executor>;
+
+/// Emails every hit as it arrives.
+pub struct Email {
+    transport: Transport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    tokens: Vec<Pattern>,
+}
+
+impl Email {
+    pub fn new(config: &EmailConfig) -> Result<Self, Error> {
+        Ok(Email {
+            transport: transport(config)?,
+            from: config.from.parse()?,
+            to: recipients(config)?,
+            tokens: patterns(config),
+        })
+    }
+}
+
+fn transport(config: &EmailConfig) -> Result<Transport, Error> {
+    let mut builder = match config.security {
+        SmtpSecurity::Tls => Transport::relay(&config.host)?,
+        SmtpSecurity::Starttls => Transport::starttls_relay(&config.host)?,
+        SmtpSecurity::None => Transport::builder_dangerous(&config.host),
+    };
+    if let Some(port) = config.port {
+        builder = builder.port(port);
+    }
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    Ok(builder.timeout(Some(TIMEOUT)).build())
+}
+
+fn recipients(config: &EmailConfig) -> Result<Vec<Mailbox>, Error> {
+    Ok(config
+        .to
+        .iter()
+        .map(|to| to.parse())
+        .collect::<Result<_, _>>()?)
+}
+
+fn patterns(config: &EmailConfig) -> Vec<Pattern> {
+    config.tokens.iter().map(|t| Pattern::parse(t)).collect()
+}
+
+fn matches(tokens: &[Pattern], token: &str) -> bool {
+    tokens.is_empty() || tokens.iter().any(|pattern| pattern.matches(token))
+}
+
+/// Whether hits for `token` count towards the digest.
+pub fn digests(config: &EmailConfig, token: &str) -> bool {
+    config.digest.is_some() && matches(&patterns(config), token)
+}
+
+/// `text` on one line, whatever a payload put in it.
+fn plain(text: &str) -> String {
+    text.replace(char::is_control, " ")
+}
+
+fn message(
+    from: &Mailbox,
+    to: &[Mailbox],
+    subject: String,
+    body: String,
+) -> Result<Message, Error> {
+    let mut builder = Message::builder()
+        .from(from.clone())
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN);
+    for to in to {
+        builder = builder.to(to.clone());
+    }
+    Ok(builder.body(body)?)
+}
+
+/// The plain text alert for `hit`.
+fn alert(hit: &Hit) -> (String, String) {
+    let notification = &hit.notification;
+    let token = match &hit.label {
+        Some(label) => format!("{label} ({})", notification.token),
+        None => notification.token.clone(),
+    };
+    let ip = notification.meta.client_ip.map(|ip| ip.to_string());
+    let mut body = format!("XSS callback #{}\n\n", notification.seq);
+    let _ = writeln!(body, "Token:      {}", plain(&token));
+    let _ = writeln!(
+        body,
+        "URL:        {}",
+        plain(hit.url().unwrap_or("unknown"))
+    );
+    let _ = writeln!(body, "IP:         {}", ip.as_deref().unwrap_or("unknown"));
+    let _ = writeln!(
+        body,
+        "User-Agent: {}",
+        plain(notification.meta.user_agent.as_deref().unwrap_or("unknown"))
+    );
+    let _ = writeln!(
+        body,
+        "Received:   {}",
+        notification.received_at.to_rfc3339()
+    );
+    if !notification.attachments.is_empty() {
+        let _ = writeln!(body, "Files:      {}", notification.attachments.len());
+    }
+    if let Some(url) = hit.notification_url() {
+        let _ = writeln!(body, "\n{url}");
+    }
+    let subject = format!(
+        "XSS callback for {}",
+        plain(&truncate(&notification.token, MAX_SUBJECT_TOKEN))
+    );
+    (subject, body)
+}
+
+#[async_trait]
+impl Notifier for Email {
+    fn kind(&self) -> &'static str {
+        "email"
+    }
+
+    fn target(&self) -> String {
+        self.to
+            .iter()
+            .map(|to| to.email.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn wants(&self, token: &str) -> bool {
+        matches(&self.tokens, token)
+    }
+
+    async fn send(&self, _: &Client, hit: &Hit) -> Result<(), Error> {
+        let (subject, body) = alert(hit);
+        self.transport
+            .send(message(&self.from, &self.to, subject, body)?)
+            .await?;
+        Ok(())
+    }
+}
+
+/// When the digest after `now` is due.
+fn next_digest(digest: Digest, now: DateTime<Utc>) -> DateTime<Utc> {
+    let period = match digest {
+        Digest::Hourly => TimeDelta::hours(1),
+        Digest::Daily => TimeDelta::days(1),
+    };
+    now.duration_trunc(period).unwrap_or(now) + period
+}
+
+/// Emails the per token hit counts gathered since the last digest, at the top
+/// of every hour or day. Nothing is sent when there were no hits.
+pub async fn digest_loop(state: AppState) {
+    let mut since = Utc::now();
+    loop {
+        let digest = state.config().email.as_ref().and_then(|email| email.digest);
+        let Some(digest) = digest else {
+            tokio::time::sleep(IDLE).await;
+            continue;
+        };
+        let now = Utc::now();
+        let wait = (next_digest(digest, now) - now)
+            .to_std()
+            .unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        let counts = state.notifiers.take_digest();
+        let until = Utc::now();
+        let config = state.config();
+        let Some(email) = &config.email else {
+            continue;
+        };
+        if !counts.is_empty() {
+            let result = send_digest(&state, email, &counts, since, until).await;
+            let outcome = if result.is_ok() { "ok" } else { "error" };
+            state
+                .metrics
+                .notifier_deliveries
+                .with_label_values(&["email-digest", outcome])
+                .inc();
+            if let Err(e) = result {
+                warn!("Sending the email digest failed: {e:#}");
+            }
+        }
+        since = until;
+    }
+}
+
+async fn send_digest(
+    state: &AppState,
+    config: &EmailConfig,
+    counts: &BTreeMap<String, u64>,
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Result<(), Error> {
+    let total: u64 = counts.values().sum();
+    let mut rows: Vec<_> = counts.iter().collect();
+    rows.sort_by(|(a, m), (b, n)| n.cmp(m).then(a.cmp(b)));
+    let mut body = format!(
+        "{total} hits on {} tokens between {} and {}\n\n",
+        counts.len(),
+        since.to_rfc3339(),
+        until.to_rfc3339()
+    );
+    {
+        let tokens = state.tokens.lock().expect("");
+        for (token, count) in rows {
+            let _ = match tokens.get(token).and_then(|info| info.label.as_ref()) {
+                Some(label) => writeln!(body, "{count:>8}  {} ({})", plain(token), plain(label)),
+                None => writeln!(body, "{count:>8}  {}", plain(token)),
+            };
+        }
+    }
+    let subject = format!("XSS digest: {total} hits on {} tokens", counts.len());
+    let message = message(&config.from.parse()?, &recipients(config)?, subject, body)?;
+    transport(config)?.send(message).await?;
+    Ok(())
+}
@@ -0,0 +1,368 @@
+use std::{
+    collections::BTreeMap,
+    mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Error;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use chrono::Utc;
+use reqwest::Client;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use utoipa::ToSchema;
+
+use crate::{config::Config, model::Notification, storage::DeadLetter, telemetry, AppState};
+
+mod discord;
+mod email;
+mod opsgenie;
+mod pagerduty;
+mod slack;
+mod telegram;
+mod webhook;
+
+pub use email::digest_loop;
+
+/// How long one delivery may take before it counts as failed.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Somewhere notifications are pushed to as they arrive, whether or not anyone polls.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// What kind of notifier this is, for metrics.
+    fn kind(&self) -> &'static str;
+    /// Where it delivers to, for logs. Must not leak secrets in the URL.
+    fn target(&self) -> String;
+    /// Whether hits for `token` go to this notifier.
+    fn wants(&self, token: &str) -> bool;
+    /// The URL failed deliveries are retried against and parked under as dead
+    /// letters. Notifiers without one get a single try.
+    fn dead_letter(&self) -> Option<&str> {
+        None
+    }
+    async fn send(&self, client: &Client, hit: &Hit) -> Result<(), Error>;
+}
+
+/// A notification on its way out, with what notifiers show besides it.
+pub struct Hit {
+    pub notification: Notification,
+    /// What the token was minted for, see `POST /tokens`.
+    pub label: Option<String>,
+    /// `public_url`, which links back to the server need. Without it there are none.
+    pub base_url: Option<String>,
+}
+
+impl Hit {
+    /// The page the payload fired on, as reported by it or else the Referer.
+    pub fn url(&self) -> Option<&str> {
+        let notification = &self.notification;
+        notification
+            .data
+            .get("url")
+            .or(notification.meta.referer.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Where the stored notification can be viewed, see `history::show`.
+    pub fn notification_url(&self) -> Option<String> {
+        let base = self.base_url.as_ref()?;
+        Some(format!("{base}/n/{}", self.notification.uuid))
+    }
+
+    /// Where the `index`th attachment can be downloaded, see `history::attachment`.
+    pub fn attachment_url(&self, index: usize) -> Option<String> {
+        let base = self.base_url.as_ref()?;
+        Some(format!(
+            "{base}/n/{}/attachments/{index}",
+            self.notification.uuid
+        ))
+    }
+}
+
+/// Identifies the incident a token's hits are grouped under, so a burst of
+/// hits pages once. Hashed, as tokens can be long and are secrets of sorts.
+pub fn dedup_key(token: &str) -> String {
+    format!("xss_check_srv-{}", telemetry::token_hash(token))
+}
+
+/// Cuts `text` down to `max` characters for chat messages, marking the cut.
+pub fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+/// Builds one notifier per configured destination.
+fn build(config: &Config) -> Result<Vec<Arc<dyn Notifier>>, Error> {
+    let mut notifiers: Vec<Arc<dyn Notifier>> = config
+        .webhooks
+        .iter()
+        .map(|webhook| Arc::new(webhook::Webhook::new(webhook)) as Arc<dyn Notifier>)
+        .collect();
+    if let Some(slack) = &config.slack {
+        notifiers.push(Arc::new(slack::Slack::new(slack)));
+    }
+    if let Some(discord) = &config.discord {
+        notifiers.push(Arc::new(discord::Discord::new(discord)));
+    }
+    if let Some(telegram) = &config.telegram {
+        notifiers.push(Arc::new(telegram::Telegram::new(telegram)));
+    }
+    if let Some(email) = config.email.as_ref().filter(|email| email.per_hit) {
+        notifiers.push(Arc::new(email::Email::new(email)?));
+    }
+    if let Some(pagerduty) = &config.pagerduty {
+        notifiers.push(Arc::new(pagerduty::PagerDuty::new(pagerduty)));
+    }
+    if let Some(opsgenie) = &config.opsgenie {
+        notifiers.push(Arc::new(opsgenie::Opsgenie::new(opsgenie)));
+    }
+    Ok(notifiers)
+}
+
+/// Outcome of the deliveries so far, reported on `/readyz`.
+#[derive(Clone, Default, Serialize, ToSchema)]
+pub struct Status {
+    pub configured: usize,
+    pub delivered: u64,
+    pub failed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Forwards every accepted hit to the notifiers that want it, in the background.
+#[derive(Clone)]
+pub struct Dispatcher {
+    notifiers: Arc<ArcSwap<Vec<Arc<dyn Notifier>>>>,
+    /// Handed in by an embedding app rather than configured, kept across reloads.
+    extra: Arc<Vec<Arc<dyn Notifier>>>,
+    client: Client,
+    status: Arc<Mutex<Status>>,
+    /// Hits per token since the last email digest.
+    digest: Arc<Mutex<BTreeMap<String, u64>>>,
+    /// Cancelled on shutdown, which parks deliveries waiting for a retry.
+    stopping: CancellationToken,
+}
+
+impl Dispatcher {
+    pub fn new(config: &Config, extra: Vec<Arc<dyn Notifier>>) -> Result<Self, Error> {
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .user_agent(concat!("xss_check_srv/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        let mut notifiers = build(config)?;
+        notifiers.extend(extra.iter().cloned());
+        let status = Status {
+            configured: notifiers.len(),
+            ..Status::default()
+        };
+        Ok(Dispatcher {
+            notifiers: Arc::new(ArcSwap::from_pointee(notifiers)),
+            extra: Arc::new(extra),
+            client,
+            status: Arc::new(Mutex::new(status)),
+            digest: Arc::default(),
+            stopping: CancellationToken::new(),
+        })
+    }
+
+    /// Picks up the notifiers of a reloaded configuration, keeping the old
+    /// ones if they cannot be set up.
+    pub fn reload(&self, config: &Config) {
+        let mut notifiers = match build(config) {
+            Ok(notifiers) => notifiers,
+            Err(e) => {
+                error!("Keeping the old notifiers, setting up the new ones failed: {e:#}");
+                return;
+            }
+        };
+        notifiers.extend(self.extra.iter().cloned());
+        self.status.lock().expect("").configured = notifiers.len();
+        self.notifiers.store(Arc::new(notifiers));
+    }
+
+    /// Takes the hit counts gathered for the email digest, starting over.
+    pub fn take_digest(&self) -> BTreeMap<String, u64> {
+        mem::take(&mut *self.digest.lock().expect(""))
+    }
+
+    pub fn status(&self) -> Status {
+        self.status.lock().expect("").clone()
+    }
+
+    /// Stops waiting for retries, parking what is left as dead letters.
+    pub fn stop(&self) {
+        self.stopping.cancel();
+    }
+
+    /// The notification with what notifiers show besides it.
+    fn hit(state: &AppState, notification: Notification) -> Arc<Hit> {
+        let label = state
+            .tokens
+            .lock()
+            .expect("")
+            .get(&notification.token)
+            .and_then(|info| info.label.clone());
+        let base_url = state
+            .config()
+            .public_url
+            .as_ref()
+            .map(|url| url.trim_end_matches('/').to_owned());
+        Arc::new(Hit {
+            notification,
+            label,
+            base_url,
+        })
+    }
+
+    /// Sends `notification` to every notifier that wants it without waiting for
+    /// them. Shutdown waits for deliveries in flight and parks those waiting
+    /// for a retry.
+    pub fn forward(&self, state: &AppState, notification: &Notification) {
+        let config = state.config();
+        if let Some(email) = &config.email {
+            if email::digests(email, &notification.token) {
+                let mut digest = self.digest.lock().expect("");
+                *digest.entry(notification.token.clone()).or_default() += 1;
+            }
+        }
+        let notifiers: Vec<_> = self
+            .notifiers
+            .load()
+            .iter()
+            .filter(|notifier| notifier.wants(&notification.token))
+            .cloned()
+            .collect();
+        if notifiers.is_empty() {
+            return;
+        }
+        let hit = Dispatcher::hit(state, notification.clone());
+        for notifier in notifiers {
+            self.spawn(state, notifier, hit.clone());
+        }
+    }
+
+    /// The configured notifier parking dead letters under `url`.
+    fn parking(&self, url: &str) -> Option<Arc<dyn Notifier>> {
+        self.notifiers
+            .load()
+            .iter()
+            .find(|notifier| notifier.dead_letter() == Some(url))
+            .cloned()
+    }
+
+    /// Whether dead letters for `url` can be re-driven, i.e. its webhook is
+    /// still configured.
+    pub fn can_redrive(&self, url: &str) -> bool {
+        self.parking(url).is_some()
+    }
+
+    /// Hands a dead letter to the webhook it was meant for again, with a fresh
+    /// set of tries. Fails with the letter if that webhook is no longer configured.
+    pub fn redrive(&self, state: &AppState, letter: DeadLetter) -> Result<(), Box<DeadLetter>> {
+        let Some(notifier) = self.parking(&letter.url) else {
+            return Err(Box::new(letter));
+        };
+        self.spawn(state, notifier, Dispatcher::hit(state, letter.notification));
+        Ok(())
+    }
+
+    fn spawn(&self, state: &AppState, notifier: Arc<dyn Notifier>, hit: Arc<Hit>) {
+        let dispatcher = self.clone();
+        let state = state.clone();
+        state
+            .writes
+            .clone()
+            .spawn(async move { dispatcher.deliver(&state, &*notifier, &hit).await });
+    }
+
+    /// Sends `hit`, retrying with backoff if the notifier has a dead letter URL
+    /// and parking it there once out of tries.
+    async fn deliver(&self, state: &AppState, notifier: &dyn Notifier, hit: &Hit) {
+        let config = state.config();
+        let retry = &config.webhook_retry;
+        let attempts = match notifier.dead_letter() {
+            Some(_) => retry.attempts,
+            None => 1,
+        };
+        let notification = &hit.notification;
+        let mut attempt = 0;
+        let error = loop {
+            attempt += 1;
+            let result = notifier.send(&self.client, hit).await;
+            let outcome = if result.is_ok() { "ok" } else { "error" };
+            state
+                .metrics
+                .notifier_deliveries
+                .with_label_values(&[notifier.kind(), outcome])
+                .inc();
+            let e = {
+                let mut status = self.status.lock().expect("");
+                match result {
+                    Ok(()) => {
+                        status.delivered += 1;
+                        return;
+                    }
+                    Err(e) => {
+                        status.failed += 1;
+                        status.last_error = Some(format!("{}: {e:#}", notifier.target()));
+                        e
+                    }
+                }
+            };
+            warn!(
+                token = telemetry::token_hash(&notification.token),
+                notification.id,
+                attempt,
+                "Forwarding to {} {} failed: {e:#}",
+                notifier.kind(),
+                notifier.target()
+            );
+            if attempt >= attempts {
+                break e;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(retry.delay(attempt)) => {}
+                _ = self.stopping.cancelled() => break e,
+            }
+        };
+        let Some(url) = notifier.dead_letter() else {
+            return;
+        };
+        let letter = DeadLetter {
+            id: 0,
+            url: url.to_owned(),
+            notification: notification.clone(),
+            attempts: attempt,
+            last_error: format!("{error:#}"),
+            failed_at: Utc::now(),
+        };
+        match state.storage.park(&letter).await {
+            Ok(id) => {
+                state
+                    .metrics
+                    .notifier_deliveries
+                    .with_label_values(&[notifier.kind(), "dead_letter"])
+                    .inc();
+                info!(
+                    notification.id,
+                    dead_letter = id,
+                    "Parked the delivery to {} {} after {attempt} tries",
+                    notifier.kind(),
+                    notifier.target()
+                );
+            }
+            Err(e) => error!(
+                notification.id,
+                "Parking the delivery to {} {} failed, it is lost: {e:#}",
+                notifier.kind(),
+                notifier.target()
+            ),
+        }
+    }
+}
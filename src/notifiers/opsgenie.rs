@@ -0,0 +1,100 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+
+use super::{dedup_key, truncate, Hit, Notifier};
+use crate::{config::OpsgenieConfig, matcher::Pattern};
+
+const ALERTS: &str = "https://api.opsgenie.com/v2/alerts";
+const ALERTS_EU: &str = "https://api.eu.opsgenie.com/v2/alerts";
+
+/// Opsgenie refuses alert messages past 130 characters.
+const MAX_MESSAGE: usize = 130;
+
+/// Longest value put in the details, well below Opsgenie's 8000.
+const MAX_DETAIL: usize = 2000;
+
+/// Opens an Opsgenie alert per token. Further hits for a token with an open
+/// alert only raise its count, as they share its alias.
+pub struct Opsgenie {
+    api_key: String,
+    url: &'static str,
+    priority: Option<String>,
+    tokens: Vec<Pattern>,
+}
+
+impl Opsgenie {
+    pub fn new(config: &OpsgenieConfig) -> Self {
+        Opsgenie {
+            api_key: config.api_key.clone(),
+            url: if config.eu { ALERTS_EU } else { ALERTS },
+            priority: config.priority.clone(),
+            tokens: config.tokens.iter().map(|t| Pattern::parse(t)).collect(),
+        }
+    }
+}
+
+/// The alert for `hit`.
+fn alert(priority: Option<&str>, hit: &Hit) -> Value {
+    let notification = &hit.notification;
+    let token = match &hit.label {
+        Some(label) => format!("{label} ({})", notification.token),
+        None => notification.token.clone(),
+    };
+    let mut details = Map::new();
+    let mut detail = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            details.insert(name.to_owned(), json!(truncate(&value, MAX_DETAIL)));
+        }
+    };
+    detail("token", Some(notification.token.clone()));
+    detail("seq", Some(notification.seq.to_string()));
+    detail("notification", Some(notification.uuid.to_string()));
+    detail("url", hit.url().map(str::to_owned));
+    detail("ip", notification.meta.client_ip.map(|ip| ip.to_string()));
+    detail("user_agent", notification.meta.user_agent.clone());
+    detail("link", hit.notification_url());
+    let mut alert = json!({
+        "message": truncate(&format!("XSS callback for {token}"), MAX_MESSAGE),
+        "alias": dedup_key(&notification.token),
+        "description": format!(
+            "Callback #{} received at {}",
+            notification.seq,
+            notification.received_at.to_rfc3339()
+        ),
+        "source": "xss_check_srv",
+        "tags": ["xss_check_srv"],
+        "details": details,
+    });
+    if let Some(priority) = priority {
+        alert["priority"] = json!(priority);
+    }
+    alert
+}
+
+#[async_trait]
+impl Notifier for Opsgenie {
+    fn kind(&self) -> &'static str {
+        "opsgenie"
+    }
+
+    fn target(&self) -> String {
+        self.url.to_owned()
+    }
+
+    fn wants(&self, token: &str) -> bool {
+        self.tokens.is_empty() || self.tokens.iter().any(|pattern| pattern.matches(token))
+    }
+
+    async fn send(&self, client: &Client, hit: &Hit) -> Result<(), Error> {
+        client
+            .post(self.url)
+            .header("Authorization", format!("GenieKey {}", self.api_key))
+            .json(&alert(self.priority.as_deref(), hit))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::Error;
+use async_trait::async_trait;
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use super::{dedup_key, truncate, Hit, Notifier};
+use crate::{
+    config::{PagerDutyConfig, Severity},
+    matcher::Pattern,
+};
+
+const ENQUEUE: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// PagerDuty cuts summaries off at 1024 characters.
+const MAX_SUMMARY: usize = 1024;
+
+/// Longest value put in the custom details.
+const MAX_DETAIL: usize = 2000;
+
+/// Triggers a PagerDuty incident per token. Hits for a token already paged
+/// about are grouped into its open incident through the dedup key.
+pub struct PagerDuty {
+    routing_key: String,
+    severity: Severity,
+    tokens: Vec<Pattern>,
+}
+
+impl PagerDuty {
+    pub fn new(config: &PagerDutyConfig) -> Self {
+        PagerDuty {
+            routing_key: config.routing_key.clone(),
+            severity: config.severity,
+            tokens: config.tokens.iter().map(|t| Pattern::parse(t)).collect(),
+        }
+    }
+}
+
+/// The trigger event for `hit`.
+fn event(routing_key: &str, severity: Severity, hit: &Hit) -> Value {
+    let notification = &hit.notification;
+    let token = match &hit.label {
+        Some(label) => format!("{label} ({})", notification.token),
+        None => notification.token.clone(),
+    };
+    let detail = |value: Option<&str>| value.map(|v| truncate(v, MAX_DETAIL));
+    let mut event = json!({
+        "routing_key": routing_key,
+        "event_action": "trigger",
+        "dedup_key": dedup_key(&notification.token),
+        "payload": {
+            "summary": truncate(&format!("XSS callback for {token}"), MAX_SUMMARY),
+            "source": "xss_check_srv",
+            "severity": severity,
+            "timestamp": notification.received_at.to_rfc3339(),
+            "custom_details": {
+                "token": notification.token,
+                "label": hit.label,
+                "seq": notification.seq,
+                "notification": notification.uuid,
+                "url": detail(hit.url()),
+                "ip": notification.meta.client_ip,
+                "user_agent": detail(notification.meta.user_agent.as_deref()),
+                "attachments": notification.attachments.len(),
+            },
+        },
+    });
+    if let Some(url) = hit.notification_url() {
+        event["links"] = json!([{ "href": url, "text": "Stored notification" }]);
+    }
+    event
+}
+
+#[async_trait]
+impl Notifier for PagerDuty {
+    fn kind(&self) -> &'static str {
+        "pagerduty"
+    }
+
+    fn target(&self) -> String {
+        "events v2".to_owned()
+    }
+
+    fn wants(&self, token: &str) -> bool {
+        self.tokens.is_empty() || self.tokens.iter().any(|pattern| pattern.matches(token))
+    }
+
+    async fn send(&self, client: &Client, hit: &Hit) -> Result<(), Error> {
+        client
+            .post(ENQUEUE)
+            .json(&event(&self.routing_key, self.severity, hit))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
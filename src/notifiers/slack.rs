@@ -0,0 +1,199 @@
+use anyhow::{bail, Error};
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{truncate, Hit, Notifier};
+use crate::{
+    config::SlackConfig,
+    matcher::{self, Pattern},
+};
+
+const POST_MESSAGE: &str = "https://slack.com/api/chat.postMessage";
+
+/// Longest value shown in a message field, Slack refuses fields past 2000.
+const MAX_FIELD: usize = 500;
+
+enum Mode {
+    /// An incoming webhook, which always posts to the channel it was made for.
+    Webhook(Url),
+    /// A bot token posting with `chat.postMessage` wherever `channel_for` says.
+    Bot {
+        token: String,
+        channel: Option<String>,
+        channels: Vec<(Pattern, String)>,
+    },
+}
+
+/// Posts a message per hit to Slack.
+pub struct Slack {
+    mode: Mode,
+    tokens: Vec<Pattern>,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    error: Option<String>,
+}
+
+impl Slack {
+    pub fn new(config: &SlackConfig) -> Self {
+        let mode = match (&config.webhook_url, &config.bot_token) {
+            (Some(url), _) => Mode::Webhook(Url::parse(url).expect("validated url")),
+            (None, token) => Mode::Bot {
+                token: token.clone().expect("validated slack mode"),
+                channel: config.channel.clone(),
+                channels: config
+                    .channels
+                    .iter()
+                    .map(|(pattern, channel)| (Pattern::parse(pattern), channel.clone()))
+                    .collect(),
+            },
+        };
+        Slack {
+            mode,
+            tokens: config.tokens.iter().map(|t| Pattern::parse(t)).collect(),
+        }
+    }
+
+    /// The channel hits for `token` go to in bot mode: an exact entry of
+    /// `channels`, else the longest matching prefix, else `channel`.
+    fn channel_for<'a>(
+        token: &str,
+        channel: &'a Option<String>,
+        channels: &'a [(Pattern, String)],
+    ) -> Option<&'a str> {
+        matcher::most_specific(channels, token)
+            .or(channel.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// Escapes the characters Slack's mrkdwn treats as markup.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn field(name: &str, value: Option<&str>) -> Value {
+    let value = value.map_or_else(
+        || "_unknown_".to_owned(),
+        |v| escape(&truncate(v, MAX_FIELD)),
+    );
+    json!({ "type": "mrkdwn", "text": format!("*{name}*\n{value}") })
+}
+
+/// The message for `hit`, with a plain `text` for notifications and blocks for the channel.
+fn message(hit: &Hit) -> Value {
+    let notification = &hit.notification;
+    let token = match &hit.label {
+        Some(label) => format!("{label} ({})", notification.token),
+        None => notification.token.clone(),
+    };
+    let ip = notification.meta.client_ip.map(|ip| ip.to_string());
+    json!({
+        "text": format!("XSS callback for {}", truncate(&token, MAX_FIELD)),
+        "blocks": [
+            {
+                "type": "section",
+                "text": {
+                    "type": "mrkdwn",
+                    "text": format!(":rotating_light: *XSS callback* #{}", notification.seq),
+                },
+            },
+            {
+                "type": "section",
+                "fields": [
+                    field("Token", Some(&token)),
+                    field("URL", hit.url()),
+                    field("IP", ip.as_deref()),
+                    field("User-Agent", notification.meta.user_agent.as_deref()),
+                ],
+            },
+            {
+                "type": "context",
+                "elements": [
+                    {
+                        "type": "mrkdwn",
+                        "text": format!(
+                            "{} · notification {}",
+                            notification.received_at.to_rfc3339(),
+                            notification.id
+                        ),
+                    },
+                ],
+            },
+        ],
+    })
+}
+
+#[async_trait]
+impl Notifier for Slack {
+    fn kind(&self) -> &'static str {
+        "slack"
+    }
+
+    fn target(&self) -> String {
+        match &self.mode {
+            Mode::Webhook(_) => "incoming webhook".to_owned(),
+            Mode::Bot { .. } => "chat.postMessage".to_owned(),
+        }
+    }
+
+    fn wants(&self, token: &str) -> bool {
+        if !self.tokens.is_empty() && !self.tokens.iter().any(|pattern| pattern.matches(token)) {
+            return false;
+        }
+        match &self.mode {
+            Mode::Webhook(_) => true,
+            Mode::Bot {
+                channel, channels, ..
+            } => Slack::channel_for(token, channel, channels).is_some(),
+        }
+    }
+
+    async fn send(&self, client: &Client, hit: &Hit) -> Result<(), Error> {
+        let mut message = message(hit);
+        match &self.mode {
+            Mode::Webhook(url) => {
+                client
+                    .post(url.clone())
+                    .json(&message)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| e.without_url())?;
+            }
+            Mode::Bot {
+                token,
+                channel,
+                channels,
+            } => {
+                let Some(channel) = Slack::channel_for(&hit.notification.token, channel, channels)
+                else {
+                    return Ok(());
+                };
+                message["channel"] = json!(channel);
+                let response: ApiResponse = client
+                    .post(POST_MESSAGE)
+                    .bearer_auth(token)
+                    .json(&message)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                if !response.ok {
+                    bail!(
+                        "slack refused the message: {}",
+                        response.error.unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
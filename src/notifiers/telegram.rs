@@ -0,0 +1,144 @@
+use anyhow::{bail, Error};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{truncate, Hit, Notifier};
+use crate::{
+    config::TelegramConfig,
+    matcher::{self, Pattern},
+};
+
+const API: &str = "https://api.telegram.org";
+
+/// Longest value shown per line, messages may not exceed 4096 characters.
+const MAX_FIELD: usize = 500;
+
+/// Sends a message per hit to a Telegram chat.
+pub struct Telegram {
+    bot_token: String,
+    chat_id: Option<String>,
+    chats: Vec<(Pattern, String)>,
+    tokens: Vec<Pattern>,
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    description: Option<String>,
+}
+
+impl Telegram {
+    pub fn new(config: &TelegramConfig) -> Self {
+        Telegram {
+            bot_token: config.bot_token.clone(),
+            chat_id: config.chat_id.clone(),
+            chats: config
+                .chats
+                .iter()
+                .map(|(pattern, chat)| (Pattern::parse(pattern), chat.clone()))
+                .collect(),
+            tokens: config.tokens.iter().map(|t| Pattern::parse(t)).collect(),
+        }
+    }
+
+    fn chat_for(&self, token: &str) -> Option<&str> {
+        matcher::most_specific(&self.chats, token)
+            .or(self.chat_id.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// Escapes text for Telegram's HTML parse mode.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn line(name: &str, value: Option<&str>) -> String {
+    let value = value.map_or_else(
+        || "<i>unknown</i>".to_owned(),
+        |v| escape(&truncate(v, MAX_FIELD)),
+    );
+    format!("<b>{name}:</b> {value}")
+}
+
+/// The HTML message for `hit`, linking the stored notification when `public_url` is known.
+fn message(hit: &Hit) -> String {
+    let notification = &hit.notification;
+    let token = match &hit.label {
+        Some(label) => format!("{label} ({})", notification.token),
+        None => notification.token.clone(),
+    };
+    let ip = notification.meta.client_ip.map(|ip| ip.to_string());
+    let mut lines = vec![
+        format!("🚨 <b>XSS callback #{}</b>", notification.seq),
+        line("Token", Some(&token)),
+        line("URL", hit.url()),
+        line("IP", ip.as_deref()),
+        line("User-Agent", notification.meta.user_agent.as_deref()),
+    ];
+    if !notification.attachments.is_empty() {
+        lines.push(line(
+            "Attachments",
+            Some(&notification.attachments.len().to_string()),
+        ));
+    }
+    if let Some(url) = hit.notification_url() {
+        lines.push(format!(
+            "<a href=\"{}\">Stored notification</a>",
+            escape(&url)
+        ));
+    }
+    lines.join("\n")
+}
+
+#[async_trait]
+impl Notifier for Telegram {
+    fn kind(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn target(&self) -> String {
+        "sendMessage".to_owned()
+    }
+
+    fn wants(&self, token: &str) -> bool {
+        if !self.tokens.is_empty() && !self.tokens.iter().any(|pattern| pattern.matches(token)) {
+            return false;
+        }
+        self.chat_for(token).is_some()
+    }
+
+    async fn send(&self, client: &Client, hit: &Hit) -> Result<(), Error> {
+        let Some(chat) = self.chat_for(&hit.notification.token) else {
+            return Ok(());
+        };
+        let body = json!({
+            "chat_id": chat,
+            "text": message(hit),
+            "parse_mode": "HTML",
+            "disable_web_page_preview": true,
+        });
+        // Telegram answers errors with a JSON description, whatever the status.
+        let response: ApiResponse = client
+            .post(format!("{API}/bot{}/sendMessage", self.bot_token))
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?
+            .json()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !response.ok {
+            bail!(
+                "telegram refused the message: {}",
+                response.description.unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+}
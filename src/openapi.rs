@@ -0,0 +1,109 @@
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{
+    admin, attachments, csp, delivery, health, history, metrics, model, ndjson, notifiers,
+    payloads, sse, storage, tokens, ws,
+};
+
+/// The contract of the HTTP API, served as `/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "xss_check_srv"),
+    paths(
+        crate::notify,
+        crate::notify_post,
+        crate::notify_path,
+        crate::notify_post_path,
+        crate::beacon_gif,
+        csp::report,
+        payloads::script,
+        payloads::named,
+        crate::poll_notified,
+        crate::poll_path,
+        delivery::ack,
+        ws::subscribe,
+        sse::events,
+        ndjson::stream,
+        tokens::create,
+        tokens::list,
+        tokens::delete,
+        history::list,
+        history::delete,
+        history::show,
+        history::attachment,
+        attachments::get,
+        admin::pollers,
+        admin::kick,
+        admin::revoke,
+        admin::dead_letters,
+        admin::redrive,
+        admin::redrive_all,
+        admin::delete_dead_letter,
+        health::healthz,
+        health::readyz,
+        metrics::export,
+    ),
+    components(schemas(
+        model::Notification,
+        model::Meta,
+        model::Geo,
+        model::Agent,
+        model::Device,
+        model::TlsFingerprint,
+        model::IpVersion,
+        model::Attachment,
+        model::ErrorBody,
+        tokens::TokenInfo,
+        tokens::NewToken,
+        tokens::Created,
+        crate::config::Fanout,
+        history::History,
+        storage::DeadLetter,
+        admin::Poller,
+        admin::Pollers,
+        admin::DeadLetters,
+        admin::Redriven,
+        health::Readiness,
+        health::Check,
+        notifiers::Status,
+    )),
+    modifiers(&ApiKeys),
+    tags(
+        (name = "beacons", description = "Called by payloads on the victim's page, never authenticated"),
+        (name = "polling", description = "Waiting for hits"),
+        (name = "tokens", description = "Minting and managing tokens"),
+        (name = "history", description = "Stored hits"),
+        (name = "admin", description = "Operating the server, needs an admin key"),
+        (name = "operations", description = "Health checks and metrics"),
+    )
+)]
+struct ApiDoc;
+
+/// The `[[api_keys]]` schemes, needed by every secured route once one is configured.
+struct ApiKeys;
+
+impl Modify for ApiKeys {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// `/openapi.json` and Swagger UI at `/docs`.
+pub fn docs() -> SwaggerUi {
+    let mut doc = ApiDoc::openapi();
+    doc.info.version = env!("CARGO_PKG_VERSION").to_owned();
+    doc.info.license = None;
+    SwaggerUi::new("/docs").url("/openapi.json", doc)
+}
@@ -0,0 +1,94 @@
+//! Static pages that make the callback host look like any other web server to
+//! whoever browses it: `pages` from the config, and with `decoy` a stock
+//! landing page and `robots.txt`.
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tracing::error;
+
+use crate::Config;
+
+/// What nginx shows before anyone configured it.
+const WELCOME: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<title>Welcome to nginx!</title>
+<style>
+html { color-scheme: light dark; }
+body { width: 35em; margin: 0 auto;
+font-family: Tahoma, Verdana, Arial, sans-serif; }
+</style>
+</head>
+<body>
+<h1>Welcome to nginx!</h1>
+<p>If you see this page, the nginx web server is successfully installed and
+working. Further configuration is required.</p>
+
+<p>For online documentation and support please refer to
+<a href="http://nginx.org/">nginx.org</a>.<br/>
+Commercial support is available at
+<a href="http://nginx.com/">nginx.com</a>.</p>
+
+<p><em>Thank you for using nginx.</em></p>
+</body>
+</html>
+"#;
+
+const ROBOTS: &str = "User-agent: *\nDisallow: /\n";
+
+/// The page configured for `path`, if there is one.
+pub fn serve(config: &Config, path: &str) -> Option<Response> {
+    if let Some(page) = config.pages.iter().find(|page| page.path == path) {
+        let body = match (&page.body, &page.file) {
+            (Some(body), _) => body.clone().into_bytes(),
+            (None, Some(file)) => match std::fs::read(file) {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Failed to read page {}: {e}", file.display());
+                    return Some(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                }
+            },
+            (None, None) => unreachable!("validated page"),
+        };
+        let content_type = page
+            .content_type
+            .clone()
+            .unwrap_or_else(|| guess(path).to_owned());
+        let status = StatusCode::from_u16(page.status).expect("validated status");
+        return Some((status, [(header::CONTENT_TYPE, content_type)], body).into_response());
+    }
+    if !config.decoy {
+        return None;
+    }
+    let body = match path {
+        "/" | "/index.html" => WELCOME,
+        "/robots.txt" => ROBOTS,
+        _ => return None,
+    };
+    Some(([(header::CONTENT_TYPE, guess(path))], body).into_response())
+}
+
+/// The content type for `path` by its extension, HTML for paths without one.
+fn guess(path: &str) -> &'static str {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    match name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+    {
+        None => "text/html",
+        Some(ext) => match ext.as_str() {
+            "html" | "htm" => "text/html",
+            "txt" => "text/plain",
+            "xml" => "application/xml",
+            "json" => "application/json",
+            "css" => "text/css",
+            "js" => "text/javascript",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            "ico" => "image/x-icon",
+            _ => "application/octet-stream",
+        },
+    }
+}
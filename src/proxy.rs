@@ -0,0 +1,188 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+
+/// Works out the real client address of a request that may have passed
+/// through trusted reverse proxies.
+///
+/// The chain of `Forwarded` (or, if absent, `X-Forwarded-For`) hops is walked
+/// from the nearest one outwards, and the first address not in `trusted` is
+/// the client. Headers are ignored unless the peer itself is trusted, since
+/// anyone can send them.
+pub fn client_ip(trusted: &[IpNet], peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    // IPv4 peers of a dual-stack listener show up as `::ffff:a.b.c.d`.
+    let peer = peer.to_canonical();
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        let Some(ip) = hop else {
+            // Obfuscated or garbled hop, nothing further out can be trusted.
+            break;
+        };
+        client = ip.to_canonical();
+        if !is_trusted(&client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Hops listed by the proxies, client first. `None` marks entries that are not an address.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = headers.get_all("forwarded").iter().collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, value)| parse_node(value.trim().trim_matches('"')))
+            })
+            .collect();
+    }
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|hop| parse_node(hop.trim()))
+        .collect()
+}
+
+/// Accepts `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` and `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| node.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let spoofed = headers(&[("x-forwarded-for", "1.1.1.1")]);
+        assert_eq!(
+            client_ip(&trusted(), ip("203.0.113.9"), &spoofed),
+            ip("203.0.113.9")
+        );
+        assert_eq!(client_ip(&[], ip("10.0.0.1"), &spoofed), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn walks_x_forwarded_for_from_the_nearest_hop() {
+        let chain = headers(&[("x-forwarded-for", "1.1.1.1, 203.0.113.9, 10.0.0.2")]);
+        assert_eq!(
+            client_ip(&trusted(), ip("10.0.0.1"), &chain),
+            ip("203.0.113.9")
+        );
+        // Split over several header lines.
+        let lines = headers(&[
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(
+            client_ip(&trusted(), ip("10.0.0.1"), &lines),
+            ip("203.0.113.9")
+        );
+        // Only trusted hops, the client is the farthest one.
+        let internal = headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            client_ip(&trusted(), ip("10.0.0.1"), &internal),
+            ip("10.0.0.3")
+        );
+    }
+
+    #[test]
+    fn prefers_forwarded() {
+        let both = headers(&[
+            ("x-forwarded-for", "1.1.1.1"),
+            (
+                "forwarded",
+                "for=203.0.113.9;proto=https, For=\"[2001:db8::1]:4711\";by=10.0.0.2",
+            ),
+        ]);
+        assert_eq!(
+            client_ip(&trusted(), ip("10.0.0.1"), &both),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn stops_at_obfuscated_hops() {
+        let hidden = headers(&[("forwarded", "for=203.0.113.9, for=_hidden, for=10.0.0.2")]);
+        assert_eq!(
+            client_ip(&trusted(), ip("10.0.0.1"), &hidden),
+            ip("10.0.0.2")
+        );
+        let garbled = headers(&[("x-forwarded-for", "203.0.113.9, unknown")]);
+        assert_eq!(
+            client_ip(&trusted(), ip("10.0.0.1"), &garbled),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn unmaps_ipv4_mapped_addresses() {
+        let chain = headers(&[("x-forwarded-for", "::ffff:203.0.113.9")]);
+        let client = client_ip(&trusted(), ip("::ffff:10.0.0.1"), &chain);
+        assert_eq!(client, ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn trusts_only_inside_each_family() {
+        let trusted: Vec<IpNet> = vec![
+            "192.168.1.0/24".parse().unwrap(),
+            "2001:db8:1::/48".parse().unwrap(),
+        ];
+        let chain = headers(&[("x-forwarded-for", "203.0.113.9")]);
+        let client = |peer: &str| client_ip(&trusted, ip(peer), &chain);
+        assert_eq!(client("192.168.1.0"), ip("203.0.113.9"));
+        assert_eq!(client("192.168.1.255"), ip("203.0.113.9"));
+        assert_eq!(client("192.168.2.0"), ip("192.168.2.0"));
+        assert_eq!(client("2001:db8:1:ffff::1"), ip("203.0.113.9"));
+        assert_eq!(client("2001:db8:2::1"), ip("2001:db8:2::1"));
+        // An IPv4 net covers the mapped form of its addresses, not arbitrary IPv6 ones.
+        assert_eq!(client("::ffff:192.168.1.7"), ip("203.0.113.9"));
+        assert_eq!(client("::192.168.1.7"), ip("::192.168.1.7"));
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node("1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("1.2.3.4:80"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("[2001:db8::1]:80"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node(""), None);
+    }
+}
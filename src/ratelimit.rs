@@ -0,0 +1,232 @@
+use std::{
+    hash::Hash,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+
+use crate::{
+    config::{RateLimitConfig, TokenLimits},
+    lru::Lru,
+    proxy, AppState,
+};
+
+/// Keys tracked at most, beyond that the least recently seen is forgotten.
+const MAX_KEYS: usize = 100_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets refilling at `rate` per second up to `burst`, one per key.
+pub struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Lru<K, Bucket>>,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            rate: config.rate,
+            burst: f64::from(config.burst),
+            buckets: Mutex::new(Lru::new(MAX_KEYS)),
+        }
+    }
+
+    /// Takes one token for `key`, or says how long until the next one is available.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("");
+        let bucket = buckets.entry(key, || Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        if self.refill(bucket, now) >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+impl RateLimiter<IpAddr> {
+    /// `check` for a client IP. IPv6 clients are limited per /64, since a
+    /// single one usually has a whole prefix to pick addresses from.
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), Duration> {
+        let ip = match ip.to_canonical() {
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !(u128::MAX >> 64))),
+            ip => ip,
+        };
+        self.check(ip)
+    }
+}
+
+/// 429 with a `Retry-After` for clients that ran out of tokens.
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs_f64().ceil() as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.max(1).to_string())],
+    )
+        .into_response()
+}
+
+/// Takes a token from the `rate_limit` bucket of `ip`, returning whether there
+/// was none left. For listeners outside the HTTP router, see `per_ip` for those.
+pub fn limited(state: &AppState, ip: IpAddr) -> bool {
+    state
+        .rate_limiter
+        .load()
+        .as_ref()
+        .is_some_and(|limiter| limiter.check_ip(ip).is_err())
+}
+
+/// Middleware limiting requests per client IP, see `rate_limit`.
+pub async fn per_ip<B>(
+    State(state): State<AppState>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(limiter) = &*state.rate_limiter.load() {
+        let ip = proxy::client_ip(&state.config().trusted_proxies, source.ip(), &headers);
+        if let Err(retry_after) = limiter.check_ip(ip) {
+            return too_many_requests(retry_after);
+        }
+    }
+    next.run(request).await
+}
+
+#[derive(Default)]
+struct Usage {
+    minute: i64,
+    minute_hits: u32,
+    day: i64,
+    day_hits: u32,
+    /// Hits refused since startup.
+    throttled: u64,
+}
+
+/// Fixed-window hit quotas per token, see `token_limits`. Tracks `MAX_KEYS`
+/// tokens at most, so junk hits for made-up tokens cannot grow it without
+/// bound; the one hit least recently starts over when it comes back.
+pub struct Quotas {
+    per_minute: Option<u32>,
+    per_day: Option<u32>,
+    usage: Mutex<Lru<String, Usage>>,
+}
+
+impl Quotas {
+    pub fn new(config: &TokenLimits) -> Self {
+        Quotas {
+            per_minute: config.per_minute,
+            per_day: config.per_day,
+            usage: Mutex::new(Lru::new(MAX_KEYS)),
+        }
+    }
+
+    /// Counts a hit for `token`. Over quota the hit is refused and the token's
+    /// throttled counter returned instead.
+    pub fn check(&self, token: &str) -> Result<(), u64> {
+        let now = Utc::now().timestamp();
+        let (minute, day) = (now / 60, now / (24 * 60 * 60));
+        let mut usage = self.usage.lock().expect("");
+        let usage = usage.entry(token.to_owned(), Usage::default);
+        if usage.minute != minute {
+            usage.minute = minute;
+            usage.minute_hits = 0;
+        }
+        if usage.day != day {
+            usage.day = day;
+            usage.day_hits = 0;
+        }
+        let over = |limit: Option<u32>, hits: u32| limit.is_some_and(|limit| hits >= limit);
+        if over(self.per_minute, usage.minute_hits) || over(self.per_day, usage.day_hits) {
+            usage.throttled += 1;
+            return Err(usage.throttled);
+        }
+        usage.minute_hits += 1;
+        usage.day_hits += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn limiter(rate: f64, burst: u32) -> RateLimiter<&'static str> {
+        RateLimiter::new(&RateLimitConfig { rate, burst })
+    }
+
+    #[test]
+    fn burst_runs_out() {
+        let limiter = limiter(1.0, 3);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at("a", now), Ok(()));
+        }
+        assert_eq!(limiter.check_at("a", now), Err(Duration::from_secs(1)));
+        // Every key has a bucket of its own.
+        assert_eq!(limiter.check_at("b", now), Ok(()));
+    }
+
+    #[test]
+    fn refills_as_time_passes() {
+        let limiter = limiter(2.0, 2);
+        let now = Instant::now();
+        limiter.check_at("a", now).unwrap();
+        limiter.check_at("a", now).unwrap();
+        assert_eq!(
+            limiter.check_at("a", now + Duration::from_millis(250)),
+            Err(Duration::from_millis(250))
+        );
+        assert_eq!(
+            limiter.check_at("a", now + Duration::from_millis(500)),
+            Ok(())
+        );
+        // Never more than the burst, however long it stays quiet.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limiter.check_at("a", later), Ok(()));
+        assert_eq!(limiter.check_at("a", later), Ok(()));
+        assert!(limiter.check_at("a", later).is_err());
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            rate: 1.0,
+            burst: 1,
+        });
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(limiter.check_ip(ip("2001:db8::1")).is_ok());
+        assert!(limiter.check_ip(ip("2001:db8::ffff:1")).is_err());
+        assert!(limiter.check_ip(ip("2001:db8:0:1::1")).is_ok());
+        assert!(limiter.check_ip(IpAddr::V4(Ipv4Addr::LOCALHOST)).is_ok());
+        assert!(limiter.check_ip(ip("::ffff:127.0.0.1")).is_err());
+    }
+}
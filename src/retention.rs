@@ -0,0 +1,61 @@
+//! Deletes stored hits once they are older than `retention.days`, or than the
+//! `retention_days` their token was minted with.
+
+use std::time::Duration;
+
+use anyhow::Error;
+use chrono::{DateTime, Utc};
+use tracing::{info, warn};
+
+use crate::AppState;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Purges expired hits every hour, starting right away.
+pub async fn purge_loop(state: AppState) {
+    loop {
+        match purge(&state).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("Deleted {deleted} hits past their retention"),
+            Err(e) => warn!("Purging old hits failed: {e:#}"),
+        }
+        tokio::time::sleep(PURGE_INTERVAL).await;
+    }
+}
+
+async fn purge(state: &AppState) -> Result<u64, Error> {
+    let now = Utc::now();
+    let overrides: Vec<(String, u64)> = {
+        let tokens = state.tokens.lock().expect("");
+        tokens
+            .values()
+            .filter_map(|info| Some((info.token.clone(), info.retention_days?)))
+            .collect()
+    };
+    let mut deleted = 0;
+    for (token, days) in &overrides {
+        if let Some(before) = cutoff(now, *days) {
+            deleted += state.storage.expire(before, Some(token), &[]).await?;
+        }
+    }
+    if let Some(before) = state
+        .config()
+        .retention
+        .days
+        .and_then(|days| cutoff(now, days))
+    {
+        let except: Vec<String> = overrides.into_iter().map(|(token, _)| token).collect();
+        deleted += state.storage.expire(before, None, &except).await?;
+    }
+    Ok(deleted)
+}
+
+/// When hits kept for `days` were received at the latest to be deleted now,
+/// `None` for 0 (forever) and lifetimes too long to matter.
+fn cutoff(now: DateTime<Utc>, days: u64) -> Option<DateTime<Utc>> {
+    if days == 0 {
+        return None;
+    }
+    let days = chrono::Duration::try_days(i64::try_from(days).ok()?)?;
+    now.checked_sub_signed(days)
+}
@@ -0,0 +1,259 @@
+//! The few S3 calls attachments need, signed with AWS Signature Version 4, and
+//! presigned download URLs. Works against AWS, MinIO and other compatible stores.
+
+use std::time::Duration;
+
+use anyhow::{bail, Error};
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::CONTENT_LENGTH, Body, Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config::S3Config, tokens::hex};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+/// The SHA-256 of no bytes, for requests without a body.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+pub struct Bucket {
+    http: Client,
+    endpoint: Url,
+    region: String,
+    bucket: String,
+    prefix: String,
+    access_key: String,
+    secret_key: String,
+    path_style: bool,
+    url_expiry: u64,
+}
+
+impl Bucket {
+    pub fn new(config: &S3Config) -> Result<Self, Error> {
+        Ok(Bucket {
+            http: Client::builder().timeout(TIMEOUT).build()?,
+            endpoint: Url::parse(&config.endpoint)?,
+            region: config.region.clone(),
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.clone(),
+            access_key: config.access_key.clone(),
+            secret_key: config.secret_key.clone(),
+            path_style: config.path_style,
+            url_expiry: config.url_expiry,
+        })
+    }
+
+    /// The URL of the object `name`, under `prefix`.
+    fn url(&self, name: &str) -> Url {
+        let key = encode(&format!("{}{name}", self.prefix), false);
+        let mut url = self.endpoint.clone();
+        let base = url.path().trim_end_matches('/').to_owned();
+        if self.path_style {
+            url.set_path(&format!("{base}/{}/{key}", encode(&self.bucket, true)));
+        } else {
+            let host = format!("{}.{}", self.bucket, url.host_str().unwrap_or_default());
+            url.set_host(Some(&host)).expect("validated endpoint");
+            url.set_path(&format!("{base}/{key}"));
+        }
+        url
+    }
+
+    /// Uploads `data` as the object `name`.
+    pub async fn put(&self, name: &str, data: Vec<u8>, content_type: &str) -> Result<(), Error> {
+        let payload = hex(&Sha256::digest(&data));
+        let request = self.request(Method::PUT, name, &payload, Some(content_type));
+        check_put(request.body(data).send().await?).await
+    }
+
+    /// Uploads the `length` bytes of `body`, whose SHA-256 is `sha256`, as the
+    /// object `name` while they are read.
+    pub async fn put_stream(
+        &self,
+        name: &str,
+        body: Body,
+        sha256: &str,
+        length: u64,
+        content_type: &str,
+    ) -> Result<(), Error> {
+        // A second per MiB on top, since a big file takes a while.
+        let timeout = TIMEOUT + Duration::from_secs(length >> 20);
+        let response = self
+            .request(Method::PUT, name, sha256, Some(content_type))
+            .header(CONTENT_LENGTH, length)
+            .timeout(timeout)
+            .body(body)
+            .send()
+            .await?;
+        check_put(response).await
+    }
+
+    /// Whether the object `name` exists.
+    pub async fn exists(&self, name: &str) -> Result<bool, Error> {
+        let request = self.request(Method::HEAD, name, EMPTY_SHA256, None);
+        match request.send().await?.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            status => bail!("S3 HEAD answered {status}"),
+        }
+    }
+
+    /// The content of the object `name` as it arrives, `None` if there is no
+    /// such object.
+    pub async fn get(
+        &self,
+        name: &str,
+    ) -> Result<Option<impl Stream<Item = reqwest::Result<Bytes>>>, Error> {
+        let request = self.request(Method::GET, name, EMPTY_SHA256, None);
+        let response = request.send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(Some(response.bytes_stream())),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => bail!("S3 GET answered {status}: {}", response.text().await?),
+        }
+    }
+
+    /// A request for the object `name`, signed for a body whose SHA-256 is `payload`.
+    fn request(
+        &self,
+        method: Method,
+        name: &str,
+        payload: &str,
+        content_type: Option<&str>,
+    ) -> RequestBuilder {
+        let url = self.url(name);
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let mut headers = vec![
+            ("host", host(&url)),
+            ("x-amz-content-sha256", payload.to_owned()),
+            ("x-amz-date", amz_date),
+        ];
+        if let Some(content_type) = content_type {
+            headers.insert(0, ("content-type", content_type.to_owned()));
+        }
+        let signed: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical = format!(
+            "{method}\n{}\n\n{canonical_headers}\n{}\n{payload}",
+            url.path(),
+            signed.join(";"),
+        );
+        let signature = self.signature(now, &canonical);
+        let authorization = format!(
+            "{ALGORITHM} Credential={}/{}, SignedHeaders={}, Signature={signature}",
+            self.access_key,
+            self.scope(now),
+            signed.join(";"),
+        );
+        let mut request = self
+            .http
+            .request(method, url)
+            .header("authorization", authorization);
+        for (name, value) in headers {
+            // reqwest derives the host from the URL.
+            if name != "host" {
+                request = request.header(name, value);
+            }
+        }
+        request
+    }
+
+    /// A URL anyone can download the object `name` from for `url_expiry` seconds.
+    pub fn presign(&self, name: &str) -> String {
+        self.presign_at(name, Utc::now())
+    }
+
+    fn presign_at(&self, name: &str, now: DateTime<Utc>) -> String {
+        let mut url = self.url(name);
+        let credential = format!("{}/{}", self.access_key, self.scope(now));
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let expires = self.url_expiry.to_string();
+        // Sorted by name, as the canonical request needs them.
+        let query = [
+            ("X-Amz-Algorithm", ALGORITHM),
+            ("X-Amz-Credential", &credential),
+            ("X-Amz-Date", &amz_date),
+            ("X-Amz-Expires", &expires),
+            ("X-Amz-SignedHeaders", "host"),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{name}={}", encode(value, true)))
+        .collect::<Vec<_>>()
+        .join("&");
+        let canonical = format!(
+            "GET\n{}\n{query}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            url.path(),
+            host(&url),
+        );
+        let signature = self.signature(now, &canonical);
+        url.set_query(Some(&format!("{query}&X-Amz-Signature={signature}")));
+        url.to_string()
+    }
+
+    fn scope(&self, now: DateTime<Utc>) -> String {
+        format!("{}/{}/s3/aws4_request", now.format("%Y%m%d"), self.region)
+    }
+
+    fn signature(&self, now: DateTime<Utc>, canonical: &str) -> String {
+        let to_sign = format!(
+            "{ALGORITHM}\n{}\n{}\n{}",
+            now.format("%Y%m%dT%H%M%SZ"),
+            self.scope(now),
+            hex(&Sha256::digest(canonical.as_bytes())),
+        );
+        let secret = format!("AWS4{}", self.secret_key);
+        let key = [
+            now.format("%Y%m%d").to_string().as_str(),
+            &self.region,
+            "s3",
+            "aws4_request",
+        ]
+        .iter()
+        .fold(secret.into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+        hex(&hmac(&key, to_sign.as_bytes()))
+    }
+}
+
+async fn check_put(response: Response) -> Result<(), Error> {
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => bail!("S3 PUT answered {status}: {}", response.text().await?),
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The host as signed, with the port unless it is the scheme's default.
+fn host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, and `/` unless `slash`.
+fn encode(text: &str, slash: bool) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if !slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
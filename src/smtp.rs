@@ -0,0 +1,516 @@
+//! A minimal SMTP server for `<token>@<smtp.domain>`, for payloads that can
+//! only get a target to send mail: header injection in a contact form, or SSRF
+//! speaking SMTP through `gopher://`. Each message becomes a hit for every
+//! token it was addressed to, with the envelope and headers as fields. The body
+//! is discarded unless `smtp.keep_body` keeps the message as an attachment.
+//! There is no STARTTLS or AUTH, and mail for anyone else is refused. Clients
+//! are held to `rate_limit` per connection and per message like `/notify`
+//! hits, and sent away after `MAX_REJECTED` unknown recipients.
+
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use anyhow::{bail, Error};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    accept,
+    model::{Attachment, IpVersion, Meta, Payload},
+    ratelimit::limited,
+    AppState,
+};
+
+/// Longest line of a command or a message.
+const MAX_LINE: u64 = 64 * 1024;
+/// How long the client may take to send a line.
+const IDLE: Duration = Duration::from_secs(60);
+/// Recipients a single message may have.
+const MAX_RECIPIENTS: usize = 100;
+/// Unknown recipients after which a client guessing tokens is sent away.
+const MAX_REJECTED: usize = 10;
+/// Sent before closing the connection to a client over `rate_limit`.
+const RATE_LIMITED: &[u8] = b"421 4.7.0 Too many messages, try again later\r\n";
+
+/// Serves `smtp.bind` until `shutdown` resolves. Returns right away when `smtp`
+/// is not configured.
+pub async fn serve(state: AppState, shutdown: impl Future<Output = ()>) -> Result<(), Error> {
+    let config = state.config();
+    let Some(smtp) = &config.smtp else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(smtp.bind).await?;
+    info!("SMTP listening on {}", smtp.bind);
+    tokio::select! {
+        () = listen(state.clone(), listener) => {}
+        () = shutdown => {}
+    }
+    Ok(())
+}
+
+async fn listen(state: AppState, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, source)) => {
+                task::spawn(session(state.clone(), stream, source));
+            }
+            Err(e) => {
+                warn!("Failed to accept an SMTP connection: {e}");
+                // Usually out of file descriptors, give some a chance to close.
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// What the client said so far in the current transaction.
+#[derive(Default)]
+struct Session {
+    helo: Option<String>,
+    /// Empty for the null sender `<>`.
+    mail_from: Option<String>,
+    /// Every accepted recipient as given.
+    rcpt_to: Vec<String>,
+    /// The token of each accepted recipient with the tag after its `+`, once
+    /// per token.
+    tokens: Vec<(String, Option<String>)>,
+}
+
+impl Session {
+    /// Forgets the transaction, but not the greeting.
+    fn reset(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+        self.tokens.clear();
+    }
+}
+
+/// A message sent with `DATA`.
+struct Message {
+    headers: String,
+    /// In bytes, after undoing dot-stuffing.
+    size: usize,
+    /// Whether it was over `smtp.max_size`, and its body discarded.
+    truncated: bool,
+    /// The whole message, when `smtp.keep_body` is set and it was not truncated.
+    raw: Vec<u8>,
+}
+
+async fn session(state: AppState, stream: TcpStream, source: SocketAddr) {
+    let mut session = Session::default();
+    if let Err(e) = converse(&state, stream, source, &mut session).await {
+        debug!("SMTP session with {source} ended: {e:#}");
+    }
+    // Recipients but no message, as when an SSRF cannot get past `DATA`.
+    if !session.tokens.is_empty() {
+        record(&state, source, &session, None).await;
+    }
+}
+
+async fn converse(
+    state: &AppState,
+    stream: TcpStream,
+    source: SocketAddr,
+    session: &mut Session,
+) -> Result<(), Error> {
+    let config = state.config();
+    let Some(smtp) = &config.smtp else {
+        return Ok(());
+    };
+    let domain = smtp.domain(&config).unwrap_or_default();
+    let hostname = smtp.hostname.clone().unwrap_or_else(|| domain.clone());
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    if limited(state, source.ip()) {
+        writer.write_all(RATE_LIMITED).await?;
+        return Ok(());
+    }
+    writer
+        .write_all(format!("220 {hostname} ESMTP\r\n").as_bytes())
+        .await?;
+    let mut rejected = 0;
+    loop {
+        let Some(line) = read_line(&mut reader).await? else {
+            return Ok(());
+        };
+        let line = String::from_utf8_lossy(&line);
+        let (verb, arg) = line.split_once(' ').unwrap_or((line.as_ref(), ""));
+        let reply: String = match verb.to_ascii_uppercase().as_str() {
+            "HELO" | "EHLO" => {
+                session.reset();
+                session.helo = Some(arg.trim().to_owned());
+                if verb.eq_ignore_ascii_case("EHLO") {
+                    format!(
+                        "250-{hostname}\r\n250-SIZE {}\r\n250 8BITMIME",
+                        smtp.max_size
+                    )
+                } else {
+                    format!("250 {hostname}")
+                }
+            }
+            "MAIL" => match path(arg, "FROM:") {
+                Some(_) if limited(state, source.ip()) => {
+                    writer.write_all(RATE_LIMITED).await?;
+                    return Ok(());
+                }
+                Some(from) => {
+                    session.reset();
+                    session.mail_from = Some(from);
+                    "250 2.1.0 Ok".into()
+                }
+                None => "501 5.5.4 Syntax: MAIL FROM:<address>".into(),
+            },
+            "RCPT" => match path(arg, "TO:") {
+                None => "501 5.5.4 Syntax: RCPT TO:<address>".into(),
+                Some(_) if session.rcpt_to.len() >= MAX_RECIPIENTS => {
+                    "452 4.5.3 Too many recipients".into()
+                }
+                Some(address) => match recipient(&address, &domain) {
+                    Some((token, tag)) if state.check_token(&token).is_ok() => {
+                        if !session.tokens.iter().any(|(known, _)| *known == token) {
+                            session.tokens.push((token, tag));
+                        }
+                        session.rcpt_to.push(address);
+                        "250 2.1.5 Ok".into()
+                    }
+                    _ => {
+                        rejected += 1;
+                        if rejected >= MAX_REJECTED {
+                            writer
+                                .write_all(b"421 4.7.0 Too many unknown recipients\r\n")
+                                .await?;
+                            return Ok(());
+                        }
+                        "550 5.1.1 No such user".into()
+                    }
+                },
+            },
+            "DATA" if session.tokens.is_empty() => "503 5.5.1 No valid recipients".into(),
+            "DATA" => {
+                writer
+                    .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                    .await?;
+                let message = read_message(&mut reader, smtp.max_size, smtp.keep_body).await?;
+                let reply = record(state, source, session, Some(&message)).await;
+                session.reset();
+                reply.into()
+            }
+            "RSET" => {
+                session.reset();
+                "250 2.0.0 Ok".into()
+            }
+            "NOOP" => "250 2.0.0 Ok".into(),
+            "VRFY" => "252 2.5.2 Cannot verify".into(),
+            "QUIT" => {
+                writer.write_all(b"221 2.0.0 Bye\r\n").await?;
+                return Ok(());
+            }
+            _ => "502 5.5.1 Not implemented".into(),
+        };
+        writer.write_all(format!("{reply}\r\n").as_bytes()).await?;
+    }
+}
+
+/// The next line without its line ending, `None` once the client is gone.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Option<Vec<u8>>, Error> {
+    let mut line = Vec::new();
+    let mut limited = reader.take(MAX_LINE);
+    let read = time::timeout(IDLE, limited.read_until(b'\n', &mut line)).await??;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") && read as u64 == MAX_LINE {
+        bail!("line over {MAX_LINE} bytes");
+    }
+    while line.ends_with(b"\n") || line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// Reads a message up to the line with the lone dot. Past `max_size` only the
+/// size is still counted.
+async fn read_message(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    max_size: usize,
+    keep_body: bool,
+) -> Result<Message, Error> {
+    let mut headers = Vec::new();
+    let mut in_headers = true;
+    let mut raw = Vec::new();
+    let mut size = 0;
+    loop {
+        let Some(line) = read_line(reader).await? else {
+            bail!("connection closed before the end of the message");
+        };
+        if line == b"." {
+            break;
+        }
+        let line = line.strip_prefix(b".").unwrap_or(&line);
+        size += line.len() + 2;
+        if size > max_size {
+            continue;
+        }
+        if in_headers {
+            if line.is_empty() {
+                in_headers = false;
+            } else {
+                headers.extend_from_slice(line);
+                headers.extend_from_slice(b"\r\n");
+            }
+        }
+        if keep_body {
+            raw.extend_from_slice(line);
+            raw.extend_from_slice(b"\r\n");
+        }
+    }
+    let truncated = size > max_size;
+    if truncated {
+        raw.clear();
+    }
+    Ok(Message {
+        headers: String::from_utf8_lossy(&headers).into_owned(),
+        size,
+        truncated,
+        raw,
+    })
+}
+
+/// Records the transaction as a hit for each of its tokens, and answers the
+/// end of `DATA` with how that went. For tokens minted with a `secret` the tag
+/// of the address is taken as the secret, like `s=` on `/notify`.
+async fn record(
+    state: &AppState,
+    source: SocketAddr,
+    session: &Session,
+    message: Option<&Message>,
+) -> &'static str {
+    let config = state.config();
+    let keep_body = config.smtp.as_ref().is_some_and(|smtp| smtp.keep_body);
+    let max_attachment = config
+        .attachments
+        .as_ref()
+        .map_or(usize::MAX, |attachments| attachments.max_size);
+    let source = SocketAddr::new(source.ip().to_canonical(), source.port());
+    let (mut recorded, mut failed) = (false, false);
+    for (token, tag) in &session.tokens {
+        let mut data = Payload::new();
+        data.insert("via".to_owned(), "smtp".to_owned());
+        if let Some(helo) = &session.helo {
+            data.insert("helo".to_owned(), helo.clone());
+        }
+        if let Some(from) = &session.mail_from {
+            data.insert("mail_from".to_owned(), from.clone());
+        }
+        data.insert("rcpt_to".to_owned(), session.rcpt_to.join(", "));
+        if let Some(tag) = tag {
+            let has_secret = state
+                .tokens
+                .lock()
+                .expect("")
+                .get(token)
+                .is_some_and(|info| info.secret.is_some());
+            let key = if has_secret { "s" } else { "tag" };
+            data.insert(key.to_owned(), tag.clone());
+        }
+        let mut attachments = Vec::new();
+        if let Some(message) = message {
+            let headers = clip(&message.headers, config.limits.max_value_len);
+            if let Some(subject) = subject(headers) {
+                data.insert("subject".to_owned(), subject);
+            }
+            data.insert("headers".to_owned(), headers.to_owned());
+            data.insert("size".to_owned(), message.size.to_string());
+            let truncated = message.truncated || message.raw.len() > max_attachment;
+            if truncated {
+                data.insert("truncated".to_owned(), "true".to_owned());
+            } else if keep_body {
+                attachments.push(Attachment {
+                    name: "message".to_owned(),
+                    filename: Some("message.eml".to_owned()),
+                    content_type: Some("message/rfc822".to_owned()),
+                    id: None,
+                    size: message.raw.len() as u64,
+                    url: None,
+                    data: message.raw.clone(),
+                    spooled: None,
+                });
+            }
+        }
+        let meta = Meta {
+            remote_addr: Some(source),
+            client_ip: Some(source.ip()),
+            ip_version: Some(IpVersion::of(source.ip())),
+            ..Meta::default()
+        };
+        match accept(state, token.clone(), data, attachments, meta).await {
+            Ok(accepted) if accepted.status.is_success() => recorded = true,
+            Ok(accepted) => debug!("SMTP hit refused with {}", accepted.status),
+            Err(e) => {
+                error!("Failed to record an SMTP hit: {e:#}");
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        "451 4.3.0 Could not record the message"
+    } else if recorded {
+        "250 2.0.0 Ok"
+    } else {
+        "550 5.7.1 Rejected"
+    }
+}
+
+/// The address in an argument like `FROM:<address> SIZE=1234`, `prefix`
+/// being `FROM:`.
+fn path(arg: &str, prefix: &str) -> Option<String> {
+    let arg = arg.trim_start();
+    if !arg.get(..prefix.len())?.eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = arg[prefix.len()..].trim_start();
+    let address = match rest.strip_prefix('<') {
+        Some(rest) => rest.split_once('>')?.0,
+        None => rest.split_whitespace().next()?,
+    };
+    // Source routes like `@relay:user@example.com` are to be ignored.
+    let address = match address.strip_prefix('@') {
+        Some(route) => route.split_once(':')?.1,
+        None => address,
+    };
+    Some(address.to_owned())
+}
+
+/// The token and `+` tag of `address`, if it is at `domain`.
+fn recipient(address: &str, domain: &str) -> Option<(String, Option<String>)> {
+    let (local, at) = address.rsplit_once('@')?;
+    if !at.trim_end_matches('.').eq_ignore_ascii_case(domain) {
+        return None;
+    }
+    let local = local.trim_matches('"');
+    let (token, tag) = match local.split_once('+') {
+        Some((token, tag)) => (token, Some(tag.to_owned())),
+        None => (local, None),
+    };
+    (!token.is_empty()).then(|| (token.to_owned(), tag))
+}
+
+/// The `Subject` header, continuation lines unfolded.
+fn subject(headers: &str) -> Option<String> {
+    let mut lines = headers.split("\r\n");
+    let mut subject = lines.by_ref().find_map(|line| {
+        let name = line.get(..8)?;
+        name.eq_ignore_ascii_case("subject:")
+            .then(|| line[8..].trim().to_owned())
+    })?;
+    for line in lines.take_while(|line| line.starts_with([' ', '\t'])) {
+        subject.push(' ');
+        subject.push_str(line.trim());
+    }
+    Some(subject)
+}
+
+/// At most `max` bytes of `text`, cut at a character boundary.
+fn clip(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_lines() {
+        let mut input: &[u8] = b"HELO a\r\nNOOP\nlast";
+        assert_eq!(read_line(&mut input).await.unwrap().unwrap(), b"HELO a");
+        assert_eq!(read_line(&mut input).await.unwrap().unwrap(), b"NOOP");
+        assert_eq!(read_line(&mut input).await.unwrap().unwrap(), b"last");
+        assert!(read_line(&mut input).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_long_lines() {
+        let mut longest = vec![b'a'; MAX_LINE as usize - 2];
+        longest.extend(b"\r\n");
+        let mut input = &longest[..];
+        assert_eq!(
+            read_line(&mut input).await.unwrap().unwrap().len(),
+            longest.len() - 2
+        );
+        let too_long = vec![b'a'; MAX_LINE as usize + 10];
+        let mut input = &too_long[..];
+        assert!(read_line(&mut input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_messages() {
+        let mut input: &[u8] = b"Subject: hi\r\nTo: a@b\r\n\r\n..leading dot\r\n.\r\nQUIT\r\n";
+        let message = read_message(&mut input, 1024, true).await.unwrap();
+        assert_eq!(message.headers, "Subject: hi\r\nTo: a@b\r\n");
+        assert_eq!(
+            message.raw,
+            b"Subject: hi\r\nTo: a@b\r\n\r\n.leading dot\r\n"
+        );
+        assert_eq!(message.size, message.raw.len());
+        assert!(!message.truncated);
+        // The terminator is consumed, the next command is not.
+        assert_eq!(input, b"QUIT\r\n");
+    }
+
+    #[tokio::test]
+    async fn truncates_large_messages() {
+        let mut input: &[u8] = b"Subject: hi\r\n\r\nbody\r\nmore body\r\n.\r\n";
+        let message = read_message(&mut input, 20, true).await.unwrap();
+        assert_eq!(message.headers, "Subject: hi\r\n");
+        assert_eq!(message.size, 32);
+        assert!(message.truncated);
+        assert!(message.raw.is_empty());
+        let mut input: &[u8] = b"Subject: hi\r\n\r\nbody\r\n";
+        assert!(read_message(&mut input, 1024, false).await.is_err());
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(path("FROM:<a@b> SIZE=12", "FROM:").unwrap(), "a@b");
+        assert_eq!(path("from: a@b", "FROM:").unwrap(), "a@b");
+        assert_eq!(path("FROM:<>", "FROM:").unwrap(), "");
+        assert_eq!(path("TO:<@relay:x@y>", "TO:").unwrap(), "x@y");
+        assert!(path("TO:<a@b", "TO:").is_none());
+        assert!(path("FROM:<a@b>", "TO:").is_none());
+    }
+
+    #[test]
+    fn finds_recipients() {
+        assert_eq!(
+            recipient("abcd+tag@XSS.example.", "xss.example").unwrap(),
+            ("abcd".to_owned(), Some("tag".to_owned()))
+        );
+        assert_eq!(
+            recipient("\"abcd\"@xss.example", "xss.example").unwrap(),
+            ("abcd".to_owned(), None)
+        );
+        assert!(recipient("abcd@elsewhere.example", "xss.example").is_none());
+        assert!(recipient("+tag@xss.example", "xss.example").is_none());
+    }
+
+    #[test]
+    fn unfolds_subjects() {
+        let headers = "From: a@b\r\nSUBJECT: one\r\n\ttwo\r\nTo: c@d\r\n";
+        assert_eq!(subject(headers).unwrap(), "one two");
+        assert!(subject("From: a@b\r\n").is_none());
+    }
+
+    #[test]
+    fn clips_at_character_boundaries() {
+        assert_eq!(clip("héllo", 2), "h");
+        assert_eq!(clip("héllo", 3), "hé");
+        assert_eq!(clip("abc", 10), "abc");
+    }
+}
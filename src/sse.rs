@@ -0,0 +1,104 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures::{stream, Stream};
+use tracing::warn;
+
+use crate::{auth::ApiKey, hub::Subscription, model::Notification, ws::Subscribe, AppState};
+
+/// How long browsers should wait before reconnecting a dropped stream.
+const RETRY: Duration = Duration::from_secs(3);
+/// Stored notifications a reconnecting stream is sent at most before it
+/// continues with new ones.
+const MAX_REPLAY: usize = 1000;
+
+/// Where a stream is at.
+struct Cursor {
+    state: AppState,
+    token: String,
+    subscription: Subscription,
+    /// The `seq` of the last notification sent, after `Last-Event-ID`.
+    last: Option<u64>,
+    /// Stored notifications still to be replayed at most.
+    replay: usize,
+}
+
+impl Cursor {
+    /// The next notification to send: first the stored ones after `last`, then
+    /// buffered and new ones, skipping those already sent while replaying.
+    async fn next(&mut self) -> Option<Notification> {
+        if let (Some(last), 1..) = (self.last, self.replay) {
+            match self.state.storage.after(&self.token, last).await {
+                Ok(Some(notification)) => {
+                    self.replay -= 1;
+                    self.last = Some(notification.seq);
+                    return Some(notification);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to replay notifications for {}: {e:#}", self.token),
+            }
+            self.replay = 0;
+        }
+        loop {
+            let notification = self.subscription.rx.recv().await?;
+            if self.last.is_some_and(|last| notification.seq <= last) {
+                continue;
+            }
+            self.last = Some(notification.seq);
+            return Some(notification);
+        }
+    }
+}
+
+/// Pushes every notification for `token` as a server-sent event, its `seq` as
+/// the event id. A client reconnecting with `Last-Event-ID` is first sent the
+/// stored notifications it missed.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "polling",
+    params(
+        Subscribe,
+        ("Last-Event-ID" = Option<u64>, Header, description = "The `seq` of the last notification received, sent by browsers on reconnect"),
+    ),
+    responses((status = 200, description = "Server-sent events, one `Notification` each", content_type = "text/event-stream")),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn events(
+    Query(Subscribe { token }): Query<Subscribe>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    key: ApiKey,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    if state.accepting_polls().is_err() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    key.check(&token)?;
+    state.check_token(&token)?;
+    let last = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    // Subscribed before replaying, so nothing arriving meanwhile is missed.
+    let cursor = Cursor {
+        subscription: Subscription::new(&state, token.clone()),
+        state,
+        token,
+        last,
+        replay: MAX_REPLAY,
+    };
+    let events = stream::unfold(cursor, |mut cursor| async move {
+        let notification = cursor.next().await?;
+        let event = Event::default()
+            .id(notification.seq.to_string())
+            .retry(RETRY)
+            .json_data(&notification)
+            .unwrap_or_default();
+        Some((Ok(event), cursor))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,445 @@
+use std::{collections::HashMap, sync::Arc};
+
+use aes_gcm::{
+    aead::{
+        generic_array::GenericArray,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, OsRng, Payload as Aad,
+    },
+    AeadCore, Aes256Gcm, KeyInit,
+};
+use anyhow::{anyhow, bail, Context, Error};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+use super::{DeadLetter, HistoryFilter, Stats, Storage};
+use crate::{
+    config::EncryptionConfig,
+    model::{Meta, Notification, Payload},
+    tokens::TokenInfo,
+};
+
+/// The only field of a sealed notification's `data`.
+const SEALED: &str = "$sealed";
+const NONCE_LEN: usize = 12;
+/// Starts every sealed file, so files kept before encryption was turned on
+/// are still served as they are.
+const FILE_MAGIC: &[u8; 4] = b"XSE1";
+/// The nonce prefix of a sealed file, the STREAM counter taking up the rest.
+const PREFIX_LEN: usize = 7;
+const FILE_HEADER_LEN: usize = FILE_MAGIC.len() + 4 + PREFIX_LEN;
+/// Plaintext bytes per sealed segment of a file.
+const SEGMENT: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// A key and the four bytes of its SHA-256 that mark what it sealed.
+struct Key {
+    id: [u8; 4],
+    cipher: Aes256Gcm,
+}
+
+impl Key {
+    fn new(key: [u8; 32]) -> Self {
+        let mut id = [0; 4];
+        id.copy_from_slice(&Sha256::digest(key)[..4]);
+        Key {
+            id,
+            cipher: Aes256Gcm::new(&key.into()),
+        }
+    }
+}
+
+/// The keys of `encryption`, the current one first.
+pub struct Keys(Vec<Key>);
+
+impl Keys {
+    /// Decodes the keys, running `key_command` for the current one if set.
+    pub async fn load(config: &EncryptionConfig) -> Result<Keys, Error> {
+        let current = match (&config.key, &config.key_command) {
+            (Some(key), _) => key.clone(),
+            (None, Some(command)) => {
+                let output = tokio::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .output()
+                    .await
+                    .context("failed to run encryption.key_command")?;
+                if !output.status.success() {
+                    bail!(
+                        "encryption.key_command failed with {}: {}",
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                }
+                String::from_utf8(output.stdout).context("encryption.key_command printed no key")?
+            }
+            (None, None) => unreachable!("validated encryption"),
+        };
+        let keys = std::iter::once(&current)
+            .chain(&config.previous_keys)
+            .map(|key| {
+                EncryptionConfig::decode(key)
+                    .map(Key::new)
+                    .ok_or_else(|| anyhow!("encryption keys must be 32 bytes, base64 encoded"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Keys(keys))
+    }
+
+    /// `plain` as key id, nonce and ciphertext, bound to `aad`.
+    fn seal(&self, plain: &[u8], aad: &[u8]) -> Vec<u8> {
+        let key = &self.0[0];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = key
+            .cipher
+            .encrypt(&nonce, Aad { msg: plain, aad })
+            .expect("plaintext within AES-GCM limits");
+        [&key.id[..], &nonce, &ciphertext].concat()
+    }
+
+    fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < 4 + NONCE_LEN {
+            bail!("sealed data is truncated");
+        }
+        let (id, rest) = sealed.split_at(4);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = self
+            .0
+            .iter()
+            .find(|key| key.id[..] == *id)
+            .context("sealed with a key not in encryption")?;
+        key.cipher
+            .decrypt(
+                nonce.into(),
+                Aad {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow!("sealed data does not decrypt"))
+    }
+}
+
+impl Keys {
+    /// How long `seal_file` makes a file of `size` bytes.
+    pub fn sealed_len(size: u64) -> u64 {
+        let segments = size.div_ceil(SEGMENT as u64).max(1);
+        (FILE_HEADER_LEN as u64) + size + segments * TAG_LEN as u64
+    }
+
+    /// Copies `plain` to `sealed` under the current key, in segments bound to
+    /// `aad` and to their position (the STREAM construction), so files of any
+    /// size are sealed without holding them in memory and cannot be truncated
+    /// or reordered unnoticed.
+    pub async fn seal_file(
+        &self,
+        mut plain: impl AsyncRead + Unpin,
+        mut sealed: impl AsyncWrite + Unpin,
+        aad: &[u8],
+    ) -> Result<(), Error> {
+        let key = &self.0[0];
+        let mut prefix = [0; PREFIX_LEN];
+        OsRng.fill_bytes(&mut prefix);
+        let mut encryptor =
+            EncryptorBE32::from_aead(key.cipher.clone(), GenericArray::from_slice(&prefix));
+        sealed.write_all(FILE_MAGIC).await?;
+        sealed.write_all(&key.id).await?;
+        sealed.write_all(&prefix).await?;
+        let seal_failed = |_| anyhow!("file too large to seal");
+        let mut segment = read_up_to(&mut plain, SEGMENT).await?;
+        loop {
+            let next = read_up_to(&mut plain, SEGMENT).await?;
+            if next.is_empty() {
+                let payload = Aad { msg: &segment, aad };
+                sealed
+                    .write_all(&encryptor.encrypt_last(payload).map_err(seal_failed)?)
+                    .await?;
+                break;
+            }
+            let payload = Aad { msg: &segment, aad };
+            sealed
+                .write_all(&encryptor.encrypt_next(payload).map_err(seal_failed)?)
+                .await?;
+            segment = next;
+        }
+        sealed.flush().await?;
+        Ok(())
+    }
+
+    /// Undoes `seal_file` a segment at a time while `sealed` is read. Files
+    /// without the header are passed on as they are.
+    pub fn open_file<R>(
+        self: Arc<Self>,
+        sealed: R,
+        aad: Vec<u8>,
+    ) -> impl Stream<Item = Result<Bytes, Error>> + Send + 'static
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        stream::try_unfold(Opening::Header(sealed), move |opening| {
+            let keys = self.clone();
+            let aad = aad.clone();
+            async move { keys.open_next(opening, &aad).await }
+        })
+    }
+
+    async fn open_next<R: AsyncRead + Unpin>(
+        &self,
+        opening: Opening<R>,
+        aad: &[u8],
+    ) -> Result<Option<(Bytes, Opening<R>)>, Error> {
+        let sealed_segment = SEGMENT + TAG_LEN;
+        let (mut sealed, mut decryptor, segment) = match opening {
+            Opening::Done => return Ok(None),
+            Opening::Plain(mut plain) => {
+                let chunk = read_up_to(&mut plain, SEGMENT).await?;
+                if chunk.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some((chunk.into(), Opening::Plain(plain))));
+            }
+            Opening::Header(mut sealed) => {
+                let header = read_up_to(&mut sealed, FILE_HEADER_LEN).await?;
+                if header.len() < FILE_HEADER_LEN || !header.starts_with(FILE_MAGIC) {
+                    return Ok(Some((header.into(), Opening::Plain(sealed))));
+                }
+                let (id, prefix) = header[FILE_MAGIC.len()..].split_at(4);
+                let key = self
+                    .0
+                    .iter()
+                    .find(|key| key.id[..] == *id)
+                    .context("file sealed with a key not in encryption")?;
+                let decryptor =
+                    DecryptorBE32::from_aead(key.cipher.clone(), GenericArray::from_slice(prefix));
+                let segment = read_up_to(&mut sealed, sealed_segment).await?;
+                (sealed, decryptor, segment)
+            }
+            Opening::Segment(sealed, decryptor, segment) => (sealed, *decryptor, segment),
+        };
+        let next = read_up_to(&mut sealed, sealed_segment).await?;
+        let payload = Aad { msg: &segment, aad };
+        let open_failed = |_| anyhow!("sealed file does not decrypt");
+        if next.is_empty() {
+            let plain = decryptor.decrypt_last(payload).map_err(open_failed)?;
+            return Ok(Some((plain.into(), Opening::Done)));
+        }
+        let plain = decryptor.decrypt_next(payload).map_err(open_failed)?;
+        Ok(Some((
+            plain.into(),
+            Opening::Segment(sealed, Box::new(decryptor), next),
+        )))
+    }
+}
+
+/// How far `Keys::open_file` got.
+enum Opening<R> {
+    Header(R),
+    /// Kept before encryption was turned on.
+    Plain(R),
+    Segment(R, Box<DecryptorBE32<Aes256Gcm>>, Vec<u8>),
+    Done,
+}
+
+/// Up to `len` bytes, fewer only at the end of `reader`.
+async fn read_up_to(reader: &mut (impl AsyncRead + Unpin), len: usize) -> Result<Vec<u8>, Error> {
+    let mut read = Vec::with_capacity(len);
+    (&mut *reader)
+        .take(len as u64)
+        .read_to_end(&mut read)
+        .await?;
+    Ok(read)
+}
+
+/// What a sealed notification keeps in `data`.
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    data: Payload,
+    meta: Meta,
+}
+
+/// Wraps another backend, sealing the fields, metadata and inline attachment
+/// data of each notification with AES-256-GCM before it is stored, bound to
+/// its uuid. Only the client IP stays readable, for the history's `ip` filter
+/// and stats. Notifications stored before encryption was turned on are
+/// returned as they are.
+pub struct Encrypted {
+    inner: Arc<dyn Storage>,
+    keys: Arc<Keys>,
+}
+
+impl Encrypted {
+    pub fn new(inner: Arc<dyn Storage>, keys: Arc<Keys>) -> Self {
+        Encrypted { inner, keys }
+    }
+
+    fn seal(&self, notification: &Notification) -> Result<Notification, Error> {
+        let aad = notification.uuid.as_bytes();
+        let sealed = serde_json::to_vec(&Sealed {
+            data: notification.data.clone(),
+            meta: notification.meta.clone(),
+        })?;
+        let sealed = STANDARD.encode(self.keys.seal(&sealed, aad));
+        let mut notification = notification.clone();
+        notification.data = HashMap::from([(SEALED.to_owned(), sealed)]);
+        notification.meta = Meta {
+            client_ip: notification.meta.client_ip,
+            ..Meta::default()
+        };
+        for attachment in &mut notification.attachments {
+            if !attachment.data.is_empty() {
+                attachment.data = self.keys.seal(&attachment.data, aad);
+            }
+        }
+        Ok(notification)
+    }
+
+    fn open(&self, mut notification: Notification) -> Result<Notification, Error> {
+        let (1, Some(sealed)) = (notification.data.len(), notification.data.get(SEALED)) else {
+            return Ok(notification);
+        };
+        let aad = *notification.uuid.as_bytes();
+        let context = || format!("failed to decrypt notification {}", notification.uuid);
+        let sealed = STANDARD.decode(sealed).with_context(context)?;
+        let sealed = self.keys.open(&sealed, &aad).with_context(context)?;
+        let Sealed { data, meta } = serde_json::from_slice(&sealed).with_context(context)?;
+        for attachment in &mut notification.attachments {
+            if !attachment.data.is_empty() {
+                attachment.data = self
+                    .keys
+                    .open(&attachment.data, &aad)
+                    .with_context(context)?;
+            }
+        }
+        notification.data = data;
+        notification.meta = meta;
+        Ok(notification)
+    }
+
+    fn open_all(&self, notifications: Vec<Notification>) -> Result<Vec<Notification>, Error> {
+        notifications.into_iter().map(|n| self.open(n)).collect()
+    }
+
+    fn open_letter(&self, mut letter: DeadLetter) -> Result<DeadLetter, Error> {
+        letter.notification = self.open(letter.notification)?;
+        Ok(letter)
+    }
+}
+
+#[async_trait]
+impl Storage for Encrypted {
+    async fn insert(&self, notification: &Notification) -> Result<i64, Error> {
+        self.inner.insert(&self.seal(notification)?).await
+    }
+
+    async fn settle(&self, ids: &[i64]) -> Result<(), Error> {
+        self.inner.settle(ids).await
+    }
+
+    async fn add_hit(&self, id: i64) -> Result<(), Error> {
+        self.inner.add_hit(id).await
+    }
+
+    async fn pending(&self) -> Result<Vec<Notification>, Error> {
+        self.open_all(self.inner.pending().await?)
+    }
+
+    async fn history(
+        &self,
+        filter: &HistoryFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<Notification>, i64), Error> {
+        let (notifications, total) = self.inner.history(filter, offset, limit).await?;
+        Ok((self.open_all(notifications)?, total))
+    }
+
+    async fn stats(&self, filter: &HistoryFilter) -> Result<Stats, Error> {
+        self.inner.stats(filter).await
+    }
+
+    async fn revision(&self, filter: &HistoryFilter) -> Result<(u64, i64), Error> {
+        self.inner.revision(filter).await
+    }
+
+    async fn notification(&self, uuid: Uuid) -> Result<Option<Notification>, Error> {
+        let notification = self.inner.notification(uuid).await?;
+        notification.map(|n| self.open(n)).transpose()
+    }
+
+    async fn after(&self, token: &str, seq: u64) -> Result<Option<Notification>, Error> {
+        let notification = self.inner.after(token, seq).await?;
+        notification.map(|n| self.open(n)).transpose()
+    }
+
+    async fn file_tokens(&self, file_id: &str) -> Result<Vec<String>, Error> {
+        self.inner.file_tokens(file_id).await
+    }
+
+    async fn sequences(&self) -> Result<HashMap<String, u64>, Error> {
+        self.inner.sequences().await
+    }
+
+    async fn delete_notification(&self, id: i64) -> Result<bool, Error> {
+        self.inner.delete_notification(id).await
+    }
+
+    async fn expire(
+        &self,
+        before: DateTime<Utc>,
+        token: Option<&str>,
+        except: &[String],
+    ) -> Result<u64, Error> {
+        self.inner.expire(before, token, except).await
+    }
+
+    async fn delete_token(&self, token: &str) -> Result<u64, Error> {
+        self.inner.delete_token(token).await
+    }
+
+    async fn save_token(&self, info: &TokenInfo) -> Result<(), Error> {
+        self.inner.save_token(info).await
+    }
+
+    async fn tokens(&self) -> Result<Vec<TokenInfo>, Error> {
+        self.inner.tokens().await
+    }
+
+    async fn park(&self, letter: &DeadLetter) -> Result<i64, Error> {
+        let letter = DeadLetter {
+            notification: self.seal(&letter.notification)?,
+            ..letter.clone()
+        };
+        self.inner.park(&letter).await
+    }
+
+    async fn dead_letters(&self, limit: i64) -> Result<Vec<DeadLetter>, Error> {
+        let letters = self.inner.dead_letters(limit).await?;
+        letters.into_iter().map(|l| self.open_letter(l)).collect()
+    }
+
+    async fn dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let letter = self.inner.dead_letter(id).await?;
+        letter.map(|l| self.open_letter(l)).transpose()
+    }
+
+    async fn take_dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let letter = self.inner.take_dead_letter(id).await?;
+        letter.map(|l| self.open_letter(l)).transpose()
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.inner.ping().await
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.inner.close().await
+    }
+}
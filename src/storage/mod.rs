@@ -0,0 +1,404 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{Context, Error};
+use async_graphql::SimpleObject;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{ColumnIndex, Decode, Row, Type};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::{
+    config::StorageConfig,
+    model::{Attachment, Notification},
+    tokens::TokenInfo,
+};
+
+mod encrypted;
+mod postgres;
+mod sqlite;
+
+pub use encrypted::{Encrypted, Keys};
+
+/// Where notifications are persisted.
+///
+/// Every accepted hit is inserted as pending and settled once it was handed to
+/// a poller or evicted, so whatever is still pending on startup gets buffered again.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Persists `notification` as pending and returns its id.
+    async fn insert(&self, notification: &Notification) -> Result<i64, Error>;
+    /// Marks notifications as no longer buffered.
+    async fn settle(&self, ids: &[i64]) -> Result<(), Error>;
+    /// Counts one more hit collapsed into notification `id`, see `dedup`.
+    async fn add_hit(&self, id: i64) -> Result<(), Error>;
+    /// Notifications that were still buffered when the server last stopped, oldest first.
+    async fn pending(&self) -> Result<Vec<Notification>, Error>;
+    /// Up to `limit` of the notifications matching `filter` after skipping
+    /// `offset`, oldest first, together with how many match in total.
+    async fn history(
+        &self,
+        filter: &HistoryFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<Notification>, i64), Error>;
+    /// Aggregates over the notifications matching `filter`.
+    async fn stats(&self, filter: &HistoryFilter) -> Result<Stats, Error>;
+    /// The highest sequence number among the notifications matching `filter`
+    /// and how many match, which together change whenever the matches do.
+    async fn revision(&self, filter: &HistoryFilter) -> Result<(u64, i64), Error>;
+    /// The stored notification with this uuid, pending or not.
+    async fn notification(&self, uuid: Uuid) -> Result<Option<Notification>, Error>;
+    /// The stored notification for `token` numbered right after `seq`, pending or not.
+    async fn after(&self, token: &str, seq: u64) -> Result<Option<Notification>, Error>;
+    /// The tokens of the stored notifications carrying the attachment file
+    /// `file_id`, pending or not.
+    async fn file_tokens(&self, file_id: &str) -> Result<Vec<String>, Error>;
+    /// The highest sequence number stored for each token.
+    async fn sequences(&self) -> Result<HashMap<String, u64>, Error>;
+    /// Deletes a notification with its attachments, returning whether it existed.
+    async fn delete_notification(&self, id: i64) -> Result<bool, Error>;
+    /// Deletes notifications received before `before` that are no longer
+    /// buffered, with their attachments, returning how many. Only `token`'s if
+    /// given, otherwise those of every token but the ones in `except`.
+    async fn expire(
+        &self,
+        before: DateTime<Utc>,
+        token: Option<&str>,
+        except: &[String],
+    ) -> Result<u64, Error>;
+    /// Deletes a registered token and every notification stored for it,
+    /// returning how many notifications were deleted.
+    async fn delete_token(&self, token: &str) -> Result<u64, Error>;
+    /// Inserts or replaces a registered token.
+    async fn save_token(&self, info: &TokenInfo) -> Result<(), Error>;
+    async fn tokens(&self) -> Result<Vec<TokenInfo>, Error>;
+    /// Keeps a delivery that failed every try, returning its id. `letter.id` is ignored.
+    async fn park(&self, letter: &DeadLetter) -> Result<i64, Error>;
+    /// Up to `limit` dead letters, oldest first.
+    async fn dead_letters(&self, limit: i64) -> Result<Vec<DeadLetter>, Error>;
+    async fn dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error>;
+    /// Removes a dead letter, returning it if it existed.
+    async fn take_dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error>;
+    /// Fails if the backend cannot currently be reached.
+    async fn ping(&self) -> Result<(), Error>;
+    /// Called once on the way out, after the last write.
+    async fn close(&self) -> Result<(), Error>;
+}
+
+/// A webhook delivery that failed every try, kept until it is re-driven or deleted.
+#[derive(Clone, Serialize, ToSchema)]
+pub struct DeadLetter {
+    pub id: i64,
+    /// The webhook it was meant for.
+    pub url: String,
+    pub notification: Notification,
+    pub attempts: u32,
+    pub last_error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Totals for the notifications matching a `HistoryFilter`.
+#[derive(Default, SimpleObject)]
+pub struct Stats {
+    pub notifications: i64,
+    /// Distinct client IPs the hits came from.
+    pub client_ips: i64,
+    pub first_received_at: Option<DateTime<Utc>>,
+    pub last_received_at: Option<DateTime<Utc>>,
+}
+
+/// Which stored notifications the history API returns. Text matches are
+/// case-insensitive substrings.
+#[derive(Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryFilter {
+    pub token: String,
+    /// Received at or after.
+    pub since: Option<DateTime<Utc>>,
+    /// Received before.
+    pub until: Option<DateTime<Utc>>,
+    /// Client IP the hit came from.
+    #[param(value_type = Option<String>)]
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// Restricts `q` to this payload field.
+    pub field: Option<String>,
+    /// Text contained in a payload value.
+    pub q: Option<String>,
+}
+
+/// `text` as a LIKE pattern matching it anywhere, with `\` as the escape character.
+fn contains_pattern(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+/// Groups attachment rows (`notification_id`, `name`, `filename`, `content_type`,
+/// `file_id`, `size`, `data`) by notification.
+fn group_attachments<R>(rows: Vec<R>) -> HashMap<i64, Vec<Attachment>>
+where
+    R: Row,
+    for<'r> &'r str: ColumnIndex<R>,
+    for<'r> i64: Decode<'r, R::Database> + Type<R::Database>,
+    for<'r> String: Decode<'r, R::Database> + Type<R::Database>,
+    for<'r> Vec<u8>: Decode<'r, R::Database> + Type<R::Database>,
+{
+    let mut grouped: HashMap<i64, Vec<Attachment>> = HashMap::new();
+    for row in rows {
+        grouped
+            .entry(row.get("notification_id"))
+            .or_default()
+            .push(Attachment {
+                name: row.get("name"),
+                filename: row.get("filename"),
+                content_type: row.get("content_type"),
+                id: row.get("file_id"),
+                size: row.get::<i64, _>("size") as u64,
+                url: None,
+                data: row.get("data"),
+                spooled: None,
+            });
+    }
+    grouped
+}
+
+pub async fn connect(config: &StorageConfig) -> Result<Arc<dyn Storage>, Error> {
+    Ok(match config {
+        StorageConfig::Memory { snapshot } => Arc::new(Memory::open(snapshot.clone())?),
+        StorageConfig::Sqlite { path } => Arc::new(sqlite::Sqlite::connect(path).await?),
+        StorageConfig::Postgres {
+            url,
+            max_connections,
+        } => Arc::new(postgres::Postgres::connect(url, *max_connections).await?),
+    })
+}
+
+/// Keeps registered tokens, buffered hits, sequence numbers and dead letters in
+/// memory. With a `snapshot` file all but the dead letters are restored from it
+/// and written back on `close`, so a quick restart does not lose them.
+#[derive(Default)]
+pub struct Memory {
+    next_id: AtomicI64,
+    snapshot: Option<PathBuf>,
+    state: Mutex<Snapshot>,
+    dead_letters: Mutex<Vec<DeadLetter>>,
+}
+
+/// What `Memory` writes to its snapshot file.
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    tokens: HashMap<String, TokenInfo>,
+    /// Buffered notifications by id.
+    pending: BTreeMap<i64, Notification>,
+    sequences: HashMap<String, u64>,
+}
+
+impl Memory {
+    /// Starts from `snapshot` if the file exists, empty otherwise.
+    pub fn open(snapshot: Option<PathBuf>) -> Result<Memory, Error> {
+        let state = match &snapshot {
+            Some(path) => match std::fs::read(path) {
+                Ok(bytes) => serde_json::from_slice(&bytes)
+                    .with_context(|| format!("reading snapshot {}", path.display()))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+                Err(e) => {
+                    return Err(
+                        Error::from(e).context(format!("reading snapshot {}", path.display()))
+                    )
+                }
+            },
+            None => Snapshot::default(),
+        };
+        let next_id = state.pending.keys().next_back().copied().unwrap_or(0);
+        Ok(Memory {
+            next_id: AtomicI64::new(next_id),
+            snapshot,
+            state: Mutex::new(state),
+            dead_letters: Mutex::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for Memory {
+    async fn insert(&self, notification: &Notification) -> Result<i64, Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut state = self.state.lock().expect("");
+        let seq = state
+            .sequences
+            .entry(notification.token.clone())
+            .or_default();
+        *seq = (*seq).max(notification.seq);
+        state.pending.insert(
+            id,
+            Notification {
+                id,
+                ..notification.clone()
+            },
+        );
+        Ok(id)
+    }
+
+    async fn settle(&self, ids: &[i64]) -> Result<(), Error> {
+        let mut state = self.state.lock().expect("");
+        for id in ids {
+            state.pending.remove(id);
+        }
+        Ok(())
+    }
+
+    async fn add_hit(&self, id: i64) -> Result<(), Error> {
+        if let Some(notification) = self.state.lock().expect("").pending.get_mut(&id) {
+            notification.hits += 1;
+        }
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<Notification>, Error> {
+        Ok(self
+            .state
+            .lock()
+            .expect("")
+            .pending
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn history(
+        &self,
+        _: &HistoryFilter,
+        _: i64,
+        _: i64,
+    ) -> Result<(Vec<Notification>, i64), Error> {
+        Ok((Vec::new(), 0))
+    }
+
+    async fn stats(&self, _: &HistoryFilter) -> Result<Stats, Error> {
+        Ok(Stats::default())
+    }
+
+    async fn revision(&self, _: &HistoryFilter) -> Result<(u64, i64), Error> {
+        Ok((0, 0))
+    }
+
+    async fn notification(&self, _: Uuid) -> Result<Option<Notification>, Error> {
+        Ok(None)
+    }
+
+    async fn after(&self, _: &str, _: u64) -> Result<Option<Notification>, Error> {
+        Ok(None)
+    }
+
+    async fn file_tokens(&self, file_id: &str) -> Result<Vec<String>, Error> {
+        let state = self.state.lock().expect("");
+        let carrying = state.pending.values().filter(|notification| {
+            notification
+                .attachments
+                .iter()
+                .any(|attachment| attachment.id.as_deref() == Some(file_id))
+        });
+        Ok(carrying
+            .map(|notification| notification.token.clone())
+            .collect())
+    }
+
+    async fn sequences(&self) -> Result<HashMap<String, u64>, Error> {
+        Ok(self.state.lock().expect("").sequences.clone())
+    }
+
+    async fn delete_notification(&self, id: i64) -> Result<bool, Error> {
+        Ok(self.state.lock().expect("").pending.remove(&id).is_some())
+    }
+
+    async fn expire(&self, _: DateTime<Utc>, _: Option<&str>, _: &[String]) -> Result<u64, Error> {
+        Ok(0)
+    }
+
+    async fn delete_token(&self, token: &str) -> Result<u64, Error> {
+        let mut state = self.state.lock().expect("");
+        state.tokens.remove(token);
+        state.sequences.remove(token);
+        let before = state.pending.len();
+        state
+            .pending
+            .retain(|_, notification| notification.token != token);
+        Ok((before - state.pending.len()) as u64)
+    }
+
+    async fn save_token(&self, info: &TokenInfo) -> Result<(), Error> {
+        let mut state = self.state.lock().expect("");
+        state.tokens.insert(info.token.clone(), info.clone());
+        Ok(())
+    }
+
+    async fn tokens(&self) -> Result<Vec<TokenInfo>, Error> {
+        Ok(self
+            .state
+            .lock()
+            .expect("")
+            .tokens
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn park(&self, letter: &DeadLetter) -> Result<i64, Error> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.dead_letters.lock().expect("").push(DeadLetter {
+            id,
+            ..letter.clone()
+        });
+        Ok(id)
+    }
+
+    async fn dead_letters(&self, limit: i64) -> Result<Vec<DeadLetter>, Error> {
+        let dead_letters = self.dead_letters.lock().expect("");
+        let limit = usize::try_from(limit).unwrap_or(0);
+        Ok(dead_letters.iter().take(limit).cloned().collect())
+    }
+
+    async fn dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let dead_letters = self.dead_letters.lock().expect("");
+        Ok(dead_letters.iter().find(|letter| letter.id == id).cloned())
+    }
+
+    async fn take_dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let mut dead_letters = self.dead_letters.lock().expect("");
+        let index = dead_letters.iter().position(|letter| letter.id == id);
+        Ok(index.map(|index| dead_letters.remove(index)))
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Writes the snapshot, through a temporary file so a crash midway leaves
+    /// the previous one intact.
+    async fn close(&self) -> Result<(), Error> {
+        let Some(path) = &self.snapshot else {
+            return Ok(());
+        };
+        let bytes = serde_json::to_vec(&*self.state.lock().expect(""))?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, bytes)
+            .with_context(|| format!("writing snapshot {}", temporary.display()))?;
+        std::fs::rename(&temporary, path)
+            .with_context(|| format!("writing snapshot {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,483 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{
+    postgres::{PgPoolOptions, PgRow},
+    types::Json,
+    PgPool, QueryBuilder, Row,
+};
+use uuid::Uuid;
+
+use super::{contains_pattern, group_attachments, DeadLetter, HistoryFilter, Stats, Storage};
+use crate::{
+    model::{Attachment, Meta, Notification, Payload, Version},
+    tokens::TokenInfo,
+};
+
+pub struct Postgres {
+    pool: PgPool,
+}
+
+impl Postgres {
+    pub async fn connect(url: &str, max_connections: u32) -> Result<Postgres, Error> {
+        let pool = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .connect(url)
+            .await?;
+        sqlx::migrate!("migrations/postgres").run(&pool).await?;
+        Ok(Postgres { pool })
+    }
+
+    /// Attachments of the notifications with these ids, grouped by notification.
+    async fn attachments(&self, ids: &[i64]) -> Result<HashMap<i64, Vec<Attachment>>, Error> {
+        Ok(group_attachments(
+            sqlx::query(
+                "SELECT notification_id, name, filename, content_type, file_id, size, data FROM attachments WHERE notification_id = ANY($1) ORDER BY id",
+            )
+            .bind(ids)
+            .fetch_all(&self.pool)
+            .await?,
+        ))
+    }
+}
+
+/// Appends the WHERE clause selecting what `Storage::expire` deletes.
+fn push_expired<'a>(
+    query: &mut QueryBuilder<'a, sqlx::Postgres>,
+    before: DateTime<Utc>,
+    token: Option<&'a str>,
+    except: &'a [String],
+) {
+    query
+        .push(" WHERE NOT pending AND received_at < ")
+        .push_bind(before);
+    if let Some(token) = token {
+        query.push(" AND token = ").push_bind(token);
+    }
+    if !except.is_empty() {
+        query.push(" AND token NOT IN (");
+        let mut list = query.separated(", ");
+        for token in except {
+            list.push_bind(token);
+        }
+        query.push(")");
+    }
+}
+
+/// Appends the WHERE clause selecting what `filter` asks for.
+fn push_filter<'a>(query: &mut QueryBuilder<'a, sqlx::Postgres>, filter: &'a HistoryFilter) {
+    query.push(" WHERE token = ").push_bind(&filter.token);
+    if let Some(since) = filter.since {
+        query.push(" AND received_at >= ").push_bind(since);
+    }
+    if let Some(until) = filter.until {
+        query.push(" AND received_at < ").push_bind(until);
+    }
+    if let Some(ip) = filter.ip {
+        query.push(" AND source = ").push_bind(ip.to_string());
+    }
+    if let Some(user_agent) = &filter.user_agent {
+        query
+            .push(" AND meta->>'user_agent' ILIKE ")
+            .push_bind(contains_pattern(user_agent))
+            .push(" ESCAPE '\\'");
+    }
+    if filter.field.is_some() || filter.q.is_some() {
+        query.push(" AND EXISTS (SELECT 1 FROM jsonb_each_text(payload) WHERE TRUE");
+        if let Some(field) = &filter.field {
+            query.push(" AND key = ").push_bind(field);
+        }
+        if let Some(q) = &filter.q {
+            query
+                .push(" AND value ILIKE ")
+                .push_bind(contains_pattern(q))
+                .push(" ESCAPE '\\'");
+        }
+        query.push(")");
+    }
+}
+
+/// `s` with NUL characters, which Postgres refuses in `text`, as U+FFFD.
+/// Hits can carry anything, SQLite stores them as they are.
+fn text(s: &str) -> Cow<'_, str> {
+    match s.contains('\0') {
+        true => Cow::Owned(s.replace('\0', "\u{fffd}")),
+        false => Cow::Borrowed(s),
+    }
+}
+
+/// `value` as `jsonb`, which refuses `\u0000` just as `text` refuses NUL, see `text`.
+fn jsonb<T: Serialize>(value: &T) -> Result<Json<Value>, Error> {
+    fn walk(value: Value) -> Value {
+        match value {
+            Value::String(s) => Value::String(text(&s).into_owned()),
+            Value::Array(values) => Value::Array(values.into_iter().map(walk).collect()),
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| (text(&key).into_owned(), walk(value)))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+    Ok(Json(walk(serde_json::to_value(value)?)))
+}
+
+fn notification(row: PgRow, attachments: &mut HashMap<i64, Vec<Attachment>>) -> Notification {
+    let id = row.get("id");
+    let meta: Option<Json<Meta>> = row.get("meta");
+    Notification {
+        v: Version,
+        id,
+        uuid: row.get("uuid"),
+        seq: row.get::<i64, _>("seq") as u64,
+        token: row.get("token"),
+        received_at: row.get::<DateTime<Utc>, _>("received_at"),
+        data: row.get::<Json<Payload>, _>("payload").0,
+        meta: meta.map(|m| m.0).unwrap_or_default(),
+        attachments: attachments.remove(&id).unwrap_or_default(),
+        hits: row.get::<i64, _>("hits") as u64,
+        delivery_id: None,
+        trace: None,
+    }
+}
+
+fn dead_letter(row: PgRow) -> Result<DeadLetter, Error> {
+    Ok(DeadLetter {
+        id: row.get("id"),
+        url: row.get("url"),
+        notification: row.get::<Json<Notification>, _>("notification").0,
+        attempts: u32::try_from(row.get::<i64, _>("attempts"))?,
+        last_error: row.get("last_error"),
+        failed_at: row.get("failed_at"),
+    })
+}
+
+#[async_trait]
+impl Storage for Postgres {
+    async fn insert(&self, notification: &Notification) -> Result<i64, Error> {
+        let mut tx = self.pool.begin().await?;
+        let id: i64 = sqlx::query(
+            "INSERT INTO notifications (token, uuid, seq, payload, source, meta, received_at) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id",
+        )
+        .bind(text(&notification.token))
+        .bind(notification.uuid)
+        .bind(i64::try_from(notification.seq)?)
+        .bind(jsonb(&notification.data)?)
+        .bind(notification.meta.client_ip.map(|ip| ip.to_string()))
+        .bind(jsonb(&notification.meta)?)
+        .bind(notification.received_at)
+        .fetch_one(&mut *tx)
+        .await?
+        .get("id");
+        for attachment in &notification.attachments {
+            sqlx::query(
+                "INSERT INTO attachments (notification_id, name, filename, content_type, file_id, size, data) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            )
+            .bind(id)
+            .bind(text(&attachment.name))
+            .bind(attachment.filename.as_deref().map(text))
+            .bind(attachment.content_type.as_deref().map(text))
+            .bind(&attachment.id)
+            .bind(i64::try_from(attachment.size)?)
+            .bind(&attachment.data)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    async fn settle(&self, ids: &[i64]) -> Result<(), Error> {
+        sqlx::query("UPDATE notifications SET pending = FALSE WHERE id = ANY($1)")
+            .bind(ids)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_hit(&self, id: i64) -> Result<(), Error> {
+        sqlx::query("UPDATE notifications SET hits = hits + 1 WHERE id = $1")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<Notification>, Error> {
+        let rows = sqlx::query(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications WHERE pending ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut attachments = group_attachments(
+            sqlx::query(
+                "SELECT a.notification_id, a.name, a.filename, a.content_type, a.file_id, a.size, a.data FROM attachments a JOIN notifications n ON n.id = a.notification_id WHERE n.pending ORDER BY a.id",
+            )
+            .fetch_all(&self.pool)
+            .await?,
+        );
+        Ok(rows
+            .into_iter()
+            .map(|row| notification(row, &mut attachments))
+            .collect())
+    }
+
+    async fn history(
+        &self,
+        filter: &HistoryFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<Notification>, i64), Error> {
+        let mut query = QueryBuilder::new("SELECT COUNT(*) AS total FROM notifications");
+        push_filter(&mut query, filter);
+        let total = query.build().fetch_one(&self.pool).await?.get("total");
+        let mut query = QueryBuilder::new(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications",
+        );
+        push_filter(&mut query, filter);
+        query
+            .push(" ORDER BY received_at, id LIMIT ")
+            .push_bind(limit)
+            .push(" OFFSET ")
+            .push_bind(offset);
+        let rows = query.build().fetch_all(&self.pool).await?;
+        let ids: Vec<i64> = rows.iter().map(|row| row.get("id")).collect();
+        let mut attachments = self.attachments(&ids).await?;
+        let notifications = rows
+            .into_iter()
+            .map(|row| notification(row, &mut attachments))
+            .collect();
+        Ok((notifications, total))
+    }
+
+    async fn stats(&self, filter: &HistoryFilter) -> Result<Stats, Error> {
+        let mut query = QueryBuilder::new(
+            "SELECT COUNT(*) AS notifications, COUNT(DISTINCT source) AS client_ips, \
+             MIN(received_at) AS first_received_at, MAX(received_at) AS last_received_at \
+             FROM notifications",
+        );
+        push_filter(&mut query, filter);
+        let row = query.build().fetch_one(&self.pool).await?;
+        Ok(Stats {
+            notifications: row.get("notifications"),
+            client_ips: row.get("client_ips"),
+            first_received_at: row.get("first_received_at"),
+            last_received_at: row.get("last_received_at"),
+        })
+    }
+
+    async fn revision(&self, filter: &HistoryFilter) -> Result<(u64, i64), Error> {
+        let mut query =
+            QueryBuilder::new("SELECT MAX(seq) AS seq, COUNT(*) AS total FROM notifications");
+        push_filter(&mut query, filter);
+        let row = query.build().fetch_one(&self.pool).await?;
+        let seq = row.get::<Option<i64>, _>("seq").unwrap_or_default();
+        Ok((seq as u64, row.get("total")))
+    }
+
+    async fn notification(&self, uuid: Uuid) -> Result<Option<Notification>, Error> {
+        let row = sqlx::query(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications WHERE uuid = $1",
+        )
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let mut attachments = self.attachments(&[row.get("id")]).await?;
+        Ok(Some(notification(row, &mut attachments)))
+    }
+
+    async fn after(&self, token: &str, seq: u64) -> Result<Option<Notification>, Error> {
+        let row = sqlx::query(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications \
+             WHERE token = $1 AND seq > $2 ORDER BY seq LIMIT 1",
+        )
+        .bind(token)
+        .bind(i64::try_from(seq)?)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let mut attachments = self.attachments(&[row.get("id")]).await?;
+        Ok(Some(notification(row, &mut attachments)))
+    }
+
+    async fn file_tokens(&self, file_id: &str) -> Result<Vec<String>, Error> {
+        let rows = sqlx::query(
+            "SELECT DISTINCT n.token FROM attachments a JOIN notifications n ON n.id = a.notification_id \
+             WHERE a.file_id = $1",
+        )
+        .bind(file_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get("token")).collect())
+    }
+
+    async fn sequences(&self) -> Result<HashMap<String, u64>, Error> {
+        let rows = sqlx::query("SELECT token, MAX(seq) AS seq FROM notifications GROUP BY token")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("token"), row.get::<i64, _>("seq") as u64))
+            .collect())
+    }
+
+    async fn delete_notification(&self, id: i64) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM attachments WHERE notification_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM notifications WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    async fn expire(
+        &self,
+        before: DateTime<Utc>,
+        token: Option<&str>,
+        except: &[String],
+    ) -> Result<u64, Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut query = QueryBuilder::new(
+            "DELETE FROM attachments WHERE notification_id IN (SELECT id FROM notifications",
+        );
+        push_expired(&mut query, before, token, except);
+        query.push(")").build().execute(&mut *tx).await?;
+        let mut query = QueryBuilder::new("DELETE FROM notifications");
+        push_expired(&mut query, before, token, except);
+        let deleted = query.build().execute(&mut *tx).await?.rows_affected();
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn delete_token(&self, token: &str) -> Result<u64, Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM attachments WHERE notification_id IN (SELECT id FROM notifications WHERE token = $1)",
+        )
+        .bind(token)
+        .execute(&mut *tx)
+        .await?;
+        let deleted = sqlx::query("DELETE FROM notifications WHERE token = $1")
+            .bind(token)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("DELETE FROM tokens WHERE token = $1")
+            .bind(token)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn save_token(&self, info: &TokenInfo) -> Result<(), Error> {
+        sqlx::query(
+            "INSERT INTO tokens (token, info) VALUES ($1, $2) ON CONFLICT (token) DO UPDATE SET info = EXCLUDED.info",
+        )
+        .bind(&info.token)
+        .bind(Json(info))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn tokens(&self) -> Result<Vec<TokenInfo>, Error> {
+        let rows = sqlx::query("SELECT info FROM tokens")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| row.get::<Json<TokenInfo>, _>("info").0)
+            .collect())
+    }
+
+    async fn park(&self, letter: &DeadLetter) -> Result<i64, Error> {
+        let row = sqlx::query(
+            "INSERT INTO dead_letters (url, notification, attempts, last_error, failed_at) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(&letter.url)
+        .bind(jsonb(&letter.notification)?)
+        .bind(i64::from(letter.attempts))
+        .bind(&letter.last_error)
+        .bind(letter.failed_at)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get("id"))
+    }
+
+    async fn dead_letters(&self, limit: i64) -> Result<Vec<DeadLetter>, Error> {
+        let rows = sqlx::query(
+            "SELECT id, url, notification, attempts, last_error, failed_at FROM dead_letters ORDER BY id LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(dead_letter).collect()
+    }
+
+    async fn dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let row = sqlx::query(
+            "SELECT id, url, notification, attempts, last_error, failed_at FROM dead_letters WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        row.map(dead_letter).transpose()
+    }
+
+    async fn take_dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let row = sqlx::query(
+            "DELETE FROM dead_letters WHERE id = $1 RETURNING id, url, notification, attempts, last_error, failed_at",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        row.map(dead_letter).transpose()
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.pool.close().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_nul_characters() {
+        assert_eq!(text("to\0ken"), "to\u{fffd}ken");
+        assert!(matches!(text("token"), Cow::Borrowed("token")));
+        let mut data = Payload::new();
+        data.insert("c\0ookie".to_owned(), "a\0b".to_owned());
+        data.insert("plain".to_owned(), "value".to_owned());
+        let Json(value) = jsonb(&data).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"c\u{fffd}ookie": "a\u{fffd}b", "plain": "value"})
+        );
+        assert!(!serde_json::to_string(&value).unwrap().contains("\\u0000"));
+    }
+}
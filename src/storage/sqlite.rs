@@ -0,0 +1,447 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Error;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    QueryBuilder, Row, SqlitePool,
+};
+use uuid::Uuid;
+
+use super::{contains_pattern, group_attachments, DeadLetter, HistoryFilter, Stats, Storage};
+use crate::{
+    model::{Attachment, Notification, Version},
+    tokens::TokenInfo,
+};
+
+pub struct Sqlite {
+    pool: SqlitePool,
+}
+
+impl Sqlite {
+    pub async fn connect(path: &Path) -> Result<Sqlite, Error> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::migrate!("migrations/sqlite").run(&pool).await?;
+        Ok(Sqlite { pool })
+    }
+
+    /// Attachments of the notifications with these ids, grouped by notification.
+    async fn attachments(&self, ids: &[i64]) -> Result<HashMap<i64, Vec<Attachment>>, Error> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let mut query = QueryBuilder::new(
+            "SELECT notification_id, name, filename, content_type, file_id, size, data FROM attachments WHERE notification_id IN (",
+        );
+        let mut list = query.separated(", ");
+        for id in ids {
+            list.push_bind(id);
+        }
+        query.push(") ORDER BY id");
+        Ok(group_attachments(
+            query.build().fetch_all(&self.pool).await?,
+        ))
+    }
+}
+
+/// Appends the WHERE clause selecting what `Storage::expire` deletes.
+fn push_expired<'a>(
+    query: &mut QueryBuilder<'a, sqlx::Sqlite>,
+    before: DateTime<Utc>,
+    token: Option<&'a str>,
+    except: &'a [String],
+) {
+    query
+        .push(" WHERE pending = 0 AND received_at < ")
+        .push_bind(before);
+    if let Some(token) = token {
+        query.push(" AND token = ").push_bind(token);
+    }
+    if !except.is_empty() {
+        query.push(" AND token NOT IN (");
+        let mut list = query.separated(", ");
+        for token in except {
+            list.push_bind(token);
+        }
+        query.push(")");
+    }
+}
+
+/// Appends the WHERE clause selecting what `filter` asks for.
+fn push_filter<'a>(query: &mut QueryBuilder<'a, sqlx::Sqlite>, filter: &'a HistoryFilter) {
+    query.push(" WHERE token = ").push_bind(&filter.token);
+    if let Some(since) = filter.since {
+        query.push(" AND received_at >= ").push_bind(since);
+    }
+    if let Some(until) = filter.until {
+        query.push(" AND received_at < ").push_bind(until);
+    }
+    if let Some(ip) = filter.ip {
+        query.push(" AND source = ").push_bind(ip.to_string());
+    }
+    if let Some(user_agent) = &filter.user_agent {
+        query
+            .push(" AND json_extract(meta, '$.user_agent') LIKE ")
+            .push_bind(contains_pattern(user_agent))
+            .push(" ESCAPE '\\'");
+    }
+    if filter.field.is_some() || filter.q.is_some() {
+        query.push(" AND EXISTS (SELECT 1 FROM json_each(payload) WHERE TRUE");
+        if let Some(field) = &filter.field {
+            query.push(" AND key = ").push_bind(field);
+        }
+        if let Some(q) = &filter.q {
+            query
+                .push(" AND value LIKE ")
+                .push_bind(contains_pattern(q))
+                .push(" ESCAPE '\\'");
+        }
+        query.push(")");
+    }
+}
+
+fn notification(
+    row: SqliteRow,
+    attachments: &mut HashMap<i64, Vec<Attachment>>,
+) -> Result<Notification, Error> {
+    let id = row.get("id");
+    let meta: Option<&str> = row.get("meta");
+    Ok(Notification {
+        v: Version,
+        id,
+        uuid: row.get("uuid"),
+        seq: row.get::<i64, _>("seq") as u64,
+        token: row.get("token"),
+        received_at: row.get::<DateTime<Utc>, _>("received_at"),
+        data: serde_json::from_str(row.get("payload"))?,
+        meta: meta
+            .map(serde_json::from_str)
+            .transpose()?
+            .unwrap_or_default(),
+        attachments: attachments.remove(&id).unwrap_or_default(),
+        hits: row.get::<i64, _>("hits") as u64,
+        delivery_id: None,
+        trace: None,
+    })
+}
+
+fn dead_letter(row: SqliteRow) -> Result<DeadLetter, Error> {
+    Ok(DeadLetter {
+        id: row.get("id"),
+        url: row.get("url"),
+        notification: serde_json::from_str(row.get("notification"))?,
+        attempts: u32::try_from(row.get::<i64, _>("attempts"))?,
+        last_error: row.get("last_error"),
+        failed_at: row.get("failed_at"),
+    })
+}
+
+#[async_trait]
+impl Storage for Sqlite {
+    async fn insert(&self, notification: &Notification) -> Result<i64, Error> {
+        let mut tx = self.pool.begin().await?;
+        let id = sqlx::query(
+            "INSERT INTO notifications (token, uuid, seq, payload, source, meta, received_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&notification.token)
+        .bind(notification.uuid)
+        .bind(i64::try_from(notification.seq)?)
+        .bind(serde_json::to_string(&notification.data)?)
+        .bind(notification.meta.client_ip.map(|ip| ip.to_string()))
+        .bind(serde_json::to_string(&notification.meta)?)
+        .bind(notification.received_at)
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+        for attachment in &notification.attachments {
+            sqlx::query(
+                "INSERT INTO attachments (notification_id, name, filename, content_type, file_id, size, data) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(id)
+            .bind(&attachment.name)
+            .bind(&attachment.filename)
+            .bind(&attachment.content_type)
+            .bind(&attachment.id)
+            .bind(i64::try_from(attachment.size)?)
+            .bind(&attachment.data)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(id)
+    }
+
+    async fn settle(&self, ids: &[i64]) -> Result<(), Error> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let mut query = QueryBuilder::new("UPDATE notifications SET pending = 0 WHERE id IN (");
+        let mut list = query.separated(", ");
+        for id in ids {
+            list.push_bind(id);
+        }
+        query.push(")").build().execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn add_hit(&self, id: i64) -> Result<(), Error> {
+        sqlx::query("UPDATE notifications SET hits = hits + 1 WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn pending(&self) -> Result<Vec<Notification>, Error> {
+        let rows = sqlx::query(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications WHERE pending = 1 ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut attachments = group_attachments(
+            sqlx::query(
+                "SELECT a.notification_id, a.name, a.filename, a.content_type, a.file_id, a.size, a.data FROM attachments a JOIN notifications n ON n.id = a.notification_id WHERE n.pending = 1 ORDER BY a.id",
+            )
+            .fetch_all(&self.pool)
+            .await?,
+        );
+        rows.into_iter()
+            .map(|row| notification(row, &mut attachments))
+            .collect()
+    }
+
+    async fn history(
+        &self,
+        filter: &HistoryFilter,
+        offset: i64,
+        limit: i64,
+    ) -> Result<(Vec<Notification>, i64), Error> {
+        let mut query = QueryBuilder::new("SELECT COUNT(*) AS total FROM notifications");
+        push_filter(&mut query, filter);
+        let total = query.build().fetch_one(&self.pool).await?.get("total");
+        let mut query = QueryBuilder::new(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications",
+        );
+        push_filter(&mut query, filter);
+        query
+            .push(" ORDER BY received_at, id LIMIT ")
+            .push_bind(limit)
+            .push(" OFFSET ")
+            .push_bind(offset);
+        let rows = query.build().fetch_all(&self.pool).await?;
+        let ids: Vec<i64> = rows.iter().map(|row| row.get("id")).collect();
+        let mut attachments = self.attachments(&ids).await?;
+        let notifications = rows
+            .into_iter()
+            .map(|row| notification(row, &mut attachments))
+            .collect::<Result<_, _>>()?;
+        Ok((notifications, total))
+    }
+
+    async fn stats(&self, filter: &HistoryFilter) -> Result<Stats, Error> {
+        let mut query = QueryBuilder::new(
+            "SELECT COUNT(*) AS notifications, COUNT(DISTINCT source) AS client_ips, \
+             MIN(received_at) AS first_received_at, MAX(received_at) AS last_received_at \
+             FROM notifications",
+        );
+        push_filter(&mut query, filter);
+        let row = query.build().fetch_one(&self.pool).await?;
+        Ok(Stats {
+            notifications: row.get("notifications"),
+            client_ips: row.get("client_ips"),
+            first_received_at: row.get("first_received_at"),
+            last_received_at: row.get("last_received_at"),
+        })
+    }
+
+    async fn revision(&self, filter: &HistoryFilter) -> Result<(u64, i64), Error> {
+        let mut query =
+            QueryBuilder::new("SELECT MAX(seq) AS seq, COUNT(*) AS total FROM notifications");
+        push_filter(&mut query, filter);
+        let row = query.build().fetch_one(&self.pool).await?;
+        let seq = row.get::<Option<i64>, _>("seq").unwrap_or_default();
+        Ok((seq as u64, row.get("total")))
+    }
+
+    async fn notification(&self, uuid: Uuid) -> Result<Option<Notification>, Error> {
+        let row = sqlx::query(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications WHERE uuid = ?",
+        )
+        .bind(uuid)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let mut attachments = self.attachments(&[row.get("id")]).await?;
+        notification(row, &mut attachments).map(Some)
+    }
+
+    async fn after(&self, token: &str, seq: u64) -> Result<Option<Notification>, Error> {
+        let row = sqlx::query(
+            "SELECT id, token, uuid, seq, payload, meta, received_at, hits FROM notifications \
+             WHERE token = ? AND seq > ? ORDER BY seq LIMIT 1",
+        )
+        .bind(token)
+        .bind(i64::try_from(seq)?)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let mut attachments = self.attachments(&[row.get("id")]).await?;
+        notification(row, &mut attachments).map(Some)
+    }
+
+    async fn file_tokens(&self, file_id: &str) -> Result<Vec<String>, Error> {
+        let rows = sqlx::query(
+            "SELECT DISTINCT n.token FROM attachments a JOIN notifications n ON n.id = a.notification_id \
+             WHERE a.file_id = ?",
+        )
+        .bind(file_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get("token")).collect())
+    }
+
+    async fn sequences(&self) -> Result<HashMap<String, u64>, Error> {
+        let rows = sqlx::query("SELECT token, MAX(seq) AS seq FROM notifications GROUP BY token")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("token"), row.get::<i64, _>("seq") as u64))
+            .collect())
+    }
+
+    async fn delete_notification(&self, id: i64) -> Result<bool, Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM attachments WHERE notification_id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM notifications WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    async fn expire(
+        &self,
+        before: DateTime<Utc>,
+        token: Option<&str>,
+        except: &[String],
+    ) -> Result<u64, Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut query = QueryBuilder::new(
+            "DELETE FROM attachments WHERE notification_id IN (SELECT id FROM notifications",
+        );
+        push_expired(&mut query, before, token, except);
+        query.push(")").build().execute(&mut *tx).await?;
+        let mut query = QueryBuilder::new("DELETE FROM notifications");
+        push_expired(&mut query, before, token, except);
+        let deleted = query.build().execute(&mut *tx).await?.rows_affected();
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn delete_token(&self, token: &str) -> Result<u64, Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM attachments WHERE notification_id IN (SELECT id FROM notifications WHERE token = ?)",
+        )
+        .bind(token)
+        .execute(&mut *tx)
+        .await?;
+        let deleted = sqlx::query("DELETE FROM notifications WHERE token = ?")
+            .bind(token)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("DELETE FROM tokens WHERE token = ?")
+            .bind(token)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn save_token(&self, info: &TokenInfo) -> Result<(), Error> {
+        sqlx::query("INSERT OR REPLACE INTO tokens (token, info) VALUES (?, ?)")
+            .bind(&info.token)
+            .bind(serde_json::to_string(info)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn tokens(&self) -> Result<Vec<TokenInfo>, Error> {
+        let rows = sqlx::query("SELECT info FROM tokens")
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(|row| Ok(serde_json::from_str(row.get("info"))?))
+            .collect()
+    }
+
+    async fn park(&self, letter: &DeadLetter) -> Result<i64, Error> {
+        let row = sqlx::query(
+            "INSERT INTO dead_letters (url, notification, attempts, last_error, failed_at) VALUES (?, ?, ?, ?, ?) RETURNING id",
+        )
+        .bind(&letter.url)
+        .bind(serde_json::to_string(&letter.notification)?)
+        .bind(i64::from(letter.attempts))
+        .bind(&letter.last_error)
+        .bind(letter.failed_at)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.get("id"))
+    }
+
+    async fn dead_letters(&self, limit: i64) -> Result<Vec<DeadLetter>, Error> {
+        let rows = sqlx::query(
+            "SELECT id, url, notification, attempts, last_error, failed_at FROM dead_letters ORDER BY id LIMIT ?",
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(dead_letter).collect()
+    }
+
+    async fn dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let row = sqlx::query(
+            "SELECT id, url, notification, attempts, last_error, failed_at FROM dead_letters WHERE id = ?",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        row.map(dead_letter).transpose()
+    }
+
+    async fn take_dead_letter(&self, id: i64) -> Result<Option<DeadLetter>, Error> {
+        let row = sqlx::query(
+            "DELETE FROM dead_letters WHERE id = ? RETURNING id, url, notification, attempts, last_error, failed_at",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        row.map(dead_letter).transpose()
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<(), Error> {
+        self.pool.close().await;
+        Ok(())
+    }
+}
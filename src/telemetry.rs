@@ -0,0 +1,155 @@
+use std::{collections::HashMap, io::IsTerminal, net::SocketAddr, time::Instant};
+
+use anyhow::Error;
+use axum::{
+    extract::{ConnectInfo, MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    trace::{SpanContext, TraceContextExt},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::LevelFilter, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, Layer,
+    Registry,
+};
+
+use crate::{
+    config::{Config, LogFormat, LogLevel, TracingConfig},
+    proxy,
+    tokens::hex,
+    AppState,
+};
+
+/// Changes the log level of a running server, see `AppState::reload`.
+pub type LogHandle = reload::Handle<LevelFilter, Registry>;
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+/// Logs to stdout in `log_format` at `log_level`, and exports handler spans over
+/// OTLP/gRPC when `tracing.otlp_endpoint` is set.
+pub fn init(config: &Config) -> LogHandle {
+    let (filter, handle) = reload::Layer::new(LevelFilter::from(config.log_level));
+    let ansi = std::io::stdout().is_terminal();
+    let logs: Box<dyn Layer<Registry> + Send + Sync> = match config.log_format {
+        LogFormat::Text => fmt::layer().with_ansi(ansi).boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().with_ansi(ansi).boxed(),
+        LogFormat::Json => fmt::layer().json().boxed(),
+    };
+    let otel = match tracer(&config.tracing) {
+        Ok(tracer) => tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)),
+        Err(e) => {
+            eprintln!("Not exporting spans, setting up OTLP failed: {e:#}");
+            None
+        }
+    };
+    tracing_subscriber::registry()
+        .with(logs.with_filter(filter))
+        .with(otel)
+        .init();
+    handle
+}
+
+fn tracer(config: &TracingConfig) -> Result<Option<trace::Tracer>, Error> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )])))
+        .install_batch(runtime::Tokio)?;
+    Ok(Some(tracer))
+}
+
+/// Stands in for a token in logs, so they cannot be used to poll or forge hits.
+pub fn token_hash(token: &str) -> String {
+    hex(&Sha256::digest(token.as_bytes())[..8])
+}
+
+/// Middleware logging one line per request with its outcome.
+pub async fn log_request<B>(
+    State(state): State<AppState>,
+    ConnectInfo(source): ConnectInfo<SocketAddr>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = request.method().clone();
+    let mut path = request.uri().path().to_owned();
+    let mut token = request
+        .uri()
+        .query()
+        .and_then(|query| serde_urlencoded::from_str::<HashMap<String, String>>(query).ok())
+        .and_then(|mut query| query.remove("token"));
+    // Routes like /p/:token are logged as such, their token only as a hash.
+    // Notification uuids double as link secrets, so they are left out entirely.
+    if let Some(route) = request.extensions().get::<MatchedPath>() {
+        let segments: Vec<&str> = route.as_str().split('/').collect();
+        if let Some(i) = segments.iter().position(|s| *s == ":token") {
+            token = path.split('/').nth(i).map(str::to_owned);
+            path = route.as_str().to_owned();
+        } else if segments.contains(&":uuid") {
+            path = route.as_str().to_owned();
+        }
+    }
+    let token = token.map(|token| token_hash(&token));
+    let client_ip = proxy::client_ip(
+        &state.config().trusted_proxies,
+        source.ip(),
+        request.headers(),
+    );
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let status = response.status().as_u16();
+    let latency_ms = start.elapsed().as_millis() as u64;
+    if response.status().is_server_error() {
+        warn!(%method, path, token, %client_ip, status, latency_ms, "request failed");
+    } else {
+        info!(%method, path, token, %client_ip, status, latency_ms, "request");
+    }
+    response
+}
+
+/// Flushes spans that are still batched. This blocks until the exporter is done,
+/// so it must not run on a runtime worker the batch task needs.
+pub async fn shutdown() {
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+}
+
+/// The current span as OpenTelemetry sees it, for linking work done on its behalf.
+pub fn current() -> Option<SpanContext> {
+    let context = Span::current().context();
+    let span = context.span().span_context().clone();
+    span.is_valid().then_some(span)
+}
+
+/// Links the current span to `origin`, e.g. a poll to the notify that fulfilled it.
+pub fn link(origin: &Option<SpanContext>) {
+    if let Some(origin) = origin {
+        Span::current().add_link(origin.clone());
+    }
+}
@@ -0,0 +1,515 @@
+//! JA3 and JA4 fingerprints of the TLS ClientHello each connection opens with,
+//! which tell real browsers from scripted clients and sandboxes replaying a
+//! payload, whatever User-Agent they claim.
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::{extract::FromRequestParts, http::request::Parts, middleware::AddExtension, Extension};
+use axum_server::accept::Accept;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tower::Layer;
+
+use crate::{model::TlsFingerprint, tokens::hex};
+
+/// How long to wait for the ClientHello, like the handshake timeout after it.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+/// A ClientHello spread over more than this is not fingerprinted.
+const MAX_HELLO: usize = 64 * 1024;
+
+/// The fingerprint of the connection a request came over, if it was TLS
+/// terminated here and its ClientHello could be read.
+#[derive(Clone, Default)]
+pub struct ClientHello(pub Option<TlsFingerprint>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientHello {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Infallible> {
+        Ok(parts
+            .extensions
+            .get::<ClientHello>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Reads the ClientHello off each new connection before handing it to rustls,
+/// through `RustlsAcceptor::acceptor`, and adds its fingerprint to the
+/// connection's requests.
+#[derive(Clone, Copy, Default)]
+pub struct Fingerprinting;
+
+impl<I, S> Accept<I, S> for Fingerprinting
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = Rewind<I>;
+    type Service = AddExtension<S, ClientHello>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Rewind<I>, Self::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: I, service: S) -> Self::Future {
+        Box::pin(async move {
+            let mut read = Vec::new();
+            let hello = tokio::time::timeout(HELLO_TIMEOUT, read_hello(&mut stream, &mut read))
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))??;
+            let fingerprint = hello.as_deref().and_then(fingerprint);
+            let service = Extension(ClientHello(fingerprint)).layer(service);
+            Ok((
+                Rewind {
+                    read,
+                    pos: 0,
+                    stream,
+                },
+                service,
+            ))
+        })
+    }
+}
+
+/// Reads whole TLS records into `read` until they hold the first handshake
+/// message, and returns that. `None` once the bytes cannot be a ClientHello,
+/// which rustls gets to refuse if they are not.
+async fn read_hello<I: AsyncRead + Unpin>(
+    stream: &mut I,
+    read: &mut Vec<u8>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut handshake = Vec::new();
+    loop {
+        let start = read.len();
+        if !read_exact(stream, read, 5).await? || read[start] != 0x16 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([read[start + 3], read[start + 4]]) as usize;
+        if read.len() + len > MAX_HELLO || !read_exact(stream, read, len).await? {
+            return Ok(None);
+        }
+        handshake.extend_from_slice(&read[start + 5..]);
+        if handshake.len() >= 4 {
+            if handshake[0] != 0x01 {
+                return Ok(None);
+            }
+            let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= 4 + len {
+                handshake.truncate(4 + len);
+                handshake.drain(..4);
+                return Ok(Some(handshake));
+            }
+        }
+    }
+}
+
+/// Appends `n` more bytes to `read`, `false` if the connection closed first.
+async fn read_exact<I: AsyncRead + Unpin>(
+    stream: &mut I,
+    read: &mut Vec<u8>,
+    n: usize,
+) -> io::Result<bool> {
+    let start = read.len();
+    read.resize(start + n, 0);
+    let mut done = 0;
+    while done < n {
+        match stream.read(&mut read[start + done..]).await? {
+            0 => {
+                read.truncate(start + done);
+                return Ok(false);
+            }
+            got => done += got,
+        }
+    }
+    Ok(true)
+}
+
+/// A connection that first gives back the bytes already read off it.
+pub struct Rewind<I> {
+    read: Vec<u8>,
+    pos: usize,
+    stream: I,
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Rewind<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.read.len() {
+            let n = buf.remaining().min(self.read.len() - self.pos);
+            buf.put_slice(&self.read[self.pos..self.pos + n]);
+            self.pos += n;
+            if self.pos == self.read.len() {
+                self.read = Vec::new();
+                self.pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Rewind<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// What a ClientHello offers, GREASE values left out.
+#[derive(Default)]
+struct Hello {
+    version: u16,
+    ciphers: Vec<u16>,
+    /// In the order sent.
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    versions: Vec<u16>,
+    sni: bool,
+    alpn: Option<Vec<u8>>,
+}
+
+/// Reserved values clients sprinkle in to keep servers tolerant, RFC 8701.
+fn grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// A cursor over the ClientHello body.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A vector with a one byte length prefix.
+    fn short(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        Some(Reader(self.take(len)?))
+    }
+
+    /// A vector with a two byte length prefix.
+    fn long(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        Some(Reader(self.take(len)?))
+    }
+
+    fn u16s(mut self) -> Vec<u16> {
+        let mut values = Vec::new();
+        while let Some(value) = self.u16() {
+            if !grease(value) {
+                values.push(value);
+            }
+        }
+        values
+    }
+}
+
+fn parse(body: &[u8]) -> Option<Hello> {
+    let mut r = Reader(body);
+    let mut hello = Hello {
+        version: r.u16()?,
+        ..Hello::default()
+    };
+    r.take(32)?;
+    r.short()?;
+    hello.ciphers = r.long()?.u16s();
+    r.short()?;
+    // A ClientHello may end without extensions.
+    if r.0.is_empty() {
+        return Some(hello);
+    }
+    let mut extensions = r.long()?;
+    while let Some(kind) = extensions.u16() {
+        let mut data = extensions.long()?;
+        if grease(kind) {
+            continue;
+        }
+        hello.extensions.push(kind);
+        match kind {
+            0x0000 => hello.sni = true,
+            0x000a => hello.groups = data.long()?.u16s(),
+            0x000b => hello.point_formats = data.short()?.0.to_vec(),
+            0x000d => hello.signature_algorithms = data.long()?.u16s(),
+            0x0010 => hello.alpn = Some(data.long()?.short()?.0.to_vec()),
+            0x002b => hello.versions = data.short()?.u16s(),
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+fn fingerprint(body: &[u8]) -> Option<TlsFingerprint> {
+    let hello = parse(body)?;
+    Some(TlsFingerprint {
+        ja3: ja3(&hello),
+        ja4: ja4(&hello),
+    })
+}
+
+fn join<T: ToString>(values: impl IntoIterator<Item = T>, separator: &str) -> String {
+    values
+        .into_iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// The MD5 of `version,ciphers,extensions,groups,point formats`, each list in
+/// the order sent and in decimal.
+fn ja3(hello: &Hello) -> String {
+    let text = [
+        hello.version.to_string(),
+        join(&hello.ciphers, "-"),
+        join(&hello.extensions, "-"),
+        join(&hello.groups, "-"),
+        join(&hello.point_formats, "-"),
+    ]
+    .join(",");
+    hex(&Md5::digest(text.as_bytes()))
+}
+
+/// `t13d1516h2_8daaf6152771_e5627efa2ab1`: a readable summary, then hashes of
+/// the sorted ciphers and of the sorted extensions with the signature algorithms.
+fn ja4(hello: &Hello) -> String {
+    let version = hello
+        .versions
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(hello.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+    let sni = if hello.sni { 'd' } else { 'i' };
+    // The first and last character of the first protocol, hex digits if they
+    // are not alphanumeric.
+    let alpn = match hello.alpn.as_deref() {
+        Some(value @ [first, .., last]) | Some(value @ [first @ last]) => {
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", *first as char, *last as char)
+            } else {
+                let hex = hex(value);
+                format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+            }
+        }
+        _ => "00".to_owned(),
+    };
+    let a = format!(
+        "t{version}{sni}{:02}{:02}{alpn}",
+        hello.ciphers.len().min(99),
+        hello.extensions.len().min(99),
+    );
+    let hash = |text: String| match text.is_empty() {
+        true => "000000000000".to_owned(),
+        false => hex(&Sha256::digest(text.as_bytes()))[..12].to_owned(),
+    };
+    let hexes = |values: &[u16]| {
+        let mut hexes: Vec<String> = values.iter().map(|v| format!("{v:04x}")).collect();
+        hexes.sort();
+        hexes.join(",")
+    };
+    let b = hash(hexes(&hello.ciphers));
+    // SNI and ALPN already show in the first part.
+    let extensions: Vec<u16> = hello
+        .extensions
+        .iter()
+        .copied()
+        .filter(|&kind| kind != 0x0000 && kind != 0x0010)
+        .collect();
+    let mut c = hexes(&extensions);
+    if !c.is_empty() && !hello.signature_algorithms.is_empty() {
+        c.push('_');
+        c.push_str(&join(
+            hello
+                .signature_algorithms
+                .iter()
+                .map(|v| format!("{v:04x}")),
+            ",",
+        ));
+    }
+    format!("{a}_{b}_{}", hash(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREASE: u16 = 0x1a1a;
+
+    /// `values` behind a length prefix of `width` bytes.
+    fn vector(width: usize, values: &[u8]) -> Vec<u8> {
+        let mut out = (values.len() as u32).to_be_bytes()[4 - width..].to_vec();
+        out.extend(values);
+        out
+    }
+
+    fn u16s(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// A ClientHello body with an empty session id and null compression.
+    fn hello(version: u16, ciphers: &[u16], extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = version.to_be_bytes().to_vec();
+        body.extend([7; 32]);
+        body.extend(vector(1, &[]));
+        body.extend(vector(2, &u16s(ciphers)));
+        body.extend(vector(1, &[0]));
+        let extensions: Vec<u8> = extensions
+            .iter()
+            .flat_map(|(kind, data)| [kind.to_be_bytes().to_vec(), vector(2, data)].concat())
+            .collect();
+        body.extend(vector(2, &extensions));
+        body
+    }
+
+    /// The example from the JA3 README.
+    fn ja3_example() -> Vec<u8> {
+        let ciphers = [47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4];
+        hello(
+            0x0301,
+            &ciphers,
+            &[
+                (0x0000, vector(2, &[0, 0, 1, b'x'])),
+                (0x000a, vector(2, &u16s(&[23, 24, 25]))),
+                (0x000b, vector(1, &[0])),
+            ],
+        )
+    }
+
+    /// A Chrome-like ClientHello matching the example in the JA4 spec, with
+    /// GREASE values in every list.
+    fn ja4_example() -> Vec<u8> {
+        let ciphers = [
+            GREASE, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+            0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ];
+        let signature_algorithms = [
+            0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+        ];
+        let alpn = [vector(1, b"h2"), vector(1, b"http/1.1")].concat();
+        hello(
+            0x0303,
+            &ciphers,
+            &[
+                (GREASE, Vec::new()),
+                (0x0000, vector(2, &[0, 0, 1, b'x'])),
+                (0x0017, Vec::new()),
+                (0xff01, vec![0]),
+                (0x000a, vector(2, &u16s(&[GREASE, 0x001d, 0x0017, 0x0018]))),
+                (0x000b, vector(1, &[0])),
+                (0x0023, Vec::new()),
+                (0x0010, vector(2, &alpn)),
+                (0x0005, vec![1, 0, 0, 0, 0]),
+                (0x000d, vector(2, &u16s(&signature_algorithms))),
+                (0x0012, Vec::new()),
+                (0x0033, vector(2, &[])),
+                (0x002d, vector(1, &[1])),
+                (0x002b, vector(1, &u16s(&[GREASE, 0x0304, 0x0303]))),
+                (0x001b, vector(1, &[0, 2])),
+                (0x4469, vector(2, &[])),
+                (0x0015, vec![0; 8]),
+            ],
+        )
+    }
+
+    #[test]
+    fn computes_ja3() {
+        let fingerprint = fingerprint(&ja3_example()).unwrap();
+        assert_eq!(fingerprint.ja3, "ada70206e40642a3e4461f35503241d5");
+        assert_eq!(fingerprint.ja4, "t10d120300_d94e65cdb899_33a13ba74d1c");
+    }
+
+    #[test]
+    fn computes_ja4() {
+        let hello = parse(&ja4_example()).unwrap();
+        assert_eq!(hello.ciphers.len(), 15);
+        assert_eq!(hello.extensions.len(), 16);
+        assert_eq!(hello.groups, [0x001d, 0x0017, 0x0018]);
+        assert_eq!(hello.versions, [0x0304, 0x0303]);
+        assert_eq!(ja4(&hello), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn parses_hellos_without_extensions() {
+        let mut body = hello(0x0303, &[0x002f], &[]);
+        body.truncate(body.len() - 2);
+        let hello = parse(&body).unwrap();
+        assert_eq!(hello.ciphers, [0x002f]);
+        assert!(hello.extensions.is_empty());
+        assert_eq!(ja4(&hello), "t12i010000_ba72b8082249_000000000000");
+    }
+
+    #[test]
+    fn refuses_truncated_hellos() {
+        let body = ja4_example();
+        // Inside the random, the cipher list and the last extension.
+        for len in [10, 40, body.len() - 1] {
+            assert!(parse(&body[..len]).is_none(), "cut at {len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_hellos_split_over_records() {
+        let body = ja3_example();
+        let mut handshake = vec![0x01];
+        handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend(&body);
+        let (first, second) = handshake.split_at(20);
+        let mut stream = Vec::new();
+        for fragment in [first, second] {
+            stream.extend([0x16, 0x03, 0x01]);
+            stream.extend(vector(2, fragment));
+        }
+        stream.extend(b"rest");
+        let mut read = Vec::new();
+        let hello = read_hello(&mut &stream[..], &mut read).await.unwrap();
+        assert_eq!(hello.unwrap(), body);
+        assert_eq!(read, stream[..stream.len() - 4]);
+        let mut read = Vec::new();
+        let not_tls = read_hello(&mut &b"GET / HTTP/1.1\r\n"[..], &mut read).await;
+        assert!(not_tls.unwrap().is_none());
+    }
+}
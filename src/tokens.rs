@@ -0,0 +1,368 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Error;
+use axum::{
+    extract::{Host, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use utoipa::ToSchema;
+
+use crate::{
+    auth::{constant_time_eq, ApiKey},
+    config::Fanout,
+    e2e,
+    hub::PollError,
+    AppError, AppState,
+};
+
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Tokens minted through `POST /tokens`. Self-made tokens keep working without an entry,
+/// unless `token_secret` is set.
+pub type Tokens = Arc<Mutex<HashMap<String, TokenInfo>>>;
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+pub struct TokenInfo {
+    pub token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// Hits and polls are refused after this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Hits must carry this as `s=`, so third parties cannot inject fake ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// Set by `POST /admin/tokens/:token/revoke`, hits and polls are refused from then on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// Overrides `delivery.fanout` for this token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fanout: Option<Fanout>,
+    /// Overrides `retention.days` for this token's stored hits, 0 keeps them forever.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u64>,
+    /// An age X25519 public key (`age1...`) hits are sealed to on arrival, see `e2e`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+impl TokenInfo {
+    pub fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= Utc::now())
+    }
+
+    /// Whether a hit presenting `secret` may be accepted for this token.
+    pub fn admits(&self, secret: Option<&str>) -> bool {
+        match (&self.secret, secret) {
+            (None, _) => true,
+            (Some(expected), Some(presented)) => {
+                constant_time_eq(expected.as_bytes(), presented.as_bytes())
+            }
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, ToSchema)]
+pub struct NewToken {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Lifetime in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// Generate a notify secret that hits must present as `s=`.
+    #[serde(default)]
+    pub secret: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fanout: Option<Fanout>,
+    /// Days to keep the token's stored hits instead of `retention.days`, 0 for forever.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u64>,
+    /// An age public key (`age1...`) to end-to-end encrypt the token's hits to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Created {
+    #[serde(flatten)]
+    pub info: TokenInfo,
+    pub notify_url: String,
+    pub poll_url: String,
+}
+
+/// 128 bits from the OS generator, hex encoded.
+fn generate() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    hex(&bytes)
+}
+
+/// Lowercase, two digits per byte.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn mac(secret: &str, id: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(id.as_bytes());
+    hex(&mac.finalize().into_bytes())
+}
+
+/// `id.mac`, where mac is the hex HMAC-SHA256 of `id` under `secret`.
+fn sign(secret: &str, id: &str) -> String {
+    format!("{id}.{}", mac(secret, id))
+}
+
+/// Whether `token` was signed with `secret`, no lookup needed.
+pub fn verify(secret: &str, token: &str) -> bool {
+    let Some((id, presented)) = token.rsplit_once('.') else {
+        return false;
+    };
+    constant_time_eq(mac(secret, id).as_bytes(), presented.as_bytes())
+}
+
+#[utoipa::path(
+    post,
+    path = "/tokens",
+    tag = "tokens",
+    request_body(content = Option<NewToken>, content_type = "application/json"),
+    responses(
+        (status = 201, description = "Minted", body = Created),
+        (status = 403, description = "Not an admin key"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn create(
+    State(state): State<AppState>,
+    Host(host): Host,
+    key: ApiKey,
+    body: Option<Json<NewToken>>,
+) -> Result<Response, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let new = body.map(|Json(new)| new).unwrap_or_default();
+    let Some(info) = mint(&state, new).await? else {
+        return Ok(StatusCode::BAD_REQUEST.into_response());
+    };
+    let base = state.public_url(&host);
+    Ok((StatusCode::CREATED, Json(Created::new(&base, info))).into_response())
+}
+
+/// Registers a token as `new` asks, `None` if its `ttl` is out of range or its
+/// `public_key` is not an age public key.
+pub async fn mint(state: &AppState, new: NewToken) -> Result<Option<TokenInfo>, Error> {
+    if new
+        .public_key
+        .as_deref()
+        .is_some_and(|key| e2e::recipient(key).is_none())
+    {
+        return Ok(None);
+    }
+    let created_at = Utc::now();
+    let expires_at = match new.ttl {
+        Some(ttl) => {
+            let expires_at = i64::try_from(ttl)
+                .ok()
+                .and_then(Duration::try_seconds)
+                .and_then(|ttl| created_at.checked_add_signed(ttl));
+            let Some(expires_at) = expires_at else {
+                return Ok(None);
+            };
+            Some(expires_at)
+        }
+        None => None,
+    };
+    let info = TokenInfo {
+        token: match &state.config().token_secret {
+            Some(secret) => sign(secret, &generate()),
+            None => generate(),
+        },
+        label: new.label,
+        created_at,
+        expires_at,
+        secret: new.secret.then(generate),
+        revoked_at: None,
+        fanout: new.fanout,
+        retention_days: new.retention_days,
+        public_key: new.public_key.map(|key| key.trim().to_owned()),
+    };
+    state.storage.save_token(&info).await?;
+    if let Some(cluster) = &state.cluster {
+        cluster.save_token(&info).await?;
+    }
+    state
+        .tokens
+        .lock()
+        .expect("")
+        .insert(info.token.clone(), info.clone());
+    Ok(Some(info))
+}
+
+impl Created {
+    /// `info` with the links to use it, under `base`.
+    pub fn new(base: &str, info: TokenInfo) -> Created {
+        Created {
+            notify_url: match &info.secret {
+                Some(secret) => format!("{base}/notify?token={}&s={secret}", info.token),
+                None => format!("{base}/notify?token={}", info.token),
+            },
+            poll_url: format!("{base}/poll-notified?token={}", info.token),
+            info,
+        }
+    }
+}
+
+/// Registered tokens the key may poll, oldest first. Only admins see notify secrets.
+#[utoipa::path(
+    get,
+    path = "/api/tokens",
+    tag = "tokens",
+    responses((status = 200, body = [TokenInfo])),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn list(State(state): State<AppState>, key: ApiKey) -> Json<Vec<TokenInfo>> {
+    Json(visible(&state, &key))
+}
+
+/// The registered tokens `key` may poll, oldest first, secrets only for admins.
+pub fn visible(state: &AppState, key: &ApiKey) -> Vec<TokenInfo> {
+    let mut tokens: Vec<TokenInfo> = state
+        .tokens
+        .lock()
+        .expect("")
+        .values()
+        .filter(|info| key.allows(&info.token))
+        .cloned()
+        .collect();
+    tokens.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.token.cmp(&b.token)));
+    if !key.is_admin() {
+        for info in &mut tokens {
+            info.secret = None;
+        }
+    }
+    tokens
+}
+
+/// Periodically drops whatever is still buffered or waiting for expired tokens.
+pub async fn purge_loop(state: AppState) {
+    loop {
+        tokio::time::sleep(PURGE_INTERVAL).await;
+        let expired: Vec<String> = {
+            let tokens = state.tokens.lock().expect("");
+            tokens
+                .values()
+                .filter(|info| info.expired())
+                .map(|info| info.token.clone())
+                .collect()
+        };
+        let mut settled = Vec::new();
+        for token in expired {
+            let (buffered, pollers) = state.futures.lock().expect("").purge(&token);
+            settled.extend(buffered.iter().map(|n| n.id));
+            for poller in pollers {
+                poller.fulfill(Err(PollError::Expired));
+            }
+        }
+        state.settle(settled);
+    }
+}
+
+/// Deletes a token and every hit for it, buffered or stored. Its waiting polls
+/// end with 410 as if it had expired.
+#[utoipa::path(
+    delete,
+    path = "/api/tokens/{token}",
+    tag = "tokens",
+    params(("token" = String, Path, description = "Token")),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 403, description = "Not an admin key"),
+        (status = 404, description = "Nothing known about the token"),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn delete(
+    Path(token): Path<String>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<StatusCode, AppError> {
+    if !key.is_admin() {
+        return Ok(StatusCode::FORBIDDEN);
+    }
+    let registered = state.tokens.lock().expect("").remove(&token).is_some();
+    let (buffered, pollers) = state.futures.lock().expect("").purge(&token);
+    let in_use = !buffered.is_empty() || !pollers.is_empty();
+    for poller in pollers {
+        poller.fulfill(Err(PollError::Expired));
+    }
+    if let Some(cluster) = &state.cluster {
+        cluster.delete_token(&token).await?;
+    }
+    let stored = state.storage.delete_token(&token).await?;
+    Ok(match registered || in_use || stored > 0 {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123";
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "what do ya want for nothing?.\
+             5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn verifies_signed_tokens() {
+        let token = sign(SECRET, &generate());
+        assert!(verify(SECRET, &token));
+        // Ids may contain dots themselves.
+        assert!(verify(SECRET, &sign(SECRET, "team.a")));
+    }
+
+    #[test]
+    fn refuses_forged_tokens() {
+        let token = sign(SECRET, "abcd");
+        let (id, mac) = token.rsplit_once('.').unwrap();
+        assert!(!verify("another secret of length", &token));
+        assert!(!verify(SECRET, &format!("abce.{mac}")));
+        assert!(!verify(SECRET, &format!("{id}.{}", &mac[1..])));
+        assert!(!verify(SECRET, &format!("{id}.{}", mac.to_uppercase())));
+        assert!(!verify(SECRET, &format!("{token}0")));
+        assert!(!verify(SECRET, id));
+        assert!(!verify(SECRET, ""));
+        assert!(!verify(SECRET, "."));
+    }
+
+    #[test]
+    fn generates_distinct_hex_ids() {
+        let (a, b) = (generate(), generate());
+        assert_eq!(a.len(), 32);
+        assert!(a
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()));
+        assert_ne!(a, b);
+    }
+}
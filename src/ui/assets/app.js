@@ -0,0 +1,260 @@
+// Dashboard for /ui: lists tokens, shows stored hits and follows new ones
+// through /events. Everything a payload sent is untrusted and only ever goes
+// into the page as text, never as markup.
+"use strict";
+
+const PER_PAGE = 50;
+const IMAGE_TYPES = ["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+const $ = (id) => document.getElementById(id);
+let current = null;
+let stream = null;
+
+function headers() {
+  const key = localStorage.getItem("xss_check_srv.key");
+  return key ? { "X-Api-Key": key } : {};
+}
+
+async function api(path) {
+  const response = await fetch(path, { headers: headers() });
+  if (!response.ok) {
+    throw new Error(`${path}: ${response.status} ${response.statusText}`);
+  }
+  return response.json();
+}
+
+function element(tag, text, className) {
+  const node = document.createElement(tag);
+  if (text !== undefined && text !== null) {
+    node.textContent = text;
+  }
+  if (className) {
+    node.className = className;
+  }
+  return node;
+}
+
+async function loadTokens() {
+  const list = $("tokens");
+  list.replaceChildren();
+  try {
+    const tokens = await api("/api/tokens");
+    $("tokens-status").textContent = tokens.length ? "" : "No tokens minted yet.";
+    for (const info of tokens) {
+      const item = element("li", info.label || info.token);
+      if (info.label) {
+        item.append(element("small", info.token));
+      }
+      if (info.revoked_at) {
+        item.classList.add("revoked");
+      }
+      item.dataset.token = info.token;
+      item.addEventListener("click", () => select(info.token));
+      list.append(item);
+    }
+  } catch (e) {
+    $("tokens-status").textContent = e.message;
+  }
+}
+
+function bytes(base64) {
+  return Uint8Array.from(atob(base64), (c) => c.charCodeAt(0));
+}
+
+// Inline attachments carry their bytes, files kept in a directory or bucket
+// are fetched through /api/attachments with the API key.
+async function content(file, type) {
+  if (file.data) {
+    return new Blob([bytes(file.data)], { type });
+  }
+  const path = `/api/attachments/${encodeURIComponent(file.id)}`;
+  const response = await fetch(path, { headers: headers() });
+  if (!response.ok) {
+    throw new Error(`${path}: ${response.status} ${response.statusText}`);
+  }
+  return new Blob([await response.blob()], { type });
+}
+
+function attachment(file) {
+  const type = (file.content_type || "").split(";")[0].trim().toLowerCase();
+  const name = file.filename || file.name;
+  if (IMAGE_TYPES.includes(type)) {
+    const image = element("img");
+    image.alt = name;
+    if (file.data) {
+      image.src = `data:${type};base64,${file.data}`;
+    } else {
+      content(file, type)
+        .then((blob) => (image.src = URL.createObjectURL(blob)))
+        .catch((e) => (image.alt = `${name}: ${e.message}`));
+    }
+    return image;
+  }
+  let blob = null;
+  const load = async () => (blob ??= await content(file, "application/octet-stream"));
+  const wrapper = element("span");
+  const download = element("a", `Download ${name}`);
+  download.href = "#";
+  download.download = name;
+  download.addEventListener("click", async (event) => {
+    if (download.href.startsWith("blob:")) {
+      return;
+    }
+    event.preventDefault();
+    try {
+      download.href = URL.createObjectURL(await load());
+      download.click();
+    } catch (e) {
+      download.textContent = `Download ${name} failed: ${e.message}`;
+    }
+  });
+  wrapper.append(download);
+  if (type === "text/html") {
+    const view = element("button", "View DOM");
+    view.type = "button";
+    view.addEventListener("click", async () => {
+      // Sandboxed without scripts, and the page's CSP keeps it from loading anything.
+      $("frame").srcdoc = await (await load()).text();
+      $("viewer").showModal();
+    });
+    wrapper.append(" ", view);
+  }
+  return wrapper;
+}
+
+function row(table, name, value) {
+  const tr = element("tr");
+  tr.append(element("th", name), element("td", value ?? "unknown"));
+  table.append(tr);
+}
+
+function render(notification, isNew) {
+  const item = element("li", null, isNew ? "hit new" : "hit");
+  item.append(
+    element("h3", `#${notification.seq} · ${new Date(notification.received_at).toLocaleString()}`)
+  );
+  const table = element("table");
+  const meta = notification.meta || {};
+  row(table, "IP", meta.client_ip);
+  row(table, "User-Agent", meta.user_agent);
+  row(table, "Referer", meta.referer);
+  for (const [name, value] of Object.entries(meta.headers || {})) {
+    row(table, name, value);
+  }
+  for (const [name, value] of Object.entries(notification.data || {})) {
+    row(table, name, value);
+  }
+  item.append(table);
+  const files = notification.attachments || [];
+  if (files.length) {
+    const box = element("div", null, "attachments");
+    for (const file of files) {
+      box.append(attachment(file));
+    }
+    item.append(box);
+  }
+  return item;
+}
+
+async function loadHistory(token) {
+  const query = (page) =>
+    `/api/notifications?token=${encodeURIComponent(token)}&page=${page}&per_page=${PER_PAGE}`;
+  let history = await api(query(1));
+  const pages = Math.ceil(history.total / PER_PAGE);
+  if (pages > 1) {
+    history = await api(query(pages));
+  }
+  const hits = $("hits");
+  for (const notification of history.notifications) {
+    hits.prepend(render(notification, false));
+  }
+  $("hits-status").textContent = history.total
+    ? `${history.total} stored hits`
+    : "No stored hits, new ones show up here as they arrive.";
+}
+
+// EventSource cannot send an API key, so read /events through fetch instead.
+async function follow(token, controller) {
+  const response = await fetch(`/events?token=${encodeURIComponent(token)}`, {
+    headers: headers(),
+    signal: controller.signal,
+  });
+  if (!response.ok) {
+    throw new Error(`/events: ${response.status} ${response.statusText}`);
+  }
+  $("live").textContent = "● live";
+  const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+  let buffer = "";
+  for (;;) {
+    const { value, done } = await reader.read();
+    if (done) {
+      break;
+    }
+    buffer += value;
+    let end;
+    while ((end = buffer.indexOf("\n\n")) >= 0) {
+      const event = buffer.slice(0, end);
+      buffer = buffer.slice(end + 2);
+      const data = event
+        .split("\n")
+        .filter((line) => line.startsWith("data:"))
+        .map((line) => line.slice(5).trimStart())
+        .join("\n");
+      if (data) {
+        $("hits").prepend(render(JSON.parse(data), true));
+      }
+    }
+  }
+}
+
+async function select(token) {
+  current = token;
+  if (stream) {
+    stream.abort();
+  }
+  stream = new AbortController();
+  const controller = stream;
+  for (const item of $("tokens").children) {
+    item.classList.toggle("selected", item.dataset.token === token);
+  }
+  $("current").textContent = token;
+  $("hits").replaceChildren();
+  $("live").textContent = "";
+  try {
+    await loadHistory(token);
+  } catch (e) {
+    $("hits-status").textContent = e.message;
+  }
+  while (!controller.signal.aborted) {
+    try {
+      await follow(token, controller);
+      $("live").textContent = "reconnecting…";
+    } catch (e) {
+      if (controller.signal.aborted) {
+        return;
+      }
+      $("live").textContent = `disconnected: ${e.message}`;
+    }
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+  }
+}
+
+$("key-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  localStorage.setItem("xss_check_srv.key", $("key").value);
+  loadTokens();
+  if (current) {
+    select(current);
+  }
+});
+
+$("watch-form").addEventListener("submit", (event) => {
+  event.preventDefault();
+  const token = $("watch").value.trim();
+  if (token) {
+    select(token);
+  }
+});
+
+$("key").value = localStorage.getItem("xss_check_srv.key") || "";
+loadTokens();
@@ -0,0 +1,41 @@
+use axum::{
+    extract::Path,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use rust_embed::RustEmbed;
+
+/// The dashboard at `/ui`, compiled into the binary.
+#[derive(RustEmbed)]
+#[folder = "src/ui/assets"]
+struct Assets;
+
+/// Nothing but the dashboard's own files, and captured screenshots as data
+/// URLs. Scripts in payload data must never run here.
+const CSP: &str = "default-src 'none'; script-src 'self'; style-src 'self'; \
+    img-src 'self' data: blob:; connect-src 'self'; frame-src 'self'; \
+    base-uri 'none'; form-action 'none'; frame-ancestors 'none'";
+
+pub async fn index() -> Response {
+    serve("index.html")
+}
+
+pub async fn asset(Path(path): Path<String>) -> Response {
+    serve(&path)
+}
+
+fn serve(path: &str) -> Response {
+    let Some(file) = Assets::get(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    (
+        [
+            (header::CONTENT_TYPE, file.metadata.mimetype().to_owned()),
+            (header::CONTENT_SECURITY_POLICY, CSP.to_owned()),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+            (header::CACHE_CONTROL, "no-cache".to_owned()),
+        ],
+        file.data,
+    )
+        .into_response()
+}
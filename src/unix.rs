@@ -0,0 +1,127 @@
+//! Serves the routes on `unix.path` for a reverse proxy on the same host.
+
+use std::{
+    fs::Permissions,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{bail, Context as _, Error};
+use axum::{extract::connect_info::Connected, Router};
+use hyper::server::accept::Accept;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{UnixListener, UnixStream},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{restrict, Config};
+
+/// Serves `app` on `unix.path` until `stopping` is cancelled, then gives open
+/// requests `grace` to finish. Replaces a socket left behind by an earlier run
+/// and removes it again on the way out, unless the socket was `inherited` from
+/// systemd, which owns it. Does nothing when `unix` is not configured.
+pub async fn serve(
+    config: Arc<Config>,
+    app: Router,
+    inherited: Option<std::os::unix::net::UnixListener>,
+    stopping: CancellationToken,
+    grace: Duration,
+) -> Result<(), Error> {
+    let Some(unix) = &config.unix else {
+        return Ok(());
+    };
+    let path = &unix.path;
+    let owned = inherited.is_none();
+    let listener = match inherited {
+        Some(listener) => UnixListener::from_std(listener)?,
+        None => bind(path, unix.mode)?,
+    };
+    info!("Listening on unix:{}", path.display());
+    let app = restrict(app, &unix.routes);
+    let server = axum::Server::builder(Listener(listener))
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(stopping.clone().cancelled_owned());
+    let served = tokio::select! {
+        served = server => served.map_err(Error::from),
+        _ = async {
+            stopping.cancelled().await;
+            tokio::time::sleep(grace).await;
+        } => Ok(()),
+    };
+    if owned {
+        let _ = std::fs::remove_file(path);
+    }
+    served
+}
+
+fn bind(path: &Path, mode: u32) -> Result<UnixListener, Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("binding {}", path.display()))?;
+    std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+struct Listener(UnixListener);
+
+impl Accept for Listener {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Connection, io::Error>>> {
+        let (stream, _) = ready!(self.0.poll_accept(cx))?;
+        Poll::Ready(Some(Ok(Connection(stream))))
+    }
+}
+
+/// A socket connection, which handlers see as coming from `127.0.0.1`.
+struct Connection(UnixStream);
+
+impl Connected<&Connection> for SocketAddr {
+    fn connect_info(_: &Connection) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0))
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,148 @@
+//! Reads `POST /notify` bodies as they arrive rather than buffering them whole.
+//! With `attachments` set, files in multipart bodies go straight to spool files
+//! and are hashed on the way, so a 200 MB screenshot never sits in memory and
+//! is refused as soon as it passes `attachments.max_size`.
+
+use std::sync::Arc;
+
+use anyhow::Error;
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::StatusCode,
+};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use crate::{attachments::Store, model::Attachment, tokens::hex};
+
+/// Fields and attachments of a body, or the status it is refused with.
+pub type Parsed = Result<(Vec<(String, String)>, Vec<Attachment>), StatusCode>;
+
+/// The whole body, refused once it is over `limit`.
+pub async fn read(mut body: Body, limit: usize) -> Result<Bytes, StatusCode> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if read.len() + chunk.len() > limit {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read.into())
+}
+
+/// What a multipart body may carry.
+pub struct Limits {
+    /// Bytes of everything but spooled files.
+    pub max_body: usize,
+    /// Parts of any kind.
+    pub max_parts: usize,
+    /// Bytes of each spooled file.
+    pub max_size: usize,
+    /// Bytes of all spooled files together.
+    pub max_total: usize,
+}
+
+/// Plain parts become fields, parts with a filename attachments. Refused as
+/// soon as any of `limits` is passed.
+pub async fn multipart(
+    content_type: &str,
+    body: Body,
+    store: Option<&Store>,
+    limits: &Limits,
+) -> Result<Parsed, Error> {
+    let Ok(boundary) = multer::parse_boundary(content_type) else {
+        return Ok(Err(StatusCode::BAD_REQUEST));
+    };
+    // Without spooling, refused before the first part when the client already
+    // said it is too big.
+    if store.is_none() && body.size_hint().lower() > limits.max_body as u64 {
+        return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+    let mut multipart = multer::Multipart::new(body, boundary);
+    let (mut fields, mut attachments) = (Vec::new(), Vec::new());
+    let (mut budget, mut spool_budget) = (limits.max_body, limits.max_total);
+    let mut parts = 0;
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(_) => return Ok(Err(StatusCode::BAD_REQUEST)),
+        };
+        parts += 1;
+        if parts > limits.max_parts {
+            return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        let name = field.name().unwrap_or_default().to_owned();
+        let filename = field.file_name().map(str::to_owned);
+        let content_type = field.content_type().map(|mime| mime.to_string());
+        let (Some(filename), Some(store)) = (&filename, store) else {
+            let mut data = Vec::new();
+            loop {
+                match field.chunk().await {
+                    Ok(Some(chunk)) if chunk.len() > budget => {
+                        return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE));
+                    }
+                    Ok(Some(chunk)) => {
+                        budget -= chunk.len();
+                        data.extend_from_slice(&chunk);
+                    }
+                    Ok(None) => break,
+                    Err(_) => return Ok(Err(StatusCode::BAD_REQUEST)),
+                }
+            }
+            match filename {
+                None => match String::from_utf8(data) {
+                    Ok(value) => fields.push((name, value)),
+                    Err(_) => return Ok(Err(StatusCode::BAD_REQUEST)),
+                },
+                Some(filename) => attachments.push(Attachment {
+                    name,
+                    filename: Some(filename),
+                    content_type,
+                    id: None,
+                    size: data.len() as u64,
+                    url: None,
+                    data,
+                    spooled: None,
+                }),
+            }
+            continue;
+        };
+        let (file, path) = store.spool()?.into_parts();
+        let mut file = tokio::fs::File::from_std(file);
+        let (mut hash, mut size) = (Sha256::new(), 0);
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk))
+                    if size + chunk.len() > limits.max_size || chunk.len() > spool_budget =>
+                {
+                    return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE));
+                }
+                Ok(Some(chunk)) => {
+                    size += chunk.len();
+                    spool_budget -= chunk.len();
+                    hash.update(&chunk);
+                    file.write_all(&chunk).await?;
+                }
+                Ok(None) => break,
+                Err(_) => return Ok(Err(StatusCode::BAD_REQUEST)),
+            }
+        }
+        file.flush().await?;
+        attachments.push(Attachment {
+            name,
+            filename: Some(filename.clone()),
+            content_type,
+            id: Some(hex(&hash.finalize())),
+            size: size as u64,
+            url: None,
+            data: Vec::new(),
+            spooled: Some(Arc::new(path)),
+        });
+    }
+    Ok(Ok((fields, attachments)))
+}
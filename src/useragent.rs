@@ -0,0 +1,26 @@
+//! Reads the browser, OS and device class out of the User-Agent of a hit.
+
+use woothee::{parser::Parser, woothee::VALUE_UNKNOWN};
+
+use crate::model::{Agent, Device};
+
+/// What `user_agent` names, `None` if it is not recognised at all.
+pub fn parse(user_agent: &str) -> Option<Agent> {
+    let parsed = Parser::new().parse(user_agent)?;
+    let known =
+        |value: &str| (!value.is_empty() && value != VALUE_UNKNOWN).then(|| value.to_owned());
+    let agent = Agent {
+        browser: known(parsed.name),
+        browser_version: known(parsed.version),
+        os: known(parsed.os),
+        os_version: known(&parsed.os_version),
+        device: match parsed.category {
+            "pc" => Device::Desktop,
+            "smartphone" | "mobilephone" => Device::Mobile,
+            "crawler" => Device::Bot,
+            "appliance" => Device::Appliance,
+            _ => Device::Other,
+        },
+    };
+    (agent.browser.is_some() || agent.os.is_some()).then_some(agent)
+}
@@ -0,0 +1,66 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use utoipa::IntoParams;
+
+use crate::{auth::ApiKey, hub::Subscription, AppState};
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Subscribe {
+    pub token: String,
+}
+
+/// Streams every notification for `token` over a websocket, one JSON text frame each.
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "polling",
+    params(Subscribe),
+    responses((status = 101, description = "Switching to a websocket carrying one `Notification` per text frame")),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn subscribe(
+    ws: WebSocketUpgrade,
+    Query(Subscribe { token }): Query<Subscribe>,
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Response {
+    if let Err(e) = state.accepting_polls() {
+        return e.into_response();
+    }
+    if let Err(status) = key.check(&token) {
+        return status.into_response();
+    }
+    if let Err(status) = state.check_token(&token) {
+        return status.into_response();
+    }
+    ws.on_upgrade(move |socket| forward(socket, Subscription::new(&state, token)))
+}
+
+async fn forward(mut socket: WebSocket, mut subscription: Subscription) {
+    loop {
+        tokio::select! {
+            notification = subscription.rx.recv() => {
+                let Some(notification) = notification else {
+                    return;
+                };
+                let Ok(text) = serde_json::to_string(&notification) else {
+                    continue;
+                };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    return;
+                }
+            }
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}